@@ -0,0 +1,64 @@
+use cloudevents::event::Event;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use tempfile::{tempdir, TempDir};
+use tokio::runtime::Runtime;
+
+use hematite::db::{Database, ExpectedRevision};
+
+fn populated_db(runtime: &Runtime, compression_block_events: Option<usize>) -> (TempDir, Database) {
+    let dir = tempdir().unwrap();
+    let mut db = Database::new(dir.path());
+    db.set_segment_bytes(Some(1024 * 1024));
+    db.set_compression_block_events(compression_block_events);
+
+    runtime
+        .block_on(async {
+            db.start().await.expect("Failed to start DB");
+
+            for _n in 1..100_000 {
+                let event = Event::default();
+                db.append(vec![event], ExpectedRevision::Any).await
+                    .expect("Could not insert value into DB");
+            }
+        });
+
+    (dir, db)
+}
+
+fn compression_bench(c: &mut Criterion) {
+    let runtime =
+        tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let (_plain_dir, plain) = populated_db(&runtime, None);
+    let (_compressed_dir, compressed) = populated_db(&runtime, Some(64));
+
+    runtime
+        .block_on(async {
+            eprintln!("uncompressed usage: {} bytes", plain.file_len().await.unwrap());
+            eprintln!("compressed usage: {} bytes", compressed.file_len().await.unwrap());
+        });
+
+    let mut group = c.benchmark_group("read event");
+
+    for (name, db) in [("uncompressed", &plain), ("compressed", &compressed)] {
+        group.bench_function(name, |b| {
+            b
+            .to_async(&runtime)
+            .iter_batched(
+                || db,
+                |db| async move {
+                    db.query(50_000, 1).await.expect("Failed to read DB");
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, compression_bench);
+criterion_main!(benches);
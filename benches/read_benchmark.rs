@@ -12,9 +12,11 @@ fn read_bench(c: &mut Criterion) {
         .unwrap();
 
     let dir = tempdir().unwrap();
-    let db = Database::new(dir.path());
+    let mut db = Database::new(dir.path());
     runtime
         .block_on(async {
+            db.start().await.expect("Failed to start DB");
+
             for _n in 1..100_000 {
                 let event = Event::default();
                 db.append(vec![event], ExpectedRevision::Any).await
@@ -26,7 +28,7 @@ fn read_bench(c: &mut Criterion) {
         b
         .to_async(&runtime)
         .iter_batched(
-            || db.clone(),
+            || &db,
             |db| async move {
                 db.query(50_000, 1).await.expect("Failed to read DB");
             },
@@ -0,0 +1,57 @@
+use cloudevents::event::Event;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use tempfile::{tempdir, TempDir};
+use tokio::runtime::Runtime;
+
+use hematite::db::{Database, ExpectedRevision, StorageFormat};
+
+fn populated_db(runtime: &Runtime, storage_format: StorageFormat) -> (TempDir, Database) {
+    let dir = tempdir().unwrap();
+    let mut db = Database::new(dir.path());
+    db.set_storage_format(storage_format);
+
+    runtime
+        .block_on(async {
+            db.start().await.expect("Failed to start DB");
+
+            for _n in 1..100_000 {
+                let event = Event::default();
+                db.append(vec![event], ExpectedRevision::Any).await
+                    .expect("Could not insert value into DB");
+            }
+        });
+
+    (dir, db)
+}
+
+fn storage_format_bench(c: &mut Criterion) {
+    let runtime =
+        tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let (_ndjson_dir, ndjson) = populated_db(&runtime, StorageFormat::Ndjson);
+    let (_binary_dir, binary) = populated_db(&runtime, StorageFormat::Binary);
+
+    let mut group = c.benchmark_group("read 1000 events");
+
+    for (name, db) in [("ndjson", &ndjson), ("binary", &binary)] {
+        group.bench_function(name, |b| {
+            b
+            .to_async(&runtime)
+            .iter_batched(
+                || db,
+                |db| async move {
+                    db.query(50_000, 1000).await.expect("Failed to read DB");
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, storage_format_bench);
+criterion_main!(benches);
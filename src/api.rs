@@ -1,51 +1,84 @@
 use axum::{
+    body::{Body, Bytes},
     Extension,
     extract::{
+        DefaultBodyLimit,
+        FromRequest,
         Json,
         Path,
         Query,
         Request,
         State,
     },
-    http::{header, StatusCode},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
     middleware::{self, Next},
     Router,
     routing::{get, post},
     response::{
+        sse::{self, KeepAlive, Sse},
         IntoResponse,
         Response,
     }
 };
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, ensure, Result};
 use axum_macros::debug_handler;
-use cloudevents::Event;
+use cloudevents::{
+    event::{Data, SpecVersion},
+    message::{self, BinaryDeserializer, BinarySerializer, MessageAttributeValue},
+    AttributesReader,
+    Event,
+};
+use futures::{future, stream, StreamExt};
 use jsonwebtoken::errors::ErrorKind;
-use tower_http::services::ServeFile;
-use tracing::{error, debug};
+use tower_http::{cors::{AllowOrigin, CorsLayer}, limit::RequestBodyLimitLayer, services::ServeFile};
+use tracing::{error, debug, info, Instrument};
 use serde::{Deserialize, Serialize};
-use time::{OffsetDateTime, format_description::well_known::Rfc2822};
+use time::{OffsetDateTime, format_description::well_known::{Rfc2822, Rfc3339}};
 use url::Url;
 use uuid::Uuid;
 use std::{
+    cmp::Reverse,
     collections::HashMap,
-    sync::Arc, path::PathBuf,
+    convert::Infallible,
+    fmt,
+    pin::pin,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use crate::{
-    db::{self, ExpectedRevision},
+    config::{Config, ContentSecurityPolicy, RequestIdFormat},
+    db::{self, ExpectedRevision, StreamMetadata, TimeRange},
+    enrichment,
+    ingest::{self, EventSource},
     server::{
         self,
         AppState,
+        Consistency,
+        EventFilter,
+        QuotaExceeded,
+        StreamExists,
         User,
-    }, openid::OpenIdClient
+    },
+    openid::{Claims, InactiveToken, InvalidUserClaim, OpenIdClient, ProviderTimeout, UnknownIssuer},
+    rate_limit::RateLimiter,
+    validation,
 };
 
 #[derive(Debug, Default, Serialize)]
 struct ApiErrorSource {
     header: Option<String>,
     query: Option<String>,
+    pointer: Option<String>,
 }
 
 impl ApiErrorSource {
+    fn pointer(pointer: &str) -> Self {
+        Self {
+            pointer: Some(pointer.to_string()),
+            ..Default::default()
+        }
+    }
+
     fn header(name: &str) -> Self {
         Self {
             header: Some(name.to_string()),
@@ -61,9 +94,57 @@ impl ApiErrorSource {
     }
 }
 
+/// Stable, machine-readable identifiers for each kind of error the API returns,
+/// serialized in `snake_case` as the `code` member of an error object.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ErrorCode {
+    /// `401`: the Bearer token is missing or invalid.
+    NotAuthenticated,
+    /// `403`: the Bearer token is valid, but doesn't grant the scope the request needs.
+    InsufficientScope,
+    /// `503`: the OpenID provider didn't respond in time, so the Bearer token couldn't be checked.
+    AuthUnavailable,
+    /// `400`: a query parameter or request document could not be parsed.
+    InvalidParameter,
+    /// `422`: a posted event failed validation.
+    InvalidEvent,
+    /// `409`: the stream's revision didn't match `expected_revision`.
+    RevisionMismatch,
+    /// `409`: the stream already contains an event with the same `source` and `id`.
+    SourceIdConflict,
+    /// `409`: the stream recently received an event with the same `id`.
+    IdConflict,
+    /// `423`: another client holds the stream's write lease.
+    LeaseHeld,
+    /// `413`: the request body, or one of the events in it, exceeds the configured limit.
+    PayloadTooLarge,
+    /// `409`: the stream's next rownums are reserved, so events can only be posted into them.
+    Reserved,
+    /// `409`: events were posted into rownums that aren't reserved or are already filled.
+    NotReserved,
+    /// `403`: the server isn't allowed to fetch events from the URL given to ingest.
+    SourceNotAllowed,
+    /// `502`: the URL given to ingest couldn't be fetched, or responded with an error.
+    SourceUnavailable,
+    /// `403`: the route is only open to the users in `admin_users`.
+    NotAdmin,
+    /// `409`: a stream can't be moved to an ID that already has a stream.
+    StreamExists,
+    /// `403`: the stream is sealed, so nothing can be appended to it.
+    StreamSealed,
+    /// `429`: the user has posted too many events recently, and should wait for `Retry-After`.
+    RateLimited,
+    /// `403`: the write would take the user past their quota of streams or bytes.
+    QuotaExceeded,
+    /// `500`: something went wrong on the server. Details are logged under the error's `id`.
+    InternalError,
+}
+
 #[derive(Debug, Serialize)]
 struct ApiError {
     id: Uuid,
+    code: ErrorCode,
     title: String,
     detail: Option<String>,
     source: Option<ApiErrorSource>,
@@ -75,25 +156,100 @@ impl ApiError {
     }
 }
 
+/// Media type of JSON:API documents.
+const JSON_API_CONTENT_TYPE: &str = "application/vnd.api+json";
+
+/// Responds with a JSON:API document, with the JSON:API media type. Plain JSON, like the health
+/// check and bare CloudEvents, is sent with `Json` instead.
+struct JsonApi<T>(T);
+
+impl<T: Serialize> IntoResponse for JsonApi<T> {
+    fn into_response(self) -> Response {
+        let mut response = Json(self.0).into_response();
+
+        // A document that failed to serialize has become a plaintext error, which keeps its type.
+        if let Some(content_type) = response.headers_mut().get_mut(header::CONTENT_TYPE).filter(|content_type| *content_type == "application/json") {
+            *content_type = HeaderValue::from_static(JSON_API_CONTENT_TYPE);
+        }
+
+        response
+    }
+}
+
 #[derive(Debug, Serialize)]
 struct ApiDataDocument<T> {
     data: ApiResource<T>,
 }
 
+/// A document with only top-level `meta`, for responses that report something other than
+/// resources.
+#[derive(Debug, Serialize)]
+struct ApiMetaDocument<T> {
+    meta: T,
+}
+
 #[derive(Debug, Serialize)]
 struct ApiDataCollectionDocument<T> {
     data: Vec<ApiResource<T>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    links: Option<PaginationLinks>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    meta: Option<CollectionMeta>,
+}
+
+impl<T> ApiDataCollectionDocument<T> {
+    fn new(data: Vec<ApiResource<T>>) -> Self {
+        Self { data, links: None, meta: None }
+    }
+
+    /// A document holding one page of a larger collection.
+    fn with_pagination(data: Vec<ApiResource<T>>, links: PaginationLinks, meta: CollectionMeta) -> Self {
+        Self { data, links: Some(links), meta: Some(meta) }
+    }
+}
+
+/// Links to the pages around a page of a collection. `prev` and `next` are null at either end.
+#[derive(Debug, Serialize)]
+struct PaginationLinks {
+    #[serde(rename = "self")]
+    this: String,
+    first: String,
+    prev: Option<String>,
+    next: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct CollectionMeta {
+    /// Number of items in the whole collection.
+    total: u64,
 }
 
 #[derive(Debug, Serialize)]
 struct ApiErrorDocument {
     errors: Option<Vec<ApiError>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    meta: Option<ApiErrorMeta>,
+}
+
+#[derive(Debug, Serialize)]
+struct ApiErrorMeta {
+    /// Number of errors found, which may be more than the document lists.
+    total_errors: usize,
 }
 
 impl ApiErrorDocument {
     fn with_error(error: ApiError) -> Self {
         Self {
             errors: Some(vec![error]),
+            meta: None,
+        }
+    }
+
+    /// A document listing some of `total_errors` errors.
+    fn with_errors(errors: Vec<ApiError>, total_errors: usize) -> Self {
+        Self {
+            errors: Some(errors),
+            meta: Some(ApiErrorMeta { total_errors }),
         }
     }
 }
@@ -121,33 +277,255 @@ impl<T> ApiResource<T> {
 async fn health(state: State<Arc<AppState>>) -> Response {
     let health = state.check_health();
 
-    return (
+    (
         [(header::CACHE_CONTROL, "public, max-age=60")],
         Json::from(health),
-    ).into_response();
+    ).into_response()
 }
 
 #[tracing::instrument]
-pub async fn stream_routes(streams_dir: PathBuf, oidc_url: Url) -> Result<Router<()>> {
-    let state = Arc::new(AppState::new(streams_dir).await?);
+pub async fn stream_routes(state: Arc<AppState>, oidc_urls: Vec<Url>) -> Result<Router<()>> {
+    let config = state.config();
+    ensure!(
+        config.token_introspection.is_some() || !config.jwt_audiences.is_empty(),
+        "Env var HEMATITE_JWT_AUD is missing.",
+    );
 
-    let oidc_client = Arc::new(OpenIdClient::new(oidc_url));
+    let oidc_client = OpenIdClient::new(oidc_urls, config.jwks_cache_ttl, config.jwt_leeway)
+        .with_audiences(config.jwt_audiences.clone())
+        .with_trusted_issuers(config.jwt_trusted_issuers.clone())
+        .with_introspection(config.token_introspection.clone())
+        .with_user_claim(config.user_claim.clone())
+        .with_cache_metrics(state.cache_metrics.clone())
+        .with_timeout(config.oidc_timeout)?;
+    let oidc_client = Arc::new(oidc_client);
 
     oidc_client.refresh().await?;
+    if config.oidc_background_refresh {
+        tokio::spawn(oidc_client.clone().refresh_on_schedule());
+    }
+
+    let router = authenticated(routes(&config), state.clone(), oidc_client);
+    let router = with_cors(router, &config.cors_allowed_origins)
+        .with_state(state);
+
+    Ok(router)
+}
+
+/// Requires every request to bring a valid token, with the scope configured for its method.
+fn authenticated(router: Router<Arc<AppState>>, state: Arc<AppState>, oidc_client: Arc<OpenIdClient>) -> Router<Arc<AppState>> {
+    router
+        .layer(middleware::from_fn_with_state(state, require_scope))
+        .layer(middleware::from_fn_with_state(oidc_client, auth))
+}
+
+/// Rejects requests whose token lacks `read_scope`, for `GET` and `HEAD`, or `write_scope`, for
+/// every other method. Runs after `auth`, which puts the token's claims in the request.
+async fn require_scope(state: State<Arc<AppState>>, Extension(claims): Extension<Claims>, request: Request, next: Next) -> Response {
+    let config = state.config();
+    let is_read = request.method() == Method::GET || request.method() == Method::HEAD;
+    let required_scope = if is_read { &config.read_scope } else { &config.write_scope };
+
+    let Some(required_scope) = required_scope.as_deref().filter(|scope| !claims.has_scope(scope)) else {
+        return next.run(request).await;
+    };
+
+    let error_id = Uuid::now_v7();
+    debug!("error_id={} user_id={} Token lacks required scope {}", error_id, claims.sub, required_scope);
+    let body = ApiError {
+        id: error_id,
+        code: ErrorCode::InsufficientScope,
+        title: "Insufficient scope".to_string(),
+        detail: Some(format!("this request needs a token with the {:?} scope", required_scope)),
+        source: Some(ApiErrorSource::header("Authorization")),
+    }.into_document();
+
+    (
+        StatusCode::FORBIDDEN,
+        [
+            (header::WWW_AUTHENTICATE, format!("Bearer realm=\"hematite\" error=\"insufficient_scope\" scope=\"{}\"", required_scope)),
+            (header::CACHE_CONTROL, "no-cache".to_string()),
+        ],
+        JsonApi(body),
+    ).into_response()
+}
+
+/// Adds CORS headers for the given origins, where `*` allows any origin. Must be layered
+/// outside of `auth`, since browsers don't send credentials with preflight requests.
+fn with_cors<S: Clone + Send + Sync + 'static>(router: Router<S>, allowed_origins: &[String]) -> Router<S> {
+    let allow_origin = if allowed_origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        // Origins were already checked when the config was read.
+        AllowOrigin::list(allowed_origins.iter().filter_map(|origin| HeaderValue::from_str(origin).ok()))
+    };
+
+    let cors = CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::GET, Method::HEAD, Method::POST, Method::PUT, Method::PATCH, Method::DELETE])
+        .allow_headers([
+            header::AUTHORIZATION,
+            header::CONTENT_TYPE,
+            header::IF_NONE_MATCH,
+            header::HeaderName::from_static(REQUEST_ID_HEADER),
+        ])
+        .expose_headers([
+            header::ETAG,
+            header::LOCATION,
+            header::HeaderName::from_static(STREAM_REVISION_HEADER),
+            header::HeaderName::from_static(SNAPSHOT_REVISION_HEADER),
+            header::HeaderName::from_static(REQUEST_ID_HEADER),
+        ]);
+
+    router
+        .layer(cors)
+        .layer(middleware::from_fn(preflight_no_content))
+}
+
+/// Answers successful CORS preflight requests with 204 instead of `CorsLayer`'s empty 200,
+/// since there's no content.
+async fn preflight_no_content(request: Request, next: Next) -> Response {
+    let is_preflight = request.method() == Method::OPTIONS
+        && request.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+
+    let mut response = next.run(request).await;
+
+    if is_preflight && response.status() == StatusCode::OK {
+        *response.status_mut() = StatusCode::NO_CONTENT;
+    }
+
+    response
+}
 
+fn routes(config: &Config) -> Router<Arc<AppState>> {
     let openapi = ServeFile::new("../openapi.yaml");
 
-    let router = Router::new()
+    // Layered onto the route itself, so it runs after `auth` and knows the user.
+    let post_events = match config.write_rate_limit {
+        Some(limit) => post(post_event)
+            .layer(middleware::from_fn_with_state(Arc::new(RateLimiter::new(limit)), limit_write_rate)),
+        None => post(post_event),
+    };
+
+    Router::new()
         .route_service("/openapi.yaml", openapi)
-        .route("/streams", get(get_streams))
-        .route("/streams/:stream/events/:rownum", get(get_event))
-        .route("/streams/:stream/events", post(post_event).get(get_event_index))
-        .route("/streams/:stream", get(get_stream).delete(delete_stream))
+        .route("/streams", get(get_streams).delete(delete_streams))
+        .route("/streams/{stream}/events/by-id", get(get_event_by_source_id))
+        .route("/streams/{stream}/events/sse", get(get_event_sse))
+        .route("/streams/{stream}/events/tail", get(get_event_tail))
+        .route("/streams/{stream}/events/{rownum}", get(get_event))
+        .route("/streams/{stream}/events/{rownum}/correct", post(post_correction))
+        .route("/streams/{stream}/events", post_events.get(get_event_index))
+        .route("/streams/{stream}/subjects/{subject}/events", get(get_subject_events))
+        .route("/streams/{stream}/types", get(get_event_types))
+        .route("/streams/{stream}/activity", get(get_activity))
+        .route("/streams/{stream}/export", get(get_export))
+        .route("/streams/{stream}/lease", post(post_lease).delete(delete_lease))
+        .route("/streams/{stream}/reserve", post(post_reserve))
+        .route("/streams/{stream}/ingest", post(post_ingest))
+        .route("/streams/{stream}/snapshot", get(get_snapshot).put(put_snapshot))
+        .route("/streams/{stream}/rename", post(post_stream_rename))
+        .route("/streams/{stream}", get(get_stream).put(put_stream).patch(patch_stream).delete(delete_stream))
+        .route("/admin/streams/move", post(post_stream_move))
+        .route("/admin/streams/{user}/{stream}/index-info", get(get_index_info))
+        .route("/admin/streams/{user}/{stream}/unseal", post(post_unseal))
+        .route("/admin/caches", get(get_cache_metrics))
+        .route("/whoami", get(get_whoami))
         .route("/health", get(health))
-        .layer(middleware::from_fn_with_state(oidc_client, auth))
-        .with_state(state);
+        // The limit replaces axum's own default, so every oversized body is rejected the same way.
+        // Any request decompression must be layered outside of this, so the limit applies to the
+        // decompressed body.
+        .layer(DefaultBodyLimit::disable())
+        .layer(RequestBodyLimitLayer::new(config.max_body_bytes))
+        .layer(middleware::from_fn(structure_payload_too_large))
+}
 
-    Ok(router)
+/// Replaces the plaintext 413 response from the body limit with an error document.
+/// Handlers' own 413 responses are already documents, and are left alone.
+async fn structure_payload_too_large(request: Request, next: Next) -> Response {
+    let response = next.run(request).await;
+
+    let is_document = response.headers().get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type == JSON_API_CONTENT_TYPE);
+
+    if response.status() != StatusCode::PAYLOAD_TOO_LARGE || is_document {
+        return response;
+    }
+
+    let error_id = Uuid::now_v7();
+    debug!("error_id={} Rejected request body over the size limit", error_id);
+
+    let body = ApiError {
+        id: error_id,
+        code: ErrorCode::PayloadTooLarge,
+        title: "Payload too large".to_string(),
+        detail: Some("the request body exceeds the maximum size accepted by this server".to_string()),
+        source: None,
+    }.into_document();
+
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        [(header::CACHE_CONTROL, "no-cache")],
+        JsonApi(body),
+    ).into_response()
+}
+
+/// Header carrying the ID of a request, echoed back on its response.
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest incoming request ID kept as-is.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Gives every request an ID, keeping the caller's `X-Request-Id` if it's valid and generating
+/// one in `format` if not. The ID is set on the request for handlers, returned on the response,
+/// and recorded on a tracing span around the request.
+pub async fn assign_request_id(State(format): State<RequestIdFormat>, mut request: Request, next: Next) -> Response {
+    let incoming = request.headers().get(REQUEST_ID_HEADER)
+        .filter(|request_id| is_valid_request_id(request_id))
+        .cloned();
+
+    let request_id = match incoming {
+        Some(request_id) => request_id,
+        None => HeaderValue::from_str(&format.generate()).expect("Expected generated request IDs to be valid header values"),
+    };
+
+    let span = tracing::info_span!("request", request_id = request_id.to_str().unwrap_or_default());
+    request.headers_mut().insert(REQUEST_ID_HEADER, request_id.clone());
+
+    let mut response = next.run(request).instrument(span).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+
+    response
+}
+
+/// Whether an incoming request ID is short and made of visible ASCII, so it's safe to log
+/// and echo back.
+fn is_valid_request_id(request_id: &HeaderValue) -> bool {
+    let bytes = request_id.as_bytes();
+
+    !bytes.is_empty()
+        && bytes.len() <= MAX_REQUEST_ID_LEN
+        && bytes.iter().all(|byte| byte.is_ascii_graphic())
+}
+
+/// Adds security headers to every response, choosing the CSP by request path.
+pub async fn apply_secure_headers(State(csp): State<Arc<ContentSecurityPolicy>>, request: Request, next: Next) -> Response {
+    let policy = HeaderValue::from_str(csp.for_path(request.uri().path()));
+    let mut response = next.run(request).await;
+
+    let headers = response.headers_mut();
+    headers.insert(header::X_CONTENT_TYPE_OPTIONS, "nosniff".parse().unwrap());
+    headers.insert(header::X_FRAME_OPTIONS, "DENY".parse().unwrap());
+    headers.insert(header::X_XSS_PROTECTION, "1; mode=block".parse().unwrap());
+
+    match policy {
+        Ok(policy) => {
+            headers.insert(header::CONTENT_SECURITY_POLICY, policy);
+        },
+        Err(err) => error!("Configured Content-Security-Policy is not a valid header value: {}", err),
+    }
+
+    response
 }
 
 #[tracing::instrument]
@@ -165,6 +543,7 @@ async fn auth(oidc: State<Arc<OpenIdClient>>, mut req: Request, next: Next) -> R
             debug!("error_id={} Request is missing Bearer token", error_id);
             let body = ApiError {
                 id: error_id,
+                code: ErrorCode::NotAuthenticated,
                 title: "Not authenticated".to_string(),
                 detail: Some("A Bearer token is required to access this API.".to_string()),
                 source: Some(ApiErrorSource::header("Authorization")),
@@ -176,31 +555,65 @@ async fn auth(oidc: State<Arc<OpenIdClient>>, mut req: Request, next: Next) -> R
                     (header::WWW_AUTHENTICATE, "Bearer realm=\"hematite\""),
                     (header::CACHE_CONTROL, "no-cache"),
                 ],
-                Json::from(body),
+                JsonApi(body),
             ).into_response();
 
             return Err(resp);
         };
 
-    match oidc.authorize_current_user(auth_token).await {
-        Ok(claims) => {
-            let current_user = User { id: claims.sub };
+    let authorized = oidc.authorize_current_user(auth_token).await
+        .and_then(|claims| Ok((oidc.user_id(&claims)?, claims)));
+
+    match authorized {
+        Ok((user_id, claims)) => {
+            let current_user = User { id: user_id };
 
             req.extensions_mut().insert(current_user);
+            req.extensions_mut().insert(claims);
             return Ok(next.run(req).await);
         },
+        Err(err) if err.is::<ProviderTimeout>() => {
+            let error_id = Uuid::now_v7();
+            error!("error_id={} Timed out validating auth token: {:?}", error_id, err);
+
+            let body = ApiError {
+                id: error_id,
+                code: ErrorCode::AuthUnavailable,
+                title: "Authentication unavailable".to_string(),
+                detail: Some("the identity provider didn't respond in time, try again later".to_string()),
+                source: None,
+            }.into_document();
+
+            let resp = (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [
+                    (header::RETRY_AFTER, "5"),
+                    (header::CACHE_CONTROL, "no-cache"),
+                ],
+                JsonApi(body),
+            ).into_response();
+
+            return Err(resp);
+        },
         Err(err) => {
             let error_id = Uuid::now_v7();
             error!("error_id={} Error validating auth token: {:?}", error_id, err);
 
             let desc =
-                if let Ok(jwt_error) = err.downcast::<jsonwebtoken::errors::Error>() {
+                if err.is::<UnknownIssuer>() {
+                    "token issuer is not trusted by this server"
+                } else if err.is::<InactiveToken>() {
+                    "token is not active"
+                } else if err.is::<InvalidUserClaim>() {
+                    "token does not identify a user"
+                } else if let Ok(jwt_error) = err.downcast::<jsonwebtoken::errors::Error>() {
                     let kind = jwt_error.kind();
 
                     debug!("Token validation failed with reason: {:?}", kind);
                     match kind {
                         ErrorKind::InvalidAudience => "token has an invalid audience",
                         ErrorKind::ExpiredSignature => "token has expired",
+                        ErrorKind::ImmatureSignature => "token is not valid yet",
                         ErrorKind::InvalidSignature => "token has an invalid signature",
                         _ => "Bearer token is invalid"
                     }
@@ -211,6 +624,7 @@ async fn auth(oidc: State<Arc<OpenIdClient>>, mut req: Request, next: Next) -> R
 
             let body = ApiError {
                 id: error_id,
+                code: ErrorCode::NotAuthenticated,
                 title: "Not authenticated".to_string(),
                 detail: Some(desc.to_string()),
                 source: Some(ApiErrorSource::header("Authorization")),
@@ -222,7 +636,7 @@ async fn auth(oidc: State<Arc<OpenIdClient>>, mut req: Request, next: Next) -> R
                     (header::WWW_AUTHENTICATE, format!("Bearer realm=\"hematite\" error=\"invalid_token\" error_description=\"{}\"", desc)),
                     (header::CACHE_CONTROL, "no-cache".to_string()),
                 ],
-                Json::from(body),
+                JsonApi(body),
             ).into_response();
 
             return Err(resp);
@@ -230,13 +644,143 @@ async fn auth(oidc: State<Arc<OpenIdClient>>, mut req: Request, next: Next) -> R
     }
 }
 
+/// Rejects a user's writes with 429 once they've used up their bucket in `rate_limiter`.
+async fn limit_write_rate(
+    State(rate_limiter): State<Arc<RateLimiter>>,
+    Extension(user): Extension<User>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Err(retry_after) = rate_limiter.try_acquire(&user.id) else {
+        return next.run(request).await;
+    };
+
+    let retry_after_secs = retry_after.as_secs_f64().ceil() as u64;
+    let error_id = Uuid::now_v7();
+    debug!("error_id={} Throttled writes by user {}", error_id, user.id);
+    let body = ApiError {
+        id: error_id,
+        code: ErrorCode::RateLimited,
+        title: "Too many requests".to_string(),
+        detail: Some(format!("you are posting events too quickly, try again in {} seconds", retry_after_secs)),
+        source: None,
+    }.into_document();
+
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [
+            (header::RETRY_AFTER, retry_after_secs.to_string()),
+            (header::CACHE_CONTROL, "no-cache".to_string()),
+        ],
+        JsonApi(body),
+    ).into_response()
+}
+
+#[derive(Debug, Serialize)]
+struct WhoAmIAttributes {
+    claims: Claims,
+}
+
+/// Reports who the request was authenticated as, and the claims read from their token, for
+/// debugging rejected or misattributed requests. The token itself isn't echoed.
+#[tracing::instrument]
+#[debug_handler]
+async fn get_whoami(
+    Extension(user): Extension<User>,
+    Extension(claims): Extension<Claims>,
+) -> Response {
+    let body = ApiResource::new(user.id, "user".to_string(), WhoAmIAttributes { claims }).into_document();
+
+    (
+        StatusCode::OK,
+        [(header::CACHE_CONTROL, "no-store")],
+        JsonApi(body),
+    ).into_response()
+}
+
+#[derive(Deserialize, Debug)]
+struct GetEventParams {
+    #[serde(default)]
+    apply_corrections: bool,
+}
+
 #[tracing::instrument]
 #[debug_handler]
-async fn get_event(state: State<Arc<AppState>>, Extension(user): Extension<User>, Path((stream_id, rownum)): Path<(String, u64)>) -> Response {
-    let event_result = state.get_event(&user.id, &stream_id, rownum).await;
+async fn get_event(
+    state: State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Path((stream_id, rownum)): Path<(String, u64)>,
+    Query(params): Query<GetEventParams>,
+    headers: HeaderMap,
+) -> Response {
+    let event_result = state.get_event(&user.id, &stream_id, rownum, params.apply_corrections).await;
 
     match event_result {
-        Ok(Some(event)) => return ([(header::CACHE_CONTROL, "max-age=31536000, immutable")], Json(event)).into_response(),
+        Ok(Some(event)) => {
+            let bytes = match serde_json::to_vec(&event) {
+                Ok(bytes) => bytes,
+                Err(err) => {
+                    let error_id = Uuid::now_v7();
+                    error!("error_id={} user_id={} stream_id={} Error serializing event: {:?}", error_id, user.id, stream_id, err);
+
+                    let body = ApiError {
+                        id: error_id,
+                        code: ErrorCode::InternalError,
+                        title: "Internal server error".to_string(),
+                        detail: None,
+                        source: None,
+                    }.into_document();
+
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        [(header::CACHE_CONTROL, "no-cache")],
+                        JsonApi(body),
+                    ).into_response();
+                },
+            };
+
+            let binary_mode = wants_binary_mode(&headers, event.datacontenttype().unwrap_or("application/json"));
+            let etag = event_etag(rownum, &bytes, binary_mode);
+            let cache_headers = [
+                (header::CACHE_CONTROL, event_cache_control(&state.config(), params.apply_corrections)),
+                (header::ETAG, etag.clone()),
+                (header::VARY, "accept".to_string()),
+            ];
+
+            if etag_matches(&headers, &etag) {
+                return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+            }
+
+            if !binary_mode {
+                return (
+                    cache_headers,
+                    [(header::CONTENT_TYPE, "application/json")],
+                    bytes,
+                ).into_response();
+            }
+
+            match event.deserialize_binary(BinaryModeSerializer::default()) {
+                Ok((event_headers, data)) => return (cache_headers, event_headers, data).into_response(),
+                Err(err) => {
+                    let error_id = Uuid::now_v7();
+                    error!("error_id={} user_id={} stream_id={} Error serializing event in binary mode: {:?}", error_id, user.id, stream_id, err);
+
+                    let body = ApiError {
+                        id: error_id,
+                        code: ErrorCode::InternalError,
+                        title: "Internal server error".to_string(),
+                        detail: None,
+                        source: None,
+                    }.into_document();
+
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        [(header::CACHE_CONTROL, "no-cache")],
+                        JsonApi(body),
+                    ).into_response();
+                },
+            }
+        },
         Ok(None) => return StatusCode::NOT_FOUND.into_response(),
         Err(err) => {
             match err.downcast::<server::Error>() {
@@ -249,6 +793,7 @@ async fn get_event(state: State<Arc<AppState>>, Extension(user): Extension<User>
 
                     let body = ApiError {
                         id: error_id,
+                        code: ErrorCode::InternalError,
                         title: "Internal server error".to_string(),
                         detail: None,
                         source: None,
@@ -257,7 +802,7 @@ async fn get_event(state: State<Arc<AppState>>, Extension(user): Extension<User>
                     return (
                         StatusCode::INTERNAL_SERVER_ERROR,
                         [(header::CACHE_CONTROL, "no-cache")],
-                        Json::from(body),
+                        JsonApi(body),
                     ).into_response();
                 }
             }
@@ -265,138 +810,276 @@ async fn get_event(state: State<Arc<AppState>>, Extension(user): Extension<User>
     }
 }
 
-#[tracing::instrument]
-#[debug_handler]
-async fn get_event_index(state: State<Arc<AppState>>, Extension(user): Extension<User>, Path(stream_id): Path<String>, Query(query): Query<HashMap<String, String>>) -> Response {
-    let start = query.get("page[offset]").unwrap_or(&"0".to_string()).parse().unwrap_or(0).max(0);
-    let limit = query.get("page[limit]").unwrap_or(&"50".to_string()).parse().unwrap_or(50).min(1000);
+/// Stored events never change, but a view with corrections applied changes whenever the event
+/// is corrected again, so only that view expires.
+fn event_cache_control(config: &Config, apply_corrections: bool) -> String {
+    if !apply_corrections {
+        return "max-age=31536000, immutable".to_string();
+    }
 
-    let events_result = state.get_event_many(&user.id, &stream_id, start, limit).await;
+    match config.corrected_event_max_age.as_secs() {
+        0 => "no-cache".to_string(),
+        max_age => format!("max-age={}", max_age),
+    }
+}
 
-    match events_result {
-        Ok(events) => {
-            let cache_header =
-                if events.len() == limit {
-                    (header::CACHE_CONTROL, "max-age=31536000, immutable")
-                } else {
-                    (header::CACHE_CONTROL, "no-cache")
-                };
+/// Strong ETag for an event, from its rownum and a checksum of its JSON. The checksum
+/// changes when a correction is applied, so corrected and uncorrected reads never match.
+/// Binary content mode is a different representation, so it gets its own tag.
+fn event_etag(rownum: u64, json: &[u8], binary_mode: bool) -> String {
+    let mode = if binary_mode { "-binary" } else { "" };
+    format!("\"{}-{:08x}{}\"", rownum, crc32fast::hash(json), mode)
+}
 
-            return (
-                [cache_header],
-                Json(events),
-            ).into_response();
-        },
-        Err(err) => {
-            let error_id = Uuid::now_v7();
-            error!("error_id={} user_id={} stream_id={} Error getting events: {:?}", error_id, user.id, stream_id, err);
+/// Whether the request's `Accept` header asks for an event's data, of `data_content_type`,
+/// rather than a CloudEvent, so the event should be sent in binary content mode. Asking for
+/// `application/cloudevents+json`, or for anything at all with `*/*`, gets structured mode.
+fn wants_binary_mode(headers: &HeaderMap, data_content_type: &str) -> bool {
+    let Some(accept) = headers.get(header::ACCEPT).and_then(|accept| accept.to_str().ok()) else {
+        return false;
+    };
 
-            let body = ApiError {
-                id: error_id,
-                title: "Internal server error".to_string(),
-                detail: None,
-                source: None,
-            }.into_document();
+    let media_ranges: Vec<&str> = accept.split(',')
+        .map(|media_range| media_range.split(';').next().unwrap_or_default().trim())
+        .collect();
 
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                [(header::CACHE_CONTROL, "no-cache")],
-                Json::from(body),
-            ).into_response();
-        },
+    if media_ranges.iter().any(|media_range| media_range.starts_with("application/cloudevents")) {
+        return false;
     }
-}
-
-#[tracing::instrument]
-#[debug_handler]
-async fn get_streams(
-    state: State<Arc<AppState>>,
-    Extension(user): Extension<User>,
-    Query(query): Query<HashMap<String, String>>,
-) -> Response {
-    let get_result = state.streams(&user.id).await;
 
-    match get_result {
-        Ok(mut streams) => {
-            let default_sort_field = "id".to_string();
-            let sort_field: &str = query.get("sort").unwrap_or(&default_sort_field);
+    let data_content_type = data_content_type.split(';').next().unwrap_or_default().trim();
+    let data_type = data_content_type.split('/').next().unwrap_or_default();
 
-            match sort_field {
-                "id" => streams.sort_by(|a, b| a.id.cmp(&b.id)),
-                "usage" => streams.sort_by(|a, b| a.usage.cmp(&b.usage)),
-                "-usage" => streams.sort_by(|a, b| b.usage.cmp(&a.usage)),
-                "revision" => streams.sort_by(|a, b| a.revision.cmp(&b.revision)),
-                "-revision" => streams.sort_by(|a, b| b.revision.cmp(&a.revision)),
-                "last_modified" => streams.sort_by(|a, b| a.last_modified.cmp(&b.last_modified)),
-                "-last_modified" => streams.sort_by(|a, b| b.last_modified.cmp(&a.last_modified)),
-                _ => {
-                    return StatusCode::BAD_REQUEST.into_response();
-                },
-            };
+    media_ranges.iter().any(|media_range| {
+        media_range.eq_ignore_ascii_case(data_content_type)
+            || media_range.strip_suffix("/*").is_some_and(|range_type| range_type != "*" && range_type.eq_ignore_ascii_case(data_type))
+    })
+}
 
-            let mut stream_resources = vec![];
-            for stream in streams.into_iter() {
-                stream_resources.push(ApiResource::new(stream.id.to_string(), "streams".to_string(), stream));
-            }
+/// Whether a posted event is in CloudEvents binary content mode, with its attributes in `ce-`
+/// headers and only its data in the body. Events in the structured and batch JSON formats
+/// either have an `application/cloudevents` content type or no `ce-specversion` header.
+fn is_binary_mode(headers: &HeaderMap) -> bool {
+    let is_structured = headers.get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type.as_bytes().starts_with(b"application/cloudevents"));
 
-            let doc = ApiDataCollectionDocument { data: stream_resources };
+    !is_structured && headers.contains_key("ce-specversion")
+}
 
-            return Json::from(doc).into_response();
-        }
-        Err(err) => {
-            let error_id = Uuid::now_v7();
-            error!("error_id={} user_id={} Error getting streams: {}", error_id, user.id, err);
+/// Builds an event from a binary content mode request. Data with a JSON content type is kept
+/// as JSON, so it reads back in structured mode as `data` instead of `data_base64`.
+fn binary_mode_event(headers: &HeaderMap, body: Vec<u8>) -> message::Result<Event> {
+    let mut event = BinaryModeDeserializer { headers, body }.into_event()?;
 
-            let body = ApiError {
-                id: error_id,
-                title: "Internal server error".to_string(),
-                detail: None,
-                source: None,
-            }.into_document();
+    let is_json = event.datacontenttype().is_some_and(|content_type| {
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        media_type.eq_ignore_ascii_case("application/json") || media_type.ends_with("+json")
+    });
 
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                [(header::CACHE_CONTROL, "no-cache")],
-                Json::from(body),
-            ).into_response()
-        }
+    if let (true, Some(Data::Binary(bytes))) = (is_json, event.data()) {
+        let data: serde_json::Value = serde_json::from_slice(bytes)?;
+        event.set_data_unchecked(data);
     }
+
+    Ok(event)
 }
 
-#[tracing::instrument]
-#[debug_handler]
-async fn get_stream(state: State<Arc<AppState>>, Extension(user): Extension<User>, Path(stream_id): Path<String>) -> Response {
-    let get_result = state.get_stream(&user.id, &stream_id).await;
+/// Reads an event's attributes from `ce-` headers and its data from the body, the reverse of
+/// `BinaryModeSerializer`. Header values are percent-decoded.
+struct BinaryModeDeserializer<'a> {
+    headers: &'a HeaderMap,
+    body: Vec<u8>,
+}
 
-    match get_result {
-        Ok(stream) => {
-            let last_modified = OffsetDateTime::from_unix_timestamp(stream.last_modified.try_into().expect("Expected app to be running after epoch")).unwrap().format(&Rfc2822).unwrap();
+impl BinaryDeserializer for BinaryModeDeserializer<'_> {
+    fn deserialize_binary<R: Sized, V: BinarySerializer<R>>(self, mut serializer: V) -> message::Result<R> {
+        let header_str = |value: &HeaderValue| value.to_str()
+            .map_err(|err| message::Error::Other { source: Box::new(err) })
+            .and_then(percent_decode_header);
 
-            let body = ApiResource {
-                id: stream_id,
-                resource_type: "streams".to_string(),
-                attributes: Some(stream),
-            }.into_document();
+        let spec_version = self.headers.get("ce-specversion")
+            .ok_or(message::Error::WrongEncoding {})
+            .and_then(header_str)?;
+        let spec_version = SpecVersion::try_from(spec_version.as_str())?;
+        let attribute_names = spec_version.attribute_names();
 
+        serializer = serializer.set_spec_version(spec_version)?;
 
-            return (
-                StatusCode::OK,
+        for (name, value) in self.headers {
+            let Some(attribute) = name.as_str().strip_prefix("ce-").filter(|attribute| *attribute != "specversion") else {
+                continue;
+            };
+
+            let value = MessageAttributeValue::String(header_str(value)?);
+
+            serializer =
+                if attribute_names.contains(&attribute) {
+                    serializer.set_attribute(attribute, value)?
+                } else {
+                    serializer.set_extension(attribute, value)?
+                };
+        }
+
+        if let Some(content_type) = self.headers.get(header::CONTENT_TYPE) {
+            serializer = serializer.set_attribute("datacontenttype", MessageAttributeValue::String(header_str(content_type)?))?;
+        }
+
+        if self.body.is_empty() {
+            serializer.end()
+        } else {
+            serializer.end_with_data(self.body)
+        }
+    }
+}
+
+/// Collects an event's attributes as `ce-` headers and its data as the body, for sending it
+/// in CloudEvents binary content mode. `datacontenttype` becomes the `Content-Type` header.
+#[derive(Default)]
+struct BinaryModeSerializer {
+    headers: HeaderMap,
+}
+
+impl BinaryModeSerializer {
+    fn header(mut self, attribute: &str, value: impl fmt::Display) -> message::Result<Self> {
+        let name =
+            if attribute == "datacontenttype" {
+                header::CONTENT_TYPE
+            } else {
+                header::HeaderName::try_from(format!("ce-{}", attribute))
+                    .map_err(|err| message::Error::Other { source: Box::new(err) })?
+            };
+
+        let value = HeaderValue::try_from(percent_encode_header(&value.to_string()))
+            .map_err(|err| message::Error::Other { source: Box::new(err) })?;
+
+        self.headers.insert(name, value);
+        Ok(self)
+    }
+}
+
+impl BinarySerializer<(HeaderMap, Vec<u8>)> for BinaryModeSerializer {
+    fn set_spec_version(self, spec_version: SpecVersion) -> message::Result<Self> {
+        self.header("specversion", spec_version)
+    }
+
+    fn set_attribute(self, name: &str, value: MessageAttributeValue) -> message::Result<Self> {
+        self.header(name, value)
+    }
+
+    fn set_extension(self, name: &str, value: MessageAttributeValue) -> message::Result<Self> {
+        self.header(name, value)
+    }
+
+    fn end_with_data(self, bytes: Vec<u8>) -> message::Result<(HeaderMap, Vec<u8>)> {
+        Ok((self.headers, bytes))
+    }
+
+    fn end(self) -> message::Result<(HeaderMap, Vec<u8>)> {
+        Ok((self.headers, Vec::new()))
+    }
+}
+
+/// Percent-encodes the characters the CloudEvents HTTP binding doesn't allow in header values
+/// as-is: space, `"`, `%`, and anything outside printable ASCII.
+fn percent_encode_header(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+
+    for byte in value.bytes() {
+        if (0x21..=0x7e).contains(&byte) && byte != b'"' && byte != b'%' {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+
+    encoded
+}
+
+/// Reverses `percent_encode_header`, failing if the decoded value isn't UTF-8.
+fn percent_decode_header(value: &str) -> message::Result<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            },
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            },
+        }
+    }
+
+    String::from_utf8(decoded).map_err(|err| message::Error::Other { source: Box::new(err) })
+}
+
+/// Weak ETag for a listing of a stream's events, which only changes when the stream does.
+fn revision_etag(revision: u64) -> String {
+    format!("W/\"{}\"", revision)
+}
+
+/// Weak ETag for a stream's resource document, from its revision and a checksum of the
+/// document, which also changes with the stream's metadata and usage.
+fn stream_etag(revision: u64, json: &[u8]) -> String {
+    format!("W/\"{}-{:08x}\"", revision, crc32fast::hash(json))
+}
+
+/// Whether the request's `If-None-Match` header lists `etag`, compared weakly as RFC 9110
+/// requires for `GET`.
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    let Some(if_none_match) = headers.get(header::IF_NONE_MATCH).and_then(|value| value.to_str().ok()) else {
+        return false;
+    };
+
+    let opaque_tag = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = opaque_tag(etag);
+
+    if_none_match.split(',').any(|tag| tag.trim() == "*" || opaque_tag(tag) == etag)
+}
+
+#[derive(Debug, Deserialize)]
+struct GetEventBySourceIdParams {
+    source: String,
+    id: String,
+}
+
+#[tracing::instrument]
+#[debug_handler]
+async fn get_event_by_source_id(state: State<Arc<AppState>>, Extension(user): Extension<User>, Path(stream_id): Path<String>, Query(params): Query<GetEventBySourceIdParams>) -> Response {
+    let event_result = state.get_event_by_source_id(&user.id, &stream_id, &params.source, &params.id).await;
+
+    match event_result {
+        Ok(Some((rownum, event))) => {
+            return (
                 [
-                    (header::CACHE_CONTROL, "no-cache"),
-                    (header::LAST_MODIFIED, &last_modified),
+                    (header::CACHE_CONTROL, "no-cache".to_string()),
+                    (header::CONTENT_LOCATION, format!("/streams/{}/events/{}", stream_id, rownum)),
                 ],
-                Json::from(body),
+                Json(event),
             ).into_response();
-        }
+        },
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
         Err(err) => {
             match err.downcast::<server::Error>() {
-                Ok(server::Error::StreamNotFound) => StatusCode::NOT_FOUND.into_response(),
+                Ok(server::Error::StreamNotFound) => {
+                    return StatusCode::NOT_FOUND.into_response();
+                },
                 Err(err) => {
                     let error_id = Uuid::now_v7();
-                    error!("error_id={} user_id={} stream_id={} Error getting stream: {:?}", error_id, user.id, stream_id, err);
+                    error!("error_id={} user_id={} stream_id={} Error getting event by source and ID: {:?}", error_id, user.id, stream_id, err);
 
                     let body = ApiError {
                         id: error_id,
+                        code: ErrorCode::InternalError,
                         title: "Internal server error".to_string(),
                         detail: None,
                         source: None,
@@ -405,28 +1088,188 @@ async fn get_stream(state: State<Arc<AppState>>, Extension(user): Extension<User
                     return (
                         StatusCode::INTERNAL_SERVER_ERROR,
                         [(header::CACHE_CONTROL, "no-cache")],
-                        Json::from(body),
-                    ).into_response()
+                        JsonApi(body),
+                    ).into_response();
                 }
             }
-        }
+        },
     }
 }
 
+/// Longest a request to the event index may wait for new events.
+const MAX_POLL_WAIT: Duration = Duration::from_secs(60);
+
+/// Lists a page of the stream's events from rownum `page[offset]` onward, with links to the
+/// pages around it. Polls for new events instead when given `after_revision`.
+///
+/// `since` and `until` only list events whose `time` is within them, inclusive, leaving out
+/// events without a `time`. Those pages are found by scanning the stream, and have no `prev` link.
+///
+/// With `wait`, like `30s`, a request for a page that's empty is held open until an event for
+/// it is appended or the wait, capped at `MAX_POLL_WAIT`, runs out. Once it runs out the empty
+/// page is returned, and the client can poll again.
 #[tracing::instrument]
 #[debug_handler]
-async fn delete_stream(state: State<Arc<AppState>>, Extension(user): Extension<User>, Path(stream_id): Path<String>) -> Response {
-    let delete_result = state.delete_stream(&user.id, &stream_id).await;
+async fn get_event_index(
+    state: State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Path(stream_id): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Response {
+    let start = query.get("page[offset]").unwrap_or(&"0".to_string()).parse().unwrap_or(0);
+    let default_limit = state.config().default_page_limit;
+    let limit = query.get("page[limit]").and_then(|limit| limit.parse().ok()).unwrap_or(default_limit).min(1000);
 
-    match delete_result {
-        Ok(true) => StatusCode::NO_CONTENT.into_response(),
-        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+    if let Some(after_revision) = query.get("after_revision") {
+        return get_events_after(&state, &user, &stream_id, after_revision, limit, &headers).await;
+    }
+
+    let apply_corrections = query.get("apply_corrections").is_some_and(|value| value == "true");
+
+    let event_type = query.get("filter[type]").map(String::as_str);
+
+    let mut time_range = TimeRange::default();
+    for (param, bound) in [("since", &mut time_range.since), ("until", &mut time_range.until)] {
+        let Some(value) = query.get(param) else {
+            continue;
+        };
+
+        match OffsetDateTime::parse(value, &Rfc3339) {
+            Ok(time) => *bound = Some(time),
+            Err(err) => {
+                let error_id = Uuid::now_v7();
+                debug!("error_id={} Invalid {} {:?}: {}", error_id, param, value, err);
+
+                let body = ApiError {
+                    id: error_id,
+                    code: ErrorCode::InvalidParameter,
+                    title: "Invalid parameter".to_string(),
+                    detail: Some(format!("{} must be an RFC 3339 timestamp, like 2024-01-31T12:00:00Z", param)),
+                    source: Some(ApiErrorSource::query(param)),
+                }.into_document();
+
+                return (
+                    StatusCode::BAD_REQUEST,
+                    [(header::CACHE_CONTROL, "no-cache")],
+                    JsonApi(body),
+                ).into_response();
+            },
+        }
+    }
+
+    let filter = EventFilter { event_type, time_range };
+
+    let wait = match query.get("wait").map(|wait| parse_duration(wait)).transpose() {
+        Ok(wait) => wait.map(|wait| wait.min(MAX_POLL_WAIT)),
         Err(err) => {
             let error_id = Uuid::now_v7();
-            error!("error_id={} user_id={} stream_id={} Error deleting stream: {}", error_id, user.id, stream_id, err);
+            debug!("error_id={} Invalid wait {:?}: {}", error_id, query.get("wait"), err);
+
+            let body = ApiError {
+                id: error_id,
+                code: ErrorCode::InvalidParameter,
+                title: "Invalid parameter".to_string(),
+                detail: Some("wait must be a positive whole number of s, m, h, or d, like 30s".to_string()),
+                source: Some(ApiErrorSource::query("wait")),
+            }.into_document();
+
+            return (
+                StatusCode::BAD_REQUEST,
+                [(header::CACHE_CONTROL, "no-cache")],
+                JsonApi(body),
+            ).into_response();
+        },
+    };
+
+    let mut events_result = state.get_event_many(&user.id, &stream_id, start, limit, &filter, apply_corrections).await;
+
+    if let Some(wait) = wait {
+        let deadline = tokio::time::Instant::now() + wait;
+
+        // Subscribing from the revision the page was read at means no append can be missed
+        // between the read and the subscription. Appends that don't land on the page, like
+        // events of another type, only cause a re-read.
+        while let Ok(page) = &events_result {
+            if !page.events.is_empty() {
+                break;
+            }
+
+            let Ok(appended) = state.subscribe(&user.id, &stream_id, Some(start.max(page.revision))).await else {
+                break;
+            };
+            let mut appended = pin!(appended);
+
+            match tokio::time::timeout_at(deadline, appended.next()).await {
+                Ok(Some(Ok(_))) => {
+                    events_result = state.get_event_many(&user.id, &stream_id, start, limit, &filter, apply_corrections).await;
+                },
+                _ => break,
+            }
+        }
+    }
+
+    match events_result {
+        Ok(page) => {
+            // The links and total count change as the stream grows, so even full pages are
+            // revalidated, by their ETag.
+            let etag = revision_etag(page.revision);
+            let cache_headers = [
+                (header::CACHE_CONTROL, "no-cache".to_string()),
+                (header::ETAG, etag.clone()),
+            ];
+
+            if etag_matches(&headers, &etag) {
+                return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+            }
+
+            let page_link = |offset: u64| {
+                let mut link_query = url::form_urlencoded::Serializer::new(String::new());
+
+                if let Some(event_type) = event_type {
+                    link_query.append_pair("filter[type]", event_type);
+                }
+                for param in ["since", "until"] {
+                    if let Some(value) = query.get(param) {
+                        link_query.append_pair(param, value);
+                    }
+                }
+                if apply_corrections {
+                    link_query.append_pair("apply_corrections", "true");
+                }
+
+                link_query
+                    .append_pair("page[offset]", &offset.to_string())
+                    .append_pair("page[limit]", &limit.to_string());
+
+                format!("/streams/{}/events?{}", stream_id, link_query.finish())
+            };
+
+            let links = PaginationLinks {
+                this: page_link(start),
+                first: page_link(0),
+                prev: page.prev.map(page_link),
+                next: page.next.map(page_link),
+            };
+
+            let resources = page.events.into_iter()
+                .map(|(rownum, event)| ApiResource::new(rownum.to_string(), "event".to_string(), event))
+                .collect();
+
+            let doc = ApiDataCollectionDocument::with_pagination(resources, links, CollectionMeta { total: page.count });
+
+            return (
+                cache_headers,
+                JsonApi(doc),
+            ).into_response();
+        },
+        Err(err) => {
+            let error_id = Uuid::now_v7();
+            error!("error_id={} user_id={} stream_id={} Error getting events: {:?}", error_id, user.id, stream_id, err);
 
             let body = ApiError {
                 id: error_id,
+                code: ErrorCode::InternalError,
                 title: "Internal server error".to_string(),
                 detail: None,
                 source: None,
@@ -435,110 +1278,141 @@ async fn delete_stream(state: State<Arc<AppState>>, Extension(user): Extension<U
             return (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 [(header::CACHE_CONTROL, "no-cache")],
-                Json::from(body),
+                JsonApi(body),
             ).into_response();
-        }
+        },
     }
 }
 
-#[derive(Deserialize, Debug)]
-struct PostEventParams {
-    expected_revision: Option<String>,
+#[derive(Debug, Default, Deserialize)]
+struct TailParams {
+    limit: Option<usize>,
+    #[serde(default)]
+    order: TailOrder,
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(untagged)]
-enum PostEventPayload {
-    Single(Event),
-    Batch(Vec<Event>),
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum TailOrder {
+    #[default]
+    Asc,
+    Desc,
 }
 
+/// Lists the stream's last `limit` events in one call, oldest first, or newest first with
+/// `order=desc`. The `prev` link leads into the event index, to read further back.
 #[tracing::instrument]
 #[debug_handler]
-async fn post_event(
+async fn get_event_tail(
     state: State<Arc<AppState>>,
     Extension(user): Extension<User>,
     Path(stream_id): Path<String>,
-    Query(query_params): Query<PostEventParams>,
-    Json(payload): Json<PostEventPayload>,
+    Query(params): Query<TailParams>,
+    headers: HeaderMap,
 ) -> Response {
-    let revision = {
-        let default_revision = "any".to_owned();
-        let revision_param = query_params.expected_revision.unwrap_or(default_revision);
-        let revision_result = parse_expected_revision(revision_param.as_str());
+    let limit = params.limit.unwrap_or(state.config().default_page_limit).min(1000);
 
-        if revision_result.is_err() {
-            let error_id = Uuid::now_v7();
-            debug!("error_id={} Failed to post event: {:?}", error_id, revision_result);
-            let body = ApiError {
-                id: error_id,
-                title: "Invalid parameter".to_string(),
-                detail: Some(format!("expected_revision is invalid. Got {:?}", revision_result)),
-                source: Some(ApiErrorSource::query("expected_revision")),
-            }.into_document();
+    match state.get_event_tail(&user.id, &stream_id, limit).await {
+        Ok(page) => {
+            let etag = revision_etag(page.revision);
+            let cache_headers = [
+                (header::CACHE_CONTROL, "no-cache".to_string()),
+                (header::ETAG, etag.clone()),
+            ];
 
-            return (
-                StatusCode::UNAUTHORIZED,
-                [(header::CACHE_CONTROL, "no-cache")],
-                Json::from(body),
-            ).into_response();
-        }
+            if etag_matches(&headers, &etag) {
+                return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+            }
 
-        revision_result.unwrap()
-    };
+            let index_link = |offset: u64| {
+                let query = url::form_urlencoded::Serializer::new(String::new())
+                    .append_pair("page[offset]", &offset.to_string())
+                    .append_pair("page[limit]", &limit.to_string())
+                    .finish();
 
-    let result = match payload {
-        PostEventPayload::Single(event) => state.insert_event(&user.id, &stream_id, event, revision).await,
-        PostEventPayload::Batch(events) => state.insert_event_many(&user.id, &stream_id, events, revision).await,
-    };
+                format!("/streams/{}/events?{}", stream_id, query)
+            };
+            let order = if params.order == TailOrder::Desc { "&order=desc" } else { "" };
 
-    match result {
-        Ok(rownum) => {
-            return (
-                StatusCode::CREATED,
-                [
-                    (header::CACHE_CONTROL, "no-cache"),
-                    (header::CONTENT_LOCATION, &format!("http://localhost:8080/streams/{}/events/{}", stream_id, rownum).to_string()),
-                ],
-            ).into_response();
-        }
-        Err(err) => {
-            let error_id = Uuid::now_v7();
-            debug!("error_id={} Failed to post event: {:?}", error_id, err);
+            let links = PaginationLinks {
+                this: format!("/streams/{}/events/tail?limit={}{}", stream_id, limit, order),
+                first: index_link(0),
+                prev: page.prev.map(index_link),
+                next: None,
+            };
 
-            match err.downcast::<db::Error>() {
-                Ok(db::Error::RevisionMismatch) => {
-                    let body = ApiError {
-                        id: error_id,
-                        title: "Revision mismatch".to_string(),
-                        detail: Some("expected revision did not match actual revision".to_string()),
-                        source: Some(ApiErrorSource::query("expected_revision")),
-                    }.into_document();
+            let mut resources: Vec<_> = page.events.into_iter()
+                .map(|(rownum, event)| ApiResource::new(rownum.to_string(), "event".to_string(), event))
+                .collect();
 
-                    return (
-                        StatusCode::CONFLICT,
-                        [(header::CACHE_CONTROL, "no-cache")],
-                        Json::from(body),
-                    ).into_response();
-                },
-                Ok(db::Error::SourceIdConflict) => {
-                    let body = ApiError {
-                        id: error_id,
-                        title: "Source/ID conflict".to_string(),
-                        detail: Some("this stream already contains an event with that source and id field. According to the CloudEvents spec, those fields in combination must be unique".to_string()),
-                        source: None,
-                    }.into_document();
+            if params.order == TailOrder::Desc {
+                resources.reverse();
+            }
 
-                    return (
-                        StatusCode::CONFLICT,
-                        [(header::CACHE_CONTROL, "no-cache")],
-                        Json::from(body),
-                    ).into_response();
-                },
+            let doc = ApiDataCollectionDocument::with_pagination(resources, links, CollectionMeta { total: page.count });
+
+            (
+                cache_headers,
+                JsonApi(doc),
+            ).into_response()
+        },
+        Err(err) => match err.downcast::<server::Error>() {
+            Ok(server::Error::StreamNotFound) => StatusCode::NOT_FOUND.into_response(),
+            Err(err) => {
+                let error_id = Uuid::now_v7();
+                error!("error_id={} user_id={} stream_id={} Error getting the tail of the stream: {:?}", error_id, user.id, stream_id, err);
+
+                let body = ApiError {
+                    id: error_id,
+                    code: ErrorCode::InternalError,
+                    title: "Internal server error".to_string(),
+                    detail: None,
+                    source: None,
+                }.into_document();
+
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    [(header::CACHE_CONTROL, "no-cache")],
+                    JsonApi(body),
+                ).into_response()
+            },
+        },
+    }
+}
+
+/// Lists one subject's events in order. `page[offset]` is a rownum to start from, like for
+/// the stream's event index.
+#[tracing::instrument]
+#[debug_handler]
+async fn get_subject_events(
+    state: State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Path((stream_id, subject)): Path<(String, String)>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Response {
+    let start = query.get("page[offset]").and_then(|start| start.parse().ok()).unwrap_or(0);
+    let default_limit = state.config().default_page_limit;
+    let limit = query.get("page[limit]").and_then(|limit| limit.parse().ok()).unwrap_or(default_limit).min(1000);
+
+    let events_result = state.get_events_by_subject(&user.id, &stream_id, &subject, start, limit).await;
+
+    match events_result {
+        Ok(events) => {
+            (
+                [(header::CACHE_CONTROL, "no-cache")],
+                Json(events),
+            ).into_response()
+        },
+        Err(err) => {
+            match err.downcast::<server::Error>() {
+                Ok(server::Error::StreamNotFound) => StatusCode::NOT_FOUND.into_response(),
                 Err(err) => {
-                    error!("error_id={} Failed to post event: {:?}", error_id, err);
+                    let error_id = Uuid::now_v7();
+                    error!("error_id={} user_id={} stream_id={} Error getting subject's events: {:?}", error_id, user.id, stream_id, err);
+
                     let body = ApiError {
                         id: error_id,
+                        code: ErrorCode::InternalError,
                         title: "Internal server error".to_string(),
                         detail: None,
                         source: None,
@@ -547,21 +1421,2244 @@ async fn post_event(
                     return (
                         StatusCode::INTERNAL_SERVER_ERROR,
                         [(header::CACHE_CONTROL, "no-cache")],
-                        Json::from(body),
+                        JsonApi(body),
                     ).into_response();
                 }
             }
+        },
+    }
+}
+
+/// A `Range` request for an inclusive span of rownums, like `events=10-19` or `events=10-`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct EventRange {
+    first: u64,
+    last: Option<u64>,
+}
+
+impl EventRange {
+    /// Parses a `Range` header value, returning `None` for other units, multiple ranges,
+    /// or anything malformed, all of which are ignored in favor of a full response.
+    fn parse(range: &str) -> Option<Self> {
+        let (first, last) = range.strip_prefix("events=")?.split_once('-')?;
+        let first = first.trim().parse().ok()?;
+        let last = match last.trim() {
+            "" => None,
+            last => Some(last.parse().ok()?),
+        };
+
+        match last {
+            Some(last) if last < first => None,
+            _ => Some(Self { first, last }),
         }
     }
 }
 
-fn parse_expected_revision(expected_revision: &str) -> Result<ExpectedRevision> {
-    match expected_revision {
-        "any" => Ok(ExpectedRevision::Any),
-        "no-stream" => Ok(ExpectedRevision::NoStream),
-        "stream-exists" => Ok(ExpectedRevision::StreamExists),
-        exact => {
-            if let Ok(exact_revision) = exact.parse() {
+/// Exports the stream as newline-delimited CloudEvents JSON.
+///
+/// The export is generated rather than stored, so byte ranges aren't supported. Instead,
+/// `Range` requests use an `events` unit counting rownums: `Range: events=10-19` returns
+/// rownums 10 through 19 inclusive as `206 Partial Content`, and `events=10-` returns
+/// everything from rownum 10, so a client can resume an interrupted download from the
+/// rownum after the last complete line it received. Byte ranges are ignored.
+#[tracing::instrument]
+#[debug_handler]
+async fn get_export(state: State<Arc<AppState>>, Extension(user): Extension<User>, Path(stream_id): Path<String>, headers: HeaderMap) -> Response {
+    let range = headers.get(header::RANGE)
+        .and_then(|range| range.to_str().ok())
+        .and_then(EventRange::parse);
+
+    let rows = match range {
+        Some(EventRange { first, last: Some(last) }) => first..last.saturating_add(1),
+        Some(EventRange { first, last: None }) => first..u64::MAX,
+        None => 0..u64::MAX,
+    };
+
+    let export_result = state.export_events(&user.id, &stream_id, rows).await;
+
+    match export_result {
+        Ok((events, rows, head_revision)) => {
+            let error_user_id = user.id.clone();
+            let error_stream_id = stream_id.clone();
+
+            // Errors partway through can only cut the body short, since the status is already sent.
+            let body = Body::from_stream(events.map(move |event| {
+                let line = event.and_then(|event| {
+                    let mut json = serde_json::to_vec(&event)?;
+                    json.push(b'\n');
+                    Ok(json)
+                });
+
+                if let Err(err) = &line {
+                    error!("user_id={} stream_id={} Error exporting events: {:?}", error_user_id, error_stream_id, err);
+                }
+
+                line
+            }));
+
+            if range.is_none() {
+                return (
+                    [
+                        (header::CONTENT_TYPE, "application/x-ndjson"),
+                        (header::CACHE_CONTROL, "no-cache"),
+                        (header::ACCEPT_RANGES, "events"),
+                    ],
+                    body,
+                ).into_response();
+            }
+
+            // Compaction leaves gaps in the rownums, so the range may hold no events even
+            // when it starts below the head revision.
+            let Some(rows) = rows else {
+                return (
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    [
+                        (header::CACHE_CONTROL, "no-cache".to_string()),
+                        (header::CONTENT_RANGE, format!("events */{}", head_revision)),
+                    ],
+                ).into_response();
+            };
+
+            return (
+                StatusCode::PARTIAL_CONTENT,
+                [
+                    (header::CONTENT_TYPE, "application/x-ndjson".to_string()),
+                    (header::CACHE_CONTROL, "no-cache".to_string()),
+                    (header::ACCEPT_RANGES, "events".to_string()),
+                    (header::CONTENT_RANGE, format!("events {}-{}/{}", rows.start(), rows.end(), head_revision)),
+                ],
+                body,
+            ).into_response();
+        },
+        Err(err) => {
+            match err.downcast::<server::Error>() {
+                Ok(server::Error::StreamNotFound) => StatusCode::NOT_FOUND.into_response(),
+                Err(err) => {
+                    let error_id = Uuid::now_v7();
+                    error!("error_id={} user_id={} stream_id={} Error exporting events: {:?}", error_id, user.id, stream_id, err);
+
+                    let body = ApiError {
+                        id: error_id,
+                        code: ErrorCode::InternalError,
+                        title: "Internal server error".to_string(),
+                        detail: None,
+                        source: None,
+                    }.into_document();
+
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        [(header::CACHE_CONTROL, "no-cache")],
+                        JsonApi(body),
+                    ).into_response();
+                }
+            }
+        },
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct SseParams {
+    from: Option<String>,
+}
+
+/// Streams the stream's events as Server-Sent Events, replaying from rownum `from` if given,
+/// then sending each event as it's appended. Each message's `id` is the event's rownum, so a
+/// reconnecting client's `Last-Event-ID` resumes right after the last event it received.
+/// When the server shuts down, a final `shutdown` message tells the client to reconnect.
+#[tracing::instrument]
+#[debug_handler]
+async fn get_event_sse(
+    state: State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Path(stream_id): Path<String>,
+    Query(params): Query<SseParams>,
+    headers: HeaderMap,
+) -> Response {
+    let last_event_id = headers.get("last-event-id").and_then(|id| id.to_str().ok());
+
+    let from = match (last_event_id, params.from.as_deref()) {
+        (Some(last_event_id), _) => last_event_id.parse::<u64>().ok().map(|rownum| Some(rownum + 1)).ok_or(ApiErrorSource::header("Last-Event-ID")),
+        (None, Some(from)) => from.parse().ok().map(Some).ok_or(ApiErrorSource::query("from")),
+        (None, None) => Ok(None),
+    };
+
+    let from = match from {
+        Ok(from) => from,
+        Err(source) => {
+            let error_id = Uuid::now_v7();
+            debug!("error_id={} Rejected event subscription with invalid start {:?}", error_id, source);
+
+            let body = ApiError {
+                id: error_id,
+                code: ErrorCode::InvalidParameter,
+                title: "Invalid parameter".to_string(),
+                detail: Some("the rownum to start from must be a non-negative integer".to_string()),
+                source: Some(source),
+            }.into_document();
+
+            return (
+                StatusCode::BAD_REQUEST,
+                [(header::CACHE_CONTROL, "no-cache")],
+                JsonApi(body),
+            ).into_response();
+        },
+    };
+
+    let subscribe_result = state.subscribe(&user.id, &stream_id, from).await;
+
+    match subscribe_result {
+        Ok(events) => {
+            let error_user_id = user.id.clone();
+            let error_stream_id = stream_id.clone();
+
+            // The subscription is dropped along with the response body when the client
+            // disconnects. A subscriber that falls behind gets an error message and is dropped.
+            let messages = events.map(move |item| {
+                let message = item.and_then(|(rownum, event)| Ok(sse::Event::default().id(rownum.to_string()).json_data(event)?));
+
+                Ok::<_, Infallible>(message.unwrap_or_else(|err| {
+                    error!("user_id={} stream_id={} Error streaming events: {:?}", error_user_id, error_stream_id, err);
+                    sse::Event::default().event("error").data(err.to_string())
+                }))
+            });
+
+            // Subscriptions end when the server starts shutting down, so the client can be told
+            // to reconnect to another instance.
+            let state = state.0.clone();
+            let shutdown_notice = stream::once(async move { state.is_shutting_down() })
+                .filter_map(|shutting_down| future::ready(shutting_down.then(|| {
+                    Ok(sse::Event::default().event("shutdown").data("server shutting down, reconnect"))
+                })));
+            let messages = messages.chain(shutdown_notice);
+
+            (
+                [(header::CACHE_CONTROL, "no-cache")],
+                Sse::new(messages).keep_alive(KeepAlive::default()),
+            ).into_response()
+        },
+        Err(err) => {
+            match err.downcast::<server::Error>() {
+                Ok(server::Error::StreamNotFound) => StatusCode::NOT_FOUND.into_response(),
+                Err(err) => {
+                    let error_id = Uuid::now_v7();
+                    error!("error_id={} user_id={} stream_id={} Error subscribing to events: {:?}", error_id, user.id, stream_id, err);
+
+                    let body = ApiError {
+                        id: error_id,
+                        code: ErrorCode::InternalError,
+                        title: "Internal server error".to_string(),
+                        detail: None,
+                        source: None,
+                    }.into_document();
+
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        [(header::CACHE_CONTROL, "no-cache")],
+                        JsonApi(body),
+                    ).into_response()
+                }
+            }
+        },
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct EventPollDocument {
+    data: Vec<Event>,
+    meta: EventPollMeta,
+}
+
+#[derive(Debug, Serialize)]
+struct EventPollMeta {
+    /// `after_revision` to poll with next. Behind `head_revision` when `page[limit]` cut the
+    /// page short.
+    next_revision: u64,
+    head_revision: u64,
+}
+
+#[tracing::instrument]
+async fn get_events_after(state: &AppState, user: &User, stream_id: &String, after_revision: &str, limit: usize, headers: &HeaderMap) -> Response {
+    let after_revision: u64 =
+        if let Ok(after_revision) = after_revision.parse() {
+            after_revision
+        } else {
+            let error_id = Uuid::now_v7();
+            debug!("error_id={} Invalid after_revision {:?}", error_id, after_revision);
+
+            let body = ApiError {
+                id: error_id,
+                code: ErrorCode::InvalidParameter,
+                title: "Invalid parameter".to_string(),
+                detail: Some("after_revision must be a non-negative integer".to_string()),
+                source: Some(ApiErrorSource::query("after_revision")),
+            }.into_document();
+
+            return (
+                StatusCode::BAD_REQUEST,
+                [(header::CACHE_CONTROL, "no-cache")],
+                JsonApi(body),
+            ).into_response();
+        };
+
+    let events_result = state.get_events_after(&user.id, stream_id, after_revision, limit).await;
+
+    match events_result {
+        Ok(poll) => {
+            // The page depends on where it starts as well as the head.
+            let etag = format!("W/\"{}-{}\"", after_revision, poll.head_revision);
+            let cache_headers = [
+                (header::CACHE_CONTROL, "no-cache".to_string()),
+                (header::ETAG, etag.clone()),
+            ];
+
+            if etag_matches(headers, &etag) {
+                return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+            }
+
+            let doc = EventPollDocument {
+                data: poll.events,
+                meta: EventPollMeta {
+                    next_revision: poll.next_revision,
+                    head_revision: poll.head_revision,
+                },
+            };
+
+            return (
+                cache_headers,
+                JsonApi(doc),
+            ).into_response();
+        },
+        Err(err) => {
+            match err.downcast::<server::Error>() {
+                Ok(server::Error::StreamNotFound) => StatusCode::NOT_FOUND.into_response(),
+                Err(err) => {
+                    let error_id = Uuid::now_v7();
+                    error!("error_id={} user_id={} stream_id={} Error getting events: {:?}", error_id, user.id, stream_id, err);
+
+                    let body = ApiError {
+                        id: error_id,
+                        code: ErrorCode::InternalError,
+                        title: "Internal server error".to_string(),
+                        detail: None,
+                        source: None,
+                    }.into_document();
+
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        [(header::CACHE_CONTROL, "no-cache")],
+                        JsonApi(body),
+                    ).into_response();
+                }
+            }
+        },
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct EventTypeStats {
+    count: u64,
+}
+
+#[tracing::instrument]
+#[debug_handler]
+async fn get_event_types(state: State<Arc<AppState>>, Extension(user): Extension<User>, Path(stream_id): Path<String>) -> Response {
+    let types_result = state.event_types(&user.id, &stream_id).await;
+
+    match types_result {
+        Ok(types) => {
+            let mut type_resources = vec![];
+            for (event_type, count) in types.into_iter() {
+                type_resources.push(ApiResource::new(event_type, "event-types".to_string(), EventTypeStats { count }));
+            }
+
+            let doc = ApiDataCollectionDocument::new(type_resources);
+
+            return (
+                [(header::CACHE_CONTROL, "no-cache")],
+                JsonApi(doc),
+            ).into_response();
+        },
+        Err(err) => {
+            match err.downcast::<server::Error>() {
+                Ok(server::Error::StreamNotFound) => StatusCode::NOT_FOUND.into_response(),
+                Err(err) => {
+                    let error_id = Uuid::now_v7();
+                    error!("error_id={} user_id={} stream_id={} Error getting event types: {:?}", error_id, user.id, stream_id, err);
+
+                    let body = ApiError {
+                        id: error_id,
+                        code: ErrorCode::InternalError,
+                        title: "Internal server error".to_string(),
+                        detail: None,
+                        source: None,
+                    }.into_document();
+
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        [(header::CACHE_CONTROL, "no-cache")],
+                        JsonApi(body),
+                    ).into_response();
+                }
+            }
+        },
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct GetActivityParams {
+    bucket: Option<String>,
+    since: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ActivityDocument {
+    data: Vec<ApiResource<ActivityBucket>>,
+    meta: ActivityMeta,
+}
+
+#[derive(Debug, Serialize)]
+struct ActivityBucket {
+    count: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct ActivityMeta {
+    bucket_seconds: u64,
+    /// Events without a `time` attribute, which aren't counted in any bucket.
+    untimed: u64,
+}
+
+/// Event counts bucketed by the events' `time` attribute. Each bucket's `id` is the RFC 3339
+/// time it starts at, and buckets without events are left out.
+#[tracing::instrument]
+#[debug_handler]
+async fn get_activity(state: State<Arc<AppState>>, Extension(user): Extension<User>, Path(stream_id): Path<String>, Query(params): Query<GetActivityParams>) -> Response {
+    let bucket = match parse_duration(params.bucket.as_deref().unwrap_or("1h")) {
+        Ok(bucket) => bucket,
+        Err(err) => {
+            let error_id = Uuid::now_v7();
+            debug!("error_id={} Invalid bucket {:?}: {}", error_id, params.bucket, err);
+
+            let body = ApiError {
+                id: error_id,
+                code: ErrorCode::InvalidParameter,
+                title: "Invalid parameter".to_string(),
+                detail: Some("bucket must be a positive whole number of s, m, h, or d, like 15m".to_string()),
+                source: Some(ApiErrorSource::query("bucket")),
+            }.into_document();
+
+            return (
+                StatusCode::BAD_REQUEST,
+                [(header::CACHE_CONTROL, "no-cache")],
+                JsonApi(body),
+            ).into_response();
+        }
+    };
+
+    let since = match params.since.as_deref().map(|since| OffsetDateTime::parse(since, &Rfc3339)).transpose() {
+        Ok(since) => since.map(|since| since.unix_timestamp()),
+        Err(err) => {
+            let error_id = Uuid::now_v7();
+            debug!("error_id={} Invalid since {:?}: {}", error_id, params.since, err);
+
+            let body = ApiError {
+                id: error_id,
+                code: ErrorCode::InvalidParameter,
+                title: "Invalid parameter".to_string(),
+                detail: Some("since must be an RFC 3339 timestamp".to_string()),
+                source: Some(ApiErrorSource::query("since")),
+            }.into_document();
+
+            return (
+                StatusCode::BAD_REQUEST,
+                [(header::CACHE_CONTROL, "no-cache")],
+                JsonApi(body),
+            ).into_response();
+        }
+    };
+
+    let activity_result = state.activity(&user.id, &stream_id, bucket, since).await;
+
+    match activity_result {
+        Ok(activity) => {
+            let mut bucket_resources = vec![];
+            for (start, count) in activity.buckets.into_iter() {
+                let start = OffsetDateTime::from_unix_timestamp(start)
+                    .ok()
+                    .and_then(|start| start.format(&Rfc3339).ok())
+                    .unwrap_or_else(|| start.to_string());
+
+                bucket_resources.push(ApiResource::new(start, "activity-buckets".to_string(), ActivityBucket { count }));
+            }
+
+            let doc = ActivityDocument {
+                data: bucket_resources,
+                meta: ActivityMeta { bucket_seconds: bucket.as_secs(), untimed: activity.untimed },
+            };
+
+            return (
+                [(header::CACHE_CONTROL, "no-cache")],
+                JsonApi(doc),
+            ).into_response();
+        },
+        Err(err) => {
+            match err.downcast::<server::Error>() {
+                Ok(server::Error::StreamNotFound) => StatusCode::NOT_FOUND.into_response(),
+                Err(err) => {
+                    let error_id = Uuid::now_v7();
+                    error!("error_id={} user_id={} stream_id={} Error getting activity: {:?}", error_id, user.id, stream_id, err);
+
+                    let body = ApiError {
+                        id: error_id,
+                        code: ErrorCode::InternalError,
+                        title: "Internal server error".to_string(),
+                        detail: None,
+                        source: None,
+                    }.into_document();
+
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        [(header::CACHE_CONTROL, "no-cache")],
+                        JsonApi(body),
+                    ).into_response();
+                }
+            }
+        },
+    }
+}
+
+#[tracing::instrument]
+#[debug_handler]
+async fn get_streams(
+    state: State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Response {
+    let get_result = state.streams(&user.id).await;
+
+    match get_result {
+        Ok(mut streams) => {
+            let default_sort_field = "id".to_string();
+            let sort_field: &str = query.get("sort").unwrap_or(&default_sort_field);
+
+            match sort_field {
+                "id" => streams.sort_by(|a, b| a.id.cmp(&b.id)),
+                "usage" => streams.sort_by_key(|a| a.usage),
+                "-usage" => streams.sort_by_key(|a| Reverse(a.usage)),
+                "revision" => streams.sort_by_key(|a| a.revision),
+                "-revision" => streams.sort_by_key(|a| Reverse(a.revision)),
+                "count" => streams.sort_by_key(|a| a.count),
+                "-count" => streams.sort_by_key(|a| Reverse(a.count)),
+                "last_modified" => streams.sort_by_key(|a| a.last_modified),
+                "-last_modified" => streams.sort_by_key(|a| Reverse(a.last_modified)),
+                "created_at" => streams.sort_by_key(|a| a.created_at),
+                "-created_at" => streams.sort_by_key(|a| Reverse(a.created_at)),
+                _ => {
+                    return StatusCode::BAD_REQUEST.into_response();
+                },
+            };
+
+            let mut stream_resources = vec![];
+            for stream in streams.into_iter() {
+                stream_resources.push(ApiResource::new(stream.id.to_string(), "streams".to_string(), stream));
+            }
+
+            let doc = ApiDataCollectionDocument::new(stream_resources);
+
+            return JsonApi(doc).into_response();
+        }
+        Err(err) => {
+            let error_id = Uuid::now_v7();
+            error!("error_id={} user_id={} Error getting streams: {}", error_id, user.id, err);
+
+            let body = ApiError {
+                id: error_id,
+                code: ErrorCode::InternalError,
+                title: "Internal server error".to_string(),
+                detail: None,
+                source: None,
+            }.into_document();
+
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                [(header::CACHE_CONTROL, "no-cache")],
+                JsonApi(body),
+            ).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct GetStreamParams {
+    #[serde(default)]
+    consistency: Consistency,
+}
+
+#[tracing::instrument]
+#[debug_handler]
+async fn get_stream(
+    state: State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Path(stream_id): Path<String>,
+    Query(query_params): Query<GetStreamParams>,
+    headers: HeaderMap,
+) -> Response {
+    let get_result = state.get_stream(&user.id, &stream_id, query_params.consistency).await;
+
+    match get_result {
+        Ok(stream) => {
+            let last_modified = OffsetDateTime::from_unix_timestamp(stream.last_modified.try_into().expect("Expected app to be running after epoch")).unwrap().format(&Rfc2822).unwrap();
+            let revision = stream.revision;
+
+            let body = ApiResource {
+                id: stream_id.clone(),
+                resource_type: "streams".to_string(),
+                attributes: Some(stream),
+            }.into_document();
+
+            let bytes = match serde_json::to_vec(&body) {
+                Ok(bytes) => bytes,
+                Err(err) => {
+                    let error_id = Uuid::now_v7();
+                    error!("error_id={} user_id={} stream_id={} Error serializing stream: {:?}", error_id, user.id, stream_id, err);
+
+                    let body = ApiError {
+                        id: error_id,
+                        code: ErrorCode::InternalError,
+                        title: "Internal server error".to_string(),
+                        detail: None,
+                        source: None,
+                    }.into_document();
+
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        [(header::CACHE_CONTROL, "no-cache")],
+                        JsonApi(body),
+                    ).into_response();
+                },
+            };
+
+            let etag = stream_etag(revision, &bytes);
+            let cache_headers = [
+                (header::CACHE_CONTROL, "no-cache".to_string()),
+                (header::LAST_MODIFIED, last_modified),
+                (header::ETAG, etag.clone()),
+            ];
+
+            if etag_matches(&headers, &etag) {
+                return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+            }
+
+            return (
+                StatusCode::OK,
+                cache_headers,
+                [(header::CONTENT_TYPE, JSON_API_CONTENT_TYPE)],
+                bytes,
+            ).into_response();
+        }
+        Err(err) => {
+            match err.downcast::<server::Error>() {
+                Ok(server::Error::StreamNotFound) => StatusCode::NOT_FOUND.into_response(),
+                Err(err) => {
+                    let error_id = Uuid::now_v7();
+                    error!("error_id={} user_id={} stream_id={} Error getting stream: {:?}", error_id, user.id, stream_id, err);
+
+                    let body = ApiError {
+                        id: error_id,
+                        code: ErrorCode::InternalError,
+                        title: "Internal server error".to_string(),
+                        detail: None,
+                        source: None,
+                    }.into_document();
+
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        [(header::CACHE_CONTROL, "no-cache")],
+                        JsonApi(body),
+                    ).into_response()
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PatchStreamDocument {
+    data: PatchStreamResource,
+}
+
+#[derive(Debug, Deserialize)]
+struct PatchStreamResource {
+    attributes: StreamMetadata,
+}
+
+#[tracing::instrument]
+#[debug_handler]
+async fn patch_stream(
+    state: State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Path(stream_id): Path<String>,
+    Json(document): Json<PatchStreamDocument>,
+) -> Response {
+    let patch_result = state.set_stream_metadata(&user.id, &stream_id, document.data.attributes).await;
+
+    match patch_result {
+        Ok(()) => get_stream(state, Extension(user), Path(stream_id), Query(GetStreamParams::default()), HeaderMap::new()).await,
+        Err(err) => {
+            match err.downcast::<server::Error>() {
+                Ok(server::Error::StreamNotFound) => StatusCode::NOT_FOUND.into_response(),
+                Err(err) => {
+                    let error_id = Uuid::now_v7();
+                    error!("error_id={} user_id={} stream_id={} Error updating stream: {:?}", error_id, user.id, stream_id, err);
+
+                    let body = ApiError {
+                        id: error_id,
+                        code: ErrorCode::InternalError,
+                        title: "Internal server error".to_string(),
+                        detail: None,
+                        source: None,
+                    }.into_document();
+
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        [(header::CACHE_CONTROL, "no-cache")],
+                        JsonApi(body),
+                    ).into_response();
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct PutStreamParams {
+    /// Replaces the settings of an existing stream instead of failing.
+    #[serde(default)]
+    overwrite: bool,
+}
+
+/// Creates an empty stream with the given settings in one call, instead of letting the first
+/// append create it with the defaults. Fails if the stream exists, unless `overwrite` is given.
+#[tracing::instrument]
+#[debug_handler]
+async fn put_stream(
+    state: State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Path(stream_id): Path<String>,
+    Query(params): Query<PutStreamParams>,
+    Json(document): Json<PatchStreamDocument>,
+) -> Response {
+    let metadata = document.data.attributes;
+
+    match state.create_stream(&user.id, &stream_id, metadata.clone()).await {
+        Ok(()) => {
+            let mut response = get_stream(state, Extension(user), Path(stream_id.clone()), Query(GetStreamParams::default()), HeaderMap::new()).await;
+
+            if response.status() == StatusCode::OK {
+                *response.status_mut() = StatusCode::CREATED;
+                if let Ok(location) = HeaderValue::from_str(&format!("http://localhost:8080/streams/{}", stream_id)) {
+                    response.headers_mut().insert(header::LOCATION, location);
+                }
+            }
+
+            response
+        },
+        Err(err) if err.is::<QuotaExceeded>() => quota_exceeded_response(err.downcast_ref().unwrap()),
+        Err(err) if err.is::<StreamExists>() && params.overwrite => {
+            patch_stream(state, Extension(user), Path(stream_id), Json(PatchStreamDocument { data: PatchStreamResource { attributes: metadata } })).await
+        },
+        Err(err) if err.is::<StreamExists>() => {
+            let error_id = Uuid::now_v7();
+            debug!("error_id={} user_id={} stream_id={} Refused to create a stream that exists", error_id, user.id, stream_id);
+
+            let body = ApiError {
+                id: error_id,
+                code: ErrorCode::StreamExists,
+                title: "Stream exists".to_string(),
+                detail: Some(format!("stream {:?} already exists, pass overwrite=true to replace its settings", stream_id)),
+                source: Some(ApiErrorSource::query("overwrite")),
+            }.into_document();
+
+            (
+                StatusCode::CONFLICT,
+                [(header::CACHE_CONTROL, "no-cache")],
+                JsonApi(body),
+            ).into_response()
+        },
+        Err(err) => {
+            let error_id = Uuid::now_v7();
+            error!("error_id={} user_id={} stream_id={} Error creating stream: {:?}", error_id, user.id, stream_id, err);
+
+            let body = ApiError {
+                id: error_id,
+                code: ErrorCode::InternalError,
+                title: "Internal server error".to_string(),
+                detail: None,
+                source: None,
+            }.into_document();
+
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                [(header::CACHE_CONTROL, "no-cache")],
+                JsonApi(body),
+            ).into_response()
+        },
+    }
+}
+
+/// Reports how often each of the server's caches has been hit and missed, for sizing them.
+#[tracing::instrument]
+#[debug_handler]
+async fn get_cache_metrics(state: State<Arc<AppState>>, Extension(user): Extension<User>) -> Response {
+    if !state.config().admin_users.contains(&user.id) {
+        return not_admin_response(&user);
+    }
+
+    let body = ApiMetaDocument { meta: state.cache_metrics.counts() };
+
+    (
+        StatusCode::OK,
+        [(header::CACHE_CONTROL, "no-cache")],
+        JsonApi(body),
+    ).into_response()
+}
+
+/// Makes any user's sealed stream writable again. Owners seal their streams with `PATCH`, but
+/// only admins can unseal them.
+#[tracing::instrument]
+#[debug_handler]
+async fn post_unseal(
+    state: State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Path((owner_id, stream_id)): Path<(String, String)>,
+) -> Response {
+    if !state.config().admin_users.contains(&user.id) {
+        return not_admin_response(&user);
+    }
+
+    match state.unseal_stream(&owner_id, &stream_id).await {
+        Ok(()) => {
+            info!("user_id={} owner_id={} stream_id={} msg=\"Unsealed stream\"", user.id, owner_id, stream_id);
+            StatusCode::NO_CONTENT.into_response()
+        },
+        Err(err) => match err.downcast::<server::Error>() {
+            Ok(server::Error::StreamNotFound) => StatusCode::NOT_FOUND.into_response(),
+            Err(err) => {
+                let error_id = Uuid::now_v7();
+                error!("error_id={} user_id={} owner_id={} stream_id={} Error unsealing stream: {:?}", error_id, user.id, owner_id, stream_id, err);
+
+                let body = ApiError {
+                    id: error_id,
+                    code: ErrorCode::InternalError,
+                    title: "Internal server error".to_string(),
+                    detail: None,
+                    source: None,
+                }.into_document();
+
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    [(header::CACHE_CONTROL, "no-cache")],
+                    JsonApi(body),
+                ).into_response()
+            },
+        },
+    }
+}
+
+/// Deletes every one of the user's streams, as when offboarding a tenant, and reports how many
+/// there were.
+#[tracing::instrument]
+#[debug_handler]
+async fn delete_streams(state: State<Arc<AppState>>, Extension(user): Extension<User>) -> Response {
+    match state.delete_all_streams(&user.id).await {
+        Ok(deleted) => {
+            info!("user_id={} deleted_streams={} msg=\"Deleted all streams\"", user.id, deleted);
+
+            let body = ApiMetaDocument { meta: DeletedStreamsMeta { deleted } };
+
+            (
+                StatusCode::OK,
+                [(header::CACHE_CONTROL, "no-cache")],
+                JsonApi(body),
+            ).into_response()
+        },
+        Err(err) => {
+            let error_id = Uuid::now_v7();
+            error!("error_id={} user_id={} Error deleting all streams: {:?}", error_id, user.id, err);
+
+            let body = ApiError {
+                id: error_id,
+                code: ErrorCode::InternalError,
+                title: "Internal server error".to_string(),
+                detail: None,
+                source: None,
+            }.into_document();
+
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                [(header::CACHE_CONTROL, "no-cache")],
+                JsonApi(body),
+            ).into_response()
+        },
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct DeletedStreamsMeta {
+    /// Number of streams deleted.
+    deleted: u64,
+}
+
+#[tracing::instrument]
+#[debug_handler]
+async fn delete_stream(state: State<Arc<AppState>>, Extension(user): Extension<User>, Path(stream_id): Path<String>) -> Response {
+    let delete_result = state.delete_stream(&user.id, &stream_id).await;
+
+    match delete_result {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            let error_id = Uuid::now_v7();
+            error!("error_id={} user_id={} stream_id={} Error deleting stream: {}", error_id, user.id, stream_id, err);
+
+            let body = ApiError {
+                id: error_id,
+                code: ErrorCode::InternalError,
+                title: "Internal server error".to_string(),
+                detail: None,
+                source: None,
+            }.into_document();
+
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                [(header::CACHE_CONTROL, "no-cache")],
+                JsonApi(body),
+            ).into_response();
+        }
+    }
+}
+
+/// `403` response for a user who isn't one of the `admin_users` using an admin route.
+fn not_admin_response(user: &User) -> Response {
+    let error_id = Uuid::now_v7();
+    debug!("error_id={} user_id={} Rejected admin request by a user who isn't an admin", error_id, user.id);
+
+    let body = ApiError {
+        id: error_id,
+        code: ErrorCode::NotAdmin,
+        title: "Forbidden".to_string(),
+        detail: Some("Only admin users can use this route".to_string()),
+        source: None,
+    }.into_document();
+
+    (
+        StatusCode::FORBIDDEN,
+        [(header::CACHE_CONTROL, "no-cache")],
+        JsonApi(body),
+    ).into_response()
+}
+
+/// Reports how many entries a stream's in-memory index holds and roughly how much memory they
+/// take, for deciding which streams are getting too large to index fully.
+#[tracing::instrument]
+#[debug_handler]
+async fn get_index_info(
+    state: State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Path((owner_id, stream_id)): Path<(String, String)>,
+) -> Response {
+    if !state.config().admin_users.contains(&user.id) {
+        return not_admin_response(&user);
+    }
+
+    match state.index_info(&owner_id, &stream_id).await {
+        Ok(info) => {
+            let body = ApiResource::new(stream_id, "index-info".to_string(), info).into_document();
+
+            (
+                StatusCode::OK,
+                [(header::CACHE_CONTROL, "no-cache")],
+                JsonApi(body),
+            ).into_response()
+        },
+        Err(err) => match err.downcast::<server::Error>() {
+            Ok(server::Error::StreamNotFound) => StatusCode::NOT_FOUND.into_response(),
+            Err(err) => {
+                let error_id = Uuid::now_v7();
+                error!("error_id={} user_id={} owner_id={} stream_id={} Error getting index info: {:?}", error_id, user.id, owner_id, stream_id, err);
+
+                let body = ApiError {
+                    id: error_id,
+                    code: ErrorCode::InternalError,
+                    title: "Internal server error".to_string(),
+                    detail: None,
+                    source: None,
+                }.into_document();
+
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    [(header::CACHE_CONTROL, "no-cache")],
+                    JsonApi(body),
+                ).into_response()
+            },
+        },
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PostStreamMoveDocument {
+    data: PostStreamMoveResource,
+}
+
+#[derive(Debug, Deserialize)]
+struct PostStreamMoveResource {
+    attributes: PostStreamMoveAttributes,
+}
+
+#[derive(Debug, Deserialize)]
+struct PostStreamMoveAttributes {
+    source: StreamLocation,
+    target: StreamLocation,
+}
+
+/// A stream ID in a user's namespace.
+#[derive(Debug, Deserialize)]
+struct StreamLocation {
+    user: String,
+    stream: String,
+}
+
+/// Moves a stream, with its events and metadata, to another user or stream ID, for
+/// reorganizing tenants or fixing a write to the wrong one. Only `admin_users` can move streams.
+#[tracing::instrument(skip(document))]
+#[debug_handler]
+async fn post_stream_move(
+    state: State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Json(document): Json<PostStreamMoveDocument>,
+) -> Response {
+    if !state.config().admin_users.contains(&user.id) {
+        return not_admin_response(&user);
+    }
+
+    let attributes = document.data.attributes;
+    let source = (attributes.source.user, attributes.source.stream);
+    let target = (attributes.target.user, attributes.target.stream);
+
+    match state.move_stream(&source, &target).await {
+        Ok(()) => {
+            info!("user_id={} source_user_id={} source_stream_id={} target_user_id={} target_stream_id={} msg=\"Moved stream\"", user.id, source.0, source.1, target.0, target.1);
+            StatusCode::NO_CONTENT.into_response()
+        },
+        Err(err) if matches!(err.downcast_ref::<server::Error>(), Some(server::Error::StreamNotFound)) => {
+            StatusCode::NOT_FOUND.into_response()
+        },
+        Err(err) if err.is::<QuotaExceeded>() => quota_exceeded_response(err.downcast_ref().unwrap()),
+        Err(err) if err.is::<StreamExists>() => {
+            let error_id = Uuid::now_v7();
+            debug!("error_id={} Rejected stream move onto an existing stream", error_id);
+
+            let body = ApiError {
+                id: error_id,
+                code: ErrorCode::StreamExists,
+                title: "Stream exists".to_string(),
+                detail: Some(format!("User {:?} already has a stream {:?}", target.0, target.1)),
+                source: Some(ApiErrorSource::pointer("/data/attributes/target")),
+            }.into_document();
+
+            (
+                StatusCode::CONFLICT,
+                [(header::CACHE_CONTROL, "no-cache")],
+                JsonApi(body),
+            ).into_response()
+        },
+        Err(err) => {
+            let error_id = Uuid::now_v7();
+            error!("error_id={} user_id={} Error moving stream: {:?}", error_id, user.id, err);
+
+            let body = ApiError {
+                id: error_id,
+                code: ErrorCode::InternalError,
+                title: "Internal server error".to_string(),
+                detail: None,
+                source: None,
+            }.into_document();
+
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                [(header::CACHE_CONTROL, "no-cache")],
+                JsonApi(body),
+            ).into_response()
+        },
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PostStreamRenameDocument {
+    data: PostStreamRenameResource,
+}
+
+#[derive(Debug, Deserialize)]
+struct PostStreamRenameResource {
+    attributes: PostStreamRenameAttributes,
+}
+
+#[derive(Debug, Deserialize)]
+struct PostStreamRenameAttributes {
+    stream: String,
+}
+
+/// Renames one of the user's streams, keeping its events and metadata, for fixing a mistyped
+/// stream ID.
+#[tracing::instrument(skip(document))]
+#[debug_handler]
+async fn post_stream_rename(
+    state: State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Path(stream_id): Path<String>,
+    Json(document): Json<PostStreamRenameDocument>,
+) -> Response {
+    let new_stream_id = document.data.attributes.stream;
+
+    match state.rename_stream(&user.id, &stream_id, &new_stream_id).await {
+        Ok(true) => {
+            info!("user_id={} stream_id={} new_stream_id={} msg=\"Renamed stream\"", user.id, stream_id, new_stream_id);
+            StatusCode::NO_CONTENT.into_response()
+        },
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) if err.is::<StreamExists>() => {
+            let error_id = Uuid::now_v7();
+            debug!("error_id={} Rejected stream rename onto an existing stream", error_id);
+
+            let body = ApiError {
+                id: error_id,
+                code: ErrorCode::StreamExists,
+                title: "Stream exists".to_string(),
+                detail: Some(format!("you already have a stream {:?}", new_stream_id)),
+                source: Some(ApiErrorSource::pointer("/data/attributes/stream")),
+            }.into_document();
+
+            (
+                StatusCode::CONFLICT,
+                [(header::CACHE_CONTROL, "no-cache")],
+                JsonApi(body),
+            ).into_response()
+        },
+        Err(err) => {
+            let error_id = Uuid::now_v7();
+            error!("error_id={} user_id={} stream_id={} Error renaming stream: {:?}", error_id, user.id, stream_id, err);
+
+            let body = ApiError {
+                id: error_id,
+                code: ErrorCode::InternalError,
+                title: "Internal server error".to_string(),
+                detail: None,
+                source: None,
+            }.into_document();
+
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                [(header::CACHE_CONTROL, "no-cache")],
+                JsonApi(body),
+            ).into_response()
+        },
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct LeaseParams {
+    lease: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PostLeaseDocument {
+    data: PostLeaseResource,
+}
+
+#[derive(Debug, Deserialize)]
+struct PostLeaseResource {
+    #[serde(default)]
+    attributes: PostLeaseAttributes,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct PostLeaseAttributes {
+    /// How long the lease should last, capped at the server's maximum.
+    ttl_ms: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct LeaseAttributes {
+    /// Unix timestamp at which the lease lapses unless it is renewed.
+    expires_at: u64,
+}
+
+/// Grants the stream's write lease, or renews it when called with the current lease's token
+/// in the `lease` query parameter.
+#[tracing::instrument]
+#[debug_handler]
+async fn post_lease(
+    state: State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Path(stream_id): Path<String>,
+    Query(lease_params): Query<LeaseParams>,
+    body: Bytes,
+) -> Response {
+    // The request body is optional, since a lease can be taken with the default TTL.
+    let document: Option<PostLeaseDocument> =
+        if body.is_empty() {
+            None
+        } else {
+            match serde_json::from_slice(&body) {
+                Ok(document) => Some(document),
+                Err(err) => {
+                    let error_id = Uuid::now_v7();
+                    debug!("error_id={} Rejected invalid lease request: {}", error_id, err);
+
+                    let body = ApiError {
+                        id: error_id,
+                        code: ErrorCode::InvalidParameter,
+                        title: "Invalid lease request".to_string(),
+                        detail: Some(err.to_string()),
+                        source: Some(ApiErrorSource::pointer("/data")),
+                    }.into_document();
+
+                    return (
+                        StatusCode::BAD_REQUEST,
+                        [(header::CACHE_CONTROL, "no-cache")],
+                        JsonApi(body),
+                    ).into_response();
+                }
+            }
+        };
+
+    let max_lease_ttl = state.config().max_lease_ttl;
+    let ttl = document
+        .and_then(|document| document.data.attributes.ttl_ms)
+        .map(Duration::from_millis)
+        .map_or(max_lease_ttl, |ttl| ttl.min(max_lease_ttl));
+
+    let lease_result = state.acquire_lease(&user.id, &stream_id, lease_params.lease.as_deref(), ttl);
+
+    match lease_result {
+        Ok((lease, granted)) => {
+            let expires_at = lease.expires_at.duration_since(UNIX_EPOCH).map_or(0, |since_epoch| since_epoch.as_secs());
+            let body = ApiResource::new(lease.token, "lease".to_string(), LeaseAttributes { expires_at }).into_document();
+            let status = if granted { StatusCode::CREATED } else { StatusCode::OK };
+
+            (
+                status,
+                [(header::CACHE_CONTROL, "no-cache")],
+                JsonApi(body),
+            ).into_response()
+        },
+        Err(err) => lease_error_response(err),
+    }
+}
+
+#[tracing::instrument]
+#[debug_handler]
+async fn delete_lease(
+    state: State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Path(stream_id): Path<String>,
+    Query(lease_params): Query<LeaseParams>,
+) -> Response {
+    let release_result = state.release_lease(&user.id, &stream_id, lease_params.lease.as_deref());
+
+    match release_result {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => lease_error_response(err),
+    }
+}
+
+fn lease_error_response(err: server::LeaseHeld) -> Response {
+    let error_id = Uuid::now_v7();
+    debug!("error_id={} Rejected request for leased stream: {}", error_id, err);
+
+    let body = ApiError {
+        id: error_id,
+        code: ErrorCode::LeaseHeld,
+        title: "Stream is leased".to_string(),
+        detail: Some(err.to_string()),
+        source: Some(ApiErrorSource::query("lease")),
+    }.into_document();
+
+    (
+        StatusCode::LOCKED,
+        [(header::CACHE_CONTROL, "no-cache")],
+        JsonApi(body),
+    ).into_response()
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ReserveParams {
+    count: Option<String>,
+    lease: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ReservationAttributes {
+    /// First reserved rownum. The reservation covers `start..start + count`.
+    start: u64,
+    count: usize,
+    /// Unix timestamp after which the reservation's unfilled rownums get tombstone events.
+    expires_at: u64,
+}
+
+/// Reserves the stream's next `count` rownums, to be filled in any order by posting events
+/// with the `reserved` query parameter.
+///
+/// Posted events aren't readable, and don't count toward the stream's revision, until every
+/// rownum before them is filled, and they're lost if the server restarts before then. Events
+/// posted without `reserved` are rejected until all reserved rownums are filled. Rownums left
+/// unfilled past the expiry are filled with `hematite.tombstone` events.
+#[tracing::instrument]
+#[debug_handler]
+async fn post_reserve(
+    state: State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Path(stream_id): Path<String>,
+    Query(params): Query<ReserveParams>,
+) -> Response {
+    let max_count = state.config().max_reservation_count;
+    let Some(count) = params.count.as_deref().and_then(|count| count.parse().ok()).filter(|count| (1..=max_count).contains(count)) else {
+        let error_id = Uuid::now_v7();
+        debug!("error_id={} Rejected reservation with invalid count {:?}", error_id, params.count);
+
+        let body = ApiError {
+            id: error_id,
+            code: ErrorCode::InvalidParameter,
+            title: "Invalid parameter".to_string(),
+            detail: Some(format!("count must be the positive number of rownums to reserve, at most {}", max_count)),
+            source: Some(ApiErrorSource::query("count")),
+        }.into_document();
+
+        return (
+            StatusCode::BAD_REQUEST,
+            [(header::CACHE_CONTROL, "no-cache")],
+            JsonApi(body),
+        ).into_response();
+    };
+
+    if let Err(err) = state.check_lease(&user.id, &stream_id, params.lease.as_deref()) {
+        return lease_error_response(err);
+    }
+
+    let ttl = state.config().reservation_ttl;
+    let reserve_result = state.reserve(&user.id, &stream_id, count, ttl).await;
+
+    match reserve_result {
+        Ok(start) => {
+            let expires_at = (SystemTime::now() + ttl).duration_since(UNIX_EPOCH).map_or(0, |since_epoch| since_epoch.as_secs());
+            let attributes = ReservationAttributes { start, count, expires_at };
+            let body = ApiResource::new(start.to_string(), "reservation".to_string(), attributes).into_document();
+
+            (
+                StatusCode::CREATED,
+                [(header::CACHE_CONTROL, "no-cache")],
+                JsonApi(body),
+            ).into_response()
+        },
+        Err(err) if matches!(err.downcast_ref::<db::Error>(), Some(db::Error::Sealed)) => sealed_response(),
+        Err(err) if err.is::<QuotaExceeded>() => quota_exceeded_response(err.downcast_ref().unwrap()),
+        Err(err) => {
+            let error_id = Uuid::now_v7();
+            error!("error_id={} user_id={} stream_id={} Error reserving rownums: {:?}", error_id, user.id, stream_id, err);
+
+            let body = ApiError {
+                id: error_id,
+                code: ErrorCode::InternalError,
+                title: "Internal server error".to_string(),
+                detail: None,
+                source: None,
+            }.into_document();
+
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                [(header::CACHE_CONTROL, "no-cache")],
+                JsonApi(body),
+            ).into_response()
+        },
+    }
+}
+
+/// Header carrying the revision a snapshot was taken at.
+const SNAPSHOT_REVISION_HEADER: &str = "snapshot-revision";
+/// Header carrying a stream's current revision when a write expected a different one.
+const STREAM_REVISION_HEADER: &str = "stream-revision";
+
+#[derive(Deserialize, Debug)]
+struct SnapshotParams {
+    revision: Option<String>,
+}
+
+#[tracing::instrument]
+#[debug_handler]
+async fn get_snapshot(state: State<Arc<AppState>>, Extension(user): Extension<User>, Path(stream_id): Path<String>) -> Response {
+    let snapshot_result = state.get_latest_snapshot(&user.id, &stream_id).await;
+
+    match snapshot_result {
+        Ok(Some((revision, bytes))) => {
+            (
+                [
+                    (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+                    (header::CACHE_CONTROL, "no-cache".to_string()),
+                    (header::HeaderName::from_static(SNAPSHOT_REVISION_HEADER), revision.to_string()),
+                ],
+                bytes,
+            ).into_response()
+        },
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            match err.downcast::<server::Error>() {
+                Ok(server::Error::StreamNotFound) => StatusCode::NOT_FOUND.into_response(),
+                Err(err) => {
+                    let error_id = Uuid::now_v7();
+                    error!("error_id={} user_id={} stream_id={} Error getting snapshot: {:?}", error_id, user.id, stream_id, err);
+
+                    let body = ApiError {
+                        id: error_id,
+                        code: ErrorCode::InternalError,
+                        title: "Internal server error".to_string(),
+                        detail: None,
+                        source: None,
+                    }.into_document();
+
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        [(header::CACHE_CONTROL, "no-cache")],
+                        JsonApi(body),
+                    ).into_response();
+                }
+            }
+        },
+    }
+}
+
+/// Stores the request body as the stream's snapshot, taken at the `revision` query parameter.
+#[tracing::instrument(skip(body))]
+#[debug_handler]
+async fn put_snapshot(
+    state: State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Path(stream_id): Path<String>,
+    Query(params): Query<SnapshotParams>,
+    body: Bytes,
+) -> Response {
+    let Some(revision) = params.revision.as_deref().and_then(|revision| revision.parse().ok()) else {
+        let error_id = Uuid::now_v7();
+        debug!("error_id={} Rejected snapshot with invalid revision {:?}", error_id, params.revision);
+
+        let body = ApiError {
+            id: error_id,
+            code: ErrorCode::InvalidParameter,
+            title: "Invalid parameter".to_string(),
+            detail: Some("revision must be the number of events the snapshot covers".to_string()),
+            source: Some(ApiErrorSource::query("revision")),
+        }.into_document();
+
+        return (
+            StatusCode::BAD_REQUEST,
+            [(header::CACHE_CONTROL, "no-cache")],
+            JsonApi(body),
+        ).into_response();
+    };
+
+    let put_result = state.put_snapshot(&user.id, &stream_id, revision, body.to_vec()).await;
+
+    match put_result {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(err) if err.is::<QuotaExceeded>() => quota_exceeded_response(err.downcast_ref().unwrap()),
+        Err(err) => {
+            if let Some(db::Error::SnapshotPastHead) = err.downcast_ref::<db::Error>() {
+                let error_id = Uuid::now_v7();
+                debug!("error_id={} Rejected snapshot past the head of the stream: {}", error_id, err);
+
+                let body = ApiError {
+                    id: error_id,
+                    code: ErrorCode::InvalidParameter,
+                    title: "Invalid parameter".to_string(),
+                    detail: Some(err.to_string()),
+                    source: Some(ApiErrorSource::query("revision")),
+                }.into_document();
+
+                return (
+                    StatusCode::BAD_REQUEST,
+                    [(header::CACHE_CONTROL, "no-cache")],
+                    JsonApi(body),
+                ).into_response();
+            }
+
+            match err.downcast::<server::Error>() {
+                Ok(server::Error::StreamNotFound) => StatusCode::NOT_FOUND.into_response(),
+                Err(err) => {
+                    let error_id = Uuid::now_v7();
+                    error!("error_id={} user_id={} stream_id={} Error putting snapshot: {:?}", error_id, user.id, stream_id, err);
+
+                    let body = ApiError {
+                        id: error_id,
+                        code: ErrorCode::InternalError,
+                        title: "Internal server error".to_string(),
+                        detail: None,
+                        source: None,
+                    }.into_document();
+
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        [(header::CACHE_CONTROL, "no-cache")],
+                        JsonApi(body),
+                    ).into_response();
+                }
+            }
+        },
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct PostEventParams {
+    expected_revision: Option<String>,
+    lease: Option<String>,
+    /// First of the reserved rownums to post the events into, instead of appending them.
+    reserved: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum PostEventPayload {
+    Single(Box<Event>),
+    Batch(Vec<Event>),
+}
+
+#[tracing::instrument]
+#[debug_handler]
+async fn post_event(
+    state: State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Path(stream_id): Path<String>,
+    Query(query_params): Query<PostEventParams>,
+    request: Request,
+) -> Response {
+    if query_params.reserved.is_some() && query_params.expected_revision.is_some() {
+        let error_id = Uuid::now_v7();
+        debug!("error_id={} Rejected post into reserved rownums with an expected revision", error_id);
+
+        let body = ApiError {
+            id: error_id,
+            code: ErrorCode::InvalidParameter,
+            title: "Invalid parameter".to_string(),
+            detail: Some("expected_revision can't be combined with reserved, since the reserved rownums are already known".to_string()),
+            source: Some(ApiErrorSource::query("expected_revision")),
+        }.into_document();
+
+        return (
+            StatusCode::BAD_REQUEST,
+            [(header::CACHE_CONTROL, "no-cache")],
+            JsonApi(body),
+        ).into_response();
+    }
+
+    let revision = {
+        let default_revision = "any".to_owned();
+        let revision_param = query_params.expected_revision.unwrap_or(default_revision);
+        match parse_expected_revision(revision_param.as_str()) {
+            Ok(revision) => revision,
+            Err(err) => {
+                let error_id = Uuid::now_v7();
+                debug!("error_id={} Failed to post event: {:#}", error_id, err);
+                let body = ApiError {
+                    id: error_id,
+                    code: ErrorCode::InvalidParameter,
+                    title: "Invalid parameter".to_string(),
+                    detail: Some(format!(
+                        "expected_revision must be any, no-stream, stream-exists, a revision number, or >= followed by a revision number, but got {:?}",
+                        revision_param,
+                    )),
+                    source: Some(ApiErrorSource::query("expected_revision")),
+                }.into_document();
+
+                return (
+                    StatusCode::BAD_REQUEST,
+                    [(header::CACHE_CONTROL, "no-cache")],
+                    JsonApi(body),
+                ).into_response();
+            },
+        }
+    };
+
+    let mut payload =
+        if is_binary_mode(request.headers()) {
+            let headers = request.headers().clone();
+            let body = match Bytes::from_request(request, &()).await {
+                Ok(body) => body,
+                Err(rejection) => return rejection.into_response(),
+            };
+
+            match binary_mode_event(&headers, body.to_vec()).map(serde_json::to_value) {
+                Ok(Ok(payload)) => payload,
+                Ok(Err(err)) => {
+                    let error_id = Uuid::now_v7();
+                    error!("error_id={} user_id={} stream_id={} Error serializing binary-mode event: {:?}", error_id, user.id, stream_id, err);
+
+                    let body = ApiError {
+                        id: error_id,
+                        code: ErrorCode::InternalError,
+                        title: "Internal server error".to_string(),
+                        detail: None,
+                        source: None,
+                    }.into_document();
+
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        [(header::CACHE_CONTROL, "no-cache")],
+                        JsonApi(body),
+                    ).into_response();
+                },
+                Err(err) => {
+                    let error_id = Uuid::now_v7();
+                    debug!("error_id={} Rejected undecodable binary-mode event: {}", error_id, err);
+
+                    let body = ApiError {
+                        id: error_id,
+                        code: ErrorCode::InvalidEvent,
+                        title: "Invalid event".to_string(),
+                        detail: Some(err.to_string()),
+                        source: None,
+                    }.into_document();
+
+                    return (
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        [(header::CACHE_CONTROL, "no-cache")],
+                        JsonApi(body),
+                    ).into_response();
+                },
+            }
+        } else {
+            match Json::<serde_json::Value>::from_request(request, &()).await {
+                Ok(Json(payload)) => payload,
+                Err(rejection) => return rejection.into_response(),
+            }
+        };
+
+    enrichment::enrich_payload(&state.config().enrichers, &mut payload, &user.id);
+
+    if let Some(response) = check_data_limits(&state.config(), &payload) {
+        return response;
+    }
+
+    let payload = match serde_json::from_value(payload) {
+        Ok(payload) => payload,
+        Err(err) => {
+            let error_id = Uuid::now_v7();
+            debug!("error_id={} Rejected undecodable event: {}", error_id, err);
+
+            let body = ApiError {
+                id: error_id,
+                code: ErrorCode::InvalidEvent,
+                title: "Invalid event".to_string(),
+                detail: Some(err.to_string()),
+                source: None,
+            }.into_document();
+
+            return (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                [(header::CACHE_CONTROL, "no-cache")],
+                JsonApi(body),
+            ).into_response();
+        }
+    };
+
+    let (mut events, is_batch) = match payload {
+        PostEventPayload::Single(event) => (vec![*event], false),
+        PostEventPayload::Batch(events) => (events, true),
+    };
+
+    let config = state.config();
+
+    for event in events.iter_mut() {
+        config.extension_policy.apply(event);
+    }
+
+    // Every invalid event in a batch is reported, up to a limit that keeps the response small.
+    let mut errors = Vec::new();
+    let mut total_errors = 0;
+
+    for (i, event) in events.iter().enumerate() {
+        if let Err(err) = validation::validate_event(&config, event) {
+            total_errors += 1;
+
+            if errors.len() >= config.max_batch_errors {
+                continue;
+            }
+
+            let error_id = Uuid::now_v7();
+            debug!("error_id={} Rejected invalid event: {}", error_id, err);
+
+            let pointer =
+                if is_batch {
+                    format!("/{}/{}", i, err.attribute())
+                } else {
+                    format!("/{}", err.attribute())
+                };
+
+            errors.push(ApiError {
+                id: error_id,
+                code: ErrorCode::InvalidEvent,
+                title: "Invalid event".to_string(),
+                detail: Some(err.to_string()),
+                source: Some(ApiErrorSource::pointer(&pointer)),
+            });
+        }
+    }
+
+    if total_errors > 0 {
+        let body = ApiErrorDocument::with_errors(errors, total_errors);
+
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            [(header::CACHE_CONTROL, "no-cache")],
+            JsonApi(body),
+        ).into_response();
+    }
+
+    if let Err(err) = state.check_lease(&user.id, &stream_id, query_params.lease.as_deref()) {
+        return lease_error_response(err);
+    }
+
+    let result = match query_params.reserved {
+        Some(start) => state.insert_reserved(&user.id, &stream_id, start, events).await,
+        None => state.insert_event_many(&user.id, &stream_id, events, revision).await,
+    };
+
+    match result {
+        Ok(rownum) => {
+            return (
+                StatusCode::CREATED,
+                [
+                    (header::CACHE_CONTROL, "no-cache"),
+                    (header::CONTENT_LOCATION, &format!("http://localhost:8080/streams/{}/events/{}", stream_id, rownum).to_string()),
+                ],
+            ).into_response();
+        }
+        Err(err) => append_error_response(err, |index| if is_batch { format!("/{}", index) } else { String::new() }),
+    }
+}
+
+/// Checks the `data` of each posted event against the configured limits, before the events
+/// are decoded. Responds with 413 for data that's too large, or 422 for data that's too deep.
+fn check_data_limits(config: &Config, payload: &serde_json::Value) -> Option<Response> {
+    let (events, is_batch) = match payload {
+        serde_json::Value::Array(events) => (events.iter().collect(), true),
+        event => (vec![event], false),
+    };
+
+    for (i, event) in events.into_iter().enumerate() {
+        let Some(data) = event.get("data") else {
+            continue;
+        };
+
+        let Err(err) = validation::validate_data(config, data) else {
+            continue;
+        };
+
+        let error_id = Uuid::now_v7();
+        debug!("error_id={} Rejected event data: {}", error_id, err);
+
+        let pointer =
+            if is_batch {
+                format!("/{}/data", i)
+            } else {
+                "/data".to_string()
+            };
+
+        let (status, code, title) = validation_error_status(&err);
+
+        let body = ApiError {
+            id: error_id,
+            code,
+            title: title.to_string(),
+            detail: Some(err.to_string()),
+            source: Some(ApiErrorSource::pointer(&pointer)),
+        }.into_document();
+
+        return Some((
+            status,
+            [(header::CACHE_CONTROL, "no-cache")],
+            JsonApi(body),
+        ).into_response());
+    }
+
+    None
+}
+
+/// How to reject an event that fails validation: 413 for data that's too large, like any other
+/// oversized body, or 422 for anything else.
+fn validation_error_status(err: &validation::Error) -> (StatusCode, ErrorCode, &'static str) {
+    match err {
+        validation::Error::DataTooLarge { .. } => (StatusCode::PAYLOAD_TOO_LARGE, ErrorCode::PayloadTooLarge, "Event data too large"),
+        _ => (StatusCode::UNPROCESSABLE_ENTITY, ErrorCode::InvalidEvent, "Invalid event"),
+    }
+}
+
+fn quota_exceeded_response(quota: &QuotaExceeded) -> Response {
+    let body = ApiError {
+        id: Uuid::now_v7(),
+        code: ErrorCode::QuotaExceeded,
+        title: "Quota exceeded".to_string(),
+        detail: Some(match quota {
+            QuotaExceeded::Streams { max_streams } => format!("you already have the most streams allowed, {}. Delete one, or append to an existing stream", max_streams),
+            QuotaExceeded::Bytes { max_bytes } => format!("this would put your streams over their limit of {} bytes. Delete a stream to free up space", max_bytes),
+        }),
+        source: None,
+    }.into_document();
+
+    (
+        StatusCode::FORBIDDEN,
+        [(header::CACHE_CONTROL, "no-cache")],
+        JsonApi(body),
+    ).into_response()
+}
+
+fn sealed_response() -> Response {
+    let body = ApiError {
+        id: Uuid::now_v7(),
+        code: ErrorCode::StreamSealed,
+        title: "Stream sealed".to_string(),
+        detail: Some("this stream is sealed, so it's read-only. An admin has to unseal it before events can be appended".to_string()),
+        source: None,
+    }.into_document();
+
+    (
+        StatusCode::FORBIDDEN,
+        [(header::CACHE_CONTROL, "no-cache")],
+        JsonApi(body),
+    ).into_response()
+}
+
+/// Responds to a failed append. `event_pointer` gives the JSON pointer to the event at an
+/// index of the appended batch.
+fn append_error_response(err: anyhow::Error, event_pointer: impl Fn(usize) -> String) -> Response {
+    let error_id = Uuid::now_v7();
+    debug!("error_id={} Failed to post event: {:?}", error_id, err);
+
+    if let Some(quota) = err.downcast_ref::<QuotaExceeded>() {
+        return quota_exceeded_response(quota);
+    }
+
+    match err.downcast::<db::Error>() {
+        Ok(err @ db::Error::RevisionMismatch { actual, .. }) => {
+            let body = ApiError {
+                id: error_id,
+                code: ErrorCode::RevisionMismatch,
+                title: "Revision mismatch".to_string(),
+                detail: Some(err.to_string()),
+                source: Some(ApiErrorSource::query("expected_revision")),
+            }.into_document();
+
+            (
+                StatusCode::CONFLICT,
+                [
+                    (header::CACHE_CONTROL, "no-cache".to_string()),
+                    (header::HeaderName::from_static(STREAM_REVISION_HEADER), actual.to_string()),
+                ],
+                JsonApi(body),
+            ).into_response()
+        },
+        Ok(db::Error::Sealed) => sealed_response(),
+        Ok(db::Error::SourceIdConflict) => {
+            let body = ApiError {
+                id: error_id,
+                code: ErrorCode::SourceIdConflict,
+                title: "Source/ID conflict".to_string(),
+                detail: Some("this stream already contains an event with that source and id field. According to the CloudEvents spec, those fields in combination must be unique".to_string()),
+                source: None,
+            }.into_document();
+
+            (
+                StatusCode::CONFLICT,
+                [(header::CACHE_CONTROL, "no-cache")],
+                JsonApi(body),
+            ).into_response()
+        },
+        Ok(db::Error::IdConflict) => {
+            let body = ApiError {
+                id: error_id,
+                code: ErrorCode::IdConflict,
+                title: "ID conflict".to_string(),
+                detail: Some("an event with that id field was recently appended to this stream, which deduplicates events by id alone".to_string()),
+                source: None,
+            }.into_document();
+
+            (
+                StatusCode::CONFLICT,
+                [(header::CACHE_CONTROL, "no-cache")],
+                JsonApi(body),
+            ).into_response()
+        },
+        Ok(err @ db::Error::Reserved) => {
+            let body = ApiError {
+                id: error_id,
+                code: ErrorCode::Reserved,
+                title: "Rownums reserved".to_string(),
+                detail: Some(err.to_string()),
+                source: None,
+            }.into_document();
+
+            (
+                StatusCode::CONFLICT,
+                [(header::CACHE_CONTROL, "no-cache")],
+                JsonApi(body),
+            ).into_response()
+        },
+        Ok(err @ db::Error::NotReserved { .. }) => {
+            let body = ApiError {
+                id: error_id,
+                code: ErrorCode::NotReserved,
+                title: "Rownums not reserved".to_string(),
+                detail: Some(err.to_string()),
+                source: Some(ApiErrorSource::query("reserved")),
+            }.into_document();
+
+            (
+                StatusCode::CONFLICT,
+                [(header::CACHE_CONTROL, "no-cache")],
+                JsonApi(body),
+            ).into_response()
+        },
+        Ok(db::Error::EventTooLarge { index, bytes, max_bytes }) => {
+            let pointer = event_pointer(index);
+
+            let body = ApiError {
+                id: error_id,
+                code: ErrorCode::PayloadTooLarge,
+                title: "Event too large".to_string(),
+                detail: Some(format!("the event is at least {} bytes of JSON, more than the maximum of {} bytes accepted by this server", bytes, max_bytes)),
+                source: Some(ApiErrorSource::pointer(&pointer)),
+            }.into_document();
+
+            (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                [(header::CACHE_CONTROL, "no-cache")],
+                JsonApi(body),
+            ).into_response()
+        },
+        err => {
+            error!("error_id={} Failed to post event: {:?}", error_id, err);
+            let body = ApiError {
+                id: error_id,
+                code: ErrorCode::InternalError,
+                title: "Internal server error".to_string(),
+                detail: None,
+                source: None,
+            }.into_document();
+
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                [(header::CACHE_CONTROL, "no-cache")],
+                JsonApi(body),
+            ).into_response()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PostIngestDocument {
+    data: PostIngestResource,
+}
+
+#[derive(Debug, Deserialize)]
+struct PostIngestResource {
+    attributes: PostIngestAttributes,
+}
+
+#[derive(Debug, Deserialize)]
+struct PostIngestAttributes {
+    /// URL serving the events, as NDJSON or the CloudEvents JSON batch format.
+    url: Url,
+    /// Value of the `Authorization` header to send to the source, if it needs one.
+    authorization: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct IngestionAttributes {
+    /// First ingested rownum. The ingested events are `start..end`.
+    start: u64,
+    end: u64,
+    count: u64,
+}
+
+/// Events read from an ingestion source and appended at a time.
+const INGEST_BATCH_EVENTS: usize = 100;
+
+/// Fetches events from a URL and appends them to the stream, a batch at a time so large
+/// sources don't have to fit in memory. Only hosts in `ingest_allowed_hosts` can be fetched.
+///
+/// Ingested events get contiguous rownums, and ingestion fails if anyone else writes to the
+/// stream meanwhile. Batches appended before a failure stay in the stream: an error pointing
+/// at an event, like `/120`, means the events before it were ingested.
+#[tracing::instrument(skip(document))]
+#[debug_handler]
+async fn post_ingest(
+    state: State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Path(stream_id): Path<String>,
+    Query(lease_params): Query<LeaseParams>,
+    Json(document): Json<PostIngestDocument>,
+) -> Response {
+    if let Err(err) = state.check_lease(&user.id, &stream_id, lease_params.lease.as_deref()) {
+        return lease_error_response(err);
+    }
+
+    let config = state.config();
+    let attributes = document.data.attributes;
+
+    let open_result = EventSource::open(&attributes.url, &config.ingest_allowed_hosts, attributes.authorization.as_deref(), config.max_body_bytes, config.ingest_timeout).await;
+
+    let mut source = match open_result {
+        Ok(source) => source,
+        Err(err) => return ingest_error_response(err),
+    };
+
+    let mut start = None;
+    let mut revision = None;
+    let mut count = 0;
+
+    loop {
+        let payloads = match source.next_batch(INGEST_BATCH_EVENTS).await {
+            Ok(payloads) if payloads.is_empty() => break,
+            Ok(payloads) => payloads,
+            Err(err) => return ingest_error_response(err),
+        };
+
+        let mut events = Vec::with_capacity(payloads.len());
+
+        for (i, mut payload) in payloads.into_iter().enumerate() {
+            let index = count + i;
+            enrichment::enrich_payload(&config.enrichers, &mut payload, &user.id);
+
+            let invalid = (StatusCode::UNPROCESSABLE_ENTITY, ErrorCode::InvalidEvent, "Invalid event");
+            let validated = serde_json::from_value::<Event>(payload)
+                .map_err(|err| (invalid, err.to_string(), format!("/{}", index)))
+                .and_then(|mut event| {
+                    config.extension_policy.apply(&mut event);
+                    validation::validate_event(&config, &event)
+                        .map(|()| event)
+                        .map_err(|err| (validation_error_status(&err), err.to_string(), format!("/{}/{}", index, err.attribute())))
+                });
+
+            match validated {
+                Ok(event) => events.push(event),
+                Err(((status, code, title), detail, pointer)) => {
+                    let error_id = Uuid::now_v7();
+                    debug!("error_id={} Rejected invalid ingested event: {}", error_id, detail);
+
+                    let body = ApiError {
+                        id: error_id,
+                        code,
+                        title: title.to_string(),
+                        detail: Some(detail),
+                        source: Some(ApiErrorSource::pointer(&pointer)),
+                    }.into_document();
+
+                    return (
+                        status,
+                        [(header::CACHE_CONTROL, "no-cache")],
+                        JsonApi(body),
+                    ).into_response();
+                }
+            }
+        }
+
+        let batch_len = events.len();
+        let expected_revision = revision.map_or(ExpectedRevision::Any, ExpectedRevision::Exact);
+
+        match state.insert_event_many(&user.id, &stream_id, events, expected_revision).await {
+            Ok(new_revision) => {
+                start.get_or_insert(new_revision - batch_len as u64);
+                revision = Some(new_revision);
+                count += batch_len;
+            },
+            Err(err) => return append_error_response(err, |index| format!("/{}", count + index)),
+        }
+    }
+
+    let end = match revision {
+        Some(revision) => revision,
+        // Nothing was ingested, so the stream is at whatever revision it was already at.
+        None => state.get_stream(&user.id, &stream_id, Consistency::Cached).await.map_or(0, |stream| stream.revision),
+    };
+    let start = start.unwrap_or(end);
+    let attributes = IngestionAttributes { start, end, count: count as u64 };
+    let body = ApiResource::new(start.to_string(), "ingestion".to_string(), attributes).into_document();
+
+    (
+        StatusCode::CREATED,
+        [(header::CACHE_CONTROL, "no-cache")],
+        JsonApi(body),
+    ).into_response()
+}
+
+fn ingest_error_response(err: anyhow::Error) -> Response {
+    let error_id = Uuid::now_v7();
+    debug!("error_id={} Failed to ingest events: {:#}", error_id, err);
+
+    let (status, code, title, source) = match err.downcast_ref::<ingest::Error>() {
+        Some(ingest::Error::HostNotAllowed(_)) =>
+            (StatusCode::FORBIDDEN, ErrorCode::SourceNotAllowed, "Source not allowed", ApiErrorSource::pointer("/data/attributes/url")),
+        Some(ingest::Error::EventTooLarge { index, .. }) =>
+            (StatusCode::PAYLOAD_TOO_LARGE, ErrorCode::PayloadTooLarge, "Event too large", ApiErrorSource::pointer(&format!("/{}", index))),
+        Some(ingest::Error::InvalidJson { index, .. }) =>
+            (StatusCode::UNPROCESSABLE_ENTITY, ErrorCode::InvalidEvent, "Invalid event", ApiErrorSource::pointer(&format!("/{}", index))),
+        Some(ingest::Error::UnexpectedStatus(_)) | None =>
+            (StatusCode::BAD_GATEWAY, ErrorCode::SourceUnavailable, "Source unavailable", ApiErrorSource::pointer("/data/attributes/url")),
+    };
+
+    let body = ApiError {
+        id: error_id,
+        code,
+        title: title.to_string(),
+        detail: Some(format!("{:#}", err)),
+        source: Some(source),
+    }.into_document();
+
+    (
+        status,
+        [(header::CACHE_CONTROL, "no-cache")],
+        JsonApi(body),
+    ).into_response()
+}
+
+#[tracing::instrument]
+#[debug_handler]
+async fn post_correction(
+    state: State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Path((stream_id, rownum)): Path<(String, u64)>,
+    Query(lease_params): Query<LeaseParams>,
+    Json(mut correction): Json<Event>,
+) -> Response {
+    state.config().extension_policy.apply(&mut correction);
+
+    if let Err(err) = validation::validate_event(&state.config(), &correction) {
+        let error_id = Uuid::now_v7();
+        debug!("error_id={} Rejected invalid correction: {}", error_id, err);
+        let (status, code, title) = validation_error_status(&err);
+
+        let body = ApiError {
+            id: error_id,
+            code,
+            title: title.to_string(),
+            detail: Some(err.to_string()),
+            source: Some(ApiErrorSource::pointer(&format!("/{}", err.attribute()))),
+        }.into_document();
+
+        return (
+            status,
+            [(header::CACHE_CONTROL, "no-cache")],
+            JsonApi(body),
+        ).into_response();
+    }
+
+    if let Err(err) = state.check_lease(&user.id, &stream_id, lease_params.lease.as_deref()) {
+        return lease_error_response(err);
+    }
+
+    let result = state.correct_event(&user.id, &stream_id, rownum, correction).await;
+
+    match result {
+        Ok(revision) => {
+            return (
+                StatusCode::CREATED,
+                [
+                    (header::CACHE_CONTROL, "no-cache"),
+                    (header::CONTENT_LOCATION, &format!("http://localhost:8080/streams/{}/events/{}", stream_id, revision - 1)),
+                ],
+            ).into_response();
+        }
+        Err(err) => {
+            let error_id = Uuid::now_v7();
+            debug!("error_id={} Failed to post correction: {:?}", error_id, err);
+
+            if let Some(quota) = err.downcast_ref::<QuotaExceeded>() {
+                return quota_exceeded_response(quota);
+            }
+
+            if let Some(server::Error::StreamNotFound) = err.downcast_ref::<server::Error>() {
+                return StatusCode::NOT_FOUND.into_response();
+            }
+
+            match err.downcast::<db::Error>() {
+                Ok(db::Error::EventNotFound) => {
+                    return StatusCode::NOT_FOUND.into_response();
+                },
+                Ok(db::Error::SourceIdConflict) => {
+                    let body = ApiError {
+                        id: error_id,
+                        code: ErrorCode::SourceIdConflict,
+                        title: "Source/ID conflict".to_string(),
+                        detail: Some("this stream already contains an event with that source and id field. According to the CloudEvents spec, those fields in combination must be unique".to_string()),
+                        source: None,
+                    }.into_document();
+
+                    return (
+                        StatusCode::CONFLICT,
+                        [(header::CACHE_CONTROL, "no-cache")],
+                        JsonApi(body),
+                    ).into_response();
+                },
+                Ok(db::Error::IdConflict) => {
+                    let body = ApiError {
+                        id: error_id,
+                        code: ErrorCode::IdConflict,
+                        title: "ID conflict".to_string(),
+                        detail: Some("an event with that id field was recently appended to this stream, which deduplicates events by id alone".to_string()),
+                        source: None,
+                    }.into_document();
+
+                    return (
+                        StatusCode::CONFLICT,
+                        [(header::CACHE_CONTROL, "no-cache")],
+                        JsonApi(body),
+                    ).into_response();
+                },
+                err => {
+                    error!("error_id={} Failed to post correction: {:?}", error_id, err);
+                    let body = ApiError {
+                        id: error_id,
+                        code: ErrorCode::InternalError,
+                        title: "Internal server error".to_string(),
+                        detail: None,
+                        source: None,
+                    }.into_document();
+
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        [(header::CACHE_CONTROL, "no-cache")],
+                        JsonApi(body),
+                    ).into_response();
+                }
+            }
+        }
+    }
+}
+
+fn parse_expected_revision(expected_revision: &str) -> Result<ExpectedRevision> {
+    match expected_revision {
+        "any" => Ok(ExpectedRevision::Any),
+        "no-stream" => Ok(ExpectedRevision::NoStream),
+        "stream-exists" => Ok(ExpectedRevision::StreamExists),
+        at_least if at_least.starts_with(">=") || at_least.starts_with("at-least:") => {
+            let revision = at_least.strip_prefix(">=").or_else(|| at_least.strip_prefix("at-least:"));
+
+            if let Some(Ok(revision)) = revision.map(str::parse) {
+                Ok(ExpectedRevision::AtLeast(revision))
+            } else {
+                bail!("Minimum revision was not a revision number")
+            }
+        },
+        exact => {
+            if let Ok(exact_revision) = exact.parse() {
                 Ok(ExpectedRevision::Exact(exact_revision))
             } else {
                 bail!("String was not a revision number or a recognized token")
@@ -569,3 +3666,2660 @@ fn parse_expected_revision(expected_revision: &str) -> Result<ExpectedRevision>
         }
     }
 }
+
+/// Parses a duration like `30s`, `15m`, `1h`, or `7d`, as used for activity buckets and
+/// long-poll waits.
+fn parse_duration(duration: &str) -> Result<Duration> {
+    let split = duration.find(|c: char| !c.is_ascii_digit()).unwrap_or(duration.len());
+    let (count, unit) = duration.split_at(split);
+
+    let unit_secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => bail!("Unknown duration unit {:?}", unit),
+    };
+
+    let count: u64 = count.parse()?;
+    ensure!(count > 0, "Duration must be positive");
+
+    let secs = count.checked_mul(unit_secs).ok_or_else(|| anyhow!("Duration is too large"))?;
+
+    Ok(Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use axum::body::{self, Body};
+    use cloudevents::{event::SpecVersion, AttributesReader, EventBuilder, EventBuilderV10};
+    use serde_json::Value;
+    use tempfile::tempdir;
+    use tower::ServiceExt;
+
+    use crate::db::RunState;
+    use crate::enrichment::{Enricher, ExtensionPolicy};
+    use crate::rate_limit::WriteRateLimit;
+
+    use super::*;
+
+    async fn test_app(streams_dir: &Path) -> (Router, Arc<AppState>) {
+        test_app_with_config(streams_dir, Config::default()).await
+    }
+
+    async fn test_app_with_config(streams_dir: &Path, config: Config) -> (Router, Arc<AppState>) {
+        let state = Arc::new(AppState::new(streams_dir.to_path_buf(), config).await.unwrap());
+
+        let app = routes(&state.config())
+            .layer(Extension(User { id: "test-user".to_string() }))
+            .with_state(state.clone());
+
+        (app, state)
+    }
+
+    fn test_event(ty: &str) -> Event {
+        EventBuilderV10::new()
+            .id(Uuid::now_v7().to_string())
+            .source("test")
+            .ty(ty)
+            .build()
+            .unwrap()
+    }
+
+    async fn get_json(app: &Router, uri: &str) -> (StatusCode, Value) {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json = if bytes.is_empty() { Value::Null } else { serde_json::from_slice(&bytes).unwrap() };
+
+        (status, json)
+    }
+
+    async fn post_json(app: &Router, uri: &str, payload: Value) -> (StatusCode, Value) {
+        let request = Request::post(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(payload.to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json = if bytes.is_empty() { Value::Null } else { serde_json::from_slice(&bytes).unwrap() };
+
+        (status, json)
+    }
+
+    fn event_json(id: &str) -> Value {
+        serde_json::json!({
+            "specversion": "1.0",
+            "id": id,
+            "source": "test",
+            "type": "com.example.test",
+        })
+    }
+
+    #[tokio::test]
+    async fn error_documents_use_the_json_api_media_type() {
+        let streams_dir = tempdir().unwrap();
+        let (app, _state) = test_app(streams_dir.path()).await;
+
+        let request = Request::post("/streams/reserved/reserve?count=none").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/vnd.api+json");
+    }
+
+    #[tokio::test]
+    async fn health_is_plain_json() {
+        let streams_dir = tempdir().unwrap();
+        let (app, _state) = test_app(streams_dir.path()).await;
+
+        let request = Request::get("/health").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+    }
+
+    #[tokio::test]
+    async fn get_event_types_lists_distinct_types() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+
+        let user_id = "test-user".to_string();
+        let stream_id = "mixed".to_string();
+        let events = vec![test_event("com.example.a"), test_event("com.example.b"), test_event("com.example.a")];
+        state.insert_event_many(&user_id, &stream_id, events, ExpectedRevision::Any).await.unwrap();
+
+        let (status, body) = get_json(&app, "/streams/mixed/types").await;
+
+        assert_eq!(status, StatusCode::OK);
+        let data = body["data"].as_array().unwrap();
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[0]["id"], "com.example.a");
+        assert_eq!(data[0]["attributes"]["count"], 2);
+        assert_eq!(data[1]["id"], "com.example.b");
+        assert_eq!(data[1]["attributes"]["count"], 1);
+    }
+
+    #[tokio::test]
+    async fn get_event_types_of_missing_stream_is_not_found() {
+        let streams_dir = tempdir().unwrap();
+        let (app, _state) = test_app(streams_dir.path()).await;
+
+        let (status, _body) = get_json(&app, "/streams/missing/types").await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn get_stream_with_cached_consistency_reflects_appends() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+
+        let user_id = "test-user".to_string();
+        let stream_id = "cached".to_string();
+        state.insert_event(&user_id, &stream_id, test_event("com.example.a"), ExpectedRevision::Any).await.unwrap();
+
+        let (status, body) = get_json(&app, "/streams/cached?consistency=cached").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["attributes"]["revision"], 1);
+
+        state.insert_event(&user_id, &stream_id, test_event("com.example.a"), ExpectedRevision::Any).await.unwrap();
+
+        let (status, body) = get_json(&app, "/streams/cached?consistency=cached").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["attributes"]["revision"], 2);
+    }
+
+    #[tokio::test]
+    async fn post_event_enforces_the_event_id_format() {
+        let streams_dir = tempdir().unwrap();
+        let config = Config {
+            event_id_format: Some("uuid".parse().unwrap()),
+            ..Default::default()
+        };
+        let (app, _state) = test_app_with_config(streams_dir.path(), config).await;
+
+        let (status, _body) = post_json(&app, "/streams/ids/events", event_json(&Uuid::now_v7().to_string())).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let (status, body) = post_json(&app, "/streams/ids/events", event_json("A234-1234-1234")).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["errors"][0]["detail"], "event id \"A234-1234-1234\" is not a valid UUID");
+        assert_eq!(body["errors"][0]["source"]["pointer"], "/id");
+    }
+
+    #[tokio::test]
+    async fn polling_after_revision_returns_only_new_events() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+
+        let user_id = "test-user".to_string();
+        let stream_id = "polled".to_string();
+        let first = vec![test_event("com.example.a"), test_event("com.example.a"), test_event("com.example.a")];
+        state.insert_event_many(&user_id, &stream_id, first.clone(), ExpectedRevision::Any).await.unwrap();
+
+        let (status, body) = get_json(&app, "/streams/polled/events?after_revision=0").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"].as_array().unwrap().len(), 3);
+        assert_eq!(body["meta"]["head_revision"], 3);
+
+        let second = vec![test_event("com.example.b"), test_event("com.example.b")];
+        state.insert_event_many(&user_id, &stream_id, second.clone(), ExpectedRevision::Any).await.unwrap();
+
+        let (status, body) = get_json(&app, "/streams/polled/events?after_revision=3").await;
+        assert_eq!(status, StatusCode::OK);
+        let ids: Vec<&str> = body["data"].as_array().unwrap().iter().map(|event| event["id"].as_str().unwrap()).collect();
+        assert_eq!(ids, vec![second[0].id(), second[1].id()]);
+        assert_eq!(body["meta"]["head_revision"], 5);
+
+        let (status, body) = get_json(&app, "/streams/polled/events?after_revision=5").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["data"].as_array().unwrap().is_empty());
+        assert_eq!(body["meta"]["head_revision"], 5);
+        assert_eq!(body["meta"]["next_revision"], 5);
+    }
+
+    #[tokio::test]
+    async fn polling_past_the_page_limit_catches_up_without_skipping() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+
+        let events: Vec<Event> = (0..7).map(|_| test_event("com.example.a")).collect();
+        state.insert_event_many(&"test-user".to_string(), &"backlog".to_string(), events.clone(), ExpectedRevision::Any).await.unwrap();
+
+        let mut after_revision = 0;
+        let mut received = vec![];
+        loop {
+            let (status, body) = get_json(&app, &format!("/streams/backlog/events?after_revision={}&page[limit]=3", after_revision)).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body["meta"]["head_revision"], 7);
+
+            let page = body["data"].as_array().unwrap();
+            received.extend(page.iter().map(|event| event["id"].as_str().unwrap().to_string()));
+            after_revision = body["meta"]["next_revision"].as_u64().unwrap();
+
+            if page.is_empty() {
+                break;
+            }
+        }
+
+        let expected: Vec<String> = events.iter().map(|event| event.id().to_string()).collect();
+        assert_eq!(received, expected);
+        assert_eq!(after_revision, 7);
+    }
+
+    #[tokio::test]
+    async fn get_streams_reports_and_sorts_by_count() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+
+        let user_id = "test-user".to_string();
+        state.insert_event_many(&user_id, &"big".to_string(), vec![test_event("a"), test_event("a"), test_event("a")], ExpectedRevision::Any).await.unwrap();
+        state.insert_event_many(&user_id, &"small".to_string(), vec![test_event("a")], ExpectedRevision::Any).await.unwrap();
+
+        let (status, body) = get_json(&app, "/streams?sort=-count").await;
+
+        assert_eq!(status, StatusCode::OK);
+        let data = body["data"].as_array().unwrap();
+        assert_eq!(data[0]["id"], "big");
+        assert_eq!(data[0]["attributes"]["count"], 3);
+        assert_eq!(data[1]["id"], "small");
+        assert_eq!(data[1]["attributes"]["count"], 1);
+    }
+
+    #[tokio::test]
+    async fn get_streams_sorts_by_creation_time() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+
+        let user_id = "test-user".to_string();
+        state.insert_event_many(&user_id, &"older".to_string(), vec![test_event("a")], ExpectedRevision::Any).await.unwrap();
+        state.insert_event_many(&user_id, &"newer".to_string(), vec![test_event("a")], ExpectedRevision::Any).await.unwrap();
+
+        let older = state.streams.get(&(user_id.clone(), "older".to_string())).unwrap().clone();
+        older.lock().await.set_created_at(1_700_000_000).await.unwrap();
+
+        let (status, body) = get_json(&app, "/streams?sort=created_at").await;
+        assert_eq!(status, StatusCode::OK);
+        let data = body["data"].as_array().unwrap();
+        assert_eq!(data[0]["id"], "older");
+        assert_eq!(data[0]["attributes"]["created_at"], 1_700_000_000);
+        assert_eq!(data[1]["id"], "newer");
+        assert!(data[1]["attributes"]["created_at"].as_u64().unwrap() > 1_700_000_000);
+
+        let (status, body) = get_json(&app, "/streams?sort=-created_at").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"][0]["id"], "newer");
+
+        // The creation time survives a restart.
+        let (_app, state) = test_app(streams_dir.path()).await;
+        let older = state.get_stream(&user_id, &"older".to_string(), Consistency::Strong).await.unwrap();
+        assert_eq!(older.created_at, Some(1_700_000_000));
+    }
+
+    #[tokio::test]
+    async fn corrections_are_applied_only_when_requested() {
+        let streams_dir = tempdir().unwrap();
+        let (app, _state) = test_app(streams_dir.path()).await;
+
+        let mut original = event_json(&Uuid::now_v7().to_string());
+        original["data"] = serde_json::json!({"amount": 10});
+        let (status, _body) = post_json(&app, "/streams/ledger/events", original).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let mut correction = event_json(&Uuid::now_v7().to_string());
+        correction["data"] = serde_json::json!({"amount": 100});
+        let (status, _body) = post_json(&app, "/streams/ledger/events/0/correct", correction.clone()).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let (status, body) = get_json(&app, "/streams/ledger/events/0").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["amount"], 10);
+
+        let (status, body) = get_json(&app, "/streams/ledger/events/0?apply_corrections=true").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["amount"], 100);
+        assert_eq!(body["hematitecorrectedby"], 1);
+
+        let request = Request::get("/streams/ledger/events/0").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "max-age=31536000, immutable");
+
+        let request = Request::get("/streams/ledger/events/0?apply_corrections=true").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "no-cache");
+
+        let (status, body) = get_json(&app, "/streams/ledger/events?apply_corrections=true").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"][0]["attributes"]["data"]["amount"], 100);
+        assert_eq!(body["data"][1]["attributes"]["hematitecorrects"], 0);
+
+        correction["id"] = Value::String(Uuid::now_v7().to_string());
+        let (status, _body) = post_json(&app, "/streams/ledger/events/7/correct", correction).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn only_the_server_links_corrections() {
+        let streams_dir = tempdir().unwrap();
+        let (app, _state) = test_app(streams_dir.path()).await;
+
+        let mut original = event_json(&Uuid::now_v7().to_string());
+        original["data"] = serde_json::json!({"amount": 10});
+        let (status, _body) = post_json(&app, "/streams/ledger/events", original).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let mut forged = event_json(&Uuid::now_v7().to_string());
+        forged["data"] = serde_json::json!({"amount": 0});
+        forged["hematitecorrects"] = serde_json::json!(0);
+        let (status, body) = post_json(&app, "/streams/ledger/events", forged.clone()).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["errors"][0]["source"]["pointer"], "/hematitecorrects");
+
+        let (status, _body) = post_json(&app, "/streams/ledger/events/0/correct", forged).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+        let mut correction = event_json(&Uuid::now_v7().to_string());
+        correction["data"] = serde_json::json!({"amount": 100});
+        let (status, _body) = post_json(&app, "/streams/ledger/events/0/correct", correction.clone()).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        // Correcting the correction corrects the original event.
+        correction["id"] = Value::String(Uuid::now_v7().to_string());
+        correction["data"] = serde_json::json!({"amount": 1000});
+        let (status, _body) = post_json(&app, "/streams/ledger/events/1/correct", correction).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let (status, body) = get_json(&app, "/streams/ledger/events/2").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["hematitecorrects"], 0);
+
+        let (status, body) = get_json(&app, "/streams/ledger/events/0?apply_corrections=true").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["amount"], 1000);
+        assert_eq!(body["hematitecorrectedby"], 2);
+    }
+
+    #[tokio::test]
+    async fn secure_headers_use_the_csp_for_the_route() {
+        let mut csp = ContentSecurityPolicy::default();
+        csp.overrides.insert("/openapi".to_string(), "default-src 'self'; frame-ancestors 'none'".to_string());
+
+        let app = Router::new()
+            .route("/openapi/ui", get(|| async { "docs" }))
+            .route("/streams", get(|| async { "streams" }))
+            .layer(middleware::from_fn_with_state(Arc::new(csp), apply_secure_headers));
+
+        let request = Request::get("/openapi/ui").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_SECURITY_POLICY], "default-src 'self'; frame-ancestors 'none'");
+
+        let request = Request::get("/streams").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_SECURITY_POLICY], "frame-ancestors 'none'");
+        assert_eq!(response.headers()[header::X_FRAME_OPTIONS], "DENY");
+    }
+
+    #[tokio::test]
+    async fn patching_a_stream_enables_id_only_deduplication() {
+        let streams_dir = tempdir().unwrap();
+        let (app, _state) = test_app(streams_dir.path()).await;
+
+        let id = Uuid::now_v7().to_string();
+        let (status, _body) = post_json(&app, "/streams/dedup/events", event_json(&id)).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let mut other_source = event_json(&id);
+        other_source["source"] = Value::String("elsewhere".to_string());
+
+        let patch = serde_json::json!({
+            "data": {
+                "type": "streams",
+                "attributes": { "deduplication": { "mode": "id", "window": 100 } },
+            },
+        });
+        let request = Request::patch("/streams/dedup")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(patch.to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let (status, body) = get_json(&app, "/streams/dedup").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["attributes"]["deduplication"]["mode"], "id");
+
+        let (status, body) = post_json(&app, "/streams/dedup/events", other_source).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["errors"][0]["title"], "ID conflict");
+    }
+
+    #[tokio::test]
+    async fn scheduled_compaction_compacts_only_compacted_streams() {
+        let streams_dir = tempdir().unwrap();
+        let config = Config {
+            compaction_idle: Duration::ZERO,
+            ..Default::default()
+        };
+        let (app, state) = test_app_with_config(streams_dir.path(), config).await;
+        let user_id = "test-user".to_string();
+
+        let with_subject = |subject: &str| {
+            EventBuilderV10::new().id(Uuid::now_v7().to_string()).source("test").ty("test").subject(subject).build().unwrap()
+        };
+
+        for stream_id in ["compacted", "plain"] {
+            let events = vec![with_subject("a"), with_subject("a"), with_subject("b"), with_subject("a")];
+            state.insert_event_many(&user_id, &stream_id.to_string(), events, ExpectedRevision::Any).await.unwrap();
+        }
+
+        let patch = serde_json::json!({
+            "data": {
+                "type": "streams",
+                "attributes": { "compacted": true, "min_dirty_ratio": 0.25 },
+            },
+        });
+        let request = Request::patch("/streams/compacted")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(patch.to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        assert_eq!(state.compact_streams().await, 2);
+
+        let (status, body) = get_json(&app, "/streams/compacted").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["attributes"]["count"], 2);
+
+        let (status, body) = get_json(&app, "/streams/plain").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["attributes"]["count"], 4);
+
+        assert_eq!(state.compact_streams().await, 0);
+    }
+
+    #[test]
+    fn parse_expected_revisions() {
+        assert_eq!(parse_expected_revision("any").unwrap(), ExpectedRevision::Any);
+        assert_eq!(parse_expected_revision("7").unwrap(), ExpectedRevision::Exact(7));
+        assert_eq!(parse_expected_revision(">=5").unwrap(), ExpectedRevision::AtLeast(5));
+        assert_eq!(parse_expected_revision("at-least:5").unwrap(), ExpectedRevision::AtLeast(5));
+        assert!(parse_expected_revision(">=").is_err());
+        assert!(parse_expected_revision("at-least:-1").is_err());
+    }
+
+    #[tokio::test]
+    async fn revision_mismatches_report_the_actual_revision() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+
+        let user_id = "test-user".to_string();
+        let stream_id = "revised".to_string();
+        state.insert_event_many(&user_id, &stream_id, vec![test_event("a"), test_event("a")], ExpectedRevision::Any).await.unwrap();
+
+        let request = Request::post("/streams/revised/events?expected_revision=1")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(event_json(&Uuid::now_v7().to_string()).to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(response.headers()["stream-revision"], "2");
+
+        let bytes = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["errors"][0]["detail"], "expected revision 1 but the stream is at revision 2");
+    }
+
+    #[tokio::test]
+    async fn oversized_bodies_get_a_structured_413() {
+        let streams_dir = tempdir().unwrap();
+        let config = Config {
+            max_body_bytes: 256,
+            ..Default::default()
+        };
+        let (app, _state) = test_app_with_config(streams_dir.path(), config).await;
+
+        let (status, _body) = post_json(&app, "/streams/limited/events", event_json(&Uuid::now_v7().to_string())).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let mut oversized = event_json(&Uuid::now_v7().to_string());
+        oversized["data"] = Value::String("x".repeat(512));
+        let (status, body) = post_json(&app, "/streams/limited/events", oversized).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body["errors"][0]["title"], "Payload too large");
+    }
+
+    #[tokio::test]
+    async fn oversized_events_in_a_batch_get_a_413() {
+        let streams_dir = tempdir().unwrap();
+        let config = Config {
+            max_event_bytes: Some(256),
+            ..Default::default()
+        };
+        let (app, _state) = test_app_with_config(streams_dir.path(), config).await;
+
+        let mut oversized = event_json(&Uuid::now_v7().to_string());
+        oversized["data"] = Value::String("x".repeat(512));
+        let batch = Value::Array(vec![event_json(&Uuid::now_v7().to_string()), oversized]);
+
+        let (status, body) = post_json(&app, "/streams/limited/events", batch).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body["errors"][0]["title"], "Event too large");
+        assert_eq!(body["errors"][0]["source"]["pointer"], "/1");
+
+        // The batch is rejected before the stream is created to hold it.
+        let (status, _body) = get_json(&app, "/streams/limited").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn oversized_events_are_rejected_before_the_byte_quota_is_checked() {
+        let streams_dir = tempdir().unwrap();
+        let config = Config {
+            max_event_bytes: Some(256),
+            max_bytes_per_user: Some(64),
+            ..Default::default()
+        };
+        let (app, state) = test_app_with_config(streams_dir.path(), config).await;
+
+        let mut oversized = event_json(&Uuid::now_v7().to_string());
+        oversized["data"] = Value::String("x".repeat(512));
+        let (status, body) = post_json(&app, "/streams/limited/events", oversized).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body["errors"][0]["title"], "Event too large");
+
+        let (status, body) = post_json(&app, "/streams/limited/events", event_json(&Uuid::now_v7().to_string())).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["errors"][0]["code"], "quota_exceeded");
+        assert_eq!(state.user_usage(&"test-user".to_string()).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn snapshots_are_stored_and_fetched() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+
+        let user_id = "test-user".to_string();
+        let stream_id = "projected".to_string();
+        state.insert_event_many(&user_id, &stream_id, vec![test_event("com.example.a")], ExpectedRevision::Any).await.unwrap();
+
+        let (status, _body) = get_json(&app, "/streams/projected/snapshot").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let request = Request::put("/streams/projected/snapshot?revision=1").body(Body::from("state")).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let request = Request::get("/streams/projected/snapshot").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["snapshot-revision"], "1");
+        let bytes = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&bytes[..], b"state");
+
+        let request = Request::put("/streams/projected/snapshot?revision=2").body(Body::from("state")).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let request = Request::put("/streams/missing/snapshot?revision=0").body(Body::from("state")).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn enrichers_default_the_source_to_the_user() {
+        let streams_dir = tempdir().unwrap();
+        let config = Config {
+            enrichers: vec![Enricher::DefaultSource],
+            ..Default::default()
+        };
+        let (app, state) = test_app_with_config(streams_dir.path(), config).await;
+
+        let mut event = event_json(&Uuid::now_v7().to_string());
+        event.as_object_mut().unwrap().remove("source");
+
+        let (status, _body) = post_json(&app, "/streams/enriched/events", event).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let user_id = "test-user".to_string();
+        let stream_id = "enriched".to_string();
+        let page = state.get_event_many(&user_id, &stream_id, 0, 10, &EventFilter::default(), false).await.unwrap();
+        assert_eq!(page.events[0].1.source().to_string(), "test-user");
+    }
+
+    #[tokio::test]
+    async fn batch_errors_are_capped() {
+        let streams_dir = tempdir().unwrap();
+        let config = Config {
+            event_id_format: Some("uuid".parse().unwrap()),
+            max_batch_errors: 3,
+            ..Default::default()
+        };
+        let (app, _state) = test_app_with_config(streams_dir.path(), config).await;
+
+        let mut batch: Vec<Value> = (0..10).map(|i| event_json(&format!("not-a-uuid-{}", i))).collect();
+        batch.push(event_json(&Uuid::now_v7().to_string()));
+
+        let (status, body) = post_json(&app, "/streams/capped/events", Value::Array(batch)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["errors"].as_array().unwrap().len(), 3);
+        assert_eq!(body["errors"][2]["source"]["pointer"], "/2/id");
+        assert_eq!(body["meta"]["total_errors"], 10);
+    }
+
+    #[tokio::test]
+    async fn subject_events_are_listed_in_order() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+
+        let user_id = "test-user".to_string();
+        let stream_id = "entities".to_string();
+        let events: Vec<Event> = (0..6).map(|i| {
+            EventBuilderV10::new().id(Uuid::now_v7().to_string()).source("test").ty("test").subject(["a", "b"][i % 2]).build().unwrap()
+        }).collect();
+        state.insert_event_many(&user_id, &stream_id, vec![events[0].clone()], ExpectedRevision::Any).await.unwrap();
+        state.set_stream_metadata(&user_id, &stream_id, StreamMetadata { index_subjects: true, ..Default::default() }).await.unwrap();
+        state.insert_event_many(&user_id, &stream_id, events[1..].to_vec(), ExpectedRevision::Any).await.unwrap();
+
+        let (status, body) = get_json(&app, "/streams/entities/subjects/a/events").await;
+        assert_eq!(status, StatusCode::OK);
+        let ids: Vec<&str> = body.as_array().unwrap().iter().map(|event| event["id"].as_str().unwrap()).collect();
+        assert_eq!(ids, [events[0].id(), events[2].id(), events[4].id()]);
+
+        let (status, body) = get_json(&app, "/streams/entities/subjects/a/events?page[offset]=1&page[limit]=1").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body[0]["id"], events[2].id());
+
+        let (status, _body) = get_json(&app, "/streams/missing/subjects/a/events").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn export_serves_event_ranges_as_partial_content() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+
+        let user_id = "test-user".to_string();
+        let stream_id = "exported".to_string();
+        let events: Vec<Event> = (0..5).map(|_| test_event("com.example.a")).collect();
+        state.insert_event_many(&user_id, &stream_id, events.clone(), ExpectedRevision::Any).await.unwrap();
+
+        let request = Request::get("/streams/exported/export").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(String::from_utf8(bytes.to_vec()).unwrap().lines().count(), 5);
+
+        let request = Request::get("/streams/exported/export")
+            .header(header::RANGE, "events=1-2")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "events 1-2/5");
+
+        let bytes = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let exported: Vec<Event> = String::from_utf8(bytes.to_vec()).unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(exported, events[1..3]);
+
+        let request = Request::get("/streams/exported/export")
+            .header(header::RANGE, "events=5-")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "events */5");
+    }
+
+    #[tokio::test]
+    async fn export_ranges_report_the_rownums_left_by_compaction() {
+        let streams_dir = tempdir().unwrap();
+        let config = Config {
+            compaction_idle: Duration::ZERO,
+            ..Default::default()
+        };
+        let (app, state) = test_app_with_config(streams_dir.path(), config).await;
+        let user_id = "test-user".to_string();
+        let stream_id = "exported".to_string();
+
+        let with_subject = |subject: &str| {
+            EventBuilderV10::new().id(Uuid::now_v7().to_string()).source("test").ty("test").subject(subject).build().unwrap()
+        };
+        let events = vec![with_subject("a"), with_subject("a"), with_subject("b"), with_subject("a")];
+        state.insert_event_many(&user_id, &stream_id, events.clone(), ExpectedRevision::Any).await.unwrap();
+
+        let patch = serde_json::json!({
+            "data": {
+                "type": "streams",
+                "attributes": { "compacted": true, "min_dirty_ratio": 0.25 },
+            },
+        });
+        let request = Request::patch("/streams/exported")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(patch.to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(state.compact_streams().await, 2);
+
+        // Only rownums 2 and 3 survive compaction.
+        let request = Request::get("/streams/exported/export")
+            .header(header::RANGE, "events=1-")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "events 2-3/4");
+
+        let bytes = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let exported: Vec<Event> = String::from_utf8(bytes.to_vec()).unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(exported, events[2..]);
+
+        let request = Request::get("/streams/exported/export")
+            .header(header::RANGE, "events=0-2")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "events 2-2/4");
+
+        let request = Request::get("/streams/exported/export")
+            .header(header::RANGE, "events=0-1")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "events */4");
+    }
+
+    #[tokio::test]
+    async fn compacted_away_events_are_not_found() {
+        let streams_dir = tempdir().unwrap();
+        let config = Config {
+            compaction_idle: Duration::ZERO,
+            ..Default::default()
+        };
+        let (app, state) = test_app_with_config(streams_dir.path(), config).await;
+        let user_id = "test-user".to_string();
+        let stream_id = "compacted".to_string();
+
+        let with_subject = |subject: &str| {
+            EventBuilderV10::new().id(Uuid::now_v7().to_string()).source("test").ty("test").subject(subject).build().unwrap()
+        };
+        let events = vec![with_subject("a"), with_subject("a"), with_subject("b"), with_subject("a")];
+        state.insert_event_many(&user_id, &stream_id, events.clone(), ExpectedRevision::Any).await.unwrap();
+
+        let patch = serde_json::json!({
+            "data": {
+                "type": "streams",
+                "attributes": { "compacted": true, "min_dirty_ratio": 0.25 },
+            },
+        });
+        let request = Request::patch("/streams/compacted")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(patch.to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(state.compact_streams().await, 2);
+
+        // Rownum 1 was compacted away, and must not be answered with rownum 2.
+        for uri in ["/streams/compacted/events/1", "/streams/compacted/events/1?apply_corrections=true"] {
+            let request = Request::get(uri).body(Body::empty()).unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            assert!(response.headers().get(header::ETAG).is_none());
+        }
+
+        let (status, json) = get_json(&app, "/streams/compacted/events/2").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["id"], events[2].id());
+    }
+
+    #[tokio::test]
+    async fn truncated_events_are_not_found() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+        let user_id = "test-user".to_string();
+        let stream_id = "truncated".to_string();
+
+        let events: Vec<Event> = (0..4).map(|_| {
+            EventBuilderV10::new().id(Uuid::now_v7().to_string()).source("test").ty("test").build().unwrap()
+        }).collect();
+        state.insert_event_many(&user_id, &stream_id, events.clone(), ExpectedRevision::Any).await.unwrap();
+
+        let db = state.streams.get(&(user_id.clone(), stream_id.clone())).unwrap().clone();
+        assert_eq!(db.lock().await.truncate_before(2).await.unwrap(), 2);
+
+        for uri in ["/streams/truncated/events/0", "/streams/truncated/events/1?apply_corrections=true"] {
+            let (status, _json) = get_json(&app, uri).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+        }
+
+        let (status, json) = get_json(&app, "/streams/truncated/events/2").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["id"], events[2].id());
+    }
+
+    #[tokio::test]
+    async fn conflicts_have_stable_error_codes() {
+        let streams_dir = tempdir().unwrap();
+        let (app, _state) = test_app(streams_dir.path()).await;
+
+        let event = event_json(&Uuid::now_v7().to_string());
+        let (status, _body) = post_json(&app, "/streams/coded/events", event.clone()).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let (status, body) = post_json(&app, "/streams/coded/events?expected_revision=0", event_json(&Uuid::now_v7().to_string())).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["errors"][0]["code"], "revision_mismatch");
+
+        let (status, body) = post_json(&app, "/streams/coded/events", event).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["errors"][0]["code"], "source_id_conflict");
+    }
+
+    #[tokio::test]
+    async fn post_event_rejects_disallowed_spec_versions() {
+        let streams_dir = tempdir().unwrap();
+        let (app, _state) = test_app(streams_dir.path()).await;
+
+        let mut event = event_json(&Uuid::now_v7().to_string());
+        event["specversion"] = Value::String("0.3".to_string());
+
+        let (status, body) = post_json(&app, "/streams/versions/events", event.clone()).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["errors"][0]["detail"], "specversion 0.3 is not accepted by this server");
+        assert_eq!(body["errors"][0]["source"]["pointer"], "/specversion");
+
+        let streams_dir = tempdir().unwrap();
+        let config = Config {
+            spec_versions: vec![SpecVersion::V03, SpecVersion::V10],
+            ..Default::default()
+        };
+        let (app, _state) = test_app_with_config(streams_dir.path(), config).await;
+
+        let (status, _body) = post_json(&app, "/streams/versions/events", event).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn post_event_rejects_times_beyond_the_clock_skew() {
+        let next_year = (OffsetDateTime::now_utc() + time::Duration::days(365)).format(&Rfc3339).unwrap();
+        let mut event = event_json(&Uuid::now_v7().to_string());
+        event["time"] = Value::String(next_year);
+
+        let streams_dir = tempdir().unwrap();
+        let (app, _state) = test_app(streams_dir.path()).await;
+
+        let (status, _body) = post_json(&app, "/streams/skewed/events", event.clone()).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let streams_dir = tempdir().unwrap();
+        let config = Config {
+            max_clock_skew: Some(Duration::from_secs(300)),
+            ..Default::default()
+        };
+        let (app, _state) = test_app_with_config(streams_dir.path(), config).await;
+
+        let (status, body) = post_json(&app, "/streams/skewed/events", event).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["errors"][0]["source"]["pointer"], "/time");
+
+        let (status, _body) = post_json(&app, "/streams/skewed/events", event_json(&Uuid::now_v7().to_string())).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn unknown_extensions_are_kept_or_stripped_by_policy() {
+        let mut event = event_json(&Uuid::now_v7().to_string());
+        event["tenant"] = Value::String("acme".to_string());
+        event["traceparent"] = Value::String("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01".to_string());
+
+        let streams_dir = tempdir().unwrap();
+        let (app, _state) = test_app(streams_dir.path()).await;
+
+        let (status, _body) = post_json(&app, "/streams/governed/events", event.clone()).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let (status, body) = get_json(&app, "/streams/governed/events/0").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["tenant"], "acme");
+        assert!(body["traceparent"].is_string());
+
+        let streams_dir = tempdir().unwrap();
+        let config = Config {
+            extension_policy: ExtensionPolicy::StripUnknown(vec!["traceparent".to_string()]),
+            ..Default::default()
+        };
+        let (app, _state) = test_app_with_config(streams_dir.path(), config).await;
+
+        let (status, _body) = post_json(&app, "/streams/governed/events", event).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let (status, body) = get_json(&app, "/streams/governed/events/0").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["tenant"], Value::Null);
+        assert!(body["traceparent"].is_string());
+    }
+
+    #[tokio::test]
+    async fn startup_skips_stray_entries_in_streams_dir() {
+        let streams_dir = tempdir().unwrap();
+
+        {
+            let (_app, state) = test_app(streams_dir.path()).await;
+            state.insert_event_many(&"test-user".to_string(), &"real".to_string(), vec![test_event("a")], ExpectedRevision::Any).await.unwrap();
+        }
+
+        let user_dir = streams_dir.path().join("test-user");
+        std::fs::write(streams_dir.path().join(".DS_Store"), b"").unwrap();
+        std::fs::create_dir(streams_dir.path().join("lost+found")).unwrap();
+        std::fs::create_dir(streams_dir.path().join("backups")).unwrap();
+        std::fs::write(user_dir.join(".DS_Store"), b"").unwrap();
+        std::fs::write(user_dir.join("notes.txt"), b"").unwrap();
+        std::fs::create_dir(user_dir.join("not-base32!")).unwrap();
+
+        let config = Config {
+            ignored_stream_entries: vec!["lost+found".to_string(), "backups".to_string()],
+            ..Default::default()
+        };
+        let (app, _state) = test_app_with_config(streams_dir.path(), config).await;
+
+        let (status, body) = get_json(&app, "/streams").await;
+
+        assert_eq!(status, StatusCode::OK);
+        let data = body["data"].as_array().unwrap();
+        assert_eq!(data.len(), 1);
+        assert_eq!(data[0]["id"], "real");
+    }
+
+    #[tokio::test]
+    async fn write_lease_rejects_other_writers_until_released() {
+        let streams_dir = tempdir().unwrap();
+        let (app, _state) = test_app(streams_dir.path()).await;
+
+        let (status, body) = post_json(&app, "/streams/leased/lease", serde_json::json!({ "data": { "attributes": {} } })).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["data"]["type"], "lease");
+        let token = body["data"]["id"].as_str().unwrap().to_string();
+
+        let (status, body) = post_json(&app, "/streams/leased/events", event_json(&Uuid::now_v7().to_string())).await;
+        assert_eq!(status, StatusCode::LOCKED);
+        assert_eq!(body["errors"][0]["code"], "lease_held");
+
+        let (status, _body) = post_json(&app, "/streams/leased/lease", serde_json::json!({ "data": { "attributes": {} } })).await;
+        assert_eq!(status, StatusCode::LOCKED);
+
+        let uri = format!("/streams/leased/events?lease={}", token);
+        let (status, _body) = post_json(&app, &uri, event_json(&Uuid::now_v7().to_string())).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let uri = format!("/streams/leased/lease?lease={}", token);
+        let (status, body) = post_json(&app, &uri, serde_json::json!({ "data": { "attributes": {} } })).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["id"], token.as_str());
+
+        let request = Request::delete(&uri).body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let (status, _body) = post_json(&app, "/streams/leased/events", event_json(&Uuid::now_v7().to_string())).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn write_lease_expires() {
+        let streams_dir = tempdir().unwrap();
+        let (app, _state) = test_app(streams_dir.path()).await;
+
+        let (status, _body) = post_json(&app, "/streams/leased/lease", serde_json::json!({ "data": { "attributes": { "ttl_ms": 50 } } })).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let (status, _body) = post_json(&app, "/streams/leased/events", event_json(&Uuid::now_v7().to_string())).await;
+        assert_eq!(status, StatusCode::LOCKED);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let (status, _body) = post_json(&app, "/streams/leased/events", event_json(&Uuid::now_v7().to_string())).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let (status, _body) = post_json(&app, "/streams/leased/lease", serde_json::json!({ "data": { "attributes": {} } })).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn reserved_rownums_are_read_in_order_however_they_are_filled() {
+        let streams_dir = tempdir().unwrap();
+        let (app, _state) = test_app(streams_dir.path()).await;
+
+        let (status, body) = post_json(&app, "/streams/parallel/reserve?count=3", Value::Null).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["data"]["type"], "reservation");
+        assert_eq!(body["data"]["attributes"]["start"], 0);
+
+        let (status, body) = post_json(&app, "/streams/parallel/events", event_json(&Uuid::now_v7().to_string())).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["errors"][0]["code"], "reserved");
+
+        let ids: Vec<String> = (0..3).map(|_| Uuid::now_v7().to_string()).collect();
+
+        for rownum in [2, 0, 1] {
+            let uri = format!("/streams/parallel/events?reserved={}", rownum);
+            let (status, _body) = post_json(&app, &uri, event_json(&ids[rownum])).await;
+            assert_eq!(status, StatusCode::CREATED);
+        }
+
+        let (status, body) = post_json(&app, "/streams/parallel/events?reserved=1", event_json(&Uuid::now_v7().to_string())).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["errors"][0]["code"], "not_reserved");
+
+        let (status, body) = get_json(&app, "/streams/parallel/events").await;
+        assert_eq!(status, StatusCode::OK);
+        let read_ids: Vec<&str> = body["data"].as_array().unwrap().iter().map(|event| event["attributes"]["id"].as_str().unwrap()).collect();
+        assert_eq!(read_ids, ids);
+
+        let (status, _body) = post_json(&app, "/streams/parallel/reserve?count=0", Value::Null).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, body) = post_json(&app, "/streams/parallel/reserve?count=10000000000", Value::Null).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["errors"][0]["source"]["query"], "count");
+
+        let uri = format!("/streams/parallel/events?reserved={}", u64::MAX);
+        let (status, body) = post_json(&app, &uri, event_json(&Uuid::now_v7().to_string())).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["errors"][0]["code"], "not_reserved");
+    }
+
+    #[tokio::test]
+    async fn get_event_by_source_and_id() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+
+        let events = vec![test_event("a"), test_event("b")];
+        state.insert_event_many(&"test-user".to_string(), &"lookup".to_string(), events.clone(), ExpectedRevision::Any).await.unwrap();
+
+        let uri = format!("/streams/lookup/events/by-id?source=test&id={}", events[1].id());
+        let request = Request::get(&uri).body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_LOCATION], "/streams/lookup/events/1");
+        let bytes = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["id"], events[1].id());
+        assert_eq!(body["type"], "b");
+
+        let uri = format!("/streams/lookup/events/by-id?source=elsewhere&id={}", events[1].id());
+        let (status, _body) = get_json(&app, &uri).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _body) = get_json(&app, "/streams/missing/events/by-id?source=test&id=1").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn get_activity_buckets_events_by_time() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+
+        let timed = |time: &str| {
+            EventBuilderV10::new().id(Uuid::now_v7().to_string()).source("test").ty("a").time(time).build().unwrap()
+        };
+
+        let events = vec![
+            timed("2024-01-01T00:10:00Z"),
+            timed("2024-01-01T00:50:00Z"),
+            timed("2024-01-01T02:30:00Z"),
+            test_event("a"),
+        ];
+        state.insert_event_many(&"test-user".to_string(), &"active".to_string(), events, ExpectedRevision::Any).await.unwrap();
+
+        let (status, body) = get_json(&app, "/streams/active/activity?bucket=1h").await;
+        assert_eq!(status, StatusCode::OK);
+        let data = body["data"].as_array().unwrap();
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[0]["id"], "2024-01-01T00:00:00Z");
+        assert_eq!(data[0]["attributes"]["count"], 2);
+        assert_eq!(data[1]["id"], "2024-01-01T02:00:00Z");
+        assert_eq!(data[1]["attributes"]["count"], 1);
+        assert_eq!(body["meta"]["bucket_seconds"], 3600);
+        assert_eq!(body["meta"]["untimed"], 1);
+
+        let (status, body) = get_json(&app, "/streams/active/activity?bucket=1d&since=2024-01-01T01:00:00Z").await;
+        assert_eq!(status, StatusCode::OK);
+        let data = body["data"].as_array().unwrap();
+        assert_eq!(data.len(), 1);
+        assert_eq!(data[0]["attributes"]["count"], 1);
+
+        let (status, body) = get_json(&app, "/streams/active/activity?bucket=0h").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["errors"][0]["source"]["query"], "bucket");
+    }
+
+    #[tokio::test]
+    async fn get_event_index_filters_by_type() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+
+        let events = vec![test_event("com.example.a"), test_event("com.example.b"), test_event("com.example.a")];
+        state.insert_event_many(&"test-user".to_string(), &"mixed".to_string(), events.clone(), ExpectedRevision::Any).await.unwrap();
+
+        let (status, body) = get_json(&app, "/streams/mixed/events?filter[type]=com.example.a").await;
+        assert_eq!(status, StatusCode::OK);
+        let ids: Vec<&str> = body["data"].as_array().unwrap().iter().map(|event| event["attributes"]["id"].as_str().unwrap()).collect();
+        assert_eq!(ids, [events[0].id(), events[2].id()]);
+
+        let (status, body) = get_json(&app, "/streams/mixed/events?filter[type]=com.example.b").await;
+        assert_eq!(status, StatusCode::OK);
+        let ids: Vec<&str> = body["data"].as_array().unwrap().iter().map(|event| event["attributes"]["id"].as_str().unwrap()).collect();
+        assert_eq!(ids, [events[1].id()]);
+
+        let (status, body) = get_json(&app, "/streams/mixed/events?filter[type]=com.example.a&page[offset]=1").await;
+        assert_eq!(status, StatusCode::OK);
+        let ids: Vec<&str> = body["data"].as_array().unwrap().iter().map(|event| event["attributes"]["id"].as_str().unwrap()).collect();
+        assert_eq!(ids, [events[2].id()]);
+    }
+
+    #[tokio::test]
+    async fn reloaded_default_page_limit_takes_effect() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+
+        let events = vec![test_event("a"), test_event("a"), test_event("a")];
+        state.insert_event_many(&"test-user".to_string(), &"paged".to_string(), events, ExpectedRevision::Any).await.unwrap();
+
+        let (status, body) = get_json(&app, "/streams/paged/events").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"].as_array().unwrap().len(), 3);
+
+        state.reload_config(Config {
+            default_page_limit: 2,
+            ..Default::default()
+        });
+
+        let (status, body) = get_json(&app, "/streams/paged/events").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"].as_array().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn ingest_appends_events_from_allowed_sources_only() {
+        let ids: Vec<String> = (0..3).map(|_| Uuid::now_v7().to_string()).collect();
+        let ndjson: String = ids.iter().map(|id| format!("{}\n", event_json(id))).collect();
+
+        let source = Router::new().route("/events.ndjson", get(move || async move { ndjson }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { axum::serve(listener, source).await });
+
+        let streams_dir = tempdir().unwrap();
+        let config = Config {
+            ingest_allowed_hosts: vec!["127.0.0.1".to_string()],
+            ..Default::default()
+        };
+        let (app, _state) = test_app_with_config(streams_dir.path(), config).await;
+
+        let document = serde_json::json!({
+            "data": { "attributes": { "url": format!("http://127.0.0.1:{}/events.ndjson", port) } },
+        });
+        let (status, body) = post_json(&app, "/streams/pulled/ingest", document).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["data"]["type"], "ingestion");
+        assert_eq!(body["data"]["attributes"]["start"], 0);
+        assert_eq!(body["data"]["attributes"]["end"], 3);
+        assert_eq!(body["data"]["attributes"]["count"], 3);
+
+        let (status, body) = get_json(&app, "/streams/pulled/events").await;
+        assert_eq!(status, StatusCode::OK);
+        let read_ids: Vec<&str> = body["data"].as_array().unwrap().iter().map(|event| event["attributes"]["id"].as_str().unwrap()).collect();
+        assert_eq!(read_ids, ids);
+
+        let document = serde_json::json!({
+            "data": { "attributes": { "url": format!("http://localhost:{}/events.ndjson", port) } },
+        });
+        let (status, body) = post_json(&app, "/streams/pulled/ingest", document).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["errors"][0]["code"], "source_not_allowed");
+
+        let (status, body) = get_json(&app, "/streams/pulled/events").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"].as_array().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn ingesting_gives_up_on_a_stalled_source_and_reports_the_revision_of_an_empty_one() {
+        let stalled_line = format!("{}\n", event_json(&Uuid::now_v7().to_string()));
+        let source = Router::new()
+            .route("/empty.ndjson", get(|| async { "" }))
+            .route("/stalled.ndjson", get(move || async move {
+                let first = stream::once(async move { Ok::<_, std::io::Error>(stalled_line) });
+                Body::from_stream(first.chain(stream::pending()))
+            }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { axum::serve(listener, source).await });
+
+        let streams_dir = tempdir().unwrap();
+        let config = Config {
+            ingest_allowed_hosts: vec!["127.0.0.1".to_string()],
+            ingest_timeout: Duration::from_secs(1),
+            ..Default::default()
+        };
+        let (app, _state) = test_app_with_config(streams_dir.path(), config).await;
+
+        let ingest = |path: &str| serde_json::json!({
+            "data": { "attributes": { "url": format!("http://127.0.0.1:{}/{}", port, path) } },
+        });
+
+        let (status, body) = post_json(&app, "/streams/pulled/ingest", ingest("empty.ndjson")).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["data"]["attributes"]["start"], 0);
+        assert_eq!(body["data"]["attributes"]["end"], 0);
+
+        for id in ["1", "2"] {
+            let (status, _) = post_json(&app, "/streams/pulled/events", event_json(id)).await;
+            assert_eq!(status, StatusCode::CREATED);
+        }
+
+        let (status, body) = post_json(&app, "/streams/pulled/ingest", ingest("empty.ndjson")).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["data"]["attributes"]["start"], 2);
+        assert_eq!(body["data"]["attributes"]["end"], 2);
+        assert_eq!(body["data"]["attributes"]["count"], 0);
+
+        let started = std::time::Instant::now();
+        let (status, body) = post_json(&app, "/streams/pulled/ingest", ingest("stalled.ndjson")).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(body["errors"][0]["code"], "source_unavailable");
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    async fn get_with_if_none_match(app: &Router, uri: &str, etag: &str) -> Response {
+        let request = Request::get(uri)
+            .header(header::IF_NONE_MATCH, etag)
+            .body(Body::empty())
+            .unwrap();
+
+        app.clone().oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn events_are_not_resent_when_their_etag_matches() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+
+        state.insert_event_many(&"test-user".to_string(), &"cached".to_string(), vec![test_event("a")], ExpectedRevision::Any).await.unwrap();
+
+        let request = Request::get("/streams/cached/events/0").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[header::ETAG].to_str().unwrap().to_string();
+        assert!(!etag.starts_with("W/"));
+
+        let response = get_with_if_none_match(&app, "/streams/cached/events/0", &etag).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], etag.as_str());
+        assert!(body::to_bytes(response.into_body(), usize::MAX).await.unwrap().is_empty());
+
+        let response = get_with_if_none_match(&app, "/streams/cached/events/0", "\"0-00000000\"").await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn event_index_etag_follows_the_revision() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+        let user_id = "test-user".to_string();
+        let stream_id = "cached".to_string();
+
+        state.insert_event_many(&user_id, &stream_id, vec![test_event("a")], ExpectedRevision::Any).await.unwrap();
+
+        let request = Request::get("/streams/cached/events").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ETAG], "W/\"1\"");
+
+        let response = get_with_if_none_match(&app, "/streams/cached/events", "W/\"1\"").await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let response = get_with_if_none_match(&app, "/streams/cached/events?after_revision=0", "W/\"0-1\"").await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let response = get_with_if_none_match(&app, "/streams/cached/events?after_revision=1", "W/\"0-1\"").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ETAG], "W/\"1-1\"");
+
+        state.insert_event_many(&user_id, &stream_id, vec![test_event("a")], ExpectedRevision::Any).await.unwrap();
+
+        let response = get_with_if_none_match(&app, "/streams/cached/events", "W/\"1\"").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ETAG], "W/\"2\"");
+    }
+    #[tokio::test]
+    async fn event_index_next_links_walk_every_page() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+
+        let events: Vec<Event> = (0..5).map(|_| test_event("a")).collect();
+        state.insert_event_many(&"test-user".to_string(), &"walked".to_string(), events.clone(), ExpectedRevision::Any).await.unwrap();
+
+        let (status, mut body) = get_json(&app, "/streams/walked/events?page[limit]=2").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["meta"]["total"], 5);
+        assert_eq!(body["links"]["prev"], Value::Null);
+
+        let mut ids = vec![];
+        let mut pages = 1;
+
+        loop {
+            for event in body["data"].as_array().unwrap() {
+                assert_eq!(event["type"], "event");
+                ids.push(event["attributes"]["id"].as_str().unwrap().to_string());
+            }
+
+            let Some(next) = body["links"]["next"].as_str() else {
+                break;
+            };
+
+            let (status, next_body) = get_json(&app, next).await;
+            assert_eq!(status, StatusCode::OK);
+            assert!(next_body["links"]["prev"].is_string());
+            body = next_body;
+            pages += 1;
+        }
+
+        assert_eq!(pages, 3);
+        assert_eq!(ids, events.iter().map(|event| event.id().to_string()).collect::<Vec<_>>());
+
+        let (status, first) = get_json(&app, body["links"]["first"].as_str().unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(first["data"][0]["id"], "0");
+    }
+    #[tokio::test]
+    async fn sse_replays_then_pushes_appended_events() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+        let user_id = "test-user".to_string();
+        let stream_id = "live".to_string();
+
+        let first = test_event("a");
+        state.insert_event_many(&user_id, &stream_id, vec![first.clone()], ExpectedRevision::Any).await.unwrap();
+
+        let request = Request::get("/streams/live/events/sse?from=0").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/event-stream");
+
+        let mut body = response.into_body().into_data_stream();
+        let mut next_message = async || {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), body.next()).await.unwrap().unwrap().unwrap();
+            String::from_utf8(chunk.to_vec()).unwrap()
+        };
+
+        let message = next_message().await;
+        assert!(message.contains("id: 0\n"));
+        assert!(message.contains(first.id()));
+
+        let second = test_event("a");
+        let (status, _body) = post_json(&app, "/streams/live/events", serde_json::to_value(&second).unwrap()).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let message = next_message().await;
+        assert!(message.contains("id: 1\n"));
+        assert!(message.contains(second.id()));
+
+        let request = Request::get("/streams/live/events/sse")
+            .header("last-event-id", "0")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let mut body = response.into_body().into_data_stream();
+        let chunk = tokio::time::timeout(Duration::from_secs(5), body.next()).await.unwrap().unwrap().unwrap();
+        assert!(String::from_utf8(chunk.to_vec()).unwrap().contains(second.id()));
+    }
+    #[tokio::test]
+    async fn event_index_waits_for_events_past_the_tail() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+        let user_id = "test-user".to_string();
+        let stream_id = "waited".to_string();
+
+        state.insert_event_many(&user_id, &stream_id, vec![test_event("a")], ExpectedRevision::Any).await.unwrap();
+
+        let started = std::time::Instant::now();
+        let (status, body) = get_json(&app, "/streams/waited/events?wait=30s").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"].as_array().unwrap().len(), 1);
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let waiting_app = app.clone();
+        let waiting = tokio::spawn(async move { get_json(&waiting_app, "/streams/waited/events?page[offset]=1&wait=30s").await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let appended = test_event("a");
+        state.insert_event_many(&user_id, &stream_id, vec![appended.clone()], ExpectedRevision::Any).await.unwrap();
+
+        let (status, body) = tokio::time::timeout(Duration::from_secs(5), waiting).await.unwrap().unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"][0]["attributes"]["id"], appended.id());
+
+        let started = std::time::Instant::now();
+        let (status, body) = get_json(&app, "/streams/waited/events?page[offset]=2&wait=1s").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["data"].as_array().unwrap().is_empty());
+        assert!(started.elapsed() >= Duration::from_secs(1));
+
+        let (status, body) = get_json(&app, "/streams/waited/events?wait=soon").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["errors"][0]["source"]["query"], "wait");
+    }
+
+    #[tokio::test]
+    async fn events_are_served_in_binary_mode_when_their_data_type_is_accepted() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+
+        let event = EventBuilderV10::new()
+            .id("binary-1")
+            .source("test")
+            .ty("a")
+            .subject("order 1")
+            .data("application/json", serde_json::json!({"total": 42}))
+            .build()
+            .unwrap();
+        state.insert_event_many(&"test-user".to_string(), &"binary".to_string(), vec![event], ExpectedRevision::Any).await.unwrap();
+
+        let get_accepting = |accept: &'static str| {
+            let request = Request::get("/streams/binary/events/0")
+                .header(header::ACCEPT, accept)
+                .body(Body::empty())
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        let response = get_accepting("application/cloudevents+json").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::VARY], "accept");
+        assert!(response.headers().get("ce-id").is_none());
+        let structured_etag = response.headers()[header::ETAG].clone();
+        let bytes = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["id"], "binary-1");
+        assert_eq!(body["data"], serde_json::json!({"total": 42}));
+
+        let response = get_accepting("application/json").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(response.headers()["ce-specversion"], "1.0");
+        assert_eq!(response.headers()["ce-id"], "binary-1");
+        assert_eq!(response.headers()["ce-source"], "test");
+        assert_eq!(response.headers()["ce-type"], "a");
+        assert_eq!(response.headers()["ce-subject"], "order%201");
+        assert_ne!(response.headers()[header::ETAG], structured_etag);
+        let bytes = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body, serde_json::json!({"total": 42}));
+    }
+
+    #[tokio::test]
+    async fn post_event_accepts_binary_mode() {
+        let streams_dir = tempdir().unwrap();
+        let (app, _state) = test_app(streams_dir.path()).await;
+
+        let request = Request::post("/streams/binary/events")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("ce-specversion", "1.0")
+            .header("ce-id", "binary-1")
+            .header("ce-source", "test")
+            .header("ce-type", "a")
+            .header("ce-subject", "order%201")
+            .header("ce-comexampleextension", "value")
+            .body(Body::from(r#"{"total": 42}"#))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let (status, event) = get_json(&app, "/streams/binary/events/0").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(event["id"], "binary-1");
+        assert_eq!(event["source"], "test");
+        assert_eq!(event["type"], "a");
+        assert_eq!(event["subject"], "order 1");
+        assert_eq!(event["comexampleextension"], "value");
+        assert_eq!(event["datacontenttype"], "application/json");
+        assert_eq!(event["data"], serde_json::json!({"total": 42}));
+
+        let request = Request::post("/streams/binary/events")
+            .header(header::CONTENT_TYPE, "text/plain")
+            .header("ce-specversion", "1.0")
+            .header("ce-source", "test")
+            .header("ce-type", "a")
+            .body(Body::from("no id"))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let (status, _) = post_json(&app, "/streams/binary/events", event_json("structured-1")).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn admins_can_move_streams_between_users() {
+        let streams_dir = tempdir().unwrap();
+        let config = Config { admin_users: vec!["test-user".to_string()], ..Default::default() };
+        let (app, state) = test_app_with_config(streams_dir.path(), config).await;
+        let other_user = "other-user".to_string();
+
+        state.insert_event_many(&other_user, &"orders".to_string(), vec![test_event("a"), test_event("b")], ExpectedRevision::Any).await.unwrap();
+        state.set_stream_metadata(&other_user, &"orders".to_string(), StreamMetadata { index_subjects: true, ..Default::default() }).await.unwrap();
+        state.insert_event_many(&other_user, &"taken".to_string(), vec![test_event("a")], ExpectedRevision::Any).await.unwrap();
+
+        let move_document = |source_user: &str, source_stream: &str, target_user: &str, target_stream: &str| serde_json::json!({
+            "data": {
+                "attributes": {
+                    "source": {"user": source_user, "stream": source_stream},
+                    "target": {"user": target_user, "stream": target_stream},
+                }
+            }
+        });
+
+        let (status, _) = post_json(&app, "/admin/streams/move", move_document("other-user", "orders", "other-user", "taken")).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let (status, _) = post_json(&app, "/admin/streams/move", move_document("other-user", "missing", "test-user", "missing")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = post_json(&app, "/admin/streams/move", move_document("other-user", "orders", "test-user", "moved")).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (status, stream) = get_json(&app, "/streams/moved").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(stream["data"]["attributes"]["count"], 2);
+        assert_eq!(stream["data"]["attributes"]["index_subjects"], true);
+
+        let (status, event) = get_json(&app, "/streams/moved/events/1").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(event["type"], "b");
+
+        assert!(state.get_stream(&other_user, &"orders".to_string(), Consistency::Strong).await.is_err());
+        assert!(!streams_dir.path().join("other-user").join(data_encoding::BASE32_NOPAD.encode(b"orders")).exists());
+        assert!(streams_dir.path().join("test-user").join(data_encoding::BASE32_NOPAD.encode(b"moved")).exists());
+
+        let (status, _) = post_json(&app, "/streams/moved/events", event_json("after-move")).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let non_admin_streams_dir = tempdir().unwrap();
+        let (app, _state) = test_app(non_admin_streams_dir.path()).await;
+        let (status, body) = post_json(&app, "/admin/streams/move", move_document("test-user", "moved", "other-user", "orders")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["errors"][0]["code"], "not_admin");
+    }
+
+    #[tokio::test]
+    async fn streams_can_be_renamed() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+        let user_id = "test-user".to_string();
+
+        state.insert_event_many(&user_id, &"ordres".to_string(), vec![test_event("a"), test_event("b")], ExpectedRevision::Any).await.unwrap();
+        state.insert_event_many(&user_id, &"taken".to_string(), vec![test_event("a")], ExpectedRevision::Any).await.unwrap();
+
+        let rename_document = |stream: &str| serde_json::json!({ "data": { "attributes": { "stream": stream } } });
+
+        let (status, body) = post_json(&app, "/streams/ordres/rename", rename_document("taken")).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["errors"][0]["code"], "stream_exists");
+
+        let (status, _) = post_json(&app, "/streams/missing/rename", rename_document("found")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = post_json(&app, "/streams/ordres/rename", rename_document("orders")).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (status, event) = get_json(&app, "/streams/orders/events/1").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(event["type"], "b");
+        let (status, _) = get_json(&app, "/streams/ordres").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(!streams_dir.path().join("test-user").join(data_encoding::BASE32_NOPAD.encode(b"ordres")).exists());
+        assert!(streams_dir.path().join("test-user").join(data_encoding::BASE32_NOPAD.encode(b"orders")).exists());
+
+        let (status, _) = post_json(&app, "/streams/orders/events", event_json("after-rename")).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn head_requests_match_get_without_a_body() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+
+        state.insert_event_many(&"test-user".to_string(), &"heads".to_string(), vec![test_event("a")], ExpectedRevision::Any).await.unwrap();
+
+        for uri in ["/streams/heads", "/streams/heads/events/0"] {
+            let request = Request::get(uri).body(Body::empty()).unwrap();
+            let get_response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(get_response.status(), StatusCode::OK);
+
+            let request = Request::head(uri).body(Body::empty()).unwrap();
+            let head_response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(head_response.status(), StatusCode::OK);
+
+            for name in [header::ETAG, header::CACHE_CONTROL, header::CONTENT_TYPE] {
+                assert_eq!(head_response.headers().get(&name), get_response.headers().get(&name), "{} of {}", name, uri);
+            }
+            assert!(body::to_bytes(head_response.into_body(), usize::MAX).await.unwrap().is_empty());
+        }
+
+        let request = Request::head("/streams/heads").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert!(response.headers().contains_key(header::LAST_MODIFIED));
+        let etag = response.headers()[header::ETAG].to_str().unwrap().to_string();
+
+        let response = get_with_if_none_match(&app, "/streams/heads", &etag).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        state.insert_event_many(&"test-user".to_string(), &"heads".to_string(), vec![test_event("a")], ExpectedRevision::Any).await.unwrap();
+        let response = get_with_if_none_match(&app, "/streams/heads", &etag).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        for uri in ["/streams/missing", "/streams/heads/events/5"] {
+            let request = Request::head(uri).body(Body::empty()).unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", uri);
+            assert!(body::to_bytes(response.into_body(), usize::MAX).await.unwrap().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn index_info_counts_an_entry_per_event() {
+        let streams_dir = tempdir().unwrap();
+        let config = Config { admin_users: vec!["test-user".to_string()], ..Default::default() };
+        let (app, state) = test_app_with_config(streams_dir.path(), config).await;
+
+        let events = (0..5).map(|_| test_event("a")).collect();
+        state.insert_event_many(&"other-user".to_string(), &"indexed".to_string(), events, ExpectedRevision::Any).await.unwrap();
+
+        let (status, body) = get_json(&app, "/admin/streams/other-user/indexed/index-info").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["type"], "index-info");
+        assert_eq!(body["data"]["attributes"]["entries"], 5);
+        assert!(body["data"]["attributes"]["approximate_bytes"].as_u64().unwrap() > 0);
+        assert_eq!(body["data"]["attributes"]["sparse"], false);
+
+        let (status, _) = get_json(&app, "/admin/streams/other-user/missing/index-info").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let non_admin_streams_dir = tempdir().unwrap();
+        let (app, _state) = test_app(non_admin_streams_dir.path()).await;
+        let (status, _) = get_json(&app, "/admin/streams/other-user/indexed/index-info").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn invalid_expected_revisions_are_bad_requests() {
+        let streams_dir = tempdir().unwrap();
+        let (app, _state) = test_app(streams_dir.path()).await;
+
+        let (status, body) = post_json(&app, "/streams/revised/events?expected_revision=latest", event_json(&Uuid::now_v7().to_string())).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let error = &body["errors"][0];
+        assert_eq!(error["code"], "invalid_parameter");
+        assert_eq!(error["source"]["query"], "expected_revision");
+        assert_eq!(
+            error["detail"],
+            "expected_revision must be any, no-stream, stream-exists, a revision number, or >= followed by a revision number, but got \"latest\"",
+        );
+    }
+
+    #[tokio::test]
+    async fn request_ids_are_generated_in_the_configured_format() {
+        let request_id = |format: RequestIdFormat, incoming: Option<&'static str>| async move {
+            let app = Router::new()
+                .route("/health", get(|| async { "ok" }))
+                .layer(middleware::from_fn_with_state(format, assign_request_id));
+
+            let mut request = Request::get("/health");
+            if let Some(incoming) = incoming {
+                request = request.header(REQUEST_ID_HEADER, incoming);
+            }
+
+            let response = app.oneshot(request.body(Body::empty()).unwrap()).await.unwrap();
+            response.headers()[REQUEST_ID_HEADER].to_str().unwrap().to_string()
+        };
+
+        let id = request_id(RequestIdFormat::UuidV7, None).await;
+        assert_eq!(Uuid::parse_str(&id).unwrap().get_version_num(), 7);
+
+        let id = request_id(RequestIdFormat::UuidV4, None).await;
+        assert_eq!(Uuid::parse_str(&id).unwrap().get_version_num(), 4);
+
+        let id = request_id(RequestIdFormat::Hex, None).await;
+        assert_eq!(id.len(), 32);
+        assert!(id.chars().all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase()));
+
+        let id = request_id(RequestIdFormat::Hex, Some("upstream-7f3a")).await;
+        assert_eq!(id, "upstream-7f3a");
+
+        let id = request_id(RequestIdFormat::UuidV7, Some("has spaces")).await;
+        assert_ne!(id, "has spaces");
+        assert!(Uuid::parse_str(&id).is_ok());
+    }
+
+    #[tokio::test]
+    async fn event_index_filters_by_inclusive_time_range() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+
+        let timed_event = |id: &str, time: Option<&str>| {
+            let mut event = EventBuilderV10::new().id(id).source("test").ty("a");
+            if let Some(time) = time {
+                event = event.time(time);
+            }
+            event.build().unwrap()
+        };
+
+        let events = vec![
+            timed_event("before", Some("2024-01-01T11:59:59Z")),
+            timed_event("at-since", Some("2024-01-01T12:00:00Z")),
+            timed_event("untimed", None),
+            timed_event("between", Some("2024-01-01T12:30:00Z")),
+            timed_event("at-until", Some("2024-01-01T13:00:00Z")),
+            timed_event("after", Some("2024-01-01T13:00:01Z")),
+        ];
+        state.insert_event_many(&"test-user".to_string(), &"audited".to_string(), events, ExpectedRevision::Any).await.unwrap();
+
+        let ids = |body: &Value| -> Vec<String> {
+            body["data"].as_array().unwrap().iter()
+                .map(|event| event["attributes"]["id"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        let (status, body) = get_json(&app, "/streams/audited/events?since=2024-01-01T12:00:00Z&until=2024-01-01T13:00:00Z").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ids(&body), ["at-since", "between", "at-until"]);
+
+        let (_, body) = get_json(&app, "/streams/audited/events?since=2024-01-01T12:30:00%2B00:00").await;
+        assert_eq!(ids(&body), ["between", "at-until", "after"]);
+
+        let (_, body) = get_json(&app, "/streams/audited/events?until=2024-01-01T12:00:00Z&page[limit]=1").await;
+        assert_eq!(ids(&body), ["before"]);
+        let next = body["links"]["next"].as_str().unwrap().to_string();
+        assert!(next.contains("until=2024-01-01T12%3A00%3A00Z"));
+        let (_, body) = get_json(&app, &next).await;
+        assert_eq!(ids(&body), ["at-since"]);
+        assert!(body["links"]["next"].is_null());
+
+        let (status, body) = get_json(&app, "/streams/audited/events?since=yesterday").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["errors"][0]["source"]["query"], "since");
+    }
+
+    #[tokio::test]
+    async fn tail_reads_the_last_events() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+
+        let short_events = (0..50).map(|_| test_event("a")).collect();
+        state.insert_event_many(&"test-user".to_string(), &"short".to_string(), short_events, ExpectedRevision::Any).await.unwrap();
+        let long_events = (0..500).map(|_| test_event("a")).collect();
+        state.insert_event_many(&"test-user".to_string(), &"long".to_string(), long_events, ExpectedRevision::Any).await.unwrap();
+
+        let rownums = |body: &Value| -> Vec<u64> {
+            body["data"].as_array().unwrap().iter()
+                .map(|event| event["id"].as_str().unwrap().parse().unwrap())
+                .collect()
+        };
+
+        let (status, body) = get_json(&app, "/streams/short/events/tail?limit=100").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(rownums(&body), (0..50).collect::<Vec<_>>());
+        assert!(body["links"]["prev"].is_null());
+
+        let (status, body) = get_json(&app, "/streams/long/events/tail?limit=100").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(rownums(&body), (400..500).collect::<Vec<_>>());
+        assert_eq!(body["meta"]["total"], 500);
+
+        let (_, previous) = get_json(&app, body["links"]["prev"].as_str().unwrap()).await;
+        assert_eq!(rownums(&previous), (300..400).collect::<Vec<_>>());
+
+        let (_, body) = get_json(&app, "/streams/long/events/tail?limit=3&order=desc").await;
+        assert_eq!(rownums(&body), [499, 498, 497]);
+
+        let (status, _) = get_json(&app, "/streams/missing/events/tail").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn deleting_the_stream_collection_deletes_every_stream_of_the_user() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+        let user_id = "test-user".to_string();
+        let other_user = "other-user".to_string();
+
+        for stream_id in ["a", "b", "c"] {
+            state.insert_event_many(&user_id, &stream_id.to_string(), vec![test_event("a")], ExpectedRevision::Any).await.unwrap();
+        }
+        state.insert_event_many(&other_user, &"a".to_string(), vec![test_event("a")], ExpectedRevision::Any).await.unwrap();
+
+        let request = Request::delete("/streams").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["meta"]["deleted"], 3);
+
+        let (status, body) = get_json(&app, "/streams").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"].as_array().unwrap().len(), 0);
+        assert!(!streams_dir.path().join("test-user").exists());
+
+        assert_eq!(state.get_stream(&other_user, &"a".to_string(), Consistency::Strong).await.unwrap().count, 1);
+
+        let (status, _) = post_json(&app, "/streams/a/events", event_json("recreated")).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (_, stream) = get_json(&app, "/streams/a").await;
+        assert_eq!(stream["data"]["attributes"]["count"], 1);
+    }
+
+    #[tokio::test]
+    async fn appends_racing_a_stream_delete_fail() {
+        let streams_dir = tempdir().unwrap();
+        let (_app, state) = test_app(streams_dir.path()).await;
+        let user_id = "test-user".to_string();
+        let stream_id = "doomed".to_string();
+        state.insert_event_many(&user_id, &stream_id, vec![test_event("a")], ExpectedRevision::Any).await.unwrap();
+
+        // Hold the stream's lock so the delete, then the append, queue up behind it.
+        let db_mutex = state.streams.get(&(user_id.clone(), stream_id.clone())).unwrap().clone();
+        let held = db_mutex.lock().await;
+
+        let delete = tokio::spawn({
+            let (state, user_id, stream_id) = (state.clone(), user_id.clone(), stream_id.clone());
+            async move { state.delete_stream(&user_id, &stream_id).await }
+        });
+        tokio::task::yield_now().await;
+
+        let append = tokio::spawn({
+            let (state, user_id, stream_id) = (state.clone(), user_id.clone(), stream_id.clone());
+            async move { state.insert_event_many(&user_id, &stream_id, vec![test_event("a")], ExpectedRevision::Any).await }
+        });
+        tokio::task::yield_now().await;
+
+        drop(held);
+        assert!(delete.await.unwrap().unwrap());
+        let err = append.await.unwrap().unwrap_err();
+        assert!(matches!(err.downcast_ref::<server::Error>(), Some(server::Error::StreamNotFound)), "{err:?}");
+        assert_eq!(std::fs::read_dir(streams_dir.path().join("test-user")).unwrap().count(), 0);
+
+        // Nothing was written back for the stream to reappear from.
+        let (app, _state) = test_app(streams_dir.path()).await;
+        let (status, _) = get_json(&app, "/streams/doomed").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn cors_headers_are_sent_to_allowed_origins() {
+        let streams_dir = tempdir().unwrap();
+        let (app, _state) = test_app(streams_dir.path()).await;
+        let app = with_cors(app, &["https://app.example".to_string()]);
+
+        let request = Request::get("/streams")
+            .header(header::ORIGIN, "https://app.example")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "https://app.example");
+
+        let request = Request::get("/streams")
+            .header(header::ORIGIN, "https://elsewhere.example")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert!(response.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+
+    #[tokio::test]
+    async fn cors_preflights_succeed_without_auth() {
+        let streams_dir = tempdir().unwrap();
+        let state = Arc::new(AppState::new(streams_dir.path().to_path_buf(), Config::default()).await.unwrap());
+        let app = routes(&state.config())
+            .layer(middleware::from_fn(|_request: Request, _next: Next| async { StatusCode::UNAUTHORIZED.into_response() }));
+        let app = with_cors(app, &["*".to_string()]).with_state(state);
+
+        let request = Request::options("/streams/a/events")
+            .header(header::ORIGIN, "https://app.example")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization,content-type")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        let allowed_methods = response.headers()[header::ACCESS_CONTROL_ALLOW_METHODS].to_str().unwrap();
+        assert!(allowed_methods.contains("POST"));
+
+        let request = Request::post("/streams/a/events")
+            .header(header::ORIGIN, "https://app.example")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn whoami_reports_the_validated_claims() {
+        let streams_dir = tempdir().unwrap();
+        let state = Arc::new(AppState::new(streams_dir.path().to_path_buf(), Config::default()).await.unwrap());
+        let app = authenticated(routes(&state.config()), state.clone(), Arc::new(crate::openid::tests::test_client()))
+            .with_state(state);
+
+        let token = crate::openid::tests::token("https://first.example", "first-key", crate::openid::tests::FIRST_KEY, "alice");
+        let request = Request::get("/whoami")
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(!String::from_utf8_lossy(&bytes).contains(&token));
+
+        let json: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(json["data"]["id"], "alice");
+        assert_eq!(json["data"]["attributes"]["claims"]["sub"], "alice");
+        assert_eq!(json["data"]["attributes"]["claims"]["iss"], "https://first.example");
+        assert_eq!(json["data"]["attributes"]["claims"]["aud"], "hematite");
+
+        let request = Request::get("/whoami").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn writes_are_throttled_after_a_burst() {
+        let streams_dir = tempdir().unwrap();
+        let config = Config {
+            write_rate_limit: Some(WriteRateLimit { per_second: 0.01, burst: 2 }),
+            ..Default::default()
+        };
+        let (app, _state) = test_app_with_config(streams_dir.path(), config).await;
+
+        for id in ["1", "2"] {
+            let (status, _) = post_json(&app, "/streams/a/events", event_json(id)).await;
+            assert_eq!(status, StatusCode::CREATED);
+        }
+
+        let request = Request::post("/streams/a/events")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(event_json("3").to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = response.headers()[header::RETRY_AFTER].to_str().unwrap().parse().unwrap();
+        assert!(retry_after > 0 && retry_after <= 100);
+        let bytes = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(json["errors"][0]["code"], "rate_limited");
+
+        // Reads aren't limited.
+        let (status, json) = get_json(&app, "/streams/a/events").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["data"].as_array().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn event_data_over_the_limits_is_rejected() {
+        let streams_dir = tempdir().unwrap();
+        let config = Config {
+            max_data_depth: Some(4),
+            max_data_bytes: Some(64),
+            ..Default::default()
+        };
+        let (app, _state) = test_app_with_config(streams_dir.path(), config).await;
+
+        let mut deep = event_json("deep");
+        deep["data"] = serde_json::json!({"a": {"b": {"c": {"d": {"e": 1}}}}});
+        let (status, json) = post_json(&app, "/streams/a/events", deep).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(json["errors"][0]["code"], "invalid_event");
+        assert_eq!(json["errors"][0]["source"]["pointer"], "/data");
+
+        let mut large = event_json("large");
+        large["data"] = serde_json::json!("x".repeat(100));
+        let (status, json) = post_json(&app, "/streams/a/events", serde_json::json!([event_json("small"), large])).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(json["errors"][0]["code"], "payload_too_large");
+        assert_eq!(json["errors"][0]["source"]["pointer"], "/1/data");
+
+        let (status, _) = get_json(&app, "/streams/a").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let mut within = event_json("within");
+        within["data"] = serde_json::json!({"a": {"b": {"c": {"d": 1}}}});
+        let (status, _) = post_json(&app, "/streams/a/events", within).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn ingested_events_and_corrections_are_held_to_the_data_limits() {
+        let mut large = event_json(&Uuid::now_v7().to_string());
+        large["data"] = serde_json::json!("x".repeat(100));
+        let ndjson = format!("{}\n{}\n", event_json(&Uuid::now_v7().to_string()), large);
+
+        let source = Router::new().route("/events.ndjson", get(move || async move { ndjson }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { axum::serve(listener, source).await });
+
+        let streams_dir = tempdir().unwrap();
+        let config = Config {
+            max_data_depth: Some(4),
+            max_data_bytes: Some(64),
+            ingest_allowed_hosts: vec!["127.0.0.1".to_string()],
+            ..Default::default()
+        };
+        let (app, _state) = test_app_with_config(streams_dir.path(), config).await;
+
+        let document = serde_json::json!({
+            "data": { "attributes": { "url": format!("http://127.0.0.1:{}/events.ndjson", port) } },
+        });
+        let (status, json) = post_json(&app, "/streams/a/ingest", document).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(json["errors"][0]["code"], "payload_too_large");
+        assert_eq!(json["errors"][0]["source"]["pointer"], "/1/data");
+
+        let (status, _) = post_json(&app, "/streams/a/events", event_json("original")).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let mut deep = event_json("deep");
+        deep["data"] = serde_json::json!({"a": {"b": {"c": {"d": {"e": 1}}}}});
+        let (status, json) = post_json(&app, "/streams/a/events/0/correct", deep).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(json["errors"][0]["code"], "invalid_event");
+        assert_eq!(json["errors"][0]["source"]["pointer"], "/data");
+
+        let mut large = event_json("large");
+        large["data"] = serde_json::json!("x".repeat(100));
+        let (status, json) = post_json(&app, "/streams/a/events/0/correct", large).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(json["errors"][0]["code"], "payload_too_large");
+
+        let (status, json) = get_json(&app, "/streams/a").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["data"]["attributes"]["revision"], 1);
+    }
+
+    #[tokio::test]
+    async fn sealed_streams_are_read_only_across_restarts() {
+        let streams_dir = tempdir().unwrap();
+        let config = || Config { admin_users: vec!["test-user".to_string()], ..Default::default() };
+
+        {
+            let (app, _state) = test_app_with_config(streams_dir.path(), config()).await;
+            let (status, _) = post_json(&app, "/streams/a/events", event_json("1")).await;
+            assert_eq!(status, StatusCode::CREATED);
+
+            let patch = serde_json::json!({ "data": { "type": "stream", "attributes": { "sealed": true } } });
+            let request = Request::patch("/streams/a")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(patch.to_string()))
+                .unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+
+            // Patching other settings doesn't unseal the stream.
+            let patch = serde_json::json!({ "data": { "type": "stream", "attributes": { "index_subjects": true } } });
+            let request = Request::patch("/streams/a")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(patch.to_string()))
+                .unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let (app, _state) = test_app_with_config(streams_dir.path(), config()).await;
+
+        let (status, json) = post_json(&app, "/streams/a/events", event_json("2")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(json["errors"][0]["code"], "stream_sealed");
+
+        let (status, json) = get_json(&app, "/streams/a/events").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["data"].as_array().unwrap().len(), 1);
+
+        let request = Request::post("/admin/streams/test-user/a/unseal").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let (status, _) = post_json(&app, "/streams/a/events", event_json("2")).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn only_admins_can_unseal_streams() {
+        let streams_dir = tempdir().unwrap();
+        let (app, _state) = test_app(streams_dir.path()).await;
+        post_json(&app, "/streams/a/events", event_json("1")).await;
+
+        let request = Request::post("/admin/streams/test-user/a/unseal").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn admins_unseal_other_users_streams() {
+        let streams_dir = tempdir().unwrap();
+        let config = Config { admin_users: vec!["test-user".to_string()], ..Default::default() };
+        let (app, state) = test_app_with_config(streams_dir.path(), config).await;
+
+        let owner = "other-user".to_string();
+        let stream = "sealed".to_string();
+        state.insert_event_many(&owner, &stream, vec![test_event("a")], ExpectedRevision::Any).await.unwrap();
+        state.set_stream_metadata(&owner, &stream, StreamMetadata { sealed: true, ..Default::default() }).await.unwrap();
+        assert!(state.insert_event_many(&owner, &stream, vec![test_event("a")], ExpectedRevision::Any).await.is_err());
+
+        let request = Request::post("/admin/streams/other-user/sealed/unseal").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        state.insert_event_many(&owner, &stream, vec![test_event("a")], ExpectedRevision::Any).await.unwrap();
+
+        // The admin's own streams are untouched, so there's nothing of theirs to unseal.
+        let request = Request::post("/admin/streams/test-user/sealed/unseal").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn put_creates_a_stream_with_its_settings() {
+        let streams_dir = tempdir().unwrap();
+        let (app, _state) = test_app(streams_dir.path()).await;
+
+        let put = |uri: &str, attributes: Value| Request::put(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::json!({ "data": { "type": "stream", "attributes": attributes } }).to_string()))
+            .unwrap();
+
+        let attributes = serde_json::json!({ "index_subjects": true, "compacted": true, "min_dirty_ratio": 0.25 });
+        let response = app.clone().oneshot(put("/streams/a", attributes.clone())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert!(response.headers().contains_key(header::LOCATION));
+
+        let (status, json) = get_json(&app, "/streams/a").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["data"]["attributes"]["revision"], 0);
+        assert_eq!(json["data"]["attributes"]["index_subjects"], true);
+        assert_eq!(json["data"]["attributes"]["compacted"], true);
+        assert_eq!(json["data"]["attributes"]["min_dirty_ratio"], 0.25);
+
+        let response = app.clone().oneshot(put("/streams/a", serde_json::json!({}))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let (_, json) = get_json(&app, "/streams/a").await;
+        assert_eq!(json["data"]["attributes"]["compacted"], true);
+
+        let response = app.clone().oneshot(put("/streams/a?overwrite=true", serde_json::json!({}))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let (_, json) = get_json(&app, "/streams/a").await;
+        assert_eq!(json["data"]["attributes"]["compacted"], false);
+
+        let (status, _) = post_json(&app, "/streams/a/events", event_json("1")).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn cached_stream_reads_count_as_cache_hits() {
+        let streams_dir = tempdir().unwrap();
+        let config = Config { admin_users: vec!["test-user".to_string()], ..Default::default() };
+        let (app, _state) = test_app_with_config(streams_dir.path(), config).await;
+        post_json(&app, "/streams/a/events", event_json("1")).await;
+
+        // The first read fills the cache, and the second is served from it.
+        get_json(&app, "/streams/a?consistency=cached").await;
+        let (status, json) = get_json(&app, "/admin/caches").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["meta"]["stream_stats"], serde_json::json!({ "hits": 0, "misses": 1 }));
+
+        get_json(&app, "/streams/a?consistency=cached").await;
+        let (_, json) = get_json(&app, "/admin/caches").await;
+        assert_eq!(json["meta"]["stream_stats"], serde_json::json!({ "hits": 1, "misses": 1 }));
+        assert_eq!(json["meta"]["jwks"], serde_json::json!({ "hits": 0, "misses": 0 }));
+    }
+
+    #[tokio::test]
+    async fn writes_need_the_write_scope() {
+        let streams_dir = tempdir().unwrap();
+        let config = Config { write_scope: Some("events:write".to_string()), ..Default::default() };
+        let state = Arc::new(AppState::new(streams_dir.path().to_path_buf(), config).await.unwrap());
+        let app = authenticated(routes(&state.config()), state.clone(), Arc::new(crate::openid::tests::test_client()))
+            .with_state(state);
+
+        let read_only = crate::openid::tests::token_with_scope("alice", "events:read");
+        let request = Request::post("/streams/a/events")
+            .header(header::AUTHORIZATION, format!("Bearer {}", read_only))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(event_json("1").to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(response.headers()[header::WWW_AUTHENTICATE].to_str().unwrap().contains("insufficient_scope"));
+        let bytes = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(json["errors"][0]["code"], "insufficient_scope");
+
+        let request = Request::get("/streams")
+            .header(header::AUTHORIZATION, format!("Bearer {}", read_only))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let read_write = crate::openid::tests::token_with_scope("alice", "events:read events:write");
+        let request = Request::post("/streams/a/events")
+            .header(header::AUTHORIZATION, format!("Bearer {}", read_write))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(event_json("1").to_string()))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn exports_exclude_events_appended_while_they_are_read() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+
+        let user_id = "test-user".to_string();
+        let stream_id = "exported".to_string();
+        let events: Vec<Event> = (0..5).map(|_| test_event("com.example.a")).collect();
+        state.insert_event_many(&user_id, &stream_id, events.clone(), ExpectedRevision::Any).await.unwrap();
+
+        let request = Request::get("/streams/exported/export").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let appender = {
+            let state = state.clone();
+            tokio::spawn(async move {
+                for _ in 0..20 {
+                    state.insert_event(&user_id, &stream_id, test_event("com.example.b"), ExpectedRevision::Any).await.unwrap();
+                }
+            })
+        };
+
+        let bytes = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        appender.await.unwrap();
+
+        let exported: Vec<Event> = String::from_utf8(bytes.to_vec()).unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(exported, events);
+    }
+
+    #[tokio::test]
+    async fn unresponsive_identity_providers_are_unavailable() {
+        let streams_dir = tempdir().unwrap();
+        let state = Arc::new(AppState::new(streams_dir.path().to_path_buf(), Config::default()).await.unwrap());
+        let (_listener, url) = crate::openid::tests::unresponsive_url().await;
+        let oidc_client = crate::openid::tests::test_client()
+            .with_introspection(Some(crate::openid::TokenIntrospection {
+                url,
+                client_id: "hematite".to_string(),
+                client_secret: "secret".to_string(),
+            }))
+            .with_timeout(Duration::from_millis(100))
+            .unwrap();
+        let app = authenticated(routes(&state.config()), state.clone(), Arc::new(oidc_client))
+            .with_state(state);
+
+        let request = Request::get("/streams")
+            .header(header::AUTHORIZATION, "Bearer opaque-token")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let bytes = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(json["errors"][0]["code"], "auth_unavailable");
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_streams_are_closed_and_reopened() {
+        let streams_dir = tempdir().unwrap();
+        let config = Config { max_open_streams: Some(2), ..Default::default() };
+        let (app, state) = test_app_with_config(streams_dir.path(), config).await;
+
+        for stream in ["a", "b", "c"] {
+            let (status, _) = post_json(&app, &format!("/streams/{}/events", stream), event_json(stream)).await;
+            assert_eq!(status, StatusCode::CREATED);
+        }
+
+        let run_state = |stream: &str| {
+            let db = state.streams.get(&("test-user".to_string(), stream.to_string())).unwrap().clone();
+            async move { db.lock().await.run_state() }
+        };
+        assert_eq!(run_state("a").await, RunState::Stopped);
+        assert_eq!(run_state("b").await, RunState::Running);
+        assert_eq!(run_state("c").await, RunState::Running);
+
+        let (status, json) = get_json(&app, "/streams/a/events/0").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["id"], "a");
+        assert_eq!(run_state("a").await, RunState::Running);
+        assert_eq!(run_state("b").await, RunState::Stopped);
+
+        // A stream in use by another request isn't closed under it.
+        let a = state.streams.get(&("test-user".to_string(), "a".to_string())).unwrap().clone();
+        let a_guard = a.lock().await;
+        get_json(&app, "/streams/b/events/0").await;
+        get_json(&app, "/streams/c/events/0").await;
+        assert_eq!(a_guard.run_state(), RunState::Running);
+    }
+
+    #[tokio::test]
+    async fn listing_streams_does_not_reopen_closed_ones() {
+        let streams_dir = tempdir().unwrap();
+        let config = Config { max_open_streams: Some(2), ..Default::default() };
+        let (app, state) = test_app_with_config(streams_dir.path(), config).await;
+
+        for stream in ["a", "b", "c", "d"] {
+            for i in 0..2 {
+                let (status, _) = post_json(&app, &format!("/streams/{}/events", stream), event_json(&format!("{}{}", stream, i))).await;
+                assert_eq!(status, StatusCode::CREATED);
+            }
+        }
+
+        let run_state = |stream: &str| {
+            let db = state.streams.get(&("test-user".to_string(), stream.to_string())).unwrap().clone();
+            async move { db.lock().await.run_state() }
+        };
+        assert_eq!(run_state("a").await, RunState::Stopped);
+        assert_eq!(run_state("b").await, RunState::Stopped);
+
+        // Streams that are still open cache their stats the first time they're listed.
+        let (status, _) = get_json(&app, "/streams").await;
+        assert_eq!(status, StatusCode::OK);
+        let misses = state.cache_metrics.counts().stream_stats.misses;
+
+        let (status, body) = get_json(&app, "/streams").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(state.cache_metrics.counts().stream_stats.misses, misses);
+        assert_eq!(run_state("a").await, RunState::Stopped);
+        assert_eq!(run_state("b").await, RunState::Stopped);
+
+        let listed = body["data"].as_array().unwrap();
+        assert_eq!(listed.len(), 4);
+        for stream in listed {
+            let stream_id = stream["id"].as_str().unwrap().to_string();
+            let fresh = state.get_stream(&"test-user".to_string(), &stream_id, Consistency::Strong).await.unwrap();
+            assert_eq!(stream["attributes"]["revision"], fresh.revision);
+            assert_eq!(stream["attributes"]["count"], fresh.count);
+            assert_eq!(stream["attributes"]["usage"], fresh.usage);
+            assert_eq!(stream["attributes"]["last_modified"], fresh.last_modified);
+        }
+    }
+
+    #[tokio::test]
+    async fn stopped_streams_keep_their_writes_when_reopened() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+
+        let (status, _) = post_json(&app, "/streams/a/events", event_json("1")).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, _) = post_json(&app, "/streams/b/events", event_json("2")).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        assert_eq!(state.stop_streams().await, 2);
+        drop(app);
+        drop(state);
+
+        let (app, _state) = test_app(streams_dir.path()).await;
+        let (status, json) = get_json(&app, "/streams/a/events/0").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["id"], "1");
+        let (status, json) = get_json(&app, "/streams/b/events/0").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["id"], "2");
+    }
+
+    #[tokio::test]
+    async fn users_cannot_create_streams_past_their_quota() {
+        let streams_dir = tempdir().unwrap();
+        let config = Config { max_streams_per_user: Some(2), ..Default::default() };
+        let (app, _state) = test_app_with_config(streams_dir.path(), config).await;
+
+        let (status, _) = post_json(&app, "/streams/a/events", event_json("1")).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, _) = post_json(&app, "/streams/b/events", event_json("2")).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let (status, json) = post_json(&app, "/streams/c/events", event_json("3")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(json["errors"][0]["code"], "quota_exceeded");
+        let (status, _) = get_json(&app, "/streams/c").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let request = Request::put("/streams/c")
+            .header(header::CONTENT_TYPE, "application/vnd.api+json")
+            .body(Body::from(serde_json::json!({ "data": { "type": "stream", "attributes": {} } }).to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let (status, _) = post_json(&app, "/streams/a/events", event_json("4")).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn concurrent_creates_cannot_overshoot_the_stream_quota() {
+        let streams_dir = tempdir().unwrap();
+        let config = Config { max_streams_per_user: Some(3), ..Default::default() };
+        let (_app, state) = test_app_with_config(streams_dir.path(), config).await;
+        let user_id = "test-user".to_string();
+
+        let creates = (0..10).map(|i| {
+            let state = state.clone();
+            let user_id = user_id.clone();
+            async move {
+                if i % 2 == 0 {
+                    state.insert_event_many(&user_id, &format!("stream-{}", i), vec![test_event("a")], ExpectedRevision::Any).await.map(|_| ())
+                } else {
+                    state.create_stream(&user_id, &format!("stream-{}", i), StreamMetadata::default()).await
+                }
+            }
+        });
+        let results = future::join_all(creates).await;
+
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 3);
+        assert!(results.iter().filter_map(|result| result.as_ref().err()).all(|err| err.is::<QuotaExceeded>()));
+        assert_eq!(state.streams(&user_id).await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn appends_are_refused_past_the_byte_quota_until_space_is_freed() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+        let user_id = "test-user".to_string();
+
+        let (status, _) = post_json(&app, "/streams/a/events", event_json("1")).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let event_bytes = state.user_usage(&user_id).await.unwrap();
+        let (status, _) = post_json(&app, "/streams/b/events", event_json("2")).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(state.user_usage(&user_id).await.unwrap(), 2 * event_bytes);
+
+        state.reload_config(Config { max_bytes_per_user: Some(2 * event_bytes + event_bytes / 2), ..Default::default() });
+
+        let (status, json) = post_json(&app, "/streams/a/events", event_json("3")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(json["errors"][0]["code"], "quota_exceeded");
+
+        let request = Request::delete("/streams/b").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let (status, _) = post_json(&app, "/streams/a/events", event_json("3")).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(state.user_usage(&user_id).await.unwrap(), 2 * event_bytes);
+    }
+
+    #[tokio::test]
+    async fn concurrent_appends_cannot_overshoot_the_byte_quota() {
+        let streams_dir = tempdir().unwrap();
+        let (_app, state) = test_app(streams_dir.path()).await;
+        let user_id = "test-user".to_string();
+
+        state.insert_event_many(&user_id, &"first".to_string(), vec![test_event("a")], ExpectedRevision::Any).await.unwrap();
+        let event_bytes = state.user_usage(&user_id).await.unwrap();
+        state.reload_config(Config { max_bytes_per_user: Some(4 * event_bytes + event_bytes / 2), ..Default::default() });
+
+        let appends = (0..10).map(|i| {
+            let state = state.clone();
+            let user_id = user_id.clone();
+            async move { state.insert_event_many(&user_id, &format!("stream-{}", i), vec![test_event("a")], ExpectedRevision::Any).await }
+        });
+        let results = future::join_all(appends).await;
+
+        let appended = results.iter().filter(|result| result.is_ok()).count();
+        assert_eq!(appended, 3);
+        assert!(results.iter().filter_map(|result| result.as_ref().err()).all(|err| err.is::<QuotaExceeded>()));
+        assert_eq!(state.user_usage(&user_id).await.unwrap(), 4 * event_bytes);
+    }
+
+    #[tokio::test]
+    async fn snapshots_count_against_the_byte_quota() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+        let user_id = "test-user".to_string();
+
+        let (status, _) = post_json(&app, "/streams/a/events", event_json("1")).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let event_bytes = state.user_usage(&user_id).await.unwrap();
+
+        let request = Request::put("/streams/a/snapshot?revision=1").body(Body::from("state")).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(state.user_usage(&user_id).await.unwrap(), event_bytes + 8 + 5);
+
+        state.reload_config(Config { max_bytes_per_user: Some(2 * event_bytes), ..Default::default() });
+
+        let request = Request::put("/streams/a/snapshot?revision=1").body(Body::from("x".repeat(event_bytes as usize))).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let bytes = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(json["errors"][0]["code"], "quota_exceeded");
+        assert_eq!(state.user_usage(&user_id).await.unwrap(), event_bytes + 8 + 5);
+    }
+
+    #[tokio::test]
+    async fn stream_listings_are_served_from_cached_stats() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+        let user_id = "test-user".to_string();
+
+        for i in 0..20 {
+            let events = (0..=i % 4).map(|_| test_event("a")).collect();
+            state.insert_event_many(&user_id, &format!("stream-{:02}", i), events, ExpectedRevision::Any).await.unwrap();
+        }
+
+        let (status, _) = get_json(&app, "/streams").await;
+        assert_eq!(status, StatusCode::OK);
+        let misses = state.cache_metrics.counts().stream_stats.misses;
+
+        let (status, body) = get_json(&app, "/streams").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(state.cache_metrics.counts().stream_stats.misses, misses);
+
+        let listed = body["data"].as_array().unwrap();
+        assert_eq!(listed.len(), 20);
+        for stream in listed {
+            let stream_id = stream["id"].as_str().unwrap().to_string();
+            let fresh = state.get_stream(&user_id, &stream_id, Consistency::Strong).await.unwrap();
+            assert_eq!(stream["attributes"]["revision"], fresh.revision);
+            assert_eq!(stream["attributes"]["count"], fresh.count);
+            assert_eq!(stream["attributes"]["usage"], fresh.usage);
+        }
+
+        let (status, _) = post_json(&app, "/streams/stream-00/events", event_json("appended")).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let (status, body) = get_json(&app, "/streams").await;
+        assert_eq!(status, StatusCode::OK);
+        let appended = body["data"].as_array().unwrap().iter()
+            .find(|stream| stream["id"] == "stream-00")
+            .unwrap();
+        let fresh = state.get_stream(&user_id, &"stream-00".to_string(), Consistency::Strong).await.unwrap();
+        assert_eq!(appended["attributes"]["revision"], 2);
+        assert_eq!(appended["attributes"]["count"], 2);
+        assert_eq!(appended["attributes"]["usage"], fresh.usage);
+    }
+
+    #[tokio::test]
+    async fn shutting_down_ends_subscriptions_and_long_polls() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+        let user_id = "test-user".to_string();
+        let stream_id = "watched".to_string();
+
+        state.insert_event_many(&user_id, &stream_id, vec![test_event("a")], ExpectedRevision::Any).await.unwrap();
+
+        let subscription = state.subscribe(&user_id, &stream_id, None).await.unwrap();
+        let long_poll = tokio::spawn({
+            let app = app.clone();
+            async move { get_json(&app, "/streams/watched/events?page[offset]=1&wait=60s").await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        state.shut_down();
+
+        let items: Vec<_> = tokio::time::timeout(Duration::from_secs(5), subscription.collect::<Vec<_>>()).await
+            .expect("Expected the subscription to end on shutdown");
+        assert!(items.is_empty());
+
+        let (status, body) = tokio::time::timeout(Duration::from_secs(5), long_poll).await
+            .expect("Expected the long poll to end on shutdown")
+            .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["data"].as_array().unwrap().is_empty());
+
+        let late = state.subscribe(&user_id, &stream_id, Some(0)).await.unwrap();
+        let items: Vec<_> = tokio::time::timeout(Duration::from_secs(5), late.collect::<Vec<_>>()).await
+            .expect("Expected subscriptions started during shutdown to end");
+        assert!(items.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sse_subscribers_are_told_to_reconnect_on_shutdown() {
+        let streams_dir = tempdir().unwrap();
+        let (app, state) = test_app(streams_dir.path()).await;
+
+        state.insert_event_many(&"test-user".to_string(), &"live".to_string(), vec![test_event("a")], ExpectedRevision::Any).await.unwrap();
+
+        let request = Request::get("/streams/live/events/sse").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        state.shut_down();
+
+        let body = tokio::time::timeout(Duration::from_secs(5), body::to_bytes(response.into_body(), usize::MAX)).await
+            .expect("Expected the event stream to close on shutdown")
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("event: shutdown\ndata: server shutting down, reconnect\n"));
+    }
+}
@@ -0,0 +1,721 @@
+use std::{collections::{BTreeMap, HashMap}, env, fs, str::FromStr, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use axum::http::HeaderValue;
+
+use cloudevents::event::SpecVersion;
+use uuid::Uuid;
+
+use crate::{db::StorageFormat, enrichment::{Enricher, ExtensionPolicy}, openid::TokenIntrospection, rate_limit::WriteRateLimit, validation::EventIdFormat};
+
+/// Server settings read from `HEMATITE_*` environment variables, and from the file named by
+/// `HEMATITE_CONFIG_FILE` if it is set.
+///
+/// Sending the server `SIGHUP` re-reads both and applies the hot-reloadable settings:
+/// `event_id_format`, `spec_versions`, `max_clock_skew`, `max_event_age`, `enrichers`,
+/// `extension_policy`, `max_batch_errors`, `max_lease_ttl`, `reservation_ttl`,
+/// `max_reservation_count`, `default_page_limit`,
+/// `ignored_stream_entries`, `ingest_allowed_hosts`, `ingest_timeout`, `compaction_interval`,
+/// `compaction_idle`,
+/// `admin_users`, `max_data_depth`, `max_data_bytes`, `read_scope`, `write_scope`,
+/// `corrected_event_max_age`, `max_streams_per_user`, and `max_bytes_per_user`. The others take
+/// effect on restart.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Format every posted event's `id` must follow. Unconstrained when `None`.
+    pub event_id_format: Option<EventIdFormat>,
+    /// CloudEvents `specversion` values accepted from clients. Only 1.0 by default, so a
+    /// stream can't end up mixing versions unless the operator opts in.
+    pub spec_versions: Vec<SpecVersion>,
+    /// How far ahead of the server's clock a posted event's `time` may be. Unchecked when
+    /// `None`.
+    pub max_clock_skew: Option<Duration>,
+    /// How far behind the server's clock a posted event's `time` may be. Unchecked when
+    /// `None`, since backfilled events are legitimately old.
+    pub max_event_age: Option<Duration>,
+    /// Enrichers applied in order to every posted event before it's validated.
+    pub enrichers: Vec<Enricher>,
+    /// Whether extension attributes the server doesn't know about are stored with posted events.
+    pub extension_policy: ExtensionPolicy,
+    /// Most errors listed in the response to a batch with invalid events. The response's
+    /// `meta.total_errors` still counts all of them.
+    pub max_batch_errors: usize,
+    /// `Content-Security-Policy` header sent with each response.
+    pub content_security_policy: ContentSecurityPolicy,
+    /// Largest request body accepted, in bytes. Larger bodies are rejected with 413.
+    pub max_body_bytes: usize,
+    /// Largest single event accepted, in bytes of JSON. Events are unlimited in size, up to
+    /// `max_body_bytes`, when `None`.
+    pub max_event_bytes: Option<usize>,
+    /// How deeply arrays and objects may be nested in a posted event's `data`. Unchecked when
+    /// `None`.
+    pub max_data_depth: Option<usize>,
+    /// Largest `data` accepted in a posted event, in bytes of JSON. Unchecked when `None`.
+    pub max_data_bytes: Option<usize>,
+    /// Size in bytes at which a stream's events file is sealed and a new segment started.
+    /// Streams stay in a single file when `None`.
+    pub segment_bytes: Option<u64>,
+    /// Number of events per gzip block in sealed segments. Segments are stored uncompressed
+    /// when `None`.
+    pub compression_block_events: Option<usize>,
+    /// Format new streams' events are written in. Existing streams keep their own.
+    pub storage_format: StorageFormat,
+    /// Entry names in the streams directory that are never treated as users or streams.
+    /// Dotfiles are always skipped.
+    pub ignored_stream_entries: Vec<String>,
+    /// Longest time a write lease can be granted or renewed for, and the default when a client
+    /// doesn't ask for a shorter one.
+    pub max_lease_ttl: Duration,
+    /// How long reserved rownums wait to be filled before their empty slots get tombstones.
+    pub reservation_ttl: Duration,
+    /// Most rownums one reservation may hold.
+    pub max_reservation_count: usize,
+    /// Number of events returned per page when a client doesn't give `page[limit]`.
+    pub default_page_limit: usize,
+    /// Hosts, or `host:port` pairs, the server may fetch events from when a client asks it to
+    /// ingest a URL. Ingestion is refused for every URL when empty.
+    pub ingest_allowed_hosts: Vec<String>,
+    /// How long ingesting a URL may take, from connecting to reading the last event, before
+    /// it's abandoned.
+    pub ingest_timeout: Duration,
+    /// Time between checks for streams marked `compacted` that are due for compaction.
+    pub compaction_interval: Duration,
+    /// How long a compacted stream must go without writes before it's compacted, so
+    /// compaction doesn't hold up busy streams.
+    pub compaction_idle: Duration,
+    /// Most background jobs, like compactions, run at once.
+    pub max_background_jobs: usize,
+    /// IDs of the users allowed to use the `/admin` routes.
+    pub admin_users: Vec<String>,
+    /// How request IDs are generated for requests that don't bring a valid `X-Request-Id`.
+    pub request_id_format: RequestIdFormat,
+    /// Origins browsers may call the API from, or `*` for any origin. No CORS headers are sent
+    /// when empty.
+    pub cors_allowed_origins: Vec<String>,
+    /// How fast each user may post events. Unlimited when `None`.
+    pub write_rate_limit: Option<WriteRateLimit>,
+    /// How long OpenID providers' discovery documents and keys are cached before they're
+    /// refetched.
+    pub jwks_cache_ttl: Duration,
+    /// Whether to refetch OpenID providers' discovery documents and keys in the background each
+    /// `jwks_cache_ttl`, rather than only when a request finds them expired.
+    pub oidc_background_refresh: bool,
+    /// Most stream databases kept open with their indexes in memory. The least recently used
+    /// are closed past this, and reopened when they're next used. Unlimited when `None`.
+    pub max_open_streams: Option<usize>,
+    /// Most streams each user may have. Creating another fails, but existing streams can still
+    /// be written to. Unlimited when `None`.
+    pub max_streams_per_user: Option<usize>,
+    /// Most bytes each user's streams may take up on disk together. Appends that would go past
+    /// this are refused. Unlimited when `None`.
+    pub max_bytes_per_user: Option<u64>,
+    /// How long a request to an OpenID provider or introspection endpoint, including
+    /// connecting, may take before it's abandoned.
+    pub oidc_timeout: Duration,
+    /// How long connections get to close after subscribers are told the server is shutting
+    /// down, before they're closed anyway.
+    pub shutdown_grace_period: Duration,
+    /// How far past its `exp`, or before its `nbf`, a token is still accepted.
+    pub jwt_leeway: Duration,
+    /// Audiences a token is accepted for. Its `aud` has to name at least one of them.
+    pub jwt_audiences: Vec<String>,
+    /// Issuers whose tokens are accepted besides the ones named in the OpenID providers'
+    /// discovery documents, such as a tenant-specific issuer of a multi-tenant provider. Their
+    /// tokens are checked with the keys of whichever provider published the signing key.
+    pub jwt_trusted_issuers: Vec<String>,
+    /// Endpoint to check opaque access tokens with, instead of validating tokens as JWTs.
+    pub token_introspection: Option<TokenIntrospection>,
+    /// Token claim identifying the user, whose streams are stored under it. `sub` when `None`.
+    /// Namespaced by the token's issuer when more than one issuer is accepted.
+    pub user_claim: Option<String>,
+    /// Scope a token must grant to read with `GET` or `HEAD`. Any valid token can read when
+    /// `None`.
+    pub read_scope: Option<String>,
+    /// Scope a token must grant to make any other request. Any valid token can write when
+    /// `None`.
+    pub write_scope: Option<String>,
+    /// How long caches may keep an event read with its corrections applied, which changes when
+    /// the event is corrected again. Caches revalidate it on every read when zero.
+    pub corrected_event_max_age: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            event_id_format: None,
+            spec_versions: vec![SpecVersion::V10],
+            max_clock_skew: None,
+            max_event_age: None,
+            enrichers: vec![],
+            extension_policy: ExtensionPolicy::default(),
+            max_batch_errors: 100,
+            content_security_policy: ContentSecurityPolicy::default(),
+            max_body_bytes: 2 * 1024 * 1024,
+            max_event_bytes: None,
+            max_data_depth: None,
+            max_data_bytes: None,
+            segment_bytes: None,
+            compression_block_events: None,
+            storage_format: StorageFormat::default(),
+            ignored_stream_entries: vec!["lost+found".to_string()],
+            max_lease_ttl: Duration::from_secs(60),
+            reservation_ttl: Duration::from_secs(60),
+            max_reservation_count: 1000,
+            default_page_limit: 50,
+            ingest_allowed_hosts: vec![],
+            ingest_timeout: Duration::from_secs(300),
+            compaction_interval: Duration::from_secs(300),
+            compaction_idle: Duration::from_secs(60),
+            max_background_jobs: 2,
+            admin_users: vec![],
+            request_id_format: RequestIdFormat::default(),
+            cors_allowed_origins: vec![],
+            write_rate_limit: None,
+            jwks_cache_ttl: Duration::from_secs(3600),
+            oidc_background_refresh: false,
+            max_open_streams: None,
+            max_streams_per_user: None,
+            max_bytes_per_user: None,
+            oidc_timeout: Duration::from_secs(10),
+            shutdown_grace_period: Duration::from_secs(10),
+            jwt_leeway: Duration::from_secs(60),
+            jwt_audiences: vec![],
+            jwt_trusted_issuers: vec![],
+            token_introspection: None,
+            user_claim: None,
+            read_scope: None,
+            write_scope: None,
+            corrected_event_max_age: Duration::ZERO,
+        }
+    }
+}
+
+/// A default CSP, plus overrides for routes that need something looser, like a docs UI.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ContentSecurityPolicy {
+    pub default: String,
+    /// Policies keyed by path prefix. A prefix matches whole path segments, so `/openapi`
+    /// covers `/openapi` and `/openapi/ui` but not `/openapix`. The longest matching prefix wins.
+    pub overrides: BTreeMap<String, String>,
+}
+
+impl Default for ContentSecurityPolicy {
+    fn default() -> Self {
+        Self {
+            default: "frame-ancestors 'none'".to_string(),
+            overrides: BTreeMap::new(),
+        }
+    }
+}
+
+impl ContentSecurityPolicy {
+    pub fn for_path(&self, path: &str) -> &str {
+        self.overrides.iter()
+            .filter(|(prefix, _)| match path.strip_prefix(prefix.as_str()) {
+                Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'),
+                None => false,
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, policy)| policy.as_str())
+            .unwrap_or(&self.default)
+    }
+}
+
+/// A format for the request IDs the server generates.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RequestIdFormat {
+    /// A time-ordered UUID, like the IDs of error objects.
+    #[default]
+    UuidV7,
+    /// A random UUID.
+    UuidV4,
+    /// 32 lowercase hex digits, the shape of a W3C trace ID.
+    Hex,
+}
+
+impl RequestIdFormat {
+    pub fn generate(&self) -> String {
+        match self {
+            RequestIdFormat::UuidV7 => Uuid::now_v7().to_string(),
+            RequestIdFormat::UuidV4 => Uuid::new_v4().to_string(),
+            RequestIdFormat::Hex => Uuid::new_v4().simple().to_string(),
+        }
+    }
+}
+
+impl FromStr for RequestIdFormat {
+    type Err = anyhow::Error;
+
+    /// Parses `uuidv7`, `uuidv4`, or `hex`.
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "uuidv7" => Ok(RequestIdFormat::UuidV7),
+            "uuidv4" => Ok(RequestIdFormat::UuidV4),
+            "hex" => Ok(RequestIdFormat::Hex),
+            other => bail!("Expected uuidv7, uuidv4, or hex but got {:?}", other),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self> {
+        let mut vars: HashMap<String, String> = env::vars()
+            .filter(|(name, _)| name.starts_with("HEMATITE_"))
+            .collect();
+
+        if let Some(config_path) = vars.get("HEMATITE_CONFIG_FILE").cloned() {
+            let config_file = fs::read_to_string(&config_path)
+                .with_context(|| format!("Failed to read HEMATITE_CONFIG_FILE at {:?}", config_path))?;
+
+            vars.extend(parse_config_file(&config_file)
+                .with_context(|| format!("Failed to parse HEMATITE_CONFIG_FILE at {:?}", config_path))?);
+        }
+
+        Self::from_vars(&vars)
+    }
+
+    /// Builds a config from `HEMATITE_*` variables, falling back to the defaults for any missing.
+    pub fn from_vars(vars: &HashMap<String, String>) -> Result<Self> {
+        let event_id_format =
+            vars.get("HEMATITE_EVENT_ID_FORMAT")
+            .map(|format| format.parse())
+            .transpose()
+            .context("Failed to parse HEMATITE_EVENT_ID_FORMAT")?;
+
+        let mut config = Self {
+            event_id_format,
+            ..Default::default()
+        };
+
+        if let Some(default) = vars.get("HEMATITE_CSP") {
+            HeaderValue::from_str(default)
+                .context("Failed to parse HEMATITE_CSP as a header value")?;
+            config.content_security_policy.default = default.clone();
+        }
+
+        if let Some(overrides) = vars.get("HEMATITE_CSP_OVERRIDES") {
+            config.content_security_policy.overrides = serde_json::from_str(overrides)
+                .context("Failed to parse HEMATITE_CSP_OVERRIDES as a JSON object of path prefixes to policies")?;
+
+            for (prefix, policy) in &config.content_security_policy.overrides {
+                HeaderValue::from_str(policy)
+                    .with_context(|| format!("Failed to parse the HEMATITE_CSP_OVERRIDES policy for {:?} as a header value", prefix))?;
+            }
+        }
+
+        if let Some(max_body_bytes) = vars.get("HEMATITE_MAX_BODY_BYTES") {
+            config.max_body_bytes = max_body_bytes.parse()
+                .context("Failed to parse HEMATITE_MAX_BODY_BYTES as a number of bytes")?;
+        }
+
+        if let Some(enrichers) = vars.get("HEMATITE_ENRICHERS") {
+            config.enrichers = enrichers.split(',')
+                .map(|enricher| enricher.trim().parse())
+                .collect::<Result<_>>()
+                .context("Failed to parse HEMATITE_ENRICHERS as a comma-separated list of enrichers")?;
+        }
+
+        if let Some(extension_policy) = vars.get("HEMATITE_EXTENSION_POLICY") {
+            config.extension_policy = match extension_policy.trim() {
+                "preserve" => ExtensionPolicy::Preserve,
+                "strip_unknown" => {
+                    let registered = vars.get("HEMATITE_REGISTERED_EXTENSIONS").map_or_else(Vec::new, |registered| {
+                        registered.split(',')
+                            .map(|name| name.trim().to_string())
+                            .filter(|name| !name.is_empty())
+                            .collect()
+                    });
+
+                    ExtensionPolicy::StripUnknown(registered)
+                },
+                other => bail!("Failed to parse HEMATITE_EXTENSION_POLICY: expected preserve or strip_unknown but got {:?}", other),
+            };
+        }
+
+        if let Some(max_batch_errors) = vars.get("HEMATITE_MAX_BATCH_ERRORS") {
+            config.max_batch_errors = max_batch_errors.parse()
+                .context("Failed to parse HEMATITE_MAX_BATCH_ERRORS as a number of errors")?;
+        }
+
+        config.max_event_bytes =
+            vars.get("HEMATITE_MAX_EVENT_BYTES")
+            .map(|max_event_bytes| max_event_bytes.parse())
+            .transpose()
+            .context("Failed to parse HEMATITE_MAX_EVENT_BYTES as a number of bytes")?;
+
+        config.max_data_depth =
+            vars.get("HEMATITE_MAX_DATA_DEPTH")
+            .map(|max_data_depth| max_data_depth.parse())
+            .transpose()
+            .context("Failed to parse HEMATITE_MAX_DATA_DEPTH as a number of levels")?;
+
+        config.max_data_bytes =
+            vars.get("HEMATITE_MAX_DATA_BYTES")
+            .map(|max_data_bytes| max_data_bytes.parse())
+            .transpose()
+            .context("Failed to parse HEMATITE_MAX_DATA_BYTES as a number of bytes")?;
+
+        if let Some(spec_versions) = vars.get("HEMATITE_SPEC_VERSIONS") {
+            config.spec_versions = spec_versions.split(',')
+                .map(|version| SpecVersion::try_from(version.trim()))
+                .collect::<Result<_, _>>()
+                .context("Failed to parse HEMATITE_SPEC_VERSIONS as a comma-separated list of CloudEvents spec versions")?;
+        }
+
+        if let Some(max_clock_skew_seconds) = vars.get("HEMATITE_MAX_CLOCK_SKEW_SECONDS") {
+            let max_clock_skew_seconds = max_clock_skew_seconds.parse()
+                .context("Failed to parse HEMATITE_MAX_CLOCK_SKEW_SECONDS as a number of seconds")?;
+            config.max_clock_skew = Some(Duration::from_secs(max_clock_skew_seconds));
+        }
+
+        if let Some(max_event_age_seconds) = vars.get("HEMATITE_MAX_EVENT_AGE_SECONDS") {
+            let max_event_age_seconds = max_event_age_seconds.parse()
+                .context("Failed to parse HEMATITE_MAX_EVENT_AGE_SECONDS as a number of seconds")?;
+            config.max_event_age = Some(Duration::from_secs(max_event_age_seconds));
+        }
+
+        config.segment_bytes =
+            vars.get("HEMATITE_SEGMENT_BYTES")
+            .map(|segment_bytes| segment_bytes.parse())
+            .transpose()
+            .context("Failed to parse HEMATITE_SEGMENT_BYTES as a number of bytes")?;
+
+        config.compression_block_events =
+            vars.get("HEMATITE_COMPRESSION_BLOCK_EVENTS")
+            .map(|block_events| block_events.parse())
+            .transpose()
+            .context("Failed to parse HEMATITE_COMPRESSION_BLOCK_EVENTS as a number of events")?;
+
+        if let Some(storage_format) = vars.get("HEMATITE_STORAGE_FORMAT") {
+            config.storage_format = storage_format.parse()
+                .context("Failed to parse HEMATITE_STORAGE_FORMAT")?;
+        }
+
+        if let Some(ignored_stream_entries) = vars.get("HEMATITE_IGNORED_STREAM_ENTRIES") {
+            config.ignored_stream_entries = ignored_stream_entries.split(',')
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .collect();
+        }
+
+        if let Some(max_lease_seconds) = vars.get("HEMATITE_MAX_LEASE_SECONDS") {
+            let max_lease_seconds = max_lease_seconds.parse()
+                .context("Failed to parse HEMATITE_MAX_LEASE_SECONDS as a number of seconds")?;
+            config.max_lease_ttl = Duration::from_secs(max_lease_seconds);
+        }
+
+        if let Some(reservation_seconds) = vars.get("HEMATITE_RESERVATION_SECONDS") {
+            let reservation_seconds = reservation_seconds.parse()
+                .context("Failed to parse HEMATITE_RESERVATION_SECONDS as a number of seconds")?;
+            config.reservation_ttl = Duration::from_secs(reservation_seconds);
+        }
+
+        if let Some(max_reservation_count) = vars.get("HEMATITE_MAX_RESERVATION_COUNT") {
+            let max_reservation_count = max_reservation_count.parse()
+                .context("Failed to parse HEMATITE_MAX_RESERVATION_COUNT as a number of rownums")?;
+            if max_reservation_count == 0 {
+                bail!("HEMATITE_MAX_RESERVATION_COUNT must be at least 1");
+            }
+            config.max_reservation_count = max_reservation_count;
+        }
+
+        if let Some(default_page_limit) = vars.get("HEMATITE_DEFAULT_PAGE_LIMIT") {
+            config.default_page_limit = default_page_limit.parse()
+                .context("Failed to parse HEMATITE_DEFAULT_PAGE_LIMIT as a number of events")?;
+        }
+
+        if let Some(ingest_allowed_hosts) = vars.get("HEMATITE_INGEST_ALLOWED_HOSTS") {
+            config.ingest_allowed_hosts = ingest_allowed_hosts.split(',')
+                .map(|host| host.trim().to_string())
+                .filter(|host| !host.is_empty())
+                .collect();
+        }
+
+        if let Some(ingest_timeout_seconds) = vars.get("HEMATITE_INGEST_TIMEOUT_SECS") {
+            let ingest_timeout_seconds = ingest_timeout_seconds.parse()
+                .context("Failed to parse HEMATITE_INGEST_TIMEOUT_SECS as a number of seconds")?;
+            config.ingest_timeout = Duration::from_secs(ingest_timeout_seconds);
+        }
+
+        if let Some(compaction_interval_seconds) = vars.get("HEMATITE_COMPACTION_INTERVAL_SECONDS") {
+            let compaction_interval_seconds = compaction_interval_seconds.parse()
+                .context("Failed to parse HEMATITE_COMPACTION_INTERVAL_SECONDS as a number of seconds")?;
+            config.compaction_interval = Duration::from_secs(compaction_interval_seconds);
+        }
+
+        if let Some(compaction_idle_seconds) = vars.get("HEMATITE_COMPACTION_IDLE_SECONDS") {
+            let compaction_idle_seconds = compaction_idle_seconds.parse()
+                .context("Failed to parse HEMATITE_COMPACTION_IDLE_SECONDS as a number of seconds")?;
+            config.compaction_idle = Duration::from_secs(compaction_idle_seconds);
+        }
+
+        if let Some(max_background_jobs) = vars.get("HEMATITE_MAX_BACKGROUND_JOBS") {
+            config.max_background_jobs = max_background_jobs.parse()
+                .context("Failed to parse HEMATITE_MAX_BACKGROUND_JOBS as a number of jobs")?;
+        }
+
+        if let Some(per_second) = vars.get("HEMATITE_WRITE_RATE_PER_SECOND") {
+            let per_second: f64 = per_second.parse()
+                .context("Failed to parse HEMATITE_WRITE_RATE_PER_SECOND as a number of requests")?;
+            if !(per_second > 0.0 && per_second.is_finite()) {
+                bail!("HEMATITE_WRITE_RATE_PER_SECOND must be a positive number, but got {}", per_second);
+            }
+
+            // Allow a second's worth of requests at once unless told otherwise.
+            let burst = match vars.get("HEMATITE_WRITE_RATE_BURST") {
+                Some(burst) => burst.parse()
+                    .context("Failed to parse HEMATITE_WRITE_RATE_BURST as a number of requests")?,
+                None => per_second.ceil() as u32,
+            };
+            if burst == 0 {
+                bail!("HEMATITE_WRITE_RATE_BURST must be at least 1");
+            }
+
+            config.write_rate_limit = Some(WriteRateLimit { per_second, burst });
+        }
+
+        if let Some(jwks_cache_seconds) = vars.get("HEMATITE_JWKS_CACHE_SECONDS") {
+            let jwks_cache_seconds = jwks_cache_seconds.parse()
+                .context("Failed to parse HEMATITE_JWKS_CACHE_SECONDS as a number of seconds")?;
+            config.jwks_cache_ttl = Duration::from_secs(jwks_cache_seconds);
+        }
+
+        if let Some(oidc_background_refresh) = vars.get("HEMATITE_OIDC_BACKGROUND_REFRESH") {
+            config.oidc_background_refresh = oidc_background_refresh.trim().parse()
+                .context("Failed to parse HEMATITE_OIDC_BACKGROUND_REFRESH as true or false")?;
+        }
+
+        if let Some(max_open_streams) = vars.get("HEMATITE_MAX_OPEN_STREAMS") {
+            let max_open_streams = max_open_streams.parse()
+                .context("Failed to parse HEMATITE_MAX_OPEN_STREAMS as a number of streams")?;
+            if max_open_streams == 0 {
+                bail!("HEMATITE_MAX_OPEN_STREAMS must be at least 1");
+            }
+            config.max_open_streams = Some(max_open_streams);
+        }
+
+        if let Some(max_streams_per_user) = vars.get("HEMATITE_MAX_STREAMS_PER_USER") {
+            let max_streams_per_user = max_streams_per_user.parse()
+                .context("Failed to parse HEMATITE_MAX_STREAMS_PER_USER as a number of streams")?;
+            config.max_streams_per_user = Some(max_streams_per_user);
+        }
+
+        if let Some(max_bytes_per_user) = vars.get("HEMATITE_MAX_BYTES_PER_USER") {
+            let max_bytes_per_user = max_bytes_per_user.parse()
+                .context("Failed to parse HEMATITE_MAX_BYTES_PER_USER as a number of bytes")?;
+            config.max_bytes_per_user = Some(max_bytes_per_user);
+        }
+
+        if let Some(oidc_timeout_seconds) = vars.get("HEMATITE_OIDC_TIMEOUT_SECS") {
+            let oidc_timeout_seconds = oidc_timeout_seconds.parse()
+                .context("Failed to parse HEMATITE_OIDC_TIMEOUT_SECS as a number of seconds")?;
+            config.oidc_timeout = Duration::from_secs(oidc_timeout_seconds);
+        }
+
+        if let Some(shutdown_grace_seconds) = vars.get("HEMATITE_SHUTDOWN_GRACE_SECS") {
+            let shutdown_grace_seconds = shutdown_grace_seconds.parse()
+                .context("Failed to parse HEMATITE_SHUTDOWN_GRACE_SECS as a number of seconds")?;
+            config.shutdown_grace_period = Duration::from_secs(shutdown_grace_seconds);
+        }
+
+        if let Some(jwt_leeway_seconds) = vars.get("HEMATITE_JWT_LEEWAY_SECS") {
+            let jwt_leeway_seconds = jwt_leeway_seconds.parse()
+                .context("Failed to parse HEMATITE_JWT_LEEWAY_SECS as a number of seconds")?;
+            config.jwt_leeway = Duration::from_secs(jwt_leeway_seconds);
+        }
+
+        if let Some(corrected_event_max_age) = vars.get("HEMATITE_CORRECTED_EVENT_MAX_AGE_SECS") {
+            let corrected_event_max_age = corrected_event_max_age.parse()
+                .context("Failed to parse HEMATITE_CORRECTED_EVENT_MAX_AGE_SECS as a number of seconds")?;
+            config.corrected_event_max_age = Duration::from_secs(corrected_event_max_age);
+        }
+
+        if let Some(read_scope) = vars.get("HEMATITE_READ_SCOPE") {
+            config.read_scope = Some(read_scope.trim().to_string()).filter(|scope| !scope.is_empty());
+        }
+
+        if let Some(write_scope) = vars.get("HEMATITE_WRITE_SCOPE") {
+            config.write_scope = Some(write_scope.trim().to_string()).filter(|scope| !scope.is_empty());
+        }
+
+        if let Some(request_id_format) = vars.get("HEMATITE_REQUEST_ID_FORMAT") {
+            config.request_id_format = request_id_format.trim().parse()
+                .context("Failed to parse HEMATITE_REQUEST_ID_FORMAT")?;
+        }
+
+        if let Some(admin_users) = vars.get("HEMATITE_ADMIN_USERS") {
+            config.admin_users = admin_users.split(',')
+                .map(|user_id| user_id.trim().to_string())
+                .filter(|user_id| !user_id.is_empty())
+                .collect();
+        }
+
+        if let Some(jwt_audiences) = vars.get("HEMATITE_JWT_AUD") {
+            config.jwt_audiences = jwt_audiences.split(',')
+                .map(|audience| audience.trim().to_string())
+                .filter(|audience| !audience.is_empty())
+                .collect();
+        }
+
+        if let Some(jwt_trusted_issuers) = vars.get("HEMATITE_JWT_TRUSTED_ISSUERS") {
+            config.jwt_trusted_issuers = jwt_trusted_issuers.split(',')
+                .map(|issuer| issuer.trim().to_string())
+                .filter(|issuer| !issuer.is_empty())
+                .collect();
+        }
+
+        if let Some(introspection_url) = vars.get("HEMATITE_OIDC_INTROSPECTION_URL") {
+            let url = introspection_url.trim().parse()
+                .context("Failed to parse HEMATITE_OIDC_INTROSPECTION_URL as a URL")?;
+            let client_id = vars.get("HEMATITE_OIDC_CLIENT_ID")
+                .context("HEMATITE_OIDC_CLIENT_ID is required with HEMATITE_OIDC_INTROSPECTION_URL")?;
+            let client_secret = vars.get("HEMATITE_OIDC_CLIENT_SECRET")
+                .context("HEMATITE_OIDC_CLIENT_SECRET is required with HEMATITE_OIDC_INTROSPECTION_URL")?;
+
+            config.token_introspection = Some(TokenIntrospection {
+                url,
+                client_id: client_id.clone(),
+                client_secret: client_secret.clone(),
+            });
+        }
+
+        if let Some(user_claim) = vars.get("HEMATITE_OIDC_USER_CLAIM") {
+            config.user_claim = Some(user_claim.trim().to_string()).filter(|claim| !claim.is_empty());
+        }
+
+        if let Some(cors_allowed_origins) = vars.get("HEMATITE_CORS_ALLOWED_ORIGINS") {
+            config.cors_allowed_origins = cors_allowed_origins.split(',')
+                .map(|origin| origin.trim().to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect();
+
+            for origin in &config.cors_allowed_origins {
+                if origin != "*" {
+                    HeaderValue::from_str(origin)
+                        .with_context(|| format!("Failed to parse HEMATITE_CORS_ALLOWED_ORIGINS entry {:?} as an origin", origin))?;
+                }
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Returns a copy of this config with the hot-reloadable settings taken from `reloaded`.
+    pub fn with_reloadable(&self, reloaded: Config) -> Config {
+        Config {
+            event_id_format: reloaded.event_id_format,
+            spec_versions: reloaded.spec_versions,
+            max_clock_skew: reloaded.max_clock_skew,
+            max_event_age: reloaded.max_event_age,
+            enrichers: reloaded.enrichers,
+            extension_policy: reloaded.extension_policy,
+            max_batch_errors: reloaded.max_batch_errors,
+            max_lease_ttl: reloaded.max_lease_ttl,
+            reservation_ttl: reloaded.reservation_ttl,
+            max_reservation_count: reloaded.max_reservation_count,
+            default_page_limit: reloaded.default_page_limit,
+            ignored_stream_entries: reloaded.ignored_stream_entries,
+            ingest_allowed_hosts: reloaded.ingest_allowed_hosts,
+            ingest_timeout: reloaded.ingest_timeout,
+            compaction_interval: reloaded.compaction_interval,
+            compaction_idle: reloaded.compaction_idle,
+            admin_users: reloaded.admin_users,
+            max_data_depth: reloaded.max_data_depth,
+            max_data_bytes: reloaded.max_data_bytes,
+            read_scope: reloaded.read_scope,
+            write_scope: reloaded.write_scope,
+            corrected_event_max_age: reloaded.corrected_event_max_age,
+            max_streams_per_user: reloaded.max_streams_per_user,
+            max_bytes_per_user: reloaded.max_bytes_per_user,
+            ..self.clone()
+        }
+    }
+}
+
+/// Parses `NAME=value` lines, skipping blank lines and `#` comments.
+fn parse_config_file(contents: &str) -> Result<HashMap<String, String>> {
+    let mut vars = HashMap::new();
+
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (name, value) = line.split_once('=')
+            .with_context(|| format!("Expected NAME=value on line {}", i + 1))?;
+
+        vars.insert(name.trim().to_string(), value.trim().to_string());
+    }
+
+    Ok(vars)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_file_overrides_defaults() {
+        let vars = parse_config_file("# Limits\nHEMATITE_DEFAULT_PAGE_LIMIT = 10\n\nHEMATITE_SPEC_VERSIONS=0.3,1.0\n").unwrap();
+        let config = Config::from_vars(&vars).unwrap();
+
+        assert_eq!(config.default_page_limit, 10);
+        assert_eq!(config.spec_versions, [SpecVersion::V03, SpecVersion::V10]);
+        assert_eq!(config.max_body_bytes, Config::default().max_body_bytes);
+    }
+
+    #[test]
+    fn config_file_lines_need_a_value() {
+        assert!(parse_config_file("HEMATITE_DEFAULT_PAGE_LIMIT").is_err());
+    }
+
+    #[test]
+    fn reloading_keeps_settings_that_need_a_restart() {
+        let config = Config::default();
+        let reloaded = Config {
+            default_page_limit: 10,
+            max_body_bytes: 1,
+            ..Default::default()
+        };
+
+        let config = config.with_reloadable(reloaded);
+
+        assert_eq!(config.default_page_limit, 10);
+        assert_eq!(config.max_body_bytes, Config::default().max_body_bytes);
+    }
+
+    #[test]
+    fn csp_overrides_match_whole_path_segments() {
+        let mut csp = ContentSecurityPolicy::default();
+        csp.overrides.insert("/openapi".to_string(), "docs".to_string());
+        csp.overrides.insert("/static/".to_string(), "static".to_string());
+
+        assert_eq!(csp.for_path("/openapi"), "docs");
+        assert_eq!(csp.for_path("/openapi/ui"), "docs");
+        assert_eq!(csp.for_path("/openapix"), csp.default);
+        assert_eq!(csp.for_path("/static/app.js"), "static");
+        assert_eq!(csp.for_path("/static"), csp.default);
+    }
+
+    #[test]
+    fn invalid_csp_values_are_rejected() {
+        let vars = parse_config_file("HEMATITE_CSP = frame-ancestors 'none'\n").unwrap();
+        assert!(Config::from_vars(&vars).is_ok());
+
+        let vars = HashMap::from([("HEMATITE_CSP".to_string(), "frame-ancestors\n'none'".to_string())]);
+        assert!(Config::from_vars(&vars).is_err());
+
+        let vars = HashMap::from([("HEMATITE_CSP_OVERRIDES".to_string(), r#"{"/openapi": "default-src\u0000"}"#.to_string())]);
+        assert!(Config::from_vars(&vars).is_err());
+    }
+
+    #[test]
+    fn audiences_are_a_comma_separated_list() {
+        let vars = parse_config_file("HEMATITE_JWT_AUD = hematite, https://hematite.example\n").unwrap();
+        let config = Config::from_vars(&vars).unwrap();
+
+        assert_eq!(config.jwt_audiences, ["hematite", "https://hematite.example"]);
+    }
+}
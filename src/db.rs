@@ -1,33 +1,442 @@
-use anyhow::{ensure, Context, Result};
+use anyhow::{anyhow, ensure, Context, Result};
 use cloudevents::*;
+use cloudevents::event::{AttributeValue, ExtensionValue};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use futures::stream::{self, Stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fmt;
 use std::io::{SeekFrom, Write};
-use std::time::SystemTime;
+use std::ops::{Range, RangeInclusive};
+use std::time::{Duration, SystemTime};
 use tokio::fs::{File, self};
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWriteExt, BufReader, Lines};
+use tokio::sync::{broadcast, Mutex, OnceCell, OwnedMutexGuard};
+use tracing::{debug, warn};
+use uuid::Uuid;
 use std::path::Path;
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::pin::pin;
+use std::sync::Arc;
+use time::OffsetDateTime;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
-    #[error("revision mismatch")]
-    RevisionMismatch,
+    #[error("expected revision {expected} but the stream is at revision {actual}")]
+    RevisionMismatch { expected: ExpectedRevision, actual: u64 },
     #[error("an event with that source and ID value is already present in the stream")]
     SourceIdConflict,
+    #[error("event not found")]
+    EventNotFound,
+    #[error("stored event does not match its checksum")]
+    ChecksumMismatch,
+    #[error("database is stopped")]
+    Stopped,
+    #[error("an event with that ID value was recently appended to the stream")]
+    IdConflict,
+    /// `bytes` is only a lower bound for events rejected by their estimated size, without
+    /// being serialized.
+    #[error("event {index} of the batch is at least {bytes} bytes of JSON, more than the maximum of {max_bytes}")]
+    EventTooLarge { index: usize, bytes: usize, max_bytes: usize },
+    #[error("snapshot revision is past the head of the stream")]
+    SnapshotPastHead,
+    #[error("the next rownums are reserved, so events can only be appended into their reserved slots")]
+    Reserved,
+    #[error("rownums {start}..{end} are not all reserved and unfilled")]
+    NotReserved { start: u64, end: u64 },
+    #[error("the stream is sealed, so no more events can be appended to it")]
+    Sealed,
 }
 
-#[derive(Debug, Default)]
+/// Extension attribute on a correction event naming the rownum of the event it corrects.
+pub const CORRECTS_EXTENSION: &str = "hematitecorrects";
+/// Extension attribute added to a corrected event's view naming the correction applied to it.
+pub const CORRECTED_BY_EXTENSION: &str = "hematitecorrectedby";
+/// `type` of the events written into reserved slots left unfilled when their reservation expires.
+pub const TOMBSTONE_TYPE: &str = "hematite.tombstone";
+
+/// Event counts over time, as returned by `Database::activity`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Activity {
+    /// Event counts keyed by the Unix timestamp each bucket starts at. Empty buckets are left out.
+    pub buckets: BTreeMap<i64, u64>,
+    /// Events without a `time` attribute, which can't be placed in a bucket.
+    pub untimed: u64,
+}
+
+/// An event read by `Database::query_with_meta`, along with where it's stored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryItem {
+    pub rownum: u64,
+    /// Segment holding the event. Segments only ever follow the ones before them, so a
+    /// `(segment, offset)` pair orders events the same way their rownums do.
+    pub segment: u64,
+    /// Byte offset of the event within its segment.
+    pub offset: u64,
+    pub event: Event,
+}
+
+/// Where a page of events starting at some rownum sits in the stream, from `Database::page`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Page {
+    /// Rownums of the events on the page.
+    pub rownums: Vec<u64>,
+    /// Rownum the previous page of the same size starts at, if any events come before this one.
+    pub prev: Option<u64>,
+    /// Rownum the next page starts at, if any events come after this one.
+    pub next: Option<u64>,
+}
+
+/// Bounds on the CloudEvents `time` of the events a read returns, both inclusive. Either can
+/// be left open. Events without a `time` are outside every bounded range.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TimeRange {
+    pub since: Option<OffsetDateTime>,
+    pub until: Option<OffsetDateTime>,
+}
+
+impl TimeRange {
+    /// Whether the range has neither bound, so it doesn't filter anything.
+    pub fn is_unbounded(&self) -> bool {
+        self.since.is_none() && self.until.is_none()
+    }
+
+    pub fn contains(&self, event: &Event) -> bool {
+        if self.is_unbounded() {
+            return true;
+        }
+
+        let Some(time) = event.time() else {
+            return false;
+        };
+
+        let nanos = i128::from(time.timestamp()) * 1_000_000_000 + i128::from(time.timestamp_subsec_nanos());
+
+        self.since.is_none_or(|since| since.unix_timestamp_nanos() <= nanos)
+            && self.until.is_none_or(|until| nanos <= until.unix_timestamp_nanos())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub enum ExpectedRevision {
     #[default]
     Any,
     NoStream,
     StreamExists,
     Exact(u64),
+    /// Matches any revision of at least the given one, for writers that only need to know
+    /// they've seen everything up to it.
+    AtLeast(u64),
+}
+
+impl fmt::Display for ExpectedRevision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExpectedRevision::Any => write!(f, "any"),
+            ExpectedRevision::NoStream => write!(f, "no-stream"),
+            ExpectedRevision::StreamExists => write!(f, "stream-exists"),
+            ExpectedRevision::Exact(revision) => write!(f, "{}", revision),
+            ExpectedRevision::AtLeast(revision) => write!(f, ">={}", revision),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub revision: u64,
+    pub count: u64,
+    pub last_modified: u64,
+    pub usage: u64,
+}
+
+/// Size of a stream's in-memory primary index, for capacity planning.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct IndexInfo {
+    /// Entries in the primary index, one per event.
+    pub entries: u64,
+    /// Rough memory taken by the entries, allowing for the space B-tree nodes leave empty.
+    pub approximate_bytes: u64,
+    /// Whether only some rownums are indexed in memory. The primary index is always complete
+    /// for now, so this is always `false`.
+    pub sparse: bool,
+}
+
+/// How appends to a stream are checked for duplicate events.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "kebab-case")]
+pub enum Deduplication {
+    /// Reject any event whose `source` and `id` together match an event already in the stream.
+    #[default]
+    SourceId,
+    /// Reject any event whose `id` matches one of the last `window` events, regardless of `source`.
+    Id { window: usize },
+}
+
+/// Fraction of a compacted stream's events that must be superseded before it's compacted, when
+/// its metadata doesn't give `min_dirty_ratio`.
+pub const DEFAULT_MIN_DIRTY_RATIO: f64 = 0.5;
+
+/// Per-stream settings, persisted alongside the stream in `meta.json`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StreamMetadata {
+    #[serde(default)]
+    pub deduplication: Deduplication,
+    /// Keeps the rownums of each `subject`'s events in memory, so `query_by_subject` doesn't
+    /// have to scan the stream. This costs a `u64` per event with a subject plus each
+    /// distinct subject.
+    #[serde(default)]
+    pub index_subjects: bool,
+    /// Lets the server compact the stream by subject in the background, like a compacted Kafka
+    /// topic, keeping only each subject's latest event.
+    #[serde(default)]
+    pub compacted: bool,
+    /// Fraction of events that must be superseded by a later event with the same subject
+    /// before a compacted stream is compacted. `DEFAULT_MIN_DIRTY_RATIO` when `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_dirty_ratio: Option<f64>,
+    /// Makes the stream read-only, refusing every append and reservation.
+    #[serde(default)]
+    pub sealed: bool,
+}
+
+/// Contents of `meta.json`: the stream's settings, and facts about the stream that clients
+/// can't change.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct MetadataFile {
+    #[serde(flatten)]
+    metadata: StreamMetadata,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at: Option<u64>,
+}
+
+/// How rows are framed in a stream's segment files. Either way each row is the event's JSON
+/// prefixed with its checksum, and segment files keep their `.ndjson` names.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StorageFormat {
+    /// Each row is followed by a newline.
+    #[default]
+    Ndjson,
+    /// Each row is preceded by its length as a little-endian `u32`, after a `BINARY_HEADER`
+    /// at the start of the file. Binary segments are never compressed.
+    Binary,
+}
+
+/// Magic bytes at the start of every segment file in `StorageFormat::Binary`.
+const BINARY_HEADER: &[u8; 8] = b"HMTBIN01";
+
+impl StorageFormat {
+    /// Bytes at the start of every segment file in this format, before the first row.
+    fn header(self) -> &'static [u8] {
+        match self {
+            StorageFormat::Ndjson => b"",
+            StorageFormat::Binary => BINARY_HEADER,
+        }
+    }
+
+    /// Appends `row` to `bytes` with this format's framing.
+    fn write_row(self, row: &[u8], bytes: &mut Vec<u8>) -> Result<()> {
+        match self {
+            StorageFormat::Ndjson => {
+                bytes.extend_from_slice(row);
+                bytes.push(b'\n');
+            },
+            StorageFormat::Binary => {
+                let len = u32::try_from(row.len())
+                    .with_context(|| format!("Row of {} bytes is too long for a binary segment", row.len()))?;
+
+                bytes.extend_from_slice(&len.to_le_bytes());
+                bytes.extend_from_slice(row);
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Length in bytes of a row of `row_len` bytes once framed.
+    fn framed_len(self, row_len: usize) -> u64 {
+        match self {
+            StorageFormat::Ndjson => row_len as u64 + 1,
+            StorageFormat::Binary => row_len as u64 + 4,
+        }
+    }
+
+    /// Strips the framing from a record read from a segment, leaving the row.
+    fn row(self, record: &[u8]) -> &[u8] {
+        match self {
+            StorageFormat::Ndjson => record,
+            StorageFormat::Binary => record.get(4..).unwrap_or_default(),
+        }
+    }
+}
+
+impl FromStr for StorageFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "ndjson" => Ok(StorageFormat::Ndjson),
+            "binary" => Ok(StorageFormat::Binary),
+            _ => Err(anyhow!("Expected ndjson or binary but got {:?}", format)),
+        }
+    }
+}
+
+/// Rownums set aside by `Database::reserve`, to be filled in any order by `append_reserved`.
+#[derive(Clone, Debug)]
+struct Reservation {
+    /// Rownum of the first slot that hasn't been written to disk yet.
+    start: u64,
+    expires_at: SystemTime,
+    /// Event and encoded row of each slot from `start` on, once filled.
+    slots: VecDeque<Option<(Event, String)>>,
+}
+
+/// Events along with their encoded rows, from `encode_events`. Encoding is done before a
+/// stream is locked, so the rows' lengths can be counted against a quota without serializing
+/// the events a second time to write them.
+#[derive(Clone, Debug)]
+pub struct EncodedEvents {
+    events: Vec<Event>,
+    rows: Vec<String>,
+}
+
+impl EncodedEvents {
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Bytes these events will take up once stored, framed as the storage format with the
+    /// largest framing would frame them, since a stream's own format isn't known without
+    /// locking it.
+    pub fn stored_len(&self) -> u64 {
+        self.rows.iter()
+            .map(|row| [StorageFormat::Ndjson, StorageFormat::Binary].into_iter().map(|format| format.framed_len(row.len())).max().unwrap_or(0))
+            .sum()
+    }
+}
+
+/// How many appended events a subscriber may fall behind by before it is dropped.
+const SUBSCRIPTION_CAPACITY: usize = 1024;
+
+/// How many events a subscription reads at a time while replaying history.
+const SUBSCRIPTION_REPLAY_PAGE: usize = 100;
+
+/// Width of one record in an index sidecar: a big-endian `u64` rownum followed by a
+/// big-endian `u64` byte offset into the segment's events file.
+const INDEX_RECORD_LEN: usize = 16;
+
+/// Whether a `Database` is accepting reads and writes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RunState {
+    #[default]
+    Stopped,
+    Running,
+}
+
+/// Indexes of a stream's events by their attributes, kept in memory alongside the primary index.
+#[derive(Debug, Default)]
+struct SecondaryIndexes {
+    source_ids: HashMap<(String, String), u64>,
+    /// IDs of the most recent events, oldest first, when deduplicating by ID alone.
+    recent_ids: VecDeque<(String, u64)>,
+    /// Latest rownum of each ID in `recent_ids`.
+    recent_id_rownums: HashMap<String, u64>,
+    corrections: HashMap<u64, u64>,
+    /// Rownums of the events of each `type`, ascending. This costs a `u64` per event plus
+    /// each distinct type name, so unlike `recent_ids` it grows with the stream.
+    type_index: HashMap<String, Vec<u64>>,
+    /// Rownums of the events of each `subject`, ascending. Only kept if the stream's
+    /// metadata enables `index_subjects`.
+    subject_index: HashMap<String, Vec<u64>>,
+    /// Rows decoded from the segments to build these indexes.
+    rows_decoded: u64,
+}
+
+impl SecondaryIndexes {
+    fn index_event(&mut self, rownum: u64, event: &Event, metadata: &StreamMetadata) {
+        self.source_ids.insert(source_id(event), rownum);
+        self.type_index.entry(event.ty().to_string()).or_default().push(rownum);
+
+        if let Some(subject) = event.subject().filter(|_| metadata.index_subjects) {
+            self.subject_index.entry(subject.to_string()).or_default().push(rownum);
+        }
+
+        if let Deduplication::Id { window } = metadata.deduplication {
+            let id = event.id().to_string();
+            self.recent_ids.push_back((id.clone(), rownum));
+            self.recent_id_rownums.insert(id, rownum);
+
+            while self.recent_ids.len() > window {
+                if let Some((id, rownum)) = self.recent_ids.pop_front() {
+                    if self.recent_id_rownums.get(&id) == Some(&rownum) {
+                        self.recent_id_rownums.remove(&id);
+                    }
+                }
+            }
+        }
+
+        if let Some(corrected_rownum) = corrected_rownum(event) {
+            // Rownums only grow, so the latest correction always wins.
+            self.corrections.insert(corrected_rownum, rownum);
+        }
+    }
+
+    /// Up to `limit` rownums of the events with the `type` attribute `event_type`, from rownum
+    /// `start` onward.
+    fn type_rownums(&self, event_type: &str, start: u64, limit: usize) -> &[u64] {
+        let Some(rownums) = self.type_index.get(event_type) else {
+            return &[];
+        };
+
+        let first = rownums.partition_point(|rownum| *rownum < start);
+        let last = first.saturating_add(limit).min(rownums.len());
+
+        &rownums[first..last]
+    }
 }
 
-#[derive(Clone)]
 pub struct Database {
     path: PathBuf,
+    run_state: RunState,
+    metadata: StreamMetadata,
+    /// Unix timestamp at which the stream was created, persisted in `meta.json`. Unknown for
+    /// streams created before it was recorded.
+    created_at: Option<u64>,
+    /// Maps each rownum to the segment holding it and its byte offset in that segment.
+    primary_index: BTreeMap<u64, (u64, u64)>,
+    /// IDs of the segment files on disk, oldest first. The last one is appended to.
+    segments: Vec<u64>,
+    /// Size at which the active segment is sealed and a new one started. Never rolls over if `None`.
+    segment_bytes: Option<u64>,
+    /// Number of events per gzip block when sealed segments are compressed. Segments stay
+    /// plain NDJSON if `None`.
+    compression_block_events: Option<usize>,
+    /// Block tables of the sealed segments stored compressed.
+    compressed_segments: HashMap<u64, Arc<Vec<Block>>>,
+    /// Largest serialized event `append` accepts, in bytes. Unlimited if `None`.
+    max_event_bytes: Option<usize>,
+    /// Format that a new stream's segments are written in.
+    storage_format: StorageFormat,
+    /// Format of the segments on disk, read from the first segment's header by `load`.
+    segment_format: StorageFormat,
+    /// Built by decoding every event the first time they're needed after a load, so starting a
+    /// stream only reads its index sidecars.
+    secondary_indexes: OnceCell<SecondaryIndexes>,
+    stats_cache: Option<Stats>,
+    index_rebuilds: u64,
+    /// Rows read from segments to rebuild their index sidecars or catch them up.
+    index_rows_scanned: u64,
+    /// Rownum of the first event that hasn't been truncated away, persisted in `events.base`.
+    base_revision: u64,
+    /// Outstanding reservations, back to back from the tail of the stream. These are only
+    /// kept in memory.
+    reservations: VecDeque<Reservation>,
+    appended: broadcast::Sender<(u64, Event)>,
 }
 
 impl fmt::Debug for Database {
@@ -40,357 +449,4107 @@ impl Database {
     pub fn new(path: &Path) -> Self {
         Self {
             path: path.to_path_buf(),
+            run_state: RunState::Stopped,
+            metadata: StreamMetadata::default(),
+            created_at: None,
+            primary_index: BTreeMap::new(),
+            segments: Vec::new(),
+            segment_bytes: None,
+            compression_block_events: None,
+            compressed_segments: HashMap::new(),
+            max_event_bytes: None,
+            storage_format: StorageFormat::default(),
+            segment_format: StorageFormat::default(),
+            secondary_indexes: OnceCell::new(),
+            stats_cache: None,
+            index_rebuilds: 0,
+            index_rows_scanned: 0,
+            base_revision: 0,
+            reservations: VecDeque::new(),
+            appended: broadcast::channel(SUBSCRIPTION_CAPACITY).0,
+        }
+    }
+
+    pub fn run_state(&self) -> RunState {
+        self.run_state
+    }
+
+    /// Sets the size in bytes past which appends roll over to a new segment file.
+    pub fn set_segment_bytes(&mut self, segment_bytes: Option<u64>) {
+        self.segment_bytes = segment_bytes;
+    }
+
+    /// Stores sealed segments as gzip blocks of `block_events` events each, so a read only has to
+    /// decompress the block holding its row. The active segment is never compressed, so this has
+    /// no effect unless segments roll over with `set_segment_bytes`.
+    pub fn set_compression_block_events(&mut self, block_events: Option<usize>) {
+        self.compression_block_events = block_events;
+    }
+
+    /// Rejects appends holding any event whose JSON is longer than `max_event_bytes`.
+    pub fn set_max_event_bytes(&mut self, max_event_bytes: Option<usize>) {
+        self.max_event_bytes = max_event_bytes;
+    }
+
+    /// Sets the format new streams are written in. A stream that already has events keeps
+    /// the format it was written in, which `start` reads from its first segment.
+    pub fn set_storage_format(&mut self, storage_format: StorageFormat) {
+        self.storage_format = storage_format;
+    }
+
+    /// Loads the stream from disk and starts accepting reads and writes.
+    /// Returns `false` if the database was already running.
+    #[tracing::instrument]
+    pub async fn start(&mut self) -> Result<bool> {
+        if self.run_state == RunState::Running {
+            return Ok(false);
+        }
+
+        self.load().await?;
+        self.run_state = RunState::Running;
+
+        Ok(true)
+    }
+
+    /// Syncs the active segment and its index sidecar to disk and stops accepting reads and writes.
+    /// Returns `false` if the database was already stopped.
+    #[tracing::instrument]
+    pub async fn stop(&mut self) -> Result<bool> {
+        if self.run_state == RunState::Stopped {
+            return Ok(false);
+        }
+
+        let active_segment = self.active_segment();
+
+        for path in [self.segment_path(active_segment), self.segment_index_path(active_segment)] {
+            match File::open(&path).await {
+                Ok(file) => file.sync_all().await
+                    .with_context(|| format!("Failed to sync {:?}", path))?,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {},
+                Err(err) => return Err(err).with_context(|| format!("Failed to open {:?} to sync it", path)),
+            }
+        }
+
+        self.run_state = RunState::Stopped;
+
+        Ok(true)
+    }
+
+    /// Stops the database and frees its in-memory indexes, which `start` reloads from disk.
+    /// Its settings, metadata, reservations, and subscribers are kept, and so are its stats,
+    /// which nothing can change until it's started again.
+    #[tracing::instrument]
+    pub async fn close(&mut self) -> Result<()> {
+        let stats = match self.run_state {
+            RunState::Running => self.cached_stats().await.ok(),
+            _ => self.stats_cache,
+        };
+
+        self.stop().await?;
+        self.clear_indexes();
+        self.compressed_segments.clear();
+        self.stats_cache = stats;
+
+        Ok(())
+    }
+
+    /// Loads the primary index from each segment's index sidecar. A sidecar that stops short of
+    /// the end of its segment, say after a crash between writing events and indexing them, is
+    /// caught up by scanning only the rest of the segment. A segment whose sidecar is missing
+    /// or unusable is scanned in full.
+    #[tracing::instrument]
+    async fn load(&mut self) -> Result<()> {
+        self.clear_indexes();
+
+        let metadata_file = self.read_metadata().await?;
+        self.metadata = metadata_file.metadata;
+        self.created_at = metadata_file.created_at;
+        self.base_revision = self.read_base_revision().await?;
+        self.segments = self.list_segments().await?;
+        self.compressed_segments.clear();
+        self.segment_format = self.storage_format;
+
+        if self.segments.is_empty() {
+            return Ok(());
+        }
+
+        for segment in self.segments.clone() {
+            if let Some(blocks) = self.read_block_table(segment).await? {
+                self.compressed_segments.insert(segment, Arc::new(blocks));
+            }
+        }
+
+        self.segment_format = self.read_segment_format().await?;
+
+        self.repair_tail().await?;
+
+        let mut next_rownum = self.base_revision;
+
+        for segment in self.segments.clone() {
+            let index = match self.read_index(segment).await {
+                Ok(index) => index,
+                Err(err) => {
+                    warn!("Failed to read index sidecar of segment {} for {:?}: {:?}", segment, self.path, err);
+                    None
+                }
+            };
+
+            let index = match index {
+                Some((index, indexed_len)) => self.index_segment_tail(segment, index, indexed_len, next_rownum).await?,
+                None => {
+                    debug!("Index sidecar of segment {} for {:?} is missing or unusable, rebuilding it", segment, self.path);
+                    self.rebuild_segment_index(segment, next_rownum).await?
+                }
+            };
+
+            if let Some((rownum, _)) = index.last_key_value() {
+                next_rownum = rownum + 1;
+            }
+
+            self.primary_index.extend(index.into_iter().map(|(rownum, offset)| (rownum, (segment, offset))));
+        }
+
+        Ok(())
+    }
+
+    /// Finds the segment files in the stream directory. The original `events.ndjson` is
+    /// segment 0, and later segments are named like `events.00000001.ndjson`, with a `.gz`
+    /// suffix once compressed.
+    async fn list_segments(&self) -> Result<Vec<u64>> {
+        let mut segments = vec![];
+        let mut entries = fs::read_dir(&self.path).await
+            .with_context(|| format!("Failed to read stream directory at {:?}", self.path))?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            let file_name = entry.file_name();
+            let Some(file_name) = file_name.to_str() else {
+                continue;
+            };
+
+            let file_name = file_name.strip_suffix(".gz").unwrap_or(file_name);
+
+            if file_name == "events.ndjson" {
+                segments.push(0);
+            } else if let Some(segment) = file_name.strip_prefix("events.").and_then(|name| name.strip_suffix(".ndjson")) {
+                if let Ok(segment) = segment.parse() {
+                    segments.push(segment);
+                }
+            }
+        }
+
+        segments.sort_unstable();
+        segments.dedup();
+
+        Ok(segments)
+    }
+
+    /// Reads the format of the stream's segments from the header of the first one, or returns
+    /// the configured format if it is still empty. Only NDJSON segments are compressed.
+    async fn read_segment_format(&self) -> Result<StorageFormat> {
+        let Some(first_segment) = self.segments.first() else {
+            return Ok(self.storage_format);
+        };
+
+        if self.compressed_segments.contains_key(first_segment) {
+            return Ok(StorageFormat::Ndjson);
+        }
+
+        let events_path = self.segment_path(*first_segment);
+        let file = File::open(&events_path).await
+            .with_context(|| format!("Could not open file to read DB at {:?}", events_path))?;
+
+        let mut header = Vec::with_capacity(BINARY_HEADER.len());
+        file.take(BINARY_HEADER.len() as u64).read_to_end(&mut header).await
+            .with_context(|| format!("Failed to read header of DB at {:?}", events_path))?;
+
+        // A header cut short by a crash is still recognized, so `repair_tail` can clean it up.
+        let format =
+            if header.is_empty() {
+                self.storage_format
+            } else if BINARY_HEADER.starts_with(&header) {
+                StorageFormat::Binary
+            } else {
+                StorageFormat::Ndjson
+            };
+
+        Ok(format)
+    }
+
+    fn active_segment(&self) -> u64 {
+        self.segments.last().copied().unwrap_or(0)
+    }
+
+    /// Reads the block table of a compressed segment, or returns `None` if the segment is plain.
+    /// A plain file takes precedence, since compression only removes it once the compressed
+    /// copy is complete, so any compressed files alongside one are removed.
+    async fn read_block_table(&self, segment: u64) -> Result<Option<Vec<Block>>> {
+        let blocks_path = self.segment_blocks_path(segment);
+
+        if self.segment_path(segment).try_exists()? {
+            remove_file_if_exists(&self.compressed_segment_path(segment)).await?;
+            remove_file_if_exists(&blocks_path).await?;
+            return Ok(None);
+        }
+
+        let bytes = fs::read(&blocks_path).await
+            .with_context(|| format!("Failed to read block table at {:?}", blocks_path))?;
+
+        ensure!(bytes.len() % INDEX_RECORD_LEN == 0 && !bytes.is_empty(), "Block table at {:?} is {} bytes, which is not a whole number of records", blocks_path, bytes.len());
+
+        let mut blocks: Vec<Block> = Vec::with_capacity(bytes.len() / INDEX_RECORD_LEN);
+
+        for record in bytes.chunks_exact(INDEX_RECORD_LEN) {
+            let (data_offset, file_offset) = record.split_at(8);
+            let block = Block {
+                data_offset: u64::from_be_bytes(data_offset.try_into()?),
+                file_offset: u64::from_be_bytes(file_offset.try_into()?),
+            };
+
+            if let Some(previous) = blocks.last() {
+                ensure!(block.data_offset >= previous.data_offset && block.file_offset >= previous.file_offset, "Block table at {:?} is out of order", blocks_path);
+            }
+
+            blocks.push(block);
+        }
+
+        Ok(Some(blocks))
+    }
+
+    fn segment_file(&self, segment: u64) -> SegmentFile {
+        match (self.compressed_segments.get(&segment), self.segment_format) {
+            (Some(blocks), _) => SegmentFile::Compressed(self.compressed_segment_path(segment), blocks.clone()),
+            (None, StorageFormat::Ndjson) => SegmentFile::Plain(self.segment_path(segment)),
+            (None, StorageFormat::Binary) => SegmentFile::Binary(self.segment_path(segment)),
+        }
+    }
+
+    fn clear_indexes(&mut self) {
+        self.primary_index.clear();
+        self.secondary_indexes = OnceCell::new();
+        self.stats_cache = None;
+    }
+
+    async fn read_base_revision(&self) -> Result<u64> {
+        let base_path = self.base_path();
+
+        match fs::read(&base_path).await {
+            Ok(bytes) => {
+                let bytes: [u8; 8] = bytes.as_slice().try_into()
+                    .with_context(|| format!("Expected base revision at {:?} to be 8 bytes", base_path))?;
+                Ok(u64::from_be_bytes(bytes))
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(0),
+            Err(err) => Err(err).with_context(|| format!("Failed to read base revision at {:?}", base_path)),
+        }
+    }
+
+    async fn read_metadata(&self) -> Result<MetadataFile> {
+        let metadata_path = self.metadata_path();
+
+        match fs::read(&metadata_path).await {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .with_context(|| format!("Failed to decode stream metadata at {:?}", metadata_path)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(MetadataFile::default()),
+            Err(err) => Err(err).with_context(|| format!("Failed to read stream metadata at {:?}", metadata_path)),
+        }
+    }
+
+    async fn write_metadata(&self, metadata_file: &MetadataFile) -> Result<()> {
+        let metadata_path = self.metadata_path();
+        let temp_path = self.path.join("meta.json.tmp");
+
+        let json = serde_json::to_vec(metadata_file).context("Failed to JSONify stream metadata")?;
+        fs::write(&temp_path, json).await
+            .with_context(|| format!("Failed to write stream metadata to {:?}", temp_path))?;
+        fs::rename(&temp_path, &metadata_path).await
+            .with_context(|| format!("Failed to move stream metadata into place at {:?}", metadata_path))
+    }
+
+    pub fn metadata(&self) -> &StreamMetadata {
+        &self.metadata
+    }
+
+    /// Persists new stream settings and rebuilds the in-memory indexes they affect.
+    #[tracing::instrument]
+    pub async fn set_metadata(&mut self, metadata: StreamMetadata) -> Result<()> {
+        ensure!(self.run_state == RunState::Running, Error::Stopped);
+
+        self.write_metadata(&MetadataFile { metadata, created_at: self.created_at }).await?;
+
+        self.load().await
+    }
+
+    pub fn created_at(&self) -> Option<u64> {
+        self.created_at
+    }
+
+    /// Records the Unix timestamp at which the stream was created.
+    #[tracing::instrument]
+    pub async fn set_created_at(&mut self, created_at: u64) -> Result<()> {
+        ensure!(self.run_state == RunState::Running, Error::Stopped);
+
+        self.write_metadata(&MetadataFile { metadata: self.metadata.clone(), created_at: Some(created_at) }).await?;
+        self.created_at = Some(created_at);
+
+        Ok(())
+    }
+
+    /// Stores an opaque snapshot of a projection built from the events before `revision`,
+    /// replacing any earlier snapshot, so consumers only have to replay the events after it.
+    pub async fn put_snapshot(&mut self, revision: u64, bytes: Vec<u8>) -> Result<()> {
+        ensure!(self.run_state == RunState::Running, Error::Stopped);
+        ensure!(revision <= self.revision(), Error::SnapshotPastHead);
+
+        let snapshot_path = self.snapshot_path();
+        let temp_path = self.path.join("snapshot.tmp");
+
+        let mut snapshot = Vec::with_capacity(8 + bytes.len());
+        snapshot.extend(revision.to_be_bytes());
+        snapshot.extend(bytes);
+
+        fs::write(&temp_path, snapshot).await
+            .with_context(|| format!("Failed to write snapshot to {:?}", temp_path))?;
+        fs::rename(&temp_path, &snapshot_path).await
+            .with_context(|| format!("Failed to move snapshot into place at {:?}", snapshot_path))?;
+
+        Ok(())
+    }
+
+    /// Returns the stored snapshot and the revision it was taken at, if there is one.
+    pub async fn get_latest_snapshot(&self) -> Result<Option<(u64, Vec<u8>)>> {
+        ensure!(self.run_state == RunState::Running, Error::Stopped);
+
+        let snapshot_path = self.snapshot_path();
+
+        let mut snapshot = match fs::read(&snapshot_path).await {
+            Ok(snapshot) => snapshot,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).with_context(|| format!("Failed to read snapshot at {:?}", snapshot_path)),
+        };
+
+        ensure!(snapshot.len() >= 8, "Snapshot at {:?} is missing its revision", snapshot_path);
+
+        let bytes = snapshot.split_off(8);
+        let revision = u64::from_be_bytes(snapshot.as_slice().try_into()?);
+
+        Ok(Some((revision, bytes)))
+    }
+
+    /// Truncates a partial or undecodable final line of the active segment, such as one left
+    /// behind by a crash in the middle of an append, back to the end of the last good line.
+    async fn repair_tail(&mut self) -> Result<()> {
+        // Only sealed segments are compressed, so a compressed one can't have a torn write.
+        if self.compressed_segments.contains_key(&self.active_segment()) {
+            return Ok(());
+        }
+
+        let events_path = self.segment_path(self.active_segment());
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .open(&events_path).await
+            .with_context(|| format!("Could not open file to repair DB at {:?}", events_path))?;
+
+        let len = file.metadata().await
+            .with_context(|| format!("Failed to access metadata of DB path {:?}", events_path))?
+            .len();
+
+        if len == 0 {
+            return Ok(());
         }
+
+        let good_len = match self.segment_format {
+            StorageFormat::Ndjson => end_of_last_line(&mut file, len).await?,
+            StorageFormat::Binary => end_of_last_record(&mut file, len).await?,
+        };
+
+        if good_len < len {
+            warn!("Truncating {} bytes of incomplete or corrupt data from the end of DB at {:?}", len - good_len, events_path);
+
+            file.set_len(good_len).await
+                .with_context(|| format!("Failed to truncate DB at {:?}", events_path))?;
+            file.sync_all().await
+                .with_context(|| format!("Failed to sync DB at {:?}", events_path))?;
+        }
+
+        Ok(())
+    }
+
+    /// The secondary indexes, decoding every event in the stream to build them if this is
+    /// their first use since the stream was loaded.
+    async fn secondary_indexes(&self) -> Result<&SecondaryIndexes> {
+        ensure!(self.run_state == RunState::Running, Error::Stopped);
+
+        self.secondary_indexes.get_or_try_init(|| self.build_secondary_indexes()).await
     }
 
-    #[tracing::instrument]
-    pub async fn rebuild_index(&self) -> Result<()> {
-        let events_path = self.events_path();
-        let file = File::options()
-            .read(true)
-            .open(&events_path).await
-            .with_context(|| format!("Could not open file to create DB at {:?}", events_path))?;
+    async fn build_secondary_indexes(&self) -> Result<SecondaryIndexes> {
+        let mut indexes = SecondaryIndexes::default();
+        let rownums: Vec<u64> = self.primary_index.keys().copied().collect();
+        let mut rownums = rownums.into_iter();
+
+        for segment in self.segments.iter().copied() {
+            let segment_file = self.segment_file(segment);
+            let mut lines = segment_file.lines_from(0).await?;
+
+            while let Some(line) = lines.next_line().await? {
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                let rownum = rownums.next()
+                    .with_context(|| format!("Events file at {:?} has more events than its index", segment_file.path()))?;
+                indexes.rows_decoded += 1;
+
+                // A corrupt row is left for reads of it to report, rather than keeping the whole
+                // stream from loading.
+                let event = match decode_event(line) {
+                    Ok(event) => event,
+                    Err(err) if matches!(err.downcast_ref::<Error>(), Some(Error::ChecksumMismatch)) => {
+                        warn!("Skipping row {} of {:?} while indexing, since it fails its checksum", rownum, segment_file.path());
+                        continue;
+                    },
+                    Err(err) => return Err(err),
+                };
+                indexes.index_event(rownum, &event, &self.metadata);
+            }
+        }
+
+        debug!("Built secondary indexes of {:?} from {} rows", self.path, indexes.rows_decoded);
+
+        Ok(indexes)
+    }
+
+    /// Reads a segment's index sidecar, returning it along with the offset of the end of the
+    /// last row it covers, or `None` if the sidecar is missing or corrupt.
+    async fn read_index(&self, segment: u64) -> Result<Option<(BTreeMap<u64, u64>, u64)>> {
+        let index_path = self.segment_index_path(segment);
+
+        if !index_path.try_exists()? {
+            return Ok(None);
+        }
+
+        let bytes = fs::read(&index_path).await
+            .with_context(|| format!("Failed to read index at {:?}", index_path))?;
+
+        let whole_records_len = bytes.len() - bytes.len() % INDEX_RECORD_LEN;
+
+        // A partial record is what a crash partway through appending to the sidecar leaves
+        // behind, so it's dropped and its row scanned again.
+        if whole_records_len < bytes.len() {
+            warn!("Index sidecar at {:?} is {} bytes, which is not a whole number of records, dropping the last one", index_path, bytes.len());
+
+            File::options().write(true).open(&index_path).await
+                .with_context(|| format!("Failed to open index at {:?}", index_path))?
+                .set_len(whole_records_len as u64).await
+                .with_context(|| format!("Failed to truncate index at {:?}", index_path))?;
+        }
+
+        let events_len = self.segment_data_len(segment).await?;
+        let mut index = BTreeMap::new();
+        let mut previous: Option<(u64, u64)> = None;
+
+        for record in bytes[..whole_records_len].chunks_exact(INDEX_RECORD_LEN) {
+            let (rownum, offset) = record.split_at(8);
+            let rownum = u64::from_be_bytes(rownum.try_into()?);
+            let offset = u64::from_be_bytes(offset.try_into()?);
+
+            let out_of_order = previous.is_some_and(|(prev_rownum, prev_offset)| rownum <= prev_rownum || offset <= prev_offset);
+
+            if offset >= events_len || out_of_order {
+                warn!("Index sidecar at {:?} has an invalid record (rownum {}, offset {})", index_path, rownum, offset);
+                return Ok(None);
+            }
+
+            index.insert(rownum, offset);
+            previous = Some((rownum, offset));
+        }
+
+        let header_len = self.segment_format.header().len() as u64;
+
+        // A sidecar left behind by a rewrite of its segment points into rows rather than at
+        // their starts, so one whose first row isn't right after the header, or whose last
+        // row doesn't decode, doesn't belong to these events.
+        if index.first_key_value().is_some_and(|(_, offset)| *offset != header_len.min(events_len)) {
+            warn!("Index sidecar at {:?} doesn't start at the first row of its segment", index_path);
+            return Ok(None);
+        }
+
+        let indexed_len = match index.last_key_value() {
+            Some((_, offset)) => match self.indexed_row_len_at(segment, *offset).await {
+                Some(len) => offset + len,
+                None => {
+                    warn!("Index sidecar at {:?} ends at offset {}, which isn't a row of its segment", index_path, offset);
+                    return Ok(None);
+                },
+            },
+            None => header_len.min(events_len),
+        };
+
+        if indexed_len > events_len {
+            return Ok(None);
+        }
+
+        Ok(Some((index, indexed_len)))
+    }
+
+    /// Length in bytes of the row starting at `offset` in `segment`, including its framing, or
+    /// `None` if there's no whole, intact row there. Part of a row can look like a row with a
+    /// bad checksum, so a row that fails its checksum doesn't count either.
+    async fn indexed_row_len_at(&self, segment: u64, offset: u64) -> Option<u64> {
+        let segment_file = self.segment_file(segment);
+        let record = SegmentReader::default().read_record(segment, &segment_file, offset).await.ok()?;
+        let format = segment_file.format();
+
+        if format == StorageFormat::Ndjson && !record.ends_with(b"\n") {
+            return None;
+        }
+
+        let row = String::from_utf8(format.row(&record).to_vec()).ok()?;
+        decode_event(row).ok().map(|_| record.len() as u64)
+    }
+
+    /// Scans every segment to rebuild the primary index, then rewrites the sidecars.
+    #[tracing::instrument]
+    pub async fn rebuild_index(&mut self) -> Result<()> {
+        self.primary_index.clear();
+        let mut next_rownum = self.base_revision;
+
+        for segment in self.segments.clone() {
+            let index = self.rebuild_segment_index(segment, next_rownum).await?;
+
+            if let Some((rownum, _)) = index.last_key_value() {
+                next_rownum = rownum + 1;
+            }
+
+            self.primary_index.extend(index.into_iter().map(|(rownum, offset)| (rownum, (segment, offset))));
+        }
+
+        Ok(())
+    }
+
+    /// Scans one segment, numbering its events from `first_rownum`, and rewrites its sidecar.
+    async fn rebuild_segment_index(&mut self, segment: u64, first_rownum: u64) -> Result<BTreeMap<u64, u64>> {
+        let header_len = self.segment_file(segment).format().header().len() as u64;
+        let index = self.scan_segment(segment, header_len, first_rownum).await?;
+
+        let index_path = self.segment_index_path(segment);
+        let records: Vec<u8> = index.iter()
+            .flat_map(|(rownum, offset)| index_record(*rownum, *offset))
+            .collect();
+
+        fs::write(&index_path, records).await
+            .with_context(|| format!("Failed to write index at {:?}", index_path))?;
+
+        self.index_rebuilds += 1;
+
+        Ok(index)
+    }
+
+    /// Scans the rows of `segment` past `indexed_len`, where its sidecar `index` stops, and
+    /// appends them to the sidecar. Rows are numbered on from the index's last rownum, or from
+    /// `first_rownum` if it's empty.
+    async fn index_segment_tail(&mut self, segment: u64, mut index: BTreeMap<u64, u64>, indexed_len: u64, first_rownum: u64) -> Result<BTreeMap<u64, u64>> {
+        if indexed_len >= self.segment_data_len(segment).await? {
+            return Ok(index);
+        }
+
+        debug!("Index sidecar of segment {} for {:?} stops at offset {}, indexing the rest", segment, self.path, indexed_len);
+
+        let next_rownum = index.last_key_value().map_or(first_rownum, |(rownum, _)| rownum + 1);
+        let tail = self.scan_segment(segment, indexed_len, next_rownum).await?;
+
+        let records: Vec<u8> = tail.iter()
+            .flat_map(|(rownum, offset)| index_record(*rownum, *offset))
+            .collect();
+
+        let index_path = self.segment_index_path(segment);
+        let mut index_file = File::options()
+            .append(true)
+            .create(true)
+            .open(&index_path).await
+            .with_context(|| format!("Failed to open file for index at {:?}", index_path))?;
+
+        index_file.write_all(&records).await
+            .with_context(|| format!("Failed to write index at {:?}", index_path))?;
+        index_file.flush().await
+            .with_context(|| format!("Failed to flush index at {:?}", index_path))?;
+
+        index.extend(tail);
+
+        Ok(index)
+    }
+
+    /// Reads the rows of `segment` from `offset` on, numbering them from `first_rownum`, and
+    /// returns the offset of each.
+    async fn scan_segment(&mut self, segment: u64, mut offset: u64, first_rownum: u64) -> Result<BTreeMap<u64, u64>> {
+        let segment_file = self.segment_file(segment);
+
+        let mut index = BTreeMap::new();
+        let mut rownum = first_rownum;
+        let mut lines = segment_file.lines_from(offset).await?;
+
+        while let Some(line) = lines.next_line().await? {
+            self.index_rows_scanned += 1;
+
+            if line.trim().is_empty() {
+                warn!("Skipping blank line at offset {} of DB at {:?}", offset, segment_file.path());
+            } else {
+                index.insert(rownum, offset);
+                rownum += 1;
+            }
+
+            // Lines come back without their framing, so add it back to find the next offset.
+            offset += segment_file.format().framed_len(line.len());
+        }
+
+        Ok(index)
+    }
+
+    #[tracing::instrument]
+    pub async fn last_modified(&self) -> Result<u64> {
+        let segment_file = self.segment_file(self.active_segment());
+        let events_path = segment_file.path();
+
+        let metadata = match fs::metadata(events_path).await {
+            Ok(metadata) => metadata,
+            // Nothing has been appended to a stream created empty, so it was last modified
+            // when it was created.
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound && self.count() == 0 => return Ok(self.created_at.unwrap_or(0)),
+            Err(err) => return Err(err).with_context(|| format!("Failed to access metadata of DB path {:?}", &events_path)),
+        };
+
+        metadata
+            .modified()
+            .with_context(|| format!("Failed to access modified time of DB path {:?}", &events_path))?
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .with_context(|| format!("Failed to convert mtime to unix time for DB path {:?}", &events_path))
+            .map(|d| d.as_secs())
+    }
+
+    /// Total size in bytes of every segment's events file, after compression.
+    #[tracing::instrument]
+    pub async fn file_len(&self) -> Result<u64> {
+        if self.segments.is_empty() {
+            return self.segment_len(0).await;
+        }
+
+        let mut size = 0;
+        for segment in self.segments.iter() {
+            size += self.segment_len(*segment).await?;
+        }
+
+        Ok(size)
+    }
+
+    /// Bytes the stream takes up on disk, counting its snapshot as well as its segments, for
+    /// holding users to `max_bytes_per_user`.
+    #[tracing::instrument]
+    pub async fn disk_usage(&self) -> Result<u64> {
+        let snapshot_path = self.snapshot_path();
+        let snapshot_len = match fs::metadata(&snapshot_path).await {
+            Ok(metadata) => metadata.len(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err).with_context(|| format!("Failed to access metadata of snapshot at {:?}", snapshot_path)),
+        };
+
+        Ok(self.file_len().await? + snapshot_len)
+    }
+
+    async fn segment_len(&self, segment: u64) -> Result<u64> {
+        let segment_file = self.segment_file(segment);
+        let events_path = segment_file.path();
+
+        match fs::metadata(events_path).await {
+            Ok(metadata) => Ok(metadata.len()),
+            // Nothing has been appended to a stream created empty.
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound && self.count() == 0 => Ok(0),
+            Err(err) => Err(err).with_context(|| format!("Failed to access metadata of DB path {:?}", &events_path)),
+        }
+    }
+
+    /// Size in bytes of a segment's NDJSON, before any compression. Index offsets are within this.
+    async fn segment_data_len(&self, segment: u64) -> Result<u64> {
+        match self.compressed_segments.get(&segment) {
+            Some(blocks) => Ok(blocks.last().map_or(0, |end| end.data_offset)),
+            None => self.segment_len(segment).await,
+        }
+    }
+
+    /// Revision the next appended event will get, one past the last event in the stream.
+    pub fn revision(&self) -> u64 {
+        self.primary_index.last_key_value().map(|(rownum, _)| rownum + 1).unwrap_or(self.base_revision)
+    }
+
+    /// Number of events in the stream.
+    pub fn count(&self) -> u64 {
+        self.primary_index.len() as u64
+    }
+
+    /// Reports the size of the in-memory primary index.
+    pub fn index_info(&self) -> IndexInfo {
+        // Appending in rownum order leaves B-tree nodes a little over half full, so each
+        // entry takes about twice its own size.
+        let entry_bytes = 2 * std::mem::size_of::<(u64, (u64, u64))>() as u64;
+
+        IndexInfo {
+            entries: self.count(),
+            approximate_bytes: self.count() * entry_bytes,
+            sparse: false,
+        }
+    }
+
+    /// Reads the stream's revision, mtime, and size from disk, refreshing the cached copy.
+    #[tracing::instrument]
+    pub async fn stats(&mut self) -> Result<Stats> {
+        let stats = Stats {
+            revision: self.revision(),
+            count: self.count(),
+            last_modified: self.last_modified().await?,
+            usage: self.file_len().await?,
+        };
+
+        self.stats_cache = Some(stats);
+
+        Ok(stats)
+    }
+
+    /// Whether `cached_stats` can answer without touching the filesystem.
+    pub fn has_cached_stats(&self) -> bool {
+        self.stats_cache.is_some()
+    }
+
+    /// Returns the stats cached by the last call to `stats` and kept current by `append`,
+    /// only touching the filesystem if nothing has been cached yet.
+    #[tracing::instrument]
+    pub async fn cached_stats(&mut self) -> Result<Stats> {
+        match self.stats_cache {
+            Some(stats) => Ok(stats),
+            None => self.stats().await,
+        }
+    }
+
+    pub async fn last_offset(&self) -> Result<u64> {
+        Ok(self.primary_index.last_key_value().map(|(_, (_, offset))| *offset).unwrap_or(0))
+    }
+
+    #[tracing::instrument]
+    pub async fn query(&self, start: u64, limit: usize) -> Result<Vec<Event>> {
+        self.query_stream(start, limit).try_collect().await
+    }
+
+    /// Like `query`, but returns each event's rownum and storage location too, so a client can
+    /// resume reading exactly where it left off.
+    #[tracing::instrument]
+    pub async fn query_with_meta(&self, start: u64, limit: usize) -> Result<Vec<QueryItem>> {
+        let locations: Vec<(u64, u64, u64)> = self.primary_index.range(start..)
+            .take(limit)
+            .map(|(rownum, (segment, offset))| (*rownum, *segment, *offset))
+            .collect();
+
+        let events = self.query(start, limit).await?;
+        ensure!(events.len() == locations.len(), "Read {} events but expected {}", events.len(), locations.len());
+
+        let items = locations.into_iter()
+            .zip(events)
+            .map(|((rownum, segment, offset), event)| QueryItem { rownum, segment, offset, event })
+            .collect();
+
+        Ok(items)
+    }
+
+    /// Streams up to `limit` events starting at rownum `start`, reading from disk as the
+    /// stream is polled. Events appended after this call are not included.
+    pub fn query_stream(&self, start: u64, limit: usize) -> impl Stream<Item = Result<Event>> + use<> {
+        self.stream_rows(start..u64::MAX, limit)
+    }
+
+    /// Captures the head revision and streams the events with rownums in `rows` below it,
+    /// returning the stream along with the first and last rownums it yields, if any, and that
+    /// revision. Events appended while the stream is read are never included, so an export
+    /// built from it is a consistent point-in-time view.
+    ///
+    /// Rownums aren't dense once a stream has been compacted, so the rows streamed can span
+    /// less than `rows`, or nothing at all even though `rows` starts below the head.
+    #[tracing::instrument]
+    pub async fn query_snapshot(&self, rows: Range<u64>) -> Result<(impl Stream<Item = Result<Event>> + use<>, Option<RangeInclusive<u64>>, u64)> {
+        let head_revision = self.revision();
+        let rows = rows.start..rows.end.min(head_revision);
+
+        let mut rownums = self.primary_index.range(rows.clone()).map(|(rownum, _)| *rownum);
+        let streamed = rownums.next().map(|first| first..=rownums.next_back().unwrap_or(first));
+
+        Ok((self.stream_rows(rows, usize::MAX), streamed, head_revision))
+    }
+
+    fn stream_rows(&self, rows: Range<u64>, limit: usize) -> impl Stream<Item = Result<Event>> + use<> {
+        let mut segments = VecDeque::new();
+        let mut remaining = 0;
+
+        if let Some((_, (start_segment, start_offset))) = self.primary_index.range(rows.clone()).next() {
+            // Read sequentially from the starting row, continuing from the top of each later segment.
+            for segment in self.segments.iter().copied().filter(|segment| segment >= start_segment) {
+                let segment_file = self.segment_file(segment);
+                let offset = if segment == *start_segment { *start_offset } else { segment_file.format().header().len() as u64 };
+                segments.push_back((segment_file, offset));
+            }
+
+            remaining = self.primary_index.range(rows).count().min(limit);
+        }
+
+        let cursor = QueryCursor {
+            running: self.run_state == RunState::Running,
+            segments,
+            current: None,
+            remaining,
+            failed: false,
+        };
+
+        stream::unfold(cursor, |mut cursor| async move {
+            let item = cursor.next_event().await?;
+            Some((item, cursor))
+        })
+    }
+
+    /// Returns the events with rownums in `start..end`. An `end` past the tail of the stream
+    /// reads to the tail, and an empty range reads nothing.
+    #[tracing::instrument]
+    pub async fn query_range(&self, start: u64, end: u64) -> Result<Vec<Event>> {
+        if start >= end {
+            return Ok(vec![]);
+        }
+
+        // Stop reading at the last row in range rather than at the end of the segment.
+        let limit = self.primary_index.range(start..end).count();
+        if limit == 0 {
+            return Ok(vec![]);
+        }
+
+        self.query(start, limit).await
+    }
+
+    /// Finds the event with the given `source` and `id` attributes, along with its rownum.
+    /// Under `Deduplication::Id` several events may share them, and the latest one is returned.
+    #[tracing::instrument]
+    pub async fn get_by_source_id(&self, source: &str, id: &str) -> Result<Option<(u64, Event)>> {
+        ensure!(self.run_state == RunState::Running, Error::Stopped);
+
+        let Some(rownum) = self.secondary_indexes().await?.source_ids.get(&(source.to_string(), id.to_string())).copied() else {
+            return Ok(None);
+        };
+
+        let event = self.get(rownum).await?
+            .with_context(|| format!("Row {} is indexed by source and ID but could not be read", rownum))?;
+
+        Ok(Some((rownum, event)))
+    }
+
+    /// Returns up to `limit` events counting down from rownum `start`, newest first.
+    /// A `start` past the end of the stream begins at the latest event.
+    #[tracing::instrument]
+    pub async fn query_backward(&self, start: u64, limit: usize) -> Result<Vec<Event>> {
+        let rownums: Vec<u64> = self.primary_index.range(..=start).rev()
+            .take(limit)
+            .map(|(rownum, _)| *rownum)
+            .collect();
+
+        self.read_rows(&rownums).await
+    }
+
+    /// Streams every event from rownum `from` onward, then each event as it is appended.
+    /// Ends with an error if the subscriber falls too far behind the live appends, or if the
+    /// database is stopped while its history is replayed.
+    ///
+    /// History is replayed a page at a time, taking the lock held by `db` for each page, so a
+    /// subscriber never holds more than a page of events or reads files while they're rewritten.
+    pub fn subscribe(db: &OwnedMutexGuard<Database>, from: u64) -> impl Stream<Item = Result<(u64, Event)>> + use<> {
+        // Subscribe before replaying so no append can slip between the two. Live events the
+        // replay already covered are skipped by rownum.
+        let subscription = Subscription {
+            replay: Some(OwnedMutexGuard::mutex(db).clone()),
+            next: from,
+            buffer: Default::default(),
+            receiver: db.appended.subscribe(),
+            failed: false,
+        };
+
+        stream::unfold(subscription, |mut subscription| async move {
+            let item = subscription.next_event().await?;
+            Some((item, subscription))
+        })
+    }
+
+    /// Reads every stored event and returns the rownum of the first one that fails its
+    /// checksum or can't be decoded, or `None` if the whole stream is intact.
+    #[tracing::instrument]
+    pub async fn verify(&self) -> Result<Option<u64>> {
+        let mut reader = SegmentReader::default();
+
+        for (rownum, (segment, offset)) in self.primary_index.iter() {
+            let segment_file = self.segment_file(*segment);
+            let line = reader.read_record(*segment, &segment_file, *offset).await
+                .and_then(|record| Ok(String::from_utf8(segment_file.format().row(&record).to_vec())?));
+
+            if !line.is_ok_and(|line| decode_event(line).is_ok()) {
+                warn!("Row {} (offset {}) of DB at {:?} is corrupt", rownum, offset, segment_file.path());
+                return Ok(Some(*rownum));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Like `query`, but each event that has been corrected has its data replaced by the
+    /// data of its latest correction.
+    #[tracing::instrument]
+    pub async fn query_corrected(&self, start: u64, limit: usize) -> Result<Vec<Event>> {
+        let events = self.query(start, limit).await?;
+        let rows = self.primary_index.range(start..).map(|(rownum, _)| *rownum).zip(events).collect();
+
+        self.correct_rows(rows).await
+    }
+
+    /// Returns the event at exactly `rownum`, or `None` if there isn't one. Unlike `query`,
+    /// this never skips ahead to a later event when `rownum` was compacted or truncated away.
+    #[tracing::instrument]
+    pub async fn get(&self, rownum: u64) -> Result<Option<Event>> {
+        ensure!(self.run_state == RunState::Running, Error::Stopped);
+
+        if !self.primary_index.contains_key(&rownum) {
+            return Ok(None);
+        }
+
+        Ok(self.read_rows(&[rownum]).await?.pop())
+    }
+
+    /// Like `get`, but with the event's latest correction applied.
+    #[tracing::instrument]
+    pub async fn get_corrected(&self, rownum: u64) -> Result<Option<Event>> {
+        let Some(event) = self.get(rownum).await? else {
+            return Ok(None);
+        };
+
+        Ok(self.correct_rows(vec![(rownum, event)]).await?.pop())
+    }
+
+    /// Returns up to `limit` events with the `type` attribute `event_type`, from rownum `start` onward.
+    #[tracing::instrument]
+    pub async fn query_by_type(&self, event_type: &str, start: u64, limit: usize) -> Result<Vec<Event>> {
+        let indexes = self.secondary_indexes().await?;
+        self.read_rows(indexes.type_rownums(event_type, start, limit)).await
+    }
+
+    /// Reads up to `limit` events with the given `subject`, in order, starting from rownum
+    /// `start`. Streams without `index_subjects` enabled are scanned from `start` instead.
+    #[tracing::instrument]
+    pub async fn query_by_subject(&self, subject: &str, start: u64, limit: usize) -> Result<Vec<Event>> {
+        ensure!(self.run_state == RunState::Running, Error::Stopped);
+
+        if self.metadata.index_subjects {
+            let rownums = self.secondary_indexes().await?.subject_index.get(subject).map_or(&[][..], |rownums| {
+                let first = rownums.partition_point(|rownum| *rownum < start);
+                &rownums[first..first.saturating_add(limit).min(rownums.len())]
+            });
+
+            return self.read_rows(rownums).await;
+        }
+
+        self.query_stream(start, usize::MAX)
+            .try_filter(|event| futures::future::ready(event.subject() == Some(subject)))
+            .take(limit)
+            .try_collect()
+            .await
+    }
+
+    /// Like `query_by_type`, but with corrections applied as in `query_corrected`.
+    #[tracing::instrument]
+    pub async fn query_by_type_corrected(&self, event_type: &str, start: u64, limit: usize) -> Result<Vec<Event>> {
+        let rownums = self.secondary_indexes().await?.type_rownums(event_type, start, limit);
+        let events = self.read_rows(rownums).await?;
+        let rows = rownums.iter().copied().zip(events).collect();
+
+        self.correct_rows(rows).await
+    }
+
+    /// Reads up to `limit` events from rownum `start` onward whose `time` is in `range`, with
+    /// their rownums, optionally only those with the `type` attribute `event_type`. There's no
+    /// index of event times, so the stream is scanned from `start` until enough are found.
+    #[tracing::instrument]
+    pub async fn query_by_time(&self, range: &TimeRange, event_type: Option<&str>, start: u64, limit: usize) -> Result<Vec<(u64, Event)>> {
+        ensure!(self.run_state == RunState::Running, Error::Stopped);
+
+        let mut events = pin!(self.query_stream(start, usize::MAX));
+        let mut matched = Vec::new();
+
+        for rownum in self.primary_index.range(start..).map(|(rownum, _)| *rownum) {
+            if matched.len() >= limit {
+                break;
+            }
+
+            let Some(event) = events.try_next().await? else {
+                break;
+            };
+
+            if range.contains(&event) && event_type.is_none_or(|event_type| event.ty() == event_type) {
+                matched.push((rownum, event));
+            }
+        }
+
+        Ok(matched)
+    }
+
+    /// Like `query_by_time`, but with corrections applied as in `query_corrected`.
+    #[tracing::instrument]
+    pub async fn query_by_time_corrected(&self, range: &TimeRange, event_type: Option<&str>, start: u64, limit: usize) -> Result<Vec<(u64, Event)>> {
+        let rows = self.query_by_time(range, event_type, start, limit).await?;
+        let rownums: Vec<u64> = rows.iter().map(|(rownum, _)| *rownum).collect();
+        let events = self.correct_rows(rows).await?;
+
+        Ok(rownums.into_iter().zip(events).collect())
+    }
+
+    /// Rownum of the first of the stream's last `limit` events, so that reading `limit` events
+    /// from it reads the tail of the stream. Gaps in the rownums are skipped over.
+    pub fn tail_start(&self, limit: usize) -> u64 {
+        match self.primary_index.keys().rev().take(limit).next_back() {
+            Some(rownum) => *rownum,
+            None => self.primary_index.keys().next_back().map_or(self.base_revision, |rownum| rownum + 1),
+        }
+    }
+
+    /// Describes the page of up to `limit` events from rownum `start` onward, optionally only
+    /// those with the `type` attribute `event_type`, as `query` and `query_by_type` read them.
+    pub async fn page(&self, event_type: Option<&str>, start: u64, limit: usize) -> Result<Page> {
+        let (before, mut rownums): (Vec<u64>, Vec<u64>) = match event_type {
+            Some(event_type) => {
+                let rownums = self.secondary_indexes().await?.type_index.get(event_type).map_or(&[][..], Vec::as_slice);
+                let first = rownums.partition_point(|rownum| *rownum < start);
+
+                (
+                    rownums[..first].iter().rev().take(limit).copied().collect(),
+                    rownums[first..].iter().take(limit.saturating_add(1)).copied().collect(),
+                )
+            },
+            None => (
+                self.primary_index.range(..start).rev().take(limit).map(|(rownum, _)| *rownum).collect(),
+                self.primary_index.range(start..).take(limit.saturating_add(1)).map(|(rownum, _)| *rownum).collect(),
+            ),
+        };
+
+        let next = rownums.get(limit).copied();
+        rownums.truncate(limit);
+
+        Ok(Page {
+            rownums,
+            prev: before.last().copied(),
+            next,
+        })
+    }
+
+    /// Reads the events at each of `rownums`, which must be in the primary index.
+    async fn read_rows(&self, rownums: &[u64]) -> Result<Vec<Event>> {
+        ensure!(self.run_state == RunState::Running, Error::Stopped);
+
+        let mut reader = SegmentReader::default();
+        let mut events = Vec::with_capacity(rownums.len());
+
+        for rownum in rownums {
+            let (segment, offset) = self.primary_index.get(rownum)
+                .with_context(|| format!("Row {} is not in the index", rownum))?;
+            let segment_file = self.segment_file(*segment);
+
+            let record = reader.read_record(*segment, &segment_file, *offset).await
+                .with_context(|| format!("Failed to read row {} (offset {}) from DB at {:?}", rownum, offset, segment_file.path()))?;
+            let line = String::from_utf8(segment_file.format().row(&record).to_vec())
+                .with_context(|| format!("Row {} (offset {}) of DB at {:?} is not UTF-8", rownum, offset, segment_file.path()))?;
+
+            events.push(decode_event(line)?);
+        }
+
+        Ok(events)
+    }
+
+    /// Replaces the data of each corrected event in `rows` with that of its latest correction.
+    async fn correct_rows(&self, rows: Vec<(u64, Event)>) -> Result<Vec<Event>> {
+        let mut corrected = Vec::with_capacity(rows.len());
+        let corrections = &self.secondary_indexes().await?.corrections;
+
+        for (rownum, event) in rows {
+            match corrections.get(&rownum) {
+                Some(correction_rownum) => {
+                    let correction = self.get(*correction_rownum).await?
+                        .with_context(|| format!("Correction {} of row {} is missing", correction_rownum, rownum))?;
+
+                    corrected.push(apply_correction(event, correction, *correction_rownum));
+                },
+                None => corrected.push(event),
+            }
+        }
+
+        Ok(corrected)
+    }
+
+    /// Appends `correction` as a correction of the event at `rownum`. Correcting a correction
+    /// corrects the event that one corrects instead, so every correction names an original
+    /// event, and the latest correction of that event is the one applied to it.
+    #[tracing::instrument]
+    pub async fn correct(&mut self, rownum: u64, correction: Event) -> Result<u64> {
+        let correction = encode_correction(rownum, correction, self.max_event_bytes)?;
+        self.correct_encoded(rownum, correction).await
+    }
+
+    /// Like `correct`, with the correction already encoded by `encode_correction`. It's only
+    /// encoded again if `rownum` is itself a correction, so the event it names changes.
+    #[tracing::instrument]
+    pub async fn correct_encoded(&mut self, rownum: u64, correction: EncodedEvents) -> Result<u64> {
+        ensure!(correction.len() == 1, "Expected exactly one correction but got {}", correction.len());
+
+        let target = self.get(rownum).await?
+            .ok_or(Error::EventNotFound)?;
+        let target_rownum = corrected_rownum(&target).unwrap_or(rownum);
+
+        let correction = match correction.events.first().and_then(corrected_rownum) {
+            Some(corrects) if corrects == target_rownum => correction,
+            _ => {
+                let event = correction.events.into_iter().next().context("Expected a correction")?;
+                encode_correction(target_rownum, event, self.max_event_bytes)?
+            },
+        };
+
+        self.append_encoded(correction, ExpectedRevision::Any).await
+    }
+
+    #[tracing::instrument]
+    pub async fn event_types(&self) -> Result<BTreeMap<String, u64>> {
+        ensure!(self.run_state == RunState::Running, Error::Stopped);
+
+        let types = self.secondary_indexes().await?.type_index.iter()
+            .map(|(event_type, rownums)| (event_type.clone(), rownums.len() as u64))
+            .collect();
+
+        Ok(types)
+    }
+
+    /// Counts events by their `time` attribute in buckets `bucket` long, aligned to the Unix epoch.
+    /// Timed events before `since`, a Unix timestamp, are left out.
+    #[tracing::instrument]
+    pub async fn activity(&self, bucket: Duration, since: Option<i64>) -> Result<Activity> {
+        let bucket_secs = i64::try_from(bucket.as_secs()).context("Activity bucket is too long")?;
+        ensure!(bucket_secs > 0, "Activity buckets must be at least one second long");
+
+        let mut activity = Activity::default();
+        let mut events = pin!(self.query_stream(0, usize::MAX));
+
+        while let Some(event) = events.try_next().await? {
+            let Some(time) = event.time() else {
+                activity.untimed += 1;
+                continue;
+            };
+
+            let timestamp = time.timestamp();
+            if since.is_some_and(|since| timestamp < since) {
+                continue;
+            }
+
+            let bucket_start = timestamp.div_euclid(bucket_secs) * bucket_secs;
+            *activity.buckets.entry(bucket_start).or_insert(0) += 1;
+        }
+
+        Ok(activity)
+    }
+
+    #[tracing::instrument]
+    pub async fn append(
+        &mut self,
+        events: Vec<Event>,
+        expected_revision: ExpectedRevision,
+    ) -> Result<u64> {
+        let events = encode_events(events, self.max_event_bytes)?;
+        self.append_encoded(events, expected_revision).await
+    }
+
+    /// Like `append`, with the events already encoded by `encode_events`.
+    #[tracing::instrument]
+    pub async fn append_encoded(
+        &mut self,
+        events: EncodedEvents,
+        expected_revision: ExpectedRevision,
+    ) -> Result<u64> {
+        ensure!(self.run_state == RunState::Running, Error::Stopped);
+        ensure!(!events.is_empty(), "Events list cannot be empty");
+        ensure!(!self.metadata.sealed, Error::Sealed);
+
+        self.expire_reservations().await?;
+        ensure!(self.reservations.is_empty(), Error::Reserved);
+
+        let current_revision = self.revision();
+
+        let revision_match: bool = match expected_revision {
+            ExpectedRevision::Any => true,
+            ExpectedRevision::NoStream => current_revision == 0,
+            ExpectedRevision::StreamExists => current_revision > 0,
+            ExpectedRevision::Exact(revision) => current_revision == revision,
+            ExpectedRevision::AtLeast(revision) => current_revision >= revision,
+        };
+
+        if !revision_match {
+            return Err(Error::RevisionMismatch { expected: expected_revision, actual: current_revision }.into());
+        }
+
+        self.check_duplicates(&events.events).await?;
+
+        self.write_rows(events.events, events.rows).await
+    }
+
+    /// Fails with `IdConflict` or `SourceIdConflict` if any of `events` duplicates another, one
+    /// already in the stream, or one waiting in a reserved slot.
+    async fn check_duplicates(&self, events: &[Event]) -> Result<()> {
+        let indexes = self.secondary_indexes().await?;
+        let reserved = self.reservations.iter()
+            .flat_map(|reservation| reservation.slots.iter().flatten())
+            .map(|(event, _)| event);
+
+        match self.metadata.deduplication {
+            Deduplication::SourceId => {
+                let mut batch_source_ids: HashSet<_> = reserved.map(source_id).collect();
+                for event in events.iter() {
+                    let source_id = source_id(event);
+
+                    if indexes.source_ids.contains_key(&source_id) || !batch_source_ids.insert(source_id) {
+                        return Err(Error::SourceIdConflict.into());
+                    }
+                }
+            },
+            Deduplication::Id { .. } => {
+                let mut batch_ids: HashSet<_> = reserved.map(|event| event.id()).collect();
+                for event in events.iter() {
+                    if indexes.recent_id_rownums.contains_key(event.id()) || !batch_ids.insert(event.id()) {
+                        return Err(Error::IdConflict.into());
+                    }
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Writes `events`, already checked and encoded as `rows`, to the tail of the stream and
+    /// indexes them. Returns the new revision.
+    async fn write_rows(&mut self, events: Vec<Event>, rows: Vec<String>) -> Result<u64> {
+        let current_revision = self.revision();
+        let mut event_offsets = Vec::new();
+        let mut bytes = Vec::new();
+
+        for row in rows {
+            event_offsets.push(bytes.len() as u64);
+            self.segment_format.write_row(row.as_bytes(), &mut bytes)?;
+        }
+
+        let mut segment = self.active_segment();
+
+        if self.segment_is_full(segment).await? {
+            self.seal_segment(segment).await?;
+
+            segment += 1;
+            debug!("Rolling over to new segment at {:?}", self.segment_path(segment));
+        }
+
+        let events_path = self.segment_path(segment);
+        let mut file = open_for_append(&events_path).await?;
+        let end_offset = file.seek(SeekFrom::End(0)).await
+            .with_context(|| format!("Failed to seek to end of file for DB at {:?}", events_path))?;
+
+        if self.segments.last() != Some(&segment) {
+            self.segments.push(segment);
+        }
+
+        // A new segment file starts with its format's header, written along with the first rows.
+        let header = if end_offset == 0 { self.segment_format.header() } else { b"" };
+        let start_offset = end_offset + header.len() as u64;
+
+        file.write_all(&[header, &bytes].concat()).await
+            .with_context(|| format!("Failed to write event to file for DB at {:?}", events_path))?;
+        file.flush().await
+            .with_context(|| format!("Failed to flush file for DB at {:?}", events_path))?;
+
+        let mut records = Vec::with_capacity(event_offsets.len() * INDEX_RECORD_LEN);
+        for (i, event_offset) in event_offsets.iter().enumerate() {
+            records.extend(index_record(current_revision + i as u64, start_offset + event_offset));
+        }
+
+        let index_path = self.segment_index_path(segment);
+        let mut index_file = File::options()
+            .append(true)
+            .create(true)
+            .open(&index_path).await
+            .with_context(|| format!("Failed to open file for index at {:?}", index_path))?;
+
+        index_file.write_all(&records).await
+            .with_context(|| format!("Failed to write index at {:?}", index_path))?;
+        index_file.flush().await
+            .with_context(|| format!("Failed to flush index at {:?}", index_path))?;
+
+        for (i, event_offset) in event_offsets.iter().enumerate() {
+            self.primary_index.insert(current_revision + i as u64, (segment, start_offset + event_offset));
+        }
+
+        // Indexes that haven't been built yet will read these events from disk when they are.
+        if let Some(indexes) = self.secondary_indexes.get_mut() {
+            for (i, event) in events.iter().enumerate() {
+                indexes.index_event(current_revision + i as u64, event, &self.metadata);
+            }
+        }
+
+        let revision = current_revision + events.len() as u64;
+
+        for (i, event) in events.into_iter().enumerate() {
+            // Sending only fails when nobody is subscribed.
+            let _ = self.appended.send((current_revision + i as u64, event));
+        }
+
+        if let Some(stats) = self.stats_cache.as_mut() {
+            stats.revision = revision;
+            stats.count = self.primary_index.len() as u64;
+            stats.usage += (header.len() + bytes.len()) as u64;
+            stats.last_modified = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(stats.last_modified);
+        }
+
+        Ok(revision)
+    }
+
+    /// Reserves the next `count` rownums after the stream's tail and any earlier reservations,
+    /// to be filled in any order with `append_reserved`. Returns the first reserved rownum.
+    ///
+    /// Events are only written to disk once every slot before them is filled, so until then
+    /// they can't be read, the stream's revision stays put, and a restart loses them. Plain
+    /// appends fail with `Reserved` until every reservation is written out. A reservation still
+    /// unfilled after `ttl` has its empty slots filled with `TOMBSTONE_TYPE` events the next
+    /// time the stream is written to, so rownums are never left out.
+    #[tracing::instrument]
+    pub async fn reserve(&mut self, count: usize, ttl: Duration) -> Result<u64> {
+        ensure!(self.run_state == RunState::Running, Error::Stopped);
+        ensure!(count > 0, "Cannot reserve zero rownums");
+        ensure!(!self.metadata.sealed, Error::Sealed);
+
+        self.expire_reservations().await?;
+
+        let start = match self.reservations.back() {
+            Some(reservation) => reservation.start + reservation.slots.len() as u64,
+            None => self.revision(),
+        };
+
+        self.reservations.push_back(Reservation {
+            start,
+            expires_at: SystemTime::now() + ttl,
+            slots: (0..count).map(|_| None).collect(),
+        });
+
+        Ok(start)
+    }
+
+    /// Fills the reserved slots from rownum `start` on with `events`, then writes out every
+    /// filled slot that no longer has an empty one before it. All of the slots must belong to
+    /// the same reservation and be unfilled. Returns the stream's revision afterward.
+    #[tracing::instrument]
+    pub async fn append_reserved(&mut self, start: u64, events: Vec<Event>) -> Result<u64> {
+        let events = encode_events(events, self.max_event_bytes)?;
+        self.append_reserved_encoded(start, events).await
+    }
+
+    /// Like `append_reserved`, with the events already encoded by `encode_events`.
+    #[tracing::instrument]
+    pub async fn append_reserved_encoded(&mut self, start: u64, events: EncodedEvents) -> Result<u64> {
+        ensure!(self.run_state == RunState::Running, Error::Stopped);
+        ensure!(!events.is_empty(), "Events list cannot be empty");
+        ensure!(!self.metadata.sealed, Error::Sealed);
+
+        self.expire_reservations().await?;
+
+        // Nothing past the last rownum can be reserved.
+        let Some(end) = start.checked_add(events.len() as u64) else {
+            return Err(Error::NotReserved { start, end: u64::MAX }.into());
+        };
+        let not_reserved = Error::NotReserved { start, end };
+
+        let Some(reservation) = self.reservations.iter()
+            .position(|reservation| reservation.start <= start && end <= reservation.start + reservation.slots.len() as u64)
+        else {
+            return Err(not_reserved.into());
+        };
+
+        let first_slot = (start - self.reservations[reservation].start) as usize;
+
+        if self.reservations[reservation].slots.range(first_slot..first_slot + events.len()).any(Option::is_some) {
+            return Err(not_reserved.into());
+        }
+
+        self.check_duplicates(&events.events).await?;
+
+        for (i, slot) in events.events.into_iter().zip(events.rows).enumerate() {
+            self.reservations[reservation].slots[first_slot + i] = Some(slot);
+        }
+
+        self.write_reserved().await?;
+
+        Ok(self.revision())
+    }
+
+    /// Fills the empty slots of expired reservations with tombstones and writes them out.
+    async fn expire_reservations(&mut self) -> Result<()> {
+        let now = SystemTime::now();
+        let mut expired = false;
+
+        for reservation in self.reservations.iter_mut().filter(|reservation| reservation.expires_at <= now) {
+            for slot in reservation.slots.iter_mut().filter(|slot| slot.is_none()) {
+                let tombstone = EventBuilderV10::new()
+                    .id(Uuid::now_v7().to_string())
+                    .source("hematite")
+                    .ty(TOMBSTONE_TYPE)
+                    .build()
+                    .context("Failed to build tombstone event")?;
+                let json = serde_json::to_string(&tombstone).context("Failed to JSONify event")?;
+
+                *slot = Some((tombstone, encode_row(&json)));
+                expired = true;
+            }
+        }
+
+        if expired {
+            warn!("Filled unused reserved rownums of DB at {:?} with tombstones", self.path);
+        }
+
+        self.write_reserved().await
+    }
+
+    /// Writes out the filled slots at the front of the reservations, stopping at the first
+    /// empty one.
+    async fn write_reserved(&mut self) -> Result<()> {
+        while let Some(reservation) = self.reservations.front() {
+            let (events, rows): (Vec<Event>, Vec<String>) = reservation.slots.iter().map_while(Clone::clone).unzip();
+            let filled = events.len();
+            let done = filled == reservation.slots.len();
+
+            if filled > 0 {
+                self.write_rows(events, rows).await?;
+            }
+
+            if let Some(reservation) = self.reservations.front_mut() {
+                reservation.slots.drain(..filled);
+                reservation.start += filled as u64;
+            }
+
+            if !done {
+                break;
+            }
+
+            self.reservations.pop_front();
+        }
+
+        Ok(())
+    }
+
+    /// Whether appends to `segment` have to roll over to a new segment.
+    async fn segment_is_full(&self, segment: u64) -> Result<bool> {
+        if self.compressed_segments.contains_key(&segment) {
+            return Ok(true);
+        }
+
+        let Some(segment_bytes) = self.segment_bytes else {
+            return Ok(false);
+        };
+
+        let events_path = self.segment_path(segment);
+        let len = match fs::metadata(&events_path).await {
+            Ok(metadata) => metadata.len(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err).with_context(|| format!("Failed to access metadata of DB path {:?}", events_path)),
+        };
+
+        Ok(len > 0 && len >= segment_bytes)
+    }
+
+    /// Syncs a segment that appends are rolling over from, then compresses it if enabled.
+    /// Binary segments stay uncompressed.
+    async fn seal_segment(&mut self, segment: u64) -> Result<()> {
+        if self.compressed_segments.contains_key(&segment) {
+            return Ok(());
+        }
+
+        let events_path = self.segment_path(segment);
+        File::open(&events_path).await
+            .with_context(|| format!("Failed to open sealed segment at {:?}", events_path))?
+            .sync_all().await
+            .with_context(|| format!("Failed to sync sealed segment at {:?}", events_path))?;
+
+        if let Some(block_events) = self.compression_block_events.filter(|_| self.segment_format == StorageFormat::Ndjson) {
+            self.compress_segment(segment, block_events).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites a plain segment as gzip blocks of `block_events` lines each. The gzip file and
+    /// its block table are moved into place before the plain file is removed, so a crash
+    /// leaves at least one complete copy.
+    async fn compress_segment(&mut self, segment: u64, block_events: usize) -> Result<()> {
+        let events_path = self.segment_path(segment);
+        let data = fs::read(&events_path).await
+            .with_context(|| format!("Failed to read segment to compress at {:?}", events_path))?;
+
+        let lines: Vec<&[u8]> = data.split_inclusive(|byte| *byte == b'\n').collect();
+        let mut compressed = Vec::new();
+        let mut blocks = Vec::new();
+        let mut data_offset = 0;
+
+        for block_lines in lines.chunks(block_events.max(1)) {
+            blocks.push(Block { data_offset, file_offset: compressed.len() as u64 });
+
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            for line in block_lines {
+                encoder.write_all(line).context("Failed to compress block")?;
+                data_offset += line.len() as u64;
+            }
+
+            compressed.extend(encoder.finish().context("Failed to compress block")?);
+        }
+
+        blocks.push(Block { data_offset, file_offset: compressed.len() as u64 });
+
+        let compressed_path = self.compressed_segment_path(segment);
+        let temp_compressed_path = compressed_path.with_extension("gz.tmp");
+        let mut temp_compressed = File::create(&temp_compressed_path).await
+            .with_context(|| format!("Failed to create temp file for compressing DB at {:?}", temp_compressed_path))?;
+        temp_compressed.write_all(&compressed).await
+            .with_context(|| format!("Failed to write compressed segment to {:?}", temp_compressed_path))?;
+        temp_compressed.sync_all().await
+            .with_context(|| format!("Failed to sync {:?}", temp_compressed_path))?;
+
+        let records: Vec<u8> = blocks.iter()
+            .flat_map(|block| [block.data_offset.to_be_bytes(), block.file_offset.to_be_bytes()].concat())
+            .collect();
+
+        let blocks_path = self.segment_blocks_path(segment);
+        let temp_blocks_path = blocks_path.with_extension("blocks.tmp");
+        fs::write(&temp_blocks_path, records).await
+            .with_context(|| format!("Failed to write block table to {:?}", temp_blocks_path))?;
+
+        fs::rename(&temp_blocks_path, &blocks_path).await
+            .with_context(|| format!("Failed to move block table into place at {:?}", blocks_path))?;
+        fs::rename(&temp_compressed_path, &compressed_path).await
+            .with_context(|| format!("Failed to move compressed segment into place at {:?}", compressed_path))?;
+        fs::remove_file(&events_path).await
+            .with_context(|| format!("Failed to remove compressed segment's plain file at {:?}", events_path))?;
+
+        debug!("Compressed segment at {:?} from {} to {} bytes", events_path, data.len(), compressed.len());
+
+        self.compressed_segments.insert(segment, Arc::new(blocks));
+        self.stats_cache = None;
+
+        Ok(())
+    }
+
+    /// Permanently removes every event with a rownum below `revision`, returning how many
+    /// were removed. Surviving events keep their rownums, and truncated rownums are never
+    /// reused. A `revision` past the end of the stream truncates everything.
+    ///
+    /// Sealed segments that lose all their events are deleted. A segment that loses only
+    /// some, or the active segment, is rewritten to temp files and renamed into place,
+    /// so a crash leaves either the old or new version of each file.
+    #[tracing::instrument]
+    pub async fn truncate_before(&mut self, revision: u64) -> Result<u64> {
+        ensure!(self.run_state == RunState::Running, Error::Stopped);
+
+        let revision = revision.min(self.revision());
+        if revision <= self.base_revision {
+            return Ok(0);
+        }
+
+        let removed = self.primary_index.range(..revision).count() as u64;
+        let active_segment = self.active_segment();
+
+        let mut affected_segments: Vec<u64> = self.primary_index.range(..revision).map(|(_, (segment, _))| *segment).collect();
+        affected_segments.dedup();
+
+        // Segments are rewritten before the base moves, each with its events ahead of its
+        // index, so a crash partway through leaves sidecars that still number the surviving
+        // rows, and at worst some rows below the base that a retry removes.
+        for segment in affected_segments {
+            let first_offset = self.primary_index.range(revision..).next()
+                .filter(|(_, (first_segment, _))| *first_segment == segment)
+                .map(|(_, (_, offset))| *offset);
+
+            if first_offset.is_none() && segment != active_segment {
+                remove_file_if_exists(&self.segment_path(segment)).await?;
+                remove_file_if_exists(&self.compressed_segment_path(segment)).await?;
+                remove_file_if_exists(&self.segment_blocks_path(segment)).await?;
+                remove_file_if_exists(&self.segment_index_path(segment)).await?;
+                continue;
+            }
+
+            self.rewrite_segment_from(segment, revision, first_offset).await?;
+        }
+
+        let temp_base_path = self.path.join("events.base.tmp");
+        let mut temp_base = File::create(&temp_base_path).await
+            .with_context(|| format!("Failed to create {:?}", temp_base_path))?;
+        temp_base.write_all(&revision.to_be_bytes()).await
+            .with_context(|| format!("Failed to write base revision to {:?}", temp_base_path))?;
+        temp_base.sync_all().await
+            .with_context(|| format!("Failed to sync {:?}", temp_base_path))?;
+
+        fs::rename(&temp_base_path, self.base_path()).await
+            .with_context(|| format!("Failed to move base revision into place for DB at {:?}", self.path))?;
+        sync_dir(&self.path).await?;
+
+        self.load().await?;
+
+        Ok(removed)
+    }
+
+    /// Fraction of the stream's events that `compact_by_subject` would remove. Streams without
+    /// `index_subjects` are scanned to find out.
+    #[tracing::instrument]
+    pub async fn dirty_ratio(&self) -> Result<f64> {
+        if self.primary_index.is_empty() {
+            return Ok(0.0);
+        }
+
+        let superseded: usize =
+            if self.metadata.index_subjects {
+                self.secondary_indexes().await?.subject_index.values().map(|rownums| rownums.len().saturating_sub(1)).sum()
+            } else {
+                let mut subject_counts: HashMap<String, usize> = HashMap::new();
+                let mut events = pin!(self.query_stream(0, usize::MAX));
+
+                while let Some(event) = events.try_next().await? {
+                    if let Some(subject) = event.subject() {
+                        *subject_counts.entry(subject.to_string()).or_default() += 1;
+                    }
+                }
+
+                subject_counts.values().map(|count| count - 1).sum()
+            };
+
+        Ok(superseded as f64 / self.primary_index.len() as f64)
+    }
+
+    /// Rewrites the stream to keep only the latest event for each `subject`, dropping the ones
+    /// it supersedes, and returns how many were removed. Events without a subject are all kept.
+    ///
+    /// Surviving events keep their rownums, which leaves gaps between them. Those rownums are
+    /// recorded only in the index sidecars, so a compacted segment whose sidecar is lost gets
+    /// its events renumbered consecutively when the index is rebuilt.
+    #[tracing::instrument]
+    pub async fn compact_by_subject(&mut self) -> Result<u64> {
+        ensure!(self.run_state == RunState::Running, Error::Stopped);
+
+        let rownums: Vec<u64> = self.primary_index.keys().copied().collect();
+        let mut latest_by_subject: HashMap<String, u64> = HashMap::new();
+        let mut subjects: Vec<(u64, String)> = Vec::new();
+
+        {
+            let mut events = pin!(self.query_stream(0, usize::MAX));
+            let mut rownums = rownums.iter();
+
+            while let Some(event) = events.try_next().await? {
+                let rownum = *rownums.next().context("Stream has more events than its index")?;
+
+                if let Some(subject) = event.subject() {
+                    latest_by_subject.insert(subject.to_string(), rownum);
+                    subjects.push((rownum, subject.to_string()));
+                }
+            }
+        }
+
+        let superseded: HashSet<u64> = subjects.into_iter()
+            .filter(|(rownum, subject)| latest_by_subject.get(subject) != Some(rownum))
+            .map(|(rownum, _)| rownum)
+            .collect();
+
+        if superseded.is_empty() {
+            return Ok(0);
+        }
+
+        let mut affected_segments: Vec<u64> = superseded.iter()
+            .filter_map(|rownum| self.primary_index.get(rownum))
+            .map(|(segment, _)| *segment)
+            .collect();
+        affected_segments.sort_unstable();
+        affected_segments.dedup();
+
+        for segment in affected_segments {
+            self.rewrite_segment_without(segment, &superseded).await?;
+        }
+
+        self.load().await?;
+
+        Ok(superseded.len() as u64)
+    }
+
+    /// Rewrites `segment` without the events in `removed`, keeping the others' rownums.
+    /// A sealed segment left with no events is deleted.
+    async fn rewrite_segment_without(&mut self, segment: u64, removed: &HashSet<u64>) -> Result<()> {
+        let segment_file = self.segment_file(segment);
+        let mut reader = SegmentReader::default();
+        let mut events = segment_file.format().header().to_vec();
+        let mut records = Vec::new();
+
+        for (rownum, (_, offset)) in self.primary_index.iter().filter(|(_, (row_segment, _))| *row_segment == segment) {
+            if removed.contains(rownum) {
+                continue;
+            }
+
+            let record = reader.read_record(segment, &segment_file, *offset).await
+                .with_context(|| format!("Failed to read row {} (offset {}) from DB at {:?}", rownum, offset, segment_file.path()))?;
+
+            records.extend(index_record(*rownum, events.len() as u64));
+            events.extend(record);
+        }
+
+        if records.is_empty() && segment != self.active_segment() {
+            remove_file_if_exists(&self.segment_path(segment)).await?;
+            remove_file_if_exists(&self.compressed_segment_path(segment)).await?;
+            remove_file_if_exists(&self.segment_blocks_path(segment)).await?;
+            remove_file_if_exists(&self.segment_index_path(segment)).await?;
+            self.compressed_segments.remove(&segment);
+            return sync_dir(&self.path).await;
+        }
+
+        let events_path = self.segment_path(segment);
+        let temp_events_path = events_path.with_extension("ndjson.tmp");
+        let mut temp_events = File::create(&temp_events_path).await
+            .with_context(|| format!("Failed to create temp file for compacting DB at {:?}", temp_events_path))?;
+        temp_events.write_all(&events).await
+            .with_context(|| format!("Failed to write compacted events to {:?}", temp_events_path))?;
+        temp_events.sync_all().await
+            .with_context(|| format!("Failed to sync {:?}", temp_events_path))?;
+
+        let index_path = self.segment_index_path(segment);
+        let temp_index_path = index_path.with_extension("index.tmp");
+        fs::write(&temp_index_path, records).await
+            .with_context(|| format!("Failed to write compacted index to {:?}", temp_index_path))?;
+
+        // The events go first, so a crash between the renames leaves the old sidecar next to
+        // the new events, where `load` notices it no longer fits and rebuilds it. A compressed
+        // segment stops being read from its gzip file before the plain one appears, and `load`
+        // deletes a gzip file and block table left next to a plain file by a crash.
+        let compressed = matches!(segment_file, SegmentFile::Compressed(..));
+        self.compressed_segments.remove(&segment);
+
+        fs::rename(&temp_events_path, &events_path).await
+            .with_context(|| format!("Failed to move compacted events into place at {:?}", events_path))?;
+
+        if compressed {
+            remove_file_if_exists(&self.compressed_segment_path(segment)).await?;
+            remove_file_if_exists(&self.segment_blocks_path(segment)).await?;
+        }
+
+        fs::rename(&temp_index_path, &index_path).await
+            .with_context(|| format!("Failed to move compacted index into place at {:?}", index_path))?;
+        sync_dir(&self.path).await?;
+
+        if compressed {
+            if let Some(block_events) = self.compression_block_events {
+                self.compress_segment(segment, block_events).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites `segment` to hold only its events from `first_offset` onward, which are the
+    /// events with rownums of at least `revision`, or nothing at all if `first_offset` is `None`.
+    ///
+    /// A compressed segment is rewritten as a plain one, and compressed again afterward.
+    async fn rewrite_segment_from(&mut self, segment: u64, revision: u64, first_offset: Option<u64>) -> Result<()> {
+        let events_path = self.segment_path(segment);
+        let temp_events_path = events_path.with_extension("ndjson.tmp");
+        let mut temp_events = File::create(&temp_events_path).await
+            .with_context(|| format!("Failed to create temp file for truncating DB at {:?}", temp_events_path))?;
+
+        let segment_file = self.segment_file(segment);
+        let header = segment_file.format().header();
+
+        temp_events.write_all(header).await
+            .with_context(|| format!("Failed to write header to {:?}", temp_events_path))?;
+
+        if let Some(first_offset) = first_offset {
+            match &segment_file {
+                SegmentFile::Plain(_) | SegmentFile::Binary(_) => {
+                    let mut events = File::open(&events_path).await
+                        .with_context(|| format!("Could not open file to truncate DB at {:?}", events_path))?;
+                    events.seek(SeekFrom::Start(first_offset)).await
+                        .with_context(|| format!("Failed to seek to offset {} of DB at {:?}", first_offset, events_path))?;
+                    tokio::io::copy(&mut events, &mut temp_events).await
+                        .with_context(|| format!("Failed to copy surviving events of DB at {:?}", events_path))?;
+                },
+                SegmentFile::Compressed(..) => {
+                    let mut lines = segment_file.lines_from(first_offset).await?;
+                    let mut surviving = Vec::new();
+
+                    while let Some(line) = lines.next_line().await? {
+                        writeln!(&mut surviving, "{}", line).context("Failed to write JSON bytes to Vec")?;
+                    }
+
+                    temp_events.write_all(&surviving).await
+                        .with_context(|| format!("Failed to copy surviving events of DB at {:?}", segment_file.path()))?;
+                },
+            }
+        }
+
+        temp_events.sync_all().await
+            .with_context(|| format!("Failed to sync {:?}", temp_events_path))?;
+
+        let records: Vec<u8> = self.primary_index.range(revision..)
+            .take_while(|(_, (row_segment, _))| *row_segment == segment)
+            .flat_map(|(rownum, (_, offset))| index_record(*rownum, offset - first_offset.unwrap_or(0) + header.len() as u64))
+            .collect();
+
+        let index_path = self.segment_index_path(segment);
+        let temp_index_path = index_path.with_extension("index.tmp");
+        fs::write(&temp_index_path, records).await
+            .with_context(|| format!("Failed to write index to {:?}", temp_index_path))?;
+
+        fs::rename(&temp_events_path, &events_path).await
+            .with_context(|| format!("Failed to move truncated events into place at {:?}", events_path))?;
+        fs::rename(&temp_index_path, &index_path).await
+            .with_context(|| format!("Failed to move truncated index into place at {:?}", index_path))?;
+        sync_dir(&self.path).await?;
+
+        if let SegmentFile::Compressed(..) = segment_file {
+            remove_file_if_exists(&self.compressed_segment_path(segment)).await?;
+            remove_file_if_exists(&self.segment_blocks_path(segment)).await?;
+            self.compressed_segments.remove(&segment);
+
+            if let Some(block_events) = self.compression_block_events {
+                self.compress_segment(segment, block_events).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Moves the stream's directory to `path`, which must not exist yet, and reopens it there.
+    /// The stream is stopped while it moves, so it's left stopped if the move fails.
+    #[tracing::instrument]
+    pub async fn relocate(&mut self, path: &Path) -> Result<()> {
+        ensure!(!fs::try_exists(path).await?, "Can't move stream to {:?}, which already exists", path);
+
+        self.stop().await?;
+
+        fs::rename(&self.path, path).await
+            .with_context(|| format!("Failed to move stream from {:?} to {:?}", self.path, path))?;
+        self.path = path.to_path_buf();
+
+        self.start().await?;
+
+        Ok(())
+    }
+
+    pub async fn delete(&mut self) -> anyhow::Result<()> {
+        self.clear_indexes();
+        self.metadata = StreamMetadata::default();
+        self.created_at = None;
+
+        for segment in std::mem::take(&mut self.segments) {
+            remove_file_if_exists(&self.segment_path(segment)).await?;
+            remove_file_if_exists(&self.compressed_segment_path(segment)).await?;
+            remove_file_if_exists(&self.segment_blocks_path(segment)).await?;
+            remove_file_if_exists(&self.segment_index_path(segment)).await?;
+        }
+
+        self.compressed_segments.clear();
+        self.segment_format = self.storage_format;
+        self.reservations.clear();
+
+        remove_file_if_exists(&self.metadata_path()).await?;
+        remove_file_if_exists(&self.base_path()).await?;
+        remove_file_if_exists(&self.snapshot_path()).await?;
+
+        self.base_revision = 0;
+
+        Ok(())
+    }
+
+    fn segment_path(&self, segment: u64) -> PathBuf {
+        match segment {
+            0 => self.path.join("events.ndjson"),
+            segment => self.path.join(format!("events.{:08}.ndjson", segment)),
+        }
+    }
+    fn compressed_segment_path(&self, segment: u64) -> PathBuf {
+        match segment {
+            0 => self.path.join("events.ndjson.gz"),
+            segment => self.path.join(format!("events.{:08}.ndjson.gz", segment)),
+        }
+    }
+    fn segment_blocks_path(&self, segment: u64) -> PathBuf {
+        match segment {
+            0 => self.path.join("events.blocks"),
+            segment => self.path.join(format!("events.{:08}.blocks", segment)),
+        }
+    }
+    fn segment_index_path(&self, segment: u64) -> PathBuf {
+        match segment {
+            0 => self.path.join("events.index"),
+            segment => self.path.join(format!("events.{:08}.index", segment)),
+        }
+    }
+    fn base_path(&self) -> PathBuf {
+        self.path.join("events.base")
+    }
+    fn metadata_path(&self) -> PathBuf {
+        self.path.join("meta.json")
+    }
+    fn snapshot_path(&self) -> PathBuf {
+        self.path.join("snapshot")
+    }
+}
+
+/// Where a compressed segment's block starts, both in the segment's NDJSON and in its gzip file.
+/// A segment's block table ends with an entry holding the length of each.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Block {
+    data_offset: u64,
+    file_offset: u64,
+}
+
+/// Index of the block holding the byte at `offset` of a compressed segment's NDJSON.
+fn block_containing(blocks: &[Block], offset: u64) -> Option<usize> {
+    let block = blocks.partition_point(|block| block.data_offset <= offset).checked_sub(1)?;
+
+    (block + 1 < blocks.len()).then_some(block)
+}
+
+async fn read_block<R: AsyncRead + AsyncSeek + Unpin>(reader: &mut R, blocks: &[Block], block: usize) -> Result<Vec<u8>> {
+    use std::io::Read;
+
+    let (start, end) = (blocks[block], blocks[block + 1]);
+
+    reader.seek(SeekFrom::Start(start.file_offset)).await
+        .with_context(|| format!("Failed to seek to block {} at offset {}", block, start.file_offset))?;
+
+    let mut compressed = vec![0u8; (end.file_offset - start.file_offset) as usize];
+    reader.read_exact(&mut compressed).await
+        .with_context(|| format!("Failed to read block {} at offset {}", block, start.file_offset))?;
+
+    let mut data = Vec::with_capacity((end.data_offset - start.data_offset) as usize);
+    GzDecoder::new(compressed.as_slice()).read_to_end(&mut data)
+        .with_context(|| format!("Failed to decompress block {}", block))?;
+
+    ensure!(data.len() as u64 == end.data_offset - start.data_offset, "Block {} decompressed to {} bytes instead of {}", block, data.len(), end.data_offset - start.data_offset);
+
+    Ok(data)
+}
+
+/// A segment's events file, which is plain NDJSON until the segment is sealed and compressed,
+/// or length-prefixed rows in `StorageFormat::Binary`.
+#[derive(Clone, Debug)]
+enum SegmentFile {
+    Plain(PathBuf),
+    Compressed(PathBuf, Arc<Vec<Block>>),
+    Binary(PathBuf),
+}
+
+impl SegmentFile {
+    fn path(&self) -> &Path {
+        match self {
+            SegmentFile::Plain(path) | SegmentFile::Compressed(path, _) | SegmentFile::Binary(path) => path,
+        }
+    }
+
+    fn format(&self) -> StorageFormat {
+        match self {
+            SegmentFile::Plain(_) | SegmentFile::Compressed(..) => StorageFormat::Ndjson,
+            SegmentFile::Binary(_) => StorageFormat::Binary,
+        }
+    }
+
+    /// Reads rows from the one starting at `offset` of the segment's data. An offset within a
+    /// binary segment's header reads from its first row.
+    async fn lines_from(&self, offset: u64) -> Result<SegmentLines> {
+        let mut file = File::open(self.path()).await
+            .with_context(|| format!("Could not open file to read DB at {:?}", self.path()))?;
+
+        match self {
+            SegmentFile::Plain(events_path) => {
+                file.seek(SeekFrom::Start(offset)).await
+                    .with_context(|| format!("Failed to seek to offset {} from DB at {:?}", offset, events_path))?;
+
+                Ok(SegmentLines::Plain(BufReader::new(file).lines()))
+            },
+            SegmentFile::Binary(events_path) => {
+                let offset = offset.max(BINARY_HEADER.len() as u64);
+
+                file.seek(SeekFrom::Start(offset)).await
+                    .with_context(|| format!("Failed to seek to offset {} from DB at {:?}", offset, events_path))?;
+
+                Ok(SegmentLines::Binary(BufReader::new(file)))
+            },
+            SegmentFile::Compressed(events_path, blocks) => {
+                let (data, position, next_block) = match block_containing(blocks, offset) {
+                    Some(block) => {
+                        let data = read_block(&mut file, blocks, block).await
+                            .with_context(|| format!("Failed to read DB at {:?}", events_path))?;
+
+                        (data, (offset - blocks[block].data_offset) as usize, block + 1)
+                    },
+                    None => (vec![], 0, blocks.len().saturating_sub(1)),
+                };
+
+                Ok(SegmentLines::Compressed { file, blocks: blocks.clone(), next_block, data, position })
+            },
+        }
+    }
+}
+
+/// A segment's rows in order, without their newlines or length prefixes.
+enum SegmentLines {
+    Plain(Lines<BufReader<File>>),
+    Binary(BufReader<File>),
+    Compressed {
+        file: File,
+        blocks: Arc<Vec<Block>>,
+        next_block: usize,
+        /// The decompressed block being read, and the position of its next line.
+        data: Vec<u8>,
+        position: usize,
+    },
+}
+
+impl SegmentLines {
+    async fn next_line(&mut self) -> Result<Option<String>> {
+        match self {
+            SegmentLines::Plain(lines) => Ok(lines.next_line().await?),
+            SegmentLines::Binary(reader) => {
+                let len = match reader.read_u32_le().await {
+                    Ok(len) => len,
+                    Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+                    Err(err) => return Err(err.into()),
+                };
+
+                // Read through `take` rather than into a buffer of `len` bytes, so a corrupt
+                // length allocates no more than what's left of the segment.
+                let mut row = Vec::new();
+                reader.take(len as u64).read_to_end(&mut row).await?;
+                ensure!(row.len() == len as usize, "Binary segment ends partway through a row");
+
+                Ok(Some(String::from_utf8(row).context("Binary segment holds a row that is not UTF-8")?))
+            },
+            SegmentLines::Compressed { file, blocks, next_block, data, position } => {
+                loop {
+                    if let Some(rest) = data.get(*position..).filter(|rest| !rest.is_empty()) {
+                        let len = rest.iter().position(|byte| *byte == b'\n').unwrap_or(rest.len());
+                        let line = String::from_utf8(rest[..len].to_vec())
+                            .context("Compressed segment holds a line that is not UTF-8")?;
+
+                        *position += len + 1;
+                        return Ok(Some(line));
+                    }
+
+                    if *next_block + 1 >= blocks.len() {
+                        return Ok(None);
+                    }
+
+                    *data = read_block(file, blocks, *next_block).await?;
+                    *position = 0;
+                    *next_block += 1;
+                }
+            },
+        }
+    }
+}
+
+/// Keeps the most recently used segment open, and its most recently decompressed block,
+/// while reading rows that may span segments.
+#[derive(Default)]
+struct SegmentReader {
+    current: Option<(u64, BufReader<File>)>,
+    block: Option<(u64, usize, Vec<u8>)>,
+}
+
+impl SegmentReader {
+    async fn open(&mut self, segment: u64, events_path: &Path) -> Result<&mut BufReader<File>> {
+        if !matches!(&self.current, Some((current, _)) if *current == segment) {
+            let file = File::open(events_path).await
+                .with_context(|| format!("Could not open file to query DB at {:?}", events_path))?;
+
+            self.current = Some((segment, BufReader::new(file)));
+        }
+
+        let (_, reader) = self.current.as_mut().context("Segment reader was not opened")?;
+
+        Ok(reader)
+    }
+
+    /// Reads the record starting at `offset` of a segment, including its newline or length prefix.
+    async fn read_record(&mut self, segment: u64, segment_file: &SegmentFile, offset: u64) -> Result<Vec<u8>> {
+        match segment_file {
+            SegmentFile::Binary(events_path) => {
+                let reader = self.open(segment, events_path).await?;
+
+                reader.seek(SeekFrom::Start(offset)).await
+                    .with_context(|| format!("Failed to seek to offset {} from DB at {:?}", offset, events_path))?;
+
+                let len = reader.read_u32_le().await
+                    .with_context(|| format!("Failed to read row length at offset {} of DB at {:?}", offset, events_path))?;
+
+                // A corrupt length can't allocate more than what's left of the segment.
+                let mut record = len.to_le_bytes().to_vec();
+                reader.take(len as u64).read_to_end(&mut record).await
+                    .with_context(|| format!("Failed to read row at offset {} of DB at {:?}", offset, events_path))?;
+                ensure!(record.len() == 4 + len as usize, "Row at offset {} of DB at {:?} is cut short", offset, events_path);
+
+                Ok(record)
+            },
+            SegmentFile::Plain(events_path) => {
+                let reader = self.open(segment, events_path).await?;
+
+                reader.seek(SeekFrom::Start(offset)).await
+                    .with_context(|| format!("Failed to seek to offset {} from DB at {:?}", offset, events_path))?;
+
+                let mut line = vec![];
+                reader.read_until(b'\n', &mut line).await
+                    .with_context(|| format!("Failed to read line at offset {} of DB at {:?}", offset, events_path))?;
+
+                Ok(line)
+            },
+            SegmentFile::Compressed(events_path, blocks) => {
+                let block = block_containing(blocks, offset)
+                    .with_context(|| format!("Offset {} is past the end of DB at {:?}", offset, events_path))?;
+
+                if !matches!(&self.block, Some((cached_segment, cached_block, _)) if *cached_segment == segment && *cached_block == block) {
+                    let reader = self.open(segment, events_path).await?;
+                    let data = read_block(reader, blocks, block).await
+                        .with_context(|| format!("Failed to read DB at {:?}", events_path))?;
+
+                    self.block = Some((segment, block, data));
+                }
+
+                let (_, _, data) = self.block.as_ref().context("Block was not read")?;
+                let rest = &data[(offset - blocks[block].data_offset) as usize..];
+                let len = rest.iter().position(|byte| *byte == b'\n').map_or(rest.len(), |newline| newline + 1);
+
+                Ok(rest[..len].to_vec())
+            },
+        }
+    }
+}
+
+/// State of a stream returned by `Database::query_stream`.
+struct QueryCursor {
+    running: bool,
+    /// Segments still to be read, with the offset to start reading each one from.
+    segments: VecDeque<(SegmentFile, u64)>,
+    /// The segment being read, with the offset of its next line.
+    current: Option<(SegmentFile, u64, SegmentLines)>,
+    remaining: usize,
+    failed: bool,
+}
+
+impl QueryCursor {
+    async fn next_event(&mut self) -> Option<Result<Event>> {
+        if self.failed {
+            return None;
+        }
+
+        if !self.running {
+            self.failed = true;
+            return Some(Err(Error::Stopped.into()));
+        }
+
+        if self.remaining == 0 {
+            return None;
+        }
+
+        match self.read_event().await {
+            Ok(Some(event)) => {
+                self.remaining -= 1;
+                Some(Ok(event))
+            },
+            Ok(None) => None,
+            Err(err) => {
+                self.failed = true;
+                Some(Err(err))
+            },
+        }
+    }
+
+    async fn read_event(&mut self) -> Result<Option<Event>> {
+        loop {
+            let Some((segment_file, offset, lines)) = &mut self.current else {
+                let Some((segment_file, offset)) = self.segments.pop_front() else {
+                    return Ok(None);
+                };
+
+                let lines = segment_file.lines_from(offset).await?;
+
+                self.current = Some((segment_file, offset, lines));
+                continue;
+            };
+
+            let Some(line) = lines.next_line().await? else {
+                self.current = None;
+                continue;
+            };
+
+            let line_offset = *offset;
+            *offset += segment_file.format().framed_len(line.len());
+
+            if line.trim().is_empty() {
+                warn!("Skipping blank line at offset {} of DB at {:?}", line_offset, segment_file.path());
+                continue;
+            }
+
+            return decode_event(line).map(Some);
+        }
+    }
+}
+
+/// State of a stream returned by `Database::subscribe`.
+struct Subscription {
+    /// The database, until the events in it when the subscription started are replayed.
+    replay: Option<Arc<Mutex<Database>>>,
+    next: u64,
+    buffer: VecDeque<(u64, Event)>,
+    receiver: broadcast::Receiver<(u64, Event)>,
+    failed: bool,
+}
+
+impl Subscription {
+    /// Reads the next page of history from rownum `next` onward, with each event's rownum.
+    async fn replay_page(db: &Database, next: u64) -> Result<Vec<(u64, Event)>> {
+        ensure!(db.run_state == RunState::Running, Error::Stopped);
+
+        let rownums: Vec<u64> = db.primary_index.range(next..)
+            .take(SUBSCRIPTION_REPLAY_PAGE)
+            .map(|(rownum, _)| *rownum)
+            .collect();
+        let events = db.query(next, SUBSCRIPTION_REPLAY_PAGE).await?;
+
+        Ok(rownums.into_iter().zip(events).collect())
+    }
+
+    async fn next_event(&mut self) -> Option<Result<(u64, Event)>> {
+        if self.failed {
+            return None;
+        }
+
+        loop {
+            if let Some(item) = self.buffer.pop_front() {
+                return Some(Ok(item));
+            }
+
+            if let Some(db) = &self.replay {
+                let page = Self::replay_page(&*db.lock().await, self.next).await;
+
+                match page {
+                    Ok(page) if page.is_empty() => {
+                        self.replay = None;
+                        continue;
+                    },
+                    Ok(page) => {
+                        self.next = page[page.len() - 1].0 + 1;
+                        self.buffer.extend(page);
+                        continue;
+                    },
+                    Err(err) => {
+                        self.failed = true;
+                        return Some(Err(err));
+                    },
+                }
+            }
+
+            match self.receiver.recv().await {
+                Ok((rownum, _)) if rownum < self.next => continue,
+                Ok((rownum, event)) => {
+                    self.next = rownum + 1;
+                    return Some(Ok((rownum, event)));
+                },
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    self.failed = true;
+                    return Some(Err(anyhow!("Subscriber fell behind by {} events", skipped)));
+                },
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+async fn open_for_append(events_path: &Path) -> Result<File> {
+    File::options()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(events_path).await
+        .with_context(|| format!("Failed to open file for DB at {:?}", events_path))
+}
+
+async fn remove_file_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path).await {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err).with_context(|| format!("Failed to delete {:?}", path)),
+    }
+}
+
+/// Syncs a directory, so files renamed into or removed from it stay that way after a crash.
+async fn sync_dir(path: &Path) -> Result<()> {
+    File::open(path).await
+        .with_context(|| format!("Failed to open directory {:?} to sync it", path))?
+        .sync_all().await
+        .with_context(|| format!("Failed to sync directory {:?}", path))
+}
+
+fn index_record(rownum: u64, offset: u64) -> [u8; INDEX_RECORD_LEN] {
+    let mut record = [0u8; INDEX_RECORD_LEN];
+    record[..8].copy_from_slice(&rownum.to_be_bytes());
+    record[8..].copy_from_slice(&offset.to_be_bytes());
+    record
+}
+
+/// End of the last good line of an NDJSON segment of `len` bytes. A partial or undecodable
+/// final row is left out, along with any blank lines after it, which readers skip anyway.
+async fn end_of_last_line(file: &mut File, len: u64) -> Result<u64> {
+    file.seek(SeekFrom::Start(len - 1)).await?;
+    if file.read_u8().await? != b'\n' {
+        return Ok(last_newline_before(file, len).await?.map(|i| i + 1).unwrap_or(0));
+    }
+
+    // Position of the newline ending the line being checked.
+    let mut line_end = len - 1;
+
+    loop {
+        let line_start = last_newline_before(file, line_end).await?.map(|i| i + 1).unwrap_or(0);
+
+        let mut line = vec![0u8; (line_end - line_start) as usize];
+        file.seek(SeekFrom::Start(line_start)).await?;
+        file.read_exact(&mut line).await?;
+
+        let line = String::from_utf8_lossy(&line).into_owned();
+
+        if line.trim().is_empty() {
+            if line_start == 0 {
+                return Ok(len);
+            }
+
+            line_end = line_start - 1;
+            continue;
+        }
+
+        // A complete line with a bad checksum is bit-rot rather than a torn write,
+        // so leave it in place for `verify` and readers to report.
+        return match decode_event(line) {
+            Ok(_) => Ok(len),
+            Err(err) if matches!(err.downcast_ref::<Error>(), Some(Error::ChecksumMismatch)) => Ok(len),
+            Err(_) => Ok(line_start),
+        };
+    }
+}
+
+/// End of the last complete record of a binary segment of `len` bytes. A partial final record,
+/// or a partial header, is left out.
+async fn end_of_last_record(file: &mut File, len: u64) -> Result<u64> {
+    let header_len = BINARY_HEADER.len() as u64;
+
+    if len < header_len {
+        return Ok(0);
+    }
+
+    let mut good_len = header_len;
+
+    while good_len + 4 <= len {
+        file.seek(SeekFrom::Start(good_len)).await?;
+        let record_len = good_len + 4 + file.read_u32_le().await? as u64;
+
+        if record_len > len {
+            break;
+        }
+
+        good_len = record_len;
+    }
+
+    Ok(good_len)
+}
+
+/// Position of the last newline in `file` before byte `end`, scanning backwards a chunk at a time.
+async fn last_newline_before(file: &mut File, end: u64) -> Result<Option<u64>> {
+    let mut buf = [0u8; 4096];
+    let mut chunk_end = end;
+
+    while chunk_end > 0 {
+        let chunk_start = chunk_end.saturating_sub(buf.len() as u64);
+        let chunk = &mut buf[..(chunk_end - chunk_start) as usize];
+
+        file.seek(SeekFrom::Start(chunk_start)).await?;
+        file.read_exact(chunk).await?;
+
+        if let Some(i) = chunk.iter().rposition(|b| *b == b'\n') {
+            return Ok(Some(chunk_start + i as u64));
+        }
+
+        chunk_end = chunk_start;
+    }
+
+    Ok(None)
+}
+
+fn corrected_rownum(event: &Event) -> Option<u64> {
+    match event.extension(CORRECTS_EXTENSION)? {
+        ExtensionValue::Integer(rownum) => (*rownum).try_into().ok(),
+        ExtensionValue::String(rownum) => rownum.parse().ok(),
+        ExtensionValue::Boolean(_) => None,
+    }
+}
+
+/// Overlays the data of `correction` onto `event`, keeping the original event's other attributes.
+fn apply_correction(mut event: Event, mut correction: Event, correction_rownum: u64) -> Event {
+    let (datacontenttype, dataschema, data) = correction.take_data();
+
+    event.take_data();
+    event.set_datacontenttype(datacontenttype);
+    event.set_dataschema(dataschema);
+    if let Some(data) = data {
+        event.set_data_unchecked(data);
+    }
+    event.set_extension(CORRECTED_BY_EXTENSION, correction_rownum as i64);
+
+    event
+}
+
+/// A lower bound on the length of an event's JSON that's much cheaper to find than serializing
+/// it: the lengths of its string attributes, and of its data, without any escaping or
+/// punctuation beyond quotes.
+fn min_json_len(event: &Event) -> usize {
+    let attributes: usize = event.iter()
+        .map(|(name, value)| {
+            let value_len = match value {
+                AttributeValue::String(value) => value.len() + 2,
+                AttributeValue::URI(value) => value.as_str().len() + 2,
+                AttributeValue::URIRef(value) => value.len() + 2,
+                _ => 1,
+            };
+
+            name.len() + 3 + value_len
+        })
+        .sum();
+
+    let data = match event.data() {
+        Some(Data::String(data)) => data.len() + 2,
+        // Binary data is written as base64.
+        Some(Data::Binary(data)) => data.len().div_ceil(3) * 4 + 2,
+        Some(Data::Json(data)) => min_value_len(data),
+        None => 0,
+    };
+
+    attributes + data
+}
+
+fn min_value_len(value: &serde_json::Value) -> usize {
+    match value {
+        serde_json::Value::Null | serde_json::Value::Bool(true) => 4,
+        serde_json::Value::Bool(false) => 5,
+        serde_json::Value::Number(_) => 1,
+        serde_json::Value::String(value) => value.len() + 2,
+        serde_json::Value::Array(values) => 2 + values.iter().map(min_value_len).sum::<usize>() + values.len().saturating_sub(1),
+        serde_json::Value::Object(members) => 2
+            + members.iter().map(|(name, value)| name.len() + 3 + min_value_len(value)).sum::<usize>()
+            + members.len().saturating_sub(1),
+    }
+}
+
+fn source_id(event: &Event) -> (String, String) {
+    (event.source().to_string(), event.id().to_string())
+}
+
+/// Encodes each event as a checksummed row, failing with `EventTooLarge` on any that's over
+/// `max_event_bytes`. Events that are clearly too large are rejected by `min_json_len` before
+/// any of them are serialized.
+pub fn encode_events(events: Vec<Event>, max_event_bytes: Option<usize>) -> Result<EncodedEvents> {
+    if let Some(max_bytes) = max_event_bytes {
+        if let Some((index, bytes)) = events.iter().map(min_json_len).enumerate().find(|(_, bytes)| *bytes > max_bytes) {
+            return Err(Error::EventTooLarge { index, bytes, max_bytes }.into());
+        }
+    }
+
+    let mut rows = Vec::with_capacity(events.len());
+
+    for (index, event) in events.iter().enumerate() {
+        let json = serde_json::to_string(event).context("Failed to JSONify event")?;
+
+        if let Some(max_bytes) = max_event_bytes.filter(|max_bytes| json.len() > *max_bytes) {
+            return Err(Error::EventTooLarge { index, bytes: json.len(), max_bytes }.into());
+        }
+
+        rows.push(encode_row(&json));
+    }
+
+    Ok(EncodedEvents { events, rows })
+}
+
+/// Encodes `correction` as a correction of the event at `rownum`, as `encode_events` would.
+pub fn encode_correction(rownum: u64, mut correction: Event, max_event_bytes: Option<usize>) -> Result<EncodedEvents> {
+    correction.set_extension(CORRECTS_EXTENSION, rownum as i64);
+    encode_events(vec![correction], max_event_bytes)
+}
+
+/// Makes a stored row out of an event's JSON by prefixing it with the CRC32 of that JSON in hex.
+fn encode_row(json: &str) -> String {
+    format!("{:08x} {}", crc32fast::hash(json.as_bytes()), json)
+}
+
+/// Decodes a stored row, verifying its checksum if it has one.
+/// Rows written before checksums were introduced are bare JSON objects.
+fn decode_event(row: String) -> Result<Event> {
+    let row = row.trim_end();
+
+    let json =
+        if row.starts_with('{') {
+            row
+        } else {
+            let (checksum, json) = row.split_once(' ')
+                .context("Row is neither a JSON object nor prefixed with a checksum")?;
+            let checksum = u32::from_str_radix(checksum, 16)
+                .context("Failed to decode row checksum")?;
+
+            if crc32fast::hash(json.as_bytes()) != checksum {
+                return Err(Error::ChecksumMismatch.into());
+            }
+
+            json
+        };
+
+    serde_json::from_str(json)
+            .context("Failed to decode event JSON")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use cloudevents::event::{Event, ExtensionValue};
+    use futures::StreamExt;
+    use tokio::sync::Mutex;
+    use cloudevents::*;
+    use tempfile::tempdir;
+    use uuid::Uuid;
+
+    use crate::db::ExpectedRevision;
+
+    use super::{decode_event, index_record, min_json_len, Database, Deduplication, Error, RunState, SegmentReader, StorageFormat, StreamMetadata, BINARY_HEADER, INDEX_RECORD_LEN, SUBSCRIPTION_REPLAY_PAGE, TOMBSTONE_TYPE};
+    use std::{io::{Read, Seek, SeekFrom, Write}, path::Path};
+
+    #[tokio::test]
+    async fn can_write_and_read() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        let event = Event::default();
+
+        let rownum = db.append(vec![event.clone()], ExpectedRevision::Any).await
+            .expect("Could not write to the DB");
+
+        let result: Event = db
+            .query(0, 1).await
+            .expect("Row not found")
+            .pop()
+            .expect("Failed to read row");
+
+        assert_eq!(rownum, 1);
+        assert_eq!(result.id(), event.id());
+    }
+
+    #[tokio::test]
+    async fn read_nonexistent() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        let result = db.query(0, 1).await.expect("Expected success reading empty db");
+
+        assert!(result.is_empty());
+    }
+
+    #[tokio::test]
+    async fn can_write_expecting_no_stream_in_empty_db() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        let event = Event::default();
+
+        db.append(vec![event], ExpectedRevision::NoStream).await
+            .expect("Could not write to the DB");
+    }
+
+    #[tokio::test]
+    async fn cannot_write_expecting_no_stream_in_non_empty_db() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        let event1 = Event::default();
+        let event2 = Event::default();
+        db.append(vec![event1], ExpectedRevision::NoStream).await
+            .expect("Could not write to the DB");
+        assert!(db.append(vec![event2], ExpectedRevision::NoStream).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn at_least_matches_revisions_from_the_given_one() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        db.append(vec![unique_event(), unique_event()], ExpectedRevision::Any).await.unwrap();
+
+        assert!(db.append(vec![unique_event()], ExpectedRevision::AtLeast(3)).await.is_err());
+        assert_eq!(db.append(vec![unique_event()], ExpectedRevision::AtLeast(2)).await.unwrap(), 3);
+        assert_eq!(db.append(vec![unique_event()], ExpectedRevision::AtLeast(1)).await.unwrap(), 4);
+    }
+
+    #[tokio::test]
+    async fn revision_mismatches_report_the_actual_revision() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        db.append(vec![unique_event(), unique_event()], ExpectedRevision::Any).await.unwrap();
+
+        let err = db.append(vec![unique_event()], ExpectedRevision::Exact(1)).await.unwrap_err();
+        let Some(Error::RevisionMismatch { expected, actual }) = err.downcast_ref::<Error>() else {
+            panic!("Expected a revision mismatch, got {:?}", err);
+        };
+        assert_eq!(*expected, ExpectedRevision::Exact(1));
+        assert_eq!(*actual, db.revision());
+    }
+
+    #[tokio::test]
+    async fn revision_counts_appended_events() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+        assert_eq!(db.revision(), 0);
+
+        db.append(vec![unique_event()], ExpectedRevision::Any).await.unwrap();
+        assert_eq!(db.revision(), 1);
+
+        db.append(vec![unique_event(), unique_event(), unique_event()], ExpectedRevision::Any).await.unwrap();
+        assert_eq!(db.revision(), 4);
+    }
+
+    #[tokio::test]
+    async fn cannot_write_to_empty_db_expecting_stream_exists() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        let event = Event::default();
+
+        assert!(db.append(vec![event], ExpectedRevision::StreamExists).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn can_write_expecting_revision_zero_with_present_row() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        let event1 = Event::default();
+        let event2 = Event::default();
+        db.append(vec![event1], ExpectedRevision::NoStream).await
+            .expect("Could not write to the DB");
+        db.append(vec![event2], ExpectedRevision::Exact(1)).await
+            .expect("Could not write to the DB");
+    }
+
+    #[tokio::test]
+    async fn can_write_and_read_many() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        let event = Event::default();
+
+        for n in 1..100 {
+            let rownum =
+                db.append(vec![Event::default()], ExpectedRevision::Any).await
+                .expect("Could not write to the DB");
+
+            assert_eq!(rownum, n);
+        }
+
+        db.append(vec![event.clone()], ExpectedRevision::Any).await
+            .expect("Could not write to the DB");
+
+        for n in 1..100 {
+            let rownum =
+                db.append(vec![Event::default()], ExpectedRevision::Any).await
+                .expect("Could not write to the DB");
+
+            assert_eq!(rownum, n + 100);
+        }
+
+        let result: Event = db
+            .query(99, 1).await
+            .expect("Row not found")
+            .pop()
+            .expect("Failed to read row");
+
+        assert_eq!(result.id(), event.id());
+    }
+
+    #[tokio::test]
+    async fn can_write_and_read_many_backward() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        let event = Event::default();
+
+        for n in 1..100 {
+            let rownum =
+                db.append(vec![Event::default()], ExpectedRevision::Any).await
+                .expect("Could not write to the DB");
+
+            assert_eq!(rownum, n);
+        }
+
+        db.append(vec![event.clone()], ExpectedRevision::Any).await
+            .expect("Could not write to the DB");
+
+        for n in 1..100 {
+            let rownum =
+                db.append(vec![Event::default()], ExpectedRevision::Any).await
+                .expect("Could not write to the DB");
+
+            assert_eq!(rownum, n + 100);
+        }
+
+        let result = db
+            .query_backward(101, 3).await
+            .expect("Rows not found");
+
+        assert_eq!(result.len(), 3);
+        assert_eq!(result[2].id(), event.id());
+
+        let forward = db.query(97, 3).await.expect("Rows not found");
+        let backward = db.query_backward(99, 3).await.expect("Rows not found");
+        let forward_ids: Vec<&str> = forward.iter().rev().map(|event| event.id()).collect();
+        let backward_ids: Vec<&str> = backward.iter().map(|event| event.id()).collect();
+        assert_eq!(forward_ids, backward_ids);
+
+        let latest = db.query(198, 1).await.expect("Row not found");
+        let clamped = db.query_backward(1_000, 1).await.expect("Row not found");
+        assert_eq!(clamped[0].id(), latest[0].id());
+    }
+
+    #[tokio::test]
+    async fn read_backward_nonexistent() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        let result = db.query_backward(10, 5).await.expect("Expected success reading empty db");
+
+        assert!(result.is_empty());
+    }
+
+    #[tokio::test]
+    async fn count_follows_appends_and_deletes() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        assert_eq!(db.count(), 0);
+
+        db.append(vec![Event::default(), Event::default()], ExpectedRevision::Any).await
+            .expect("Could not write to the DB");
+        db.append(vec![Event::default()], ExpectedRevision::Any).await
+            .expect("Could not write to the DB");
+
+        assert_eq!(db.count(), 3);
+        assert_eq!(db.stats().await.unwrap().count, 3);
+
+        db.delete().await.expect("Failed to delete the DB");
+
+        assert_eq!(db.count(), 0);
+    }
+
+    #[tokio::test]
+    async fn event_types_lists_distinct_types_with_counts() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        let events: Vec<Event> = ["com.example.created", "com.example.updated", "com.example.created"]
+            .into_iter()
+            .map(|ty| EventBuilderV10::new().id(Uuid::now_v7().to_string()).source("test").ty(ty).build().unwrap())
+            .collect();
+
+        db.append(events, ExpectedRevision::Any).await
+            .expect("Could not write to the DB");
+
+        let types = db.event_types().await.expect("Failed to list event types");
+
+        assert_eq!(types.len(), 2);
+        assert_eq!(types["com.example.created"], 2);
+        assert_eq!(types["com.example.updated"], 1);
+    }
+
+    #[tokio::test]
+    async fn rebuild_index_skips_blank_lines() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+
+        let events: Vec<Event> = (0..3)
+            .map(|_| EventBuilderV10::new().id(Uuid::now_v7().to_string()).source("test").ty("test").build().unwrap())
+            .collect();
+
+        let mut contents = String::new();
+        for event in events.iter() {
+            contents.push_str(&serde_json::to_string(event).unwrap());
+            contents.push_str("\n   \n\n");
+        }
+        std::fs::write(db.segment_path(0), contents).unwrap();
+
+        db.start().await.expect("Failed to start DB");
+
+        assert_eq!(db.revision(), 3);
+
+        for (rownum, event) in events.iter().enumerate() {
+            let result = db.query(rownum as u64, 1).await
+                .expect("Row not found")
+                .pop()
+                .expect("Failed to read row");
+
+            assert_eq!(result.id(), event.id());
+        }
+
+        let all = db.query(0, 10).await.expect("Failed to read rows");
+        assert_eq!(all.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn cached_stats_are_served_from_memory_and_follow_appends() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        db.append(vec![Event::default()], ExpectedRevision::Any).await
+            .expect("Could not write to the DB");
+
+        let stats = db.stats().await.expect("Failed to read stats");
+        assert_eq!(stats.revision, 1);
+
+        // Removing the events file makes any filesystem stat fail, so only the cache can answer.
+        let contents = std::fs::read(db.segment_path(0)).unwrap();
+        std::fs::remove_file(db.segment_path(0)).unwrap();
+
+        assert!(db.stats().await.is_err());
+        assert_eq!(db.cached_stats().await.expect("Expected cached stats"), stats);
+
+        std::fs::write(db.segment_path(0), contents).unwrap();
+
+        db.append(vec![Event::default()], ExpectedRevision::Any).await
+            .expect("Could not write to the DB");
+
+        let cached = db.cached_stats().await.expect("Expected cached stats");
+        let fresh = db.stats().await.expect("Failed to read stats");
+
+        assert_eq!(cached.revision, 2);
+        assert_eq!(cached.usage, fresh.usage);
+    }
+
+    #[tokio::test]
+    async fn reopening_reads_the_persisted_index() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        let mut events = vec![];
+        for _ in 0..5 {
+            let batch: Vec<Event> = (0..3).map(|_| Event::default()).collect();
+            events.extend(batch.clone());
+            db.append(batch, ExpectedRevision::Any).await
+                .expect("Could not write to the DB");
+        }
+        drop(db);
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        assert_eq!(db.index_rebuilds, 0);
+        assert_eq!(db.revision(), 15);
+
+        // Loading reads only the index sidecars, without decoding any rows.
+        let rows_decoded = |db: &Database| db.secondary_indexes.get().map_or(0, |indexes| indexes.rows_decoded);
+        assert_eq!(rows_decoded(&db), 0);
+
+        let result = db.query(7, 1).await
+            .expect("Row not found")
+            .pop()
+            .expect("Failed to read row");
+        assert_eq!(result.id(), events[7].id());
+        assert_eq!(rows_decoded(&db), 0);
+
+        // The secondary indexes are built from every row the first time they're needed.
+        assert_eq!(db.query_by_type(events[7].ty(), 7, 1).await.unwrap(), vec![events[7].clone()]);
+        assert_eq!(rows_decoded(&db), 15);
+        db.append(vec![unique_event()], ExpectedRevision::Any).await.unwrap();
+        assert_eq!(rows_decoded(&db), 15);
+    }
+
+    #[tokio::test]
+    async fn reopening_indexes_only_the_tail_past_a_stale_index() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        let events: Vec<Event> = (0..12).map(|_| Event::default()).collect();
+        for batch in [&events[..4], &events[4..7], &events[7..]] {
+            db.append(batch.to_vec(), ExpectedRevision::Any).await
+                .expect("Could not write to the DB");
+        }
+        drop(db);
+
+        // Lose the last batch's records, and half of the one before, as a crash might.
+        let index = std::fs::read(test_file.path().join("events.index")).unwrap();
+        std::fs::write(test_file.path().join("events.index"), &index[..INDEX_RECORD_LEN * 6 + INDEX_RECORD_LEN / 2]).unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        assert_eq!(db.index_rebuilds, 0);
+        assert_eq!(db.index_rows_scanned, 6);
+        assert_eq!(db.revision(), 12);
+        assert_eq!(std::fs::read(test_file.path().join("events.index")).unwrap(), index);
+
+        let result = db.query(9, 1).await
+            .expect("Row not found")
+            .pop()
+            .expect("Failed to read row");
+        assert_eq!(result.id(), events[9].id());
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        assert_eq!(db.index_rows_scanned, 0);
+    }
+
+    fn unique_event() -> Event {
+        EventBuilderV10::new().id(Uuid::now_v7().to_string()).source("test").ty("test").build().unwrap()
+    }
+
+    #[tokio::test]
+    async fn cannot_append_the_same_event_twice() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        let event = unique_event();
+        db.append(vec![event.clone()], ExpectedRevision::Any).await
+            .expect("Could not write to the DB");
+
+        let err = db.append(vec![event], ExpectedRevision::Any).await
+            .expect_err("Expected a duplicate event to be rejected");
+
+        assert!(matches!(err.downcast::<Error>(), Ok(Error::SourceIdConflict)));
+        assert_eq!(db.revision(), 1);
+    }
+
+    #[tokio::test]
+    async fn duplicate_within_a_batch_fails_atomically() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        let event = unique_event();
+        let batch = vec![event.clone(), unique_event(), event];
+
+        let err = db.append(batch, ExpectedRevision::Any).await
+            .expect_err("Expected a batch with a duplicate event to be rejected");
+
+        assert!(matches!(err.downcast::<Error>(), Ok(Error::SourceIdConflict)));
+        assert_eq!(db.revision(), 0);
+        assert!(!db.segment_path(0).exists());
+    }
+
+    #[tokio::test]
+    async fn source_ids_survive_a_reload() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        let event = unique_event();
+        db.append(vec![event.clone()], ExpectedRevision::Any).await
+            .expect("Could not write to the DB");
+        drop(db);
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        assert!(db.append(vec![event], ExpectedRevision::Any).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reopening_rebuilds_a_corrupt_index() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        let events: Vec<Event> = (0..10).map(|_| unique_event()).collect();
+        db.append(events.clone(), ExpectedRevision::Any).await
+            .expect("Could not write to the DB");
+        drop(db);
+
+        let index = std::fs::read(test_file.path().join("events.index")).unwrap();
+        let garbage: Vec<u8> = (0..index.len()).map(|i| (i * 31 % 251) as u8).collect();
+        std::fs::write(test_file.path().join("events.index"), garbage).unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Expected a corrupt index to be rebuilt");
+
+        assert_eq!(db.index_rebuilds, 1);
+        assert_eq!(db.revision(), 10);
+        assert_eq!(std::fs::read(test_file.path().join("events.index")).unwrap(), index);
+
+        let result = db.query(0, 10).await.expect("Failed to read rows");
+        let ids: Vec<&str> = result.iter().map(|event| event.id()).collect();
+        let expected: Vec<&str> = events.iter().map(|event| event.id()).collect();
+        assert_eq!(ids, expected);
+
+        db.append(vec![unique_event()], ExpectedRevision::Exact(10)).await
+            .expect("Could not write to the DB");
+    }
+
+    #[tokio::test]
+    async fn reopening_rebuilds_an_index_that_points_into_rows() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        let events: Vec<Event> = (0..10).map(|_| unique_event()).collect();
+        db.append(events.clone(), ExpectedRevision::Any).await
+            .expect("Could not write to the DB");
+        drop(db);
+
+        // Like a sidecar left over from before its segment was rewritten: ordered, in bounds,
+        // and starting at the first row, but with later offsets landing partway into rows.
+        let index = std::fs::read(test_file.path().join("events.index")).unwrap();
+        let stale: Vec<u8> = index.chunks_exact(INDEX_RECORD_LEN).take(3).enumerate()
+            .flat_map(|(i, record)| {
+                let offset = u64::from_be_bytes(record[8..].try_into().unwrap());
+                index_record(i as u64, if i == 0 { offset } else { offset + 7 })
+            })
+            .collect();
+        std::fs::write(test_file.path().join("events.index"), stale).unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Expected a stale index to be rebuilt");
+
+        assert_eq!(db.index_rebuilds, 1);
+        assert_eq!(db.revision(), 10);
+        assert_eq!(std::fs::read(test_file.path().join("events.index")).unwrap(), index);
+        assert_eq!(db.verify().await.unwrap(), None);
+        assert_eq!(db.query(0, 10).await.unwrap(), events);
+    }
+
+    #[tokio::test]
+    async fn reopening_truncates_a_partial_trailing_line() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        let event = unique_event();
+        db.append(vec![event.clone()], ExpectedRevision::Any).await
+            .expect("Could not write to the DB");
+        drop(db);
+
+        let mut file = std::fs::OpenOptions::new().append(true).open(test_file.path().join("events.ndjson")).unwrap();
+        std::io::Write::write_all(&mut file, b"{\"specversion\":\"1.0\",\"id\":\"trunc").unwrap();
+        drop(file);
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        let result = db.query(0, 10).await.expect("Failed to read rows");
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].id(), event.id());
+
+        let next = unique_event();
+        let revision = db.append(vec![next.clone()], ExpectedRevision::Exact(1)).await
+            .expect("Could not write to the DB");
+        assert_eq!(revision, 2);
+
+        let result = db.query(1, 1).await.expect("Row not found");
+        assert_eq!(result[0].id(), next.id());
+    }
+
+    #[tokio::test]
+    async fn reopening_truncates_an_undecodable_trailing_line() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        let event = unique_event();
+        db.append(vec![event.clone()], ExpectedRevision::Any).await
+            .expect("Could not write to the DB");
+        let good_len = db.file_len().await.unwrap();
+        drop(db);
+
+        let mut file = std::fs::OpenOptions::new().append(true).open(test_file.path().join("events.ndjson")).unwrap();
+        std::io::Write::write_all(&mut file, b"{\"not\": \"an event\n").unwrap();
+        drop(file);
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        assert_eq!(db.file_len().await.unwrap(), good_len);
+        assert_eq!(db.revision(), 1);
+    }
+
+    #[tokio::test]
+    async fn reopening_looks_past_trailing_blank_lines_for_an_undecodable_row() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        let event = unique_event();
+        db.append(vec![event.clone()], ExpectedRevision::Any).await
+            .expect("Could not write to the DB");
+        let good_len = db.file_len().await.unwrap();
+        drop(db);
+
+        let mut file = std::fs::OpenOptions::new().append(true).open(test_file.path().join("events.ndjson")).unwrap();
+        std::io::Write::write_all(&mut file, b"{\"not\": \"an event\n\n  \n").unwrap();
+        drop(file);
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        assert_eq!(db.file_len().await.unwrap(), good_len);
+        assert_eq!(db.revision(), 1);
+        assert_eq!(db.query(0, 10).await.unwrap(), vec![event]);
+    }
+
+    #[tokio::test]
+    async fn corrections_overlay_only_when_applied() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        let mut original = unique_event();
+        original.set_data("application/json", serde_json::json!({"amount": 10}));
+        db.append(vec![original.clone(), unique_event()], ExpectedRevision::Any).await
+            .expect("Could not write to the DB");
+
+        let mut correction = unique_event();
+        correction.set_data("application/json", serde_json::json!({"amount": 100}));
+        let revision = db.correct(0, correction).await.expect("Failed to correct event");
+        assert_eq!(revision, 3);
+
+        let raw = db.query(0, 1).await.unwrap().pop().unwrap();
+        assert_eq!(raw.data(), original.data());
+
+        let corrected = db.query_corrected(0, 3).await.unwrap();
+        assert_eq!(corrected.len(), 3);
+        assert_eq!(corrected[0].id(), original.id());
+        assert_eq!(corrected[0].data(), Some(&Data::Json(serde_json::json!({"amount": 100}))));
+        assert_eq!(corrected[0].extension(super::CORRECTED_BY_EXTENSION), Some(&ExtensionValue::Integer(2)));
+        assert_eq!(corrected[2].extension(super::CORRECTS_EXTENSION), Some(&ExtensionValue::Integer(0)));
+
+        assert!(db.correct(10, unique_event()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn verify_reports_the_first_corrupt_row() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+        db.append(vec![unique_event(), unique_event(), unique_event()], ExpectedRevision::Any).await
+            .expect("Could not write to the DB");
+
+        assert_eq!(db.verify().await.unwrap(), None);
+
+        corrupt_second_row(&test_file.path().join("events.ndjson"));
+
+        assert_eq!(db.verify().await.unwrap(), Some(1));
+
+        let err = db.query(1, 1).await.expect_err("Expected the corrupt row to fail to decode");
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::ChecksumMismatch)));
+
+        assert_eq!(db.query(0, 1).await.unwrap().len(), 1);
+    }
+
+    /// Flips a bit in the middle of the second row's JSON, leaving it well-formed enough to parse.
+    fn corrupt_second_row(events_path: &Path) {
+        let mut file = std::fs::File::options().read(true).write(true).open(events_path).unwrap();
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).unwrap();
+
+        let second_row = contents.find('\n').unwrap() as u64 + 1;
+        let id_offset = second_row + contents[second_row as usize..].find("\"id\":\"").unwrap() as u64 + 6;
+        let mut byte = [0u8; 1];
+        file.seek(SeekFrom::Start(id_offset)).unwrap();
+        file.read_exact(&mut byte).unwrap();
+        byte[0] ^= 0x01;
+        file.seek(SeekFrom::Start(id_offset)).unwrap();
+        file.write_all(&byte).unwrap();
+        file.sync_all().unwrap();
+    }
+
+    #[tokio::test]
+    async fn streams_with_a_corrupt_row_still_load() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+        let events = vec![unique_event(), unique_event(), unique_event()];
+        db.append(events.clone(), ExpectedRevision::Any).await
+            .expect("Could not write to the DB");
+        db.stop().await.expect("Failed to stop DB");
+
+        corrupt_second_row(&test_file.path().join("events.ndjson"));
+
+        let mut reopened = Database::new(test_file.path());
+        reopened.start().await.expect("Expected a corrupt row to be skipped while loading");
+
+        assert_eq!(reopened.revision(), 3);
+        assert_eq!(reopened.query(0, 1).await.unwrap(), vec![events[0].clone()]);
+        assert_eq!(reopened.query(2, 1).await.unwrap(), vec![events[2].clone()]);
+        assert_eq!(reopened.event_types().await.unwrap()["test"], 2);
+
+        let err = reopened.query(1, 1).await.expect_err("Expected the corrupt row to fail to decode");
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::ChecksumMismatch)));
+    }
+
+    #[tokio::test]
+    async fn rows_without_checksums_are_still_readable() {
+        let test_file = tempdir().unwrap();
+
+        let events = [unique_event(), unique_event()];
+        let mut contents = String::new();
+        for event in events.iter() {
+            contents.push_str(&serde_json::to_string(event).unwrap());
+            contents.push('\n');
+        }
+        std::fs::write(test_file.path().join("events.ndjson"), contents).unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.unwrap();
+        db.append(vec![unique_event()], ExpectedRevision::Any).await.unwrap();
+
+        assert_eq!(db.verify().await.unwrap(), None);
+        let read = db.query(0, 3).await.unwrap();
+        assert_eq!(read.len(), 3);
+        assert_eq!(read[..2], events[..]);
+    }
+
+    #[tokio::test]
+    async fn stopped_db_rejects_reads_and_writes() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+
+        let err = db.append(vec![unique_event()], ExpectedRevision::Any).await
+            .expect_err("Expected a DB that was never started to reject writes");
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::Stopped)));
+
+        assert!(db.start().await.unwrap());
+        db.append(vec![unique_event()], ExpectedRevision::Any).await.unwrap();
+
+        assert!(db.stop().await.unwrap());
+        assert!(!db.stop().await.unwrap());
+        assert_eq!(db.run_state(), RunState::Stopped);
+
+        let err = db.append(vec![unique_event()], ExpectedRevision::Any).await
+            .expect_err("Expected a stopped DB to reject writes");
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::Stopped)));
+
+        let err = db.query(0, 1).await.expect_err("Expected a stopped DB to reject reads");
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::Stopped)));
+    }
+
+    #[tokio::test]
+    async fn can_start_again_after_stopping() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        assert!(db.start().await.unwrap());
+        assert!(!db.start().await.unwrap());
+
+        let event = unique_event();
+        db.append(vec![event.clone()], ExpectedRevision::Any).await.unwrap();
+
+        assert!(db.stop().await.unwrap());
+        assert!(db.start().await.unwrap());
+        assert_eq!(db.run_state(), RunState::Running);
+
+        assert_eq!(db.query(0, 1).await.unwrap(), vec![event]);
+        assert_eq!(db.append(vec![unique_event()], ExpectedRevision::Exact(1)).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn id_only_deduplication_ignores_source() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        let id = Uuid::now_v7().to_string();
+        let first = EventBuilderV10::new().id(&id).source("producer-a").ty("test").build().unwrap();
+        let second = EventBuilderV10::new().id(&id).source("producer-b").ty("test").build().unwrap();
+
+        db.append(vec![first], ExpectedRevision::Any).await.unwrap();
+        db.append(vec![second.clone()], ExpectedRevision::Any).await
+            .expect("Expected events with different sources to be accepted in source+id mode");
+
+        db.set_metadata(StreamMetadata { deduplication: Deduplication::Id { window: 2 }, ..Default::default() }).await.unwrap();
+
+        let err = db.append(vec![second.clone()], ExpectedRevision::Any).await
+            .expect_err("Expected a duplicate id to be rejected in id-only mode");
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::IdConflict)));
+
+        // Once the id falls out of the window it may be reused.
+        db.append(vec![unique_event(), unique_event()], ExpectedRevision::Any).await.unwrap();
+        db.append(vec![second], ExpectedRevision::Any).await.unwrap();
+
+        let mut reopened = Database::new(test_file.path());
+        reopened.start().await.expect("Failed to start DB");
+        assert_eq!(reopened.metadata().deduplication, Deduplication::Id { window: 2 });
+    }
+
+    #[tokio::test]
+    async fn subscribers_replay_history_then_receive_appends_in_order() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        let mut expected = vec![unique_event(), unique_event()];
+        db.append(expected.clone(), ExpectedRevision::Any).await.unwrap();
+
+        let db = Arc::new(Mutex::new(db));
+        let subscription = Database::subscribe(&db.clone().lock_owned().await, 1);
+
+        let live: Vec<Event> = (0..3).map(|_| unique_event()).collect();
+        expected.extend(live.clone());
+
+        let writer = {
+            let db = db.clone();
+            tokio::spawn(async move {
+                for event in live {
+                    db.lock().await.append(vec![event], ExpectedRevision::Any).await.unwrap();
+                }
+            })
+        };
+
+        let received: Vec<(u64, Event)> = tokio::time::timeout(Duration::from_secs(5), subscription.take(4).collect::<Vec<_>>()).await
+            .expect("Timed out waiting for subscribed events")
+            .into_iter()
+            .collect::<anyhow::Result<_>>()
+            .unwrap();
+
+        writer.await.unwrap();
+
+        let rownums: Vec<u64> = received.iter().map(|(rownum, _)| *rownum).collect();
+        assert_eq!(rownums, vec![1, 2, 3, 4]);
+
+        let events: Vec<Event> = received.into_iter().map(|(_, event)| event).collect();
+        assert_eq!(events, expected[1..]);
+    }
+
+    #[tokio::test]
+    async fn subscribers_replay_history_from_the_live_database_a_page_at_a_time() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        let events: Vec<Event> = (0..SUBSCRIPTION_REPLAY_PAGE + 10).map(|_| unique_event()).collect();
+        db.append(events.clone(), ExpectedRevision::Any).await.unwrap();
+
+        let db = Arc::new(Mutex::new(db));
+        let mut subscription = std::pin::pin!(Database::subscribe(&db.clone().lock_owned().await, 0));
+
+        for event in &events[..SUBSCRIPTION_REPLAY_PAGE] {
+            assert_eq!(&subscription.next().await.unwrap().unwrap().1, event);
+        }
+
+        // The rest of the history is read from the database itself, not a copy of it.
+        db.lock().await.stop().await.unwrap();
+
+        let err = subscription.next().await.unwrap().expect_err("Expected replay to stop with the database");
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::Stopped)));
+        assert!(subscription.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn truncating_to_the_middle_keeps_rownums_stable() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
 
-        let index_path = self.index_path();
-        let mut index_file = File::options()
-            .read(true)
-            .truncate(true)
-            .create(true)
-            .open(&index_path).await
-            .with_context(|| format!("Failed to open file for index at {:?}", index_path))?;
+        let events: Vec<Event> = (0..5).map(|_| unique_event()).collect();
+        db.append(events.clone(), ExpectedRevision::Any).await.unwrap();
 
-        let mut offset = 0u64;
-        let mut lines = BufReader::new(file).lines();
+        assert_eq!(db.truncate_before(2).await.unwrap(), 2);
+        assert_eq!(db.truncate_before(2).await.unwrap(), 0);
 
-        index_file.write_u64(offset).await?;
+        assert_eq!(db.count(), 3);
+        assert_eq!(db.revision(), 5);
+        assert_eq!(db.query(0, 10).await.unwrap(), events[2..]);
+        assert_eq!(db.query(3, 1).await.unwrap(), events[3..4]);
+        assert_eq!(db.get(0).await.unwrap(), None);
+        assert_eq!(db.get(1).await.unwrap(), None);
+        assert_eq!(db.get(2).await.unwrap(), Some(events[2].clone()));
 
-        while let Some(line) = lines.next_line().await? {
-            // offset addend is `rowlen + 1` because `BufReader::lines()` strips newlines for us
-            offset += line.len() as u64 + 1;
+        let appended = unique_event();
+        assert_eq!(db.append(vec![appended.clone()], ExpectedRevision::Exact(5)).await.unwrap(), 6);
+        assert_eq!(db.query(5, 1).await.unwrap(), vec![appended]);
 
-            index_file.write_u64(offset).await?;
-        }
+        // Rownums survive both reading the index and rebuilding it from a scan.
+        let mut reopened = Database::new(test_file.path());
+        reopened.start().await.expect("Failed to start DB");
+        assert_eq!(reopened.query(3, 1).await.unwrap(), events[3..4]);
+        assert_eq!(reopened.get(1).await.unwrap(), None);
 
-        Ok(())
+        std::fs::remove_file(test_file.path().join("events.index")).unwrap();
+        let mut rebuilt = Database::new(test_file.path());
+        rebuilt.start().await.expect("Failed to start DB");
+        assert_eq!(rebuilt.query(3, 1).await.unwrap(), events[3..4]);
+        assert_eq!(rebuilt.revision(), 6);
     }
 
-    #[tracing::instrument]
-    pub async fn last_modified(&self) -> Result<u64> {
-        let events_path = self.events_path();
+    #[tokio::test]
+    async fn truncation_interrupted_before_moving_the_base_keeps_rownums() {
+        let test_file = tempdir().unwrap();
 
-        fs::metadata(&events_path).await
-            .with_context(|| format!("Failed to access metadata of DB path {:?}", &events_path))?
-            .modified()
-            .with_context(|| format!("Failed to access modified time of DB path {:?}", &events_path))?
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .with_context(|| format!("Failed to convert mtime to unix time for DB path {:?}", &events_path))
-            .map(|d| d.as_secs())
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        let events: Vec<Event> = (0..5).map(|_| unique_event()).collect();
+        db.append(events.clone(), ExpectedRevision::Any).await.unwrap();
+        assert_eq!(db.truncate_before(2).await.unwrap(), 2);
+        drop(db);
+
+        // As if the segment was rewritten, but the server crashed before writing the base.
+        std::fs::remove_file(test_file.path().join("events.base")).unwrap();
+
+        let mut reopened = Database::new(test_file.path());
+        reopened.start().await.expect("Failed to start DB");
+        assert_eq!(reopened.revision(), 5);
+        assert_eq!(reopened.query(0, 10).await.unwrap(), events[2..]);
+        assert_eq!(reopened.query(3, 1).await.unwrap(), events[3..4]);
+
+        assert_eq!(reopened.truncate_before(2).await.unwrap(), 0);
+        assert_eq!(reopened.query(0, 10).await.unwrap(), events[2..]);
     }
 
-    #[tracing::instrument]
-    pub async fn file_len(&self) -> Result<u64> {
-        let events_path = self.events_path();
+    #[tokio::test]
+    async fn truncating_everything_never_reuses_rownums() {
+        let test_file = tempdir().unwrap();
 
-        let size =
-            fs::metadata(&events_path).await
-                .with_context(|| format!("Failed to access metadata of DB path {:?}", &events_path))?
-                .len();
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
 
-        Ok(size)
+        db.append(vec![unique_event(), unique_event(), unique_event()], ExpectedRevision::Any).await.unwrap();
+
+        assert_eq!(db.truncate_before(100).await.unwrap(), 3);
+        assert_eq!(db.count(), 0);
+        assert_eq!(db.revision(), 3);
+        assert!(db.query(0, 10).await.unwrap().is_empty());
+
+        let mut reopened = Database::new(test_file.path());
+        reopened.start().await.expect("Failed to start DB");
+        assert_eq!(reopened.revision(), 3);
+
+        let event = unique_event();
+        assert_eq!(reopened.append(vec![event.clone()], ExpectedRevision::Exact(3)).await.unwrap(), 4);
+        assert_eq!(reopened.query(0, 10).await.unwrap(), vec![event]);
+        assert_eq!(reopened.query_backward(3, 10).await.unwrap().len(), 1);
     }
 
-    #[tracing::instrument]
-    pub async fn revision(&self) -> Result<u64> {
-        let index_path = self.index_path();
+    #[tokio::test]
+    async fn appends_roll_over_to_new_segments() {
+        let test_file = tempdir().unwrap();
 
-        if index_path.try_exists()? {
-            fs::metadata(&index_path).await
-                .with_context(|| format!("Failed to metadata of index at {:?}", index_path))
-                .map(|m| m.len() / 8)
-        } else {
-            Ok(0)
+        let mut db = Database::new(test_file.path());
+        db.set_segment_bytes(Some(300));
+        db.start().await.expect("Failed to start DB");
+
+        let events: Vec<Event> = (0..10).map(|_| unique_event()).collect();
+        for event in events.iter() {
+            db.append(vec![event.clone()], ExpectedRevision::Any).await.unwrap();
         }
-    }
 
-    pub async fn last_offset(&self) -> Result<u64> {
-        let index_path = self.index_path();
+        assert!(db.segments.len() > 2, "Expected several segments, got {:?}", db.segments);
+        assert!(test_file.path().join("events.00000001.ndjson").exists());
 
-        if index_path.try_exists()? {
-        let mut index_file = File::options()
-            .read(true)
-            .open(&index_path).await
-            .with_context(|| format!("Failed to open file for index at {:?}", index_path))?;
+        assert_eq!(db.query(0, 10).await.unwrap(), events);
+        assert_eq!(db.query(3, 4).await.unwrap(), events[3..7]);
 
-        index_file.seek(SeekFrom::End(8)).await?;
-        index_file.read_u64().await
-            .with_context(|| format!("Failed to read u64 from index at {:?}", index_path))
-        } else {
-            Ok(0)
+        let mut backward = events.clone();
+        backward.reverse();
+        assert_eq!(db.query_backward(9, 10).await.unwrap(), backward);
+        assert_eq!(db.verify().await.unwrap(), None);
+
+        let mut reopened = Database::new(test_file.path());
+        reopened.set_segment_bytes(Some(300));
+        reopened.start().await.expect("Failed to start DB");
+        assert_eq!(reopened.revision(), 10);
+        assert_eq!(reopened.query(0, 10).await.unwrap(), events);
+        assert_eq!(reopened.file_len().await.unwrap(), db.file_len().await.unwrap());
+
+        // Rownums carry across segments even when their indexes have to be rebuilt.
+        for segment in reopened.segments.clone() {
+            std::fs::remove_file(reopened.segment_index_path(segment)).unwrap();
         }
+        let mut rebuilt = Database::new(test_file.path());
+        rebuilt.start().await.expect("Failed to start DB");
+        assert_eq!(rebuilt.query(5, 5).await.unwrap(), events[5..]);
+
+        assert_eq!(rebuilt.truncate_before(5).await.unwrap(), 5);
+        assert_eq!(rebuilt.query(0, 10).await.unwrap(), events[5..]);
+        assert_eq!(rebuilt.append(vec![unique_event()], ExpectedRevision::Exact(10)).await.unwrap(), 11);
+
+        rebuilt.delete().await.unwrap();
+        assert!(!test_file.path().join("events.00000001.ndjson").exists());
     }
 
-    #[tracing::instrument]
-    pub async fn query(&self, start: u64, limit: usize) -> Result<Vec<Event>> {
-        let index_path = self.index_path();
+    #[tokio::test]
+    async fn compressed_segments_round_trip() {
+        let test_file = tempdir().unwrap();
+        let plain_file = tempdir().unwrap();
 
-        if !index_path.try_exists()? {
-            return Ok(vec![]);
+        let mut db = Database::new(test_file.path());
+        db.set_segment_bytes(Some(1000));
+        db.set_compression_block_events(Some(2));
+        db.start().await.expect("Failed to start DB");
+
+        let mut plain = Database::new(plain_file.path());
+        plain.set_segment_bytes(Some(1000));
+        plain.start().await.expect("Failed to start DB");
+
+        let events: Vec<Event> = (0..30).map(|_| unique_event()).collect();
+        for event in events.iter() {
+            db.append(vec![event.clone()], ExpectedRevision::Any).await.unwrap();
+            plain.append(vec![event.clone()], ExpectedRevision::Any).await.unwrap();
         }
 
-        let mut index_file = File::options()
-            .read(true)
-            .append(true)
-            .create(true)
-            .open(&index_path).await
-            .with_context(|| format!("Could not open index file at {:?}", index_path))?;
+        assert!(!db.compressed_segments.is_empty(), "Expected compressed segments, got {:?}", db.segments);
+        assert!(test_file.path().join("events.ndjson.gz").exists());
+        assert!(!test_file.path().join("events.ndjson").exists());
+        assert!(db.file_len().await.unwrap() < plain.file_len().await.unwrap());
 
-        index_file.seek(SeekFrom::Start(start * 8)).await?;
+        assert_eq!(db.query(0, 30).await.unwrap(), events);
+        assert_eq!(db.query(3, 4).await.unwrap(), events[3..7]);
+        assert_eq!(db.query_by_type("test", 0, 30).await.unwrap().len(), 30);
+        assert_eq!(db.verify().await.unwrap(), None);
 
-        let start_offset = index_file.read_u64().await?;
-        let events_path = self.events_path();
+        let mut backward = events.clone();
+        backward.reverse();
+        assert_eq!(db.query_backward(29, 30).await.unwrap(), backward);
 
-        let mut file = File::options()
-            .read(true)
-            .append(true)
-            .create(true)
-            .open(&events_path).await
-            .with_context(|| format!("Could not open file to query DB at {:?}", events_path))?;
+        let mut reopened = Database::new(test_file.path());
+        reopened.set_compression_block_events(Some(2));
+        reopened.start().await.expect("Failed to start DB");
+        assert_eq!(reopened.query(0, 30).await.unwrap(), events);
 
-        let _position = file
-            .seek(SeekFrom::Start(start_offset)).await
-            .with_context(|| format!("Failed to seek to row {} (offset {}) from DB at {:?}", start, start_offset, events_path))?;
+        reopened.rebuild_index().await.unwrap();
+        assert_eq!(reopened.query(11, 5).await.unwrap(), events[11..16]);
 
-        let mut events = vec![];
+        assert_eq!(reopened.truncate_before(5).await.unwrap(), 5);
+        assert_eq!(reopened.query(0, 30).await.unwrap(), events[5..]);
+        assert_eq!(reopened.verify().await.unwrap(), None);
 
-        let mut lines = BufReader::new(file).lines();
+        reopened.delete().await.unwrap();
+        assert!(!test_file.path().join("events.blocks").exists());
+    }
 
-        while let Some(line) = lines.next_line().await? {
-            let event = decode_event(line)?;
-            events.push(event);
+    #[tokio::test]
+    async fn append_rejects_events_over_the_maximum_size() {
+        let test_file = tempdir().unwrap();
 
-            if events.len() >= limit {
-                break
-            }
-        }
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
 
-        Ok(events)
+        let event = unique_event();
+        let event_bytes = serde_json::to_string(&event).unwrap().len();
+        let too_large = EventBuilderV10::new().id(Uuid::now_v7().to_string()).source("test").ty("testx").build().unwrap();
+        assert_eq!(serde_json::to_string(&too_large).unwrap().len(), event_bytes + 1);
+
+        db.set_max_event_bytes(Some(event_bytes));
+        let err = db.append(vec![unique_event(), too_large], ExpectedRevision::Any).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::EventTooLarge { index: 1, .. })));
+        assert_eq!(db.revision(), 0);
+
+        assert_eq!(db.append(vec![event.clone()], ExpectedRevision::Any).await.unwrap(), 1);
+        assert_eq!(db.query(0, 10).await.unwrap(), vec![event]);
     }
 
-    #[tracing::instrument]
-    pub async fn append(
-        &self,
-        events: Vec<Event>,
-        expected_revision: ExpectedRevision,
-    ) -> Result<u64> {
-        ensure!(!events.is_empty(), "Events list cannot be empty");
+    #[tokio::test]
+    async fn clearly_oversized_events_are_rejected_without_serializing_them() {
+        let test_file = tempdir().unwrap();
 
-        let current_revision = self.revision().await?;
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+        db.set_max_event_bytes(Some(1024));
 
-        let revision_match: bool = match expected_revision {
-            ExpectedRevision::Any => true,
-            ExpectedRevision::NoStream => current_revision == 0,
-            ExpectedRevision::StreamExists => current_revision > 0,
-            ExpectedRevision::Exact(revision) => current_revision == revision,
+        let just_over = EventBuilderV10::new().id(Uuid::now_v7().to_string()).source("test").ty("test")
+            .data("text/plain", "\"".repeat(500))
+            .build().unwrap();
+        assert!(min_json_len(&just_over) <= 1024);
+        assert!(serde_json::to_string(&just_over).unwrap().len() > 1024);
+        let err = db.append(vec![just_over.clone()], ExpectedRevision::Any).await.unwrap_err();
+        let Some(Error::EventTooLarge { index: 0, bytes, max_bytes: 1024 }) = err.downcast_ref::<Error>() else {
+            panic!("expected EventTooLarge, got {err:?}");
         };
+        // Only the serialized length is exact, so this one had to be serialized.
+        assert_eq!(*bytes, serde_json::to_string(&just_over).unwrap().len());
 
-        if !revision_match {
-            return Err(Error::RevisionMismatch.into());
-        }
+        let huge = EventBuilderV10::new().id(Uuid::now_v7().to_string()).source("test").ty("test")
+            .data("application/json", serde_json::json!({"items": vec!["x".repeat(100); 100]}))
+            .build().unwrap();
+        let err = db.append(vec![unique_event(), huge.clone()], ExpectedRevision::Any).await.unwrap_err();
+        let Some(Error::EventTooLarge { index: 1, bytes, max_bytes: 1024 }) = err.downcast_ref::<Error>() else {
+            panic!("expected EventTooLarge, got {err:?}");
+        };
+        assert_eq!(*bytes, min_json_len(&huge));
+        assert!(*bytes < serde_json::to_string(&huge).unwrap().len());
+        assert_eq!(db.revision(), 0);
+    }
+
+    #[tokio::test]
+    async fn snapshots_are_replaced_and_deleted_with_the_stream() {
+        let test_file = tempdir().unwrap();
 
-        let events_path = self.events_path();
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+        assert_eq!(db.get_latest_snapshot().await.unwrap(), None);
 
-        let mut event_lengths = Vec::new();
-        let mut bytes = Vec::new();
+        db.append(vec![unique_event(), unique_event()], ExpectedRevision::Any).await.unwrap();
 
-        for event in events.iter() {
-            let json = serde_json::to_string(&event).with_context(|| format!("Failed to JSONify event"))?;
+        db.put_snapshot(1, b"first".to_vec()).await.unwrap();
+        assert_eq!(db.get_latest_snapshot().await.unwrap(), Some((1, b"first".to_vec())));
 
-            event_lengths.push(bytes.len());
-            write!(&mut bytes, "{}\n", json).with_context(|| format!("Failed to write JSON bytes to Vec"))?;
-        }
+        db.put_snapshot(2, b"second".to_vec()).await.unwrap();
+        assert_eq!(db.get_latest_snapshot().await.unwrap(), Some((2, b"second".to_vec())));
 
-        let mut file = File::options()
-            .read(true)
-            .append(true)
-            .create(true)
-            .open(&events_path).await
-            .with_context(|| format!("Failed to open file for DB at {:?}", events_path))?;
+        let err = db.put_snapshot(3, b"third".to_vec()).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::SnapshotPastHead)));
+        assert_eq!(db.get_latest_snapshot().await.unwrap(), Some((2, b"second".to_vec())));
 
-        let mut event_offset = file.seek(SeekFrom::End(0)).await
-            .with_context(|| format!("Failed to seek to end of file for DB at {:?}", events_path))?;
+        db.delete().await.unwrap();
+        assert_eq!(db.get_latest_snapshot().await.unwrap(), None);
+    }
 
-        file.write_all(&bytes).await
-            .with_context(|| format!("Failed to write event to file for DB at {:?}", events_path))?;
+    #[tokio::test]
+    async fn compact_by_subject_keeps_the_latest_event_per_subject() {
+        let test_file = tempdir().unwrap();
 
-        let index_path = self.index_path();
-        let mut index_file = File::options()
-            .read(true)
-            .append(true)
-            .create(true)
-            .open(&index_path).await
-            .with_context(|| format!("Failed to open file for index at {:?}", index_path))?;
+        let mut db = Database::new(test_file.path());
+        db.set_segment_bytes(Some(300));
+        db.start().await.expect("Failed to start DB");
 
-        for event_length in event_lengths.iter() {
-            index_file.write_u64(event_offset).await?;
+        let with_subject = |subject: &str| {
+            EventBuilderV10::new().id(Uuid::now_v7().to_string()).source("test").ty("test").subject(subject).build().unwrap()
+        };
 
-            event_offset += *event_length as u64 + 1;
+        let events = [
+            with_subject("a"),
+            unique_event(),
+            with_subject("b"),
+            with_subject("a"),
+            unique_event(),
+            with_subject("b"),
+            with_subject("a"),
+            with_subject("c"),
+        ];
+        for event in events.iter() {
+            db.append(vec![event.clone()], ExpectedRevision::Any).await.unwrap();
         }
 
-        Ok(current_revision + events.len() as u64)
-    }
+        assert_eq!(db.dirty_ratio().await.unwrap(), 3.0 / 8.0);
+        assert_eq!(db.compact_by_subject().await.unwrap(), 3);
+        assert_eq!(db.dirty_ratio().await.unwrap(), 0.0);
+        assert_eq!(db.count(), 5);
+        assert_eq!(db.revision(), 8);
 
-    pub async fn delete(&mut self) -> anyhow::Result<()> {
-        let events_path = self.events_path();
-        fs::remove_file(&events_path).await
-            .with_context(|| format!("Failed to delete database file at {:?}", events_path))?;
+        let survivors: [u64; 5] = [1, 4, 5, 6, 7];
+        let expected: Vec<Event> = survivors.iter().map(|rownum| events[*rownum as usize].clone()).collect();
+        assert_eq!(db.query(0, 10).await.unwrap(), expected);
+        assert_eq!(db.primary_index.keys().copied().collect::<Vec<u64>>(), survivors);
+        assert_eq!(db.query_backward(7, 10).await.unwrap().len(), 5);
+        assert_eq!(db.verify().await.unwrap(), None);
 
-        let index_path = self.index_path();
-        fs::remove_file(&index_path).await
-            .with_context(|| format!("Failed to delete index file at {:?}", events_path))?;
+        assert_eq!(db.compact_by_subject().await.unwrap(), 0);
 
-        Ok(())
+        let mut reopened = Database::new(test_file.path());
+        reopened.start().await.expect("Failed to start DB");
+        assert_eq!(reopened.primary_index.keys().copied().collect::<Vec<u64>>(), survivors);
+        assert_eq!(reopened.append(vec![with_subject("c")], ExpectedRevision::Exact(8)).await.unwrap(), 9);
     }
 
-    fn events_path(&self) -> PathBuf {
-        self.path.join("events.ndjson")
+    #[tokio::test]
+    async fn query_with_meta_returns_each_events_location() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.set_segment_bytes(Some(300));
+        db.start().await.expect("Failed to start DB");
+
+        let events: Vec<Event> = (0..6).map(|_| unique_event()).collect();
+        for event in events.iter() {
+            db.append(vec![event.clone()], ExpectedRevision::Any).await.unwrap();
+        }
+
+        let items = db.query_with_meta(1, 4).await.unwrap();
+        assert_eq!(items.iter().map(|item| item.rownum).collect::<Vec<u64>>(), [1, 2, 3, 4]);
+        assert_eq!(items.iter().map(|item| item.event.clone()).collect::<Vec<Event>>(), events[1..5]);
+        assert!(items.windows(2).all(|pair| (pair[0].segment, pair[0].offset) < (pair[1].segment, pair[1].offset)));
+
+        for item in items {
+            let mut reader = SegmentReader::default();
+            let record = reader.read_record(item.segment, &db.segment_file(item.segment), item.offset).await.unwrap();
+            assert_eq!(decode_event(String::from_utf8(record).unwrap()).unwrap(), item.event);
+        }
     }
-    fn index_path(&self) -> PathBuf {
-        self.path.join("index.dat")
+
+    #[tokio::test]
+    async fn query_by_subject_reads_one_subjects_events_in_order() {
+        let test_file = tempdir().unwrap();
+
+        let with_subject = |subject: &str| {
+            EventBuilderV10::new().id(Uuid::now_v7().to_string()).source("test").ty("test").subject(subject).build().unwrap()
+        };
+
+        let events: Vec<Event> = (0..9).map(|i| with_subject(["a", "b", "c"][i % 3])).collect();
+        let a_events: Vec<Event> = events.iter().step_by(3).cloned().collect();
+
+        for index_subjects in [false, true] {
+            let mut db = Database::new(test_file.path());
+            db.start().await.expect("Failed to start DB");
+            db.set_metadata(StreamMetadata { index_subjects, ..Default::default() }).await.unwrap();
+
+            if !index_subjects {
+                db.append(events.clone(), ExpectedRevision::Any).await.unwrap();
+            }
+
+            assert_eq!(db.secondary_indexes().await.unwrap().subject_index.is_empty(), !index_subjects);
+            assert_eq!(db.query_by_subject("a", 0, 10).await.unwrap(), a_events);
+            assert_eq!(db.query_by_subject("a", 1, 1).await.unwrap(), a_events[1..2]);
+            assert!(db.query_by_subject("missing", 0, 10).await.unwrap().is_empty());
+        }
     }
-}
 
-fn decode_event(row: String) -> Result<Event> {
-    let json = row.trim_end();
+    #[tokio::test]
+    async fn query_range_reads_inner_windows() {
+        let test_file = tempdir().unwrap();
 
-    serde_json::from_str(&json)
-            .with_context(|| format!("Failed to decode event JSON"))
-}
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
 
-#[cfg(test)]
-mod tests {
-    use cloudevents::event::Event;
-    use cloudevents::*;
-    use tempfile::tempdir;
+        let events: Vec<Event> = (0..6).map(|_| unique_event()).collect();
+        db.append(events.clone(), ExpectedRevision::Any).await.unwrap();
 
-    use crate::db::ExpectedRevision;
+        assert_eq!(db.query_range(1, 4).await.unwrap(), events[1..4]);
+        assert_eq!(db.query_range(2, 3).await.unwrap(), events[2..3]);
+        assert!(db.query_range(3, 3).await.unwrap().is_empty());
+        assert!(db.query_range(4, 2).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn query_range_clamps_to_the_tail() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
 
-    use super::Database;
+        let events: Vec<Event> = (0..3).map(|_| unique_event()).collect();
+        db.append(events.clone(), ExpectedRevision::Any).await.unwrap();
+
+        assert_eq!(db.query_range(1, 100).await.unwrap(), events[1..]);
+        assert!(db.query_range(3, 100).await.unwrap().is_empty());
+    }
 
     #[tokio::test]
-    async fn can_write_and_read() {
+    async fn query_stream_reads_lazily_in_order() {
         let test_file = tempdir().unwrap();
 
-        let db = Database::new(test_file.path());
+        let mut db = Database::new(test_file.path());
+        db.set_segment_bytes(Some(1));
+        db.start().await.expect("Failed to start DB");
 
-        let event = Event::default();
+        let events: Vec<Event> = (0..5).map(|_| unique_event()).collect();
+        for event in events.iter() {
+            db.append(vec![event.clone()], ExpectedRevision::Any).await.unwrap();
+        }
 
-        let rownum = db.append(vec![event.clone()], ExpectedRevision::Any).await
-            .expect("Could not write to the DB");
+        let mut stream = Box::pin(db.query_stream(1, 3));
 
-        let result: Event = db
-            .query(0, 1).await
-            .expect("Row not found")
-            .pop()
-            .expect("Failed to read row");
+        assert_eq!(stream.next().await.unwrap().unwrap(), events[1]);
 
-        assert_eq!(rownum, 1);
-        assert_eq!(result.id(), event.id());
+        // Appends made while the stream is being consumed are past the end of its snapshot.
+        db.append(vec![unique_event()], ExpectedRevision::Any).await.unwrap();
+
+        assert_eq!(stream.next().await.unwrap().unwrap(), events[2]);
+        assert_eq!(stream.next().await.unwrap().unwrap(), events[3]);
+        assert!(stream.next().await.is_none());
+
+        let rest: Vec<Event> = db.query_stream(3, usize::MAX).map(|event| event.unwrap()).collect().await;
+        assert_eq!(rest.len(), 3);
+        assert_eq!(rest[..2], events[3..]);
     }
 
     #[tokio::test]
-    async fn read_nonexistent() {
+    async fn get_by_source_id_finds_events() {
         let test_file = tempdir().unwrap();
 
-        let db = Database::new(test_file.path());
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
 
-        let result = db.query(0, 1).await.expect("Expected success reading empty db");
+        let events: Vec<Event> = (0..3).map(|_| unique_event()).collect();
+        db.append(events.clone(), ExpectedRevision::Any).await.unwrap();
 
-        assert!(result.is_empty());
+        let found = db.get_by_source_id(&events[1].source().to_string(), events[1].id()).await.unwrap();
+        assert_eq!(found, Some((1, events[1].clone())));
+
+        let missing = db.get_by_source_id(&events[1].source().to_string(), "missing").await.unwrap();
+        assert_eq!(missing, None);
     }
 
     #[tokio::test]
-    async fn can_write_expecting_no_stream_in_empty_db() {
+    async fn activity_counts_events_per_bucket() {
         let test_file = tempdir().unwrap();
 
-        let db = Database::new(test_file.path());
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
 
-        let event = Event::default();
+        let timed = |time: &str| {
+            EventBuilderV10::new().id(Uuid::now_v7().to_string()).source("test").ty("test").time(time).build().unwrap()
+        };
 
-        db.append(vec![event], ExpectedRevision::NoStream).await
-            .expect("Could not write to the DB");
+        let events = vec![
+            timed("2024-01-01T00:10:00Z"),
+            timed("2024-01-01T00:50:00Z"),
+            timed("2024-01-01T02:30:00Z"),
+            unique_event(),
+            timed("2023-12-31T23:59:59Z"),
+        ];
+        db.append(events, ExpectedRevision::Any).await.unwrap();
+
+        let activity = db.activity(Duration::from_secs(3600), None).await.unwrap();
+        assert_eq!(activity.buckets.into_iter().collect::<Vec<_>>(), [(1704063600, 1), (1704067200, 2), (1704074400, 1)]);
+        assert_eq!(activity.untimed, 1);
+
+        let activity = db.activity(Duration::from_secs(3600), Some(1704067200)).await.unwrap();
+        assert_eq!(activity.buckets.into_iter().collect::<Vec<_>>(), [(1704067200, 2), (1704074400, 1)]);
+
+        assert!(db.activity(Duration::ZERO, None).await.is_err());
     }
 
     #[tokio::test]
-    async fn cannot_write_expecting_no_stream_in_non_empty_db() {
+    async fn query_by_type_returns_only_matching_events() {
         let test_file = tempdir().unwrap();
 
-        let db = Database::new(test_file.path());
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
 
-        let event1 = Event::default();
-        let event2 = Event::default();
-        db.append(vec![event1], ExpectedRevision::NoStream).await
-            .expect("Could not write to the DB");
-        assert!(db.append(vec![event2], ExpectedRevision::NoStream).await.is_err());
+        let typed = |ty: &str| {
+            EventBuilderV10::new().id(Uuid::now_v7().to_string()).source("test").ty(ty).build().unwrap()
+        };
+
+        let events = vec![typed("a"), typed("b"), typed("a"), typed("c"), typed("a"), typed("b")];
+        db.append(events.clone(), ExpectedRevision::Any).await.unwrap();
+
+        assert_eq!(db.query_by_type("a", 0, 10).await.unwrap(), [events[0].clone(), events[2].clone(), events[4].clone()]);
+        assert_eq!(db.query_by_type("b", 0, 10).await.unwrap(), [events[1].clone(), events[5].clone()]);
+        assert_eq!(db.query_by_type("c", 0, 10).await.unwrap(), [events[3].clone()]);
+        assert!(db.query_by_type("d", 0, 10).await.unwrap().is_empty());
+
+        assert_eq!(db.query_by_type("a", 1, 1).await.unwrap(), [events[2].clone()]);
+        assert_eq!(db.query_by_type("a", 3, 10).await.unwrap(), [events[4].clone()]);
+
+        let mut reopened = Database::new(test_file.path());
+        reopened.start().await.expect("Failed to start DB");
+        assert_eq!(reopened.query_by_type("b", 0, 10).await.unwrap(), [events[1].clone(), events[5].clone()]);
     }
 
     #[tokio::test]
-    async fn cannot_write_to_empty_db_expecting_stream_exists() {
+    async fn both_storage_formats_round_trip() {
+        for format in [StorageFormat::Ndjson, StorageFormat::Binary] {
+            let test_file = tempdir().unwrap();
+
+            let mut db = Database::new(test_file.path());
+            db.set_storage_format(format);
+            db.set_segment_bytes(Some(300));
+            db.start().await.expect("Failed to start DB");
+
+            let events: Vec<Event> = (0..6).map(|_| unique_event()).collect();
+            for event in events.iter() {
+                db.append(vec![event.clone()], ExpectedRevision::Any).await.unwrap();
+            }
+
+            assert_eq!(db.query(0, 10).await.unwrap(), events, "{:?}", format);
+            assert_eq!(db.query_by_type("test", 2, 2).await.unwrap(), events[2..4], "{:?}", format);
+            assert_eq!(db.verify().await.unwrap(), None, "{:?}", format);
+
+            let header = std::fs::read(test_file.path().join("events.ndjson")).unwrap();
+            assert_eq!(header.starts_with(BINARY_HEADER), format == StorageFormat::Binary);
+
+            // The format is read back from disk rather than taken from the setting.
+            let mut reopened = Database::new(test_file.path());
+            reopened.set_segment_bytes(Some(300));
+            reopened.start().await.expect("Failed to start DB");
+            assert_eq!(reopened.query(0, 10).await.unwrap(), events, "{:?}", format);
+
+            reopened.rebuild_index().await.unwrap();
+            assert_eq!(reopened.query(3, 10).await.unwrap(), events[3..], "{:?}", format);
+
+            assert_eq!(reopened.truncate_before(4).await.unwrap(), 4);
+            assert_eq!(reopened.query(0, 10).await.unwrap(), events[4..], "{:?}", format);
+
+            let next = unique_event();
+            reopened.append(vec![next.clone()], ExpectedRevision::Exact(6)).await.unwrap();
+            assert_eq!(reopened.query(6, 1).await.unwrap(), [next], "{:?}", format);
+            assert_eq!(reopened.verify().await.unwrap(), None, "{:?}", format);
+        }
+    }
+
+    #[tokio::test]
+    async fn reopening_truncates_a_partial_binary_record() {
         let test_file = tempdir().unwrap();
 
-        let db = Database::new(test_file.path());
+        let mut db = Database::new(test_file.path());
+        db.set_storage_format(StorageFormat::Binary);
+        db.start().await.expect("Failed to start DB");
 
-        let event = Event::default();
+        let event = unique_event();
+        db.append(vec![event.clone()], ExpectedRevision::Any).await.unwrap();
+        drop(db);
 
-        assert!(db.append(vec![event], ExpectedRevision::StreamExists).await.is_err());
+        let mut file = std::fs::OpenOptions::new().append(true).open(test_file.path().join("events.ndjson")).unwrap();
+        file.write_all(&100u32.to_le_bytes()).unwrap();
+        file.write_all(b"00000000 {\"specversion\"").unwrap();
+        drop(file);
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+        assert_eq!(db.query(0, 10).await.unwrap(), [event]);
+
+        let next = unique_event();
+        db.append(vec![next.clone()], ExpectedRevision::Exact(1)).await.unwrap();
+        assert_eq!(db.query(1, 1).await.unwrap(), [next]);
     }
 
     #[tokio::test]
-    async fn can_write_expecting_revision_zero_with_present_row() {
+    async fn corrupt_binary_lengths_fail_reads_without_allocating_them() {
         let test_file = tempdir().unwrap();
 
-        let db = Database::new(test_file.path());
+        let mut db = Database::new(test_file.path());
+        db.set_storage_format(StorageFormat::Binary);
+        db.start().await.expect("Failed to start DB");
+        db.append(vec![unique_event(), unique_event()], ExpectedRevision::Any).await.unwrap();
 
-        let event1 = Event::default();
-        let event2 = Event::default();
-        db.append(vec![event1], ExpectedRevision::NoStream).await
-            .expect("Could not write to the DB");
-        db.append(vec![event2], ExpectedRevision::Exact(1)).await
-            .expect("Could not write to the DB");
+        let mut file = std::fs::OpenOptions::new().write(true).open(test_file.path().join("events.ndjson")).unwrap();
+        std::io::Seek::seek(&mut file, SeekFrom::Start(BINARY_HEADER.len() as u64)).unwrap();
+        file.write_all(&u32::MAX.to_le_bytes()).unwrap();
+        drop(file);
+
+        assert_eq!(db.verify().await.unwrap(), Some(0));
+        assert!(db.query_backward(0, 1).await.is_err());
+        assert!(db.query(0, 2).await.is_err());
     }
 
     #[tokio::test]
-    async fn can_write_and_read_many() {
+    async fn reserved_rownums_can_be_filled_out_of_order() {
         let test_file = tempdir().unwrap();
 
-        let db = Database::new(test_file.path());
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
 
-        let event = Event::default();
+        let first = unique_event();
+        db.append(vec![first.clone()], ExpectedRevision::Any).await.unwrap();
 
-        for n in 1..100 {
-            let rownum =
-                db.append(vec![Event::default()], ExpectedRevision::Any).await
-                .expect("Could not write to the DB");
+        let start = db.reserve(4, Duration::from_secs(60)).await.unwrap();
+        assert_eq!(start, 1);
 
-            assert_eq!(rownum, n);
-        }
+        let events: Vec<Event> = (0..4).map(|_| unique_event()).collect();
 
-        db.append(vec![event.clone()], ExpectedRevision::Any).await
-            .expect("Could not write to the DB");
+        let result = db.append(vec![unique_event()], ExpectedRevision::Any).await.unwrap_err();
+        assert!(matches!(result.downcast_ref::<Error>(), Some(Error::Reserved)));
 
-        for n in 1..100 {
-            let rownum =
-                db.append(vec![Event::default()], ExpectedRevision::Any).await
-                .expect("Could not write to the DB");
+        // Nothing is written until the slots before it are filled.
+        assert_eq!(db.append_reserved(3, vec![events[2].clone(), events[3].clone()]).await.unwrap(), 1);
+        assert_eq!(db.query(0, 10).await.unwrap(), std::slice::from_ref(&first));
 
-            assert_eq!(rownum, n + 100);
-        }
+        let result = db.append_reserved(3, vec![unique_event()]).await.unwrap_err();
+        assert!(matches!(result.downcast_ref::<Error>(), Some(Error::NotReserved { start: 3, end: 4 })));
 
-        let result: Event = db
-            .query(99, 1).await
-            .expect("Row not found")
-            .pop()
-            .expect("Failed to read row");
+        assert_eq!(db.append_reserved(2, vec![events[1].clone()]).await.unwrap(), 1);
+        assert_eq!(db.append_reserved(1, vec![events[0].clone()]).await.unwrap(), 5);
 
-        assert_eq!(result.id(), event.id());
+        let mut expected = vec![first];
+        expected.extend(events);
+        assert_eq!(db.query(0, 10).await.unwrap(), expected);
+
+        let next = unique_event();
+        db.append(vec![next.clone()], ExpectedRevision::Exact(5)).await.unwrap();
+        assert_eq!(db.query(5, 1).await.unwrap(), [next]);
+    }
+
+    #[tokio::test]
+    async fn expired_reservations_are_filled_with_tombstones() {
+        let test_file = tempdir().unwrap();
+
+        let mut db = Database::new(test_file.path());
+        db.start().await.expect("Failed to start DB");
+
+        db.reserve(3, Duration::ZERO).await.unwrap();
+        let event = unique_event();
+
+        let result = db.append_reserved(1, vec![event.clone()]).await.unwrap_err();
+        assert!(matches!(result.downcast_ref::<Error>(), Some(Error::NotReserved { .. })));
+
+        assert_eq!(db.append(vec![event.clone()], ExpectedRevision::Exact(3)).await.unwrap(), 4);
+
+        let events = db.query(0, 10).await.unwrap();
+        assert_eq!(events.iter().map(|event| event.ty()).collect::<Vec<&str>>(), [TOMBSTONE_TYPE, TOMBSTONE_TYPE, TOMBSTONE_TYPE, "test"]);
+        assert_eq!(events[3], event);
     }
 }
@@ -0,0 +1,152 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use cloudevents::Event;
+use serde_json::{Map, Value};
+
+/// A built-in step that fills in or normalizes attributes of posted events before they're
+/// decoded and appended. Enrichers run in the order they're configured, and only ever add
+/// attributes a client left out.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Enricher {
+    /// Sets a missing `source` to the posting user's ID.
+    DefaultSource,
+    /// Sets a missing `datacontenttype` to the given type on events that have `data`.
+    DefaultDataContentType(String),
+}
+
+impl Enricher {
+    /// Applies this enricher to an event's JSON object.
+    pub fn enrich(&self, event: &mut Map<String, Value>, user_id: &str) {
+        match self {
+            Enricher::DefaultSource => {
+                event.entry("source").or_insert_with(|| Value::String(user_id.to_string()));
+            },
+            Enricher::DefaultDataContentType(content_type) => {
+                if event.contains_key("data") {
+                    event.entry("datacontenttype").or_insert_with(|| Value::String(content_type.clone()));
+                }
+            },
+        }
+    }
+}
+
+/// Runs each enricher in order over a posted event, or over each event of a posted batch.
+/// Anything that isn't a JSON object is left for decoding to reject.
+pub fn enrich_payload(enrichers: &[Enricher], payload: &mut Value, user_id: &str) {
+    let events = match payload {
+        Value::Array(events) => events.iter_mut().collect(),
+        event => vec![event],
+    };
+
+    for event in events {
+        if let Value::Object(event) = event {
+            for enricher in enrichers {
+                enricher.enrich(event, user_id);
+            }
+        }
+    }
+}
+
+/// What happens to extension attributes of posted events that the server doesn't know about.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ExtensionPolicy {
+    /// Stores every extension attribute as posted.
+    #[default]
+    Preserve,
+    /// Drops every extension attribute not in the registered set before the event is stored.
+    StripUnknown(Vec<String>),
+}
+
+impl ExtensionPolicy {
+    /// Applies this policy to a decoded event.
+    pub fn apply(&self, event: &mut Event) {
+        let ExtensionPolicy::StripUnknown(registered) = self else {
+            return;
+        };
+
+        let unknown: Vec<String> = event.iter_extensions()
+            .map(|(name, _)| name)
+            .filter(|name| !registered.iter().any(|registered| registered == name))
+            .map(str::to_string)
+            .collect();
+
+        for name in unknown {
+            event.remove_extension(&name);
+        }
+    }
+}
+
+impl FromStr for Enricher {
+    type Err = anyhow::Error;
+
+    /// Parses `default-source` or `default-datacontenttype`, which takes an optional
+    /// `:<media type>` and defaults to `application/json`.
+    fn from_str(enricher: &str) -> Result<Self> {
+        match enricher.split_once(':') {
+            None if enricher == "default-source" => Ok(Enricher::DefaultSource),
+            None if enricher == "default-datacontenttype" => Ok(Enricher::DefaultDataContentType("application/json".to_string())),
+            Some(("default-datacontenttype", content_type)) => Ok(Enricher::DefaultDataContentType(content_type.to_string())),
+            _ => Err(anyhow!("Expected default-source or default-datacontenttype[:<media type>] but got {:?}", enricher)),
+        }
+    }
+}
+
+impl fmt::Display for Enricher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Enricher::DefaultSource => write!(f, "default-source"),
+            Enricher::DefaultDataContentType(content_type) => write!(f, "default-datacontenttype:{}", content_type),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn enrichers_only_fill_in_missing_attributes() {
+        let enrichers = [Enricher::DefaultSource, "default-datacontenttype".parse().unwrap()];
+        let mut payload = json!([
+            { "id": "1", "data": {} },
+            { "id": "2", "source": "elsewhere", "data": "x", "datacontenttype": "text/plain" },
+            { "id": "3" },
+        ]);
+
+        enrich_payload(&enrichers, &mut payload, "test-user");
+
+        assert_eq!(payload, json!([
+            { "id": "1", "source": "test-user", "data": {}, "datacontenttype": "application/json" },
+            { "id": "2", "source": "elsewhere", "data": "x", "datacontenttype": "text/plain" },
+            { "id": "3", "source": "test-user" },
+        ]));
+    }
+
+    #[test]
+    fn strip_unknown_keeps_only_registered_extensions() {
+        let mut event: Event = serde_json::from_value(json!({
+            "specversion": "1.0",
+            "id": "1",
+            "source": "test",
+            "type": "test",
+            "traceparent": "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            "tenant": "acme",
+        })).unwrap();
+
+        ExtensionPolicy::Preserve.apply(&mut event);
+        assert_eq!(event.iter_extensions().count(), 2);
+
+        ExtensionPolicy::StripUnknown(vec!["traceparent".to_string()]).apply(&mut event);
+        assert_eq!(event.iter_extensions().map(|(name, _)| name).collect::<Vec<_>>(), ["traceparent"]);
+    }
+
+    #[test]
+    fn parse_enrichers() {
+        assert_eq!("default-source".parse::<Enricher>().unwrap(), Enricher::DefaultSource);
+        assert_eq!("default-datacontenttype:text/csv".parse::<Enricher>().unwrap(), Enricher::DefaultDataContentType("text/csv".to_string()));
+        assert!("tenant".parse::<Enricher>().is_err());
+    }
+}
@@ -0,0 +1,189 @@
+use std::{mem, time::Duration};
+
+use anyhow::{Context, Result};
+use reqwest::{header, redirect, StatusCode};
+use serde_json::Value;
+use url::Url;
+
+/// Media type of the CloudEvents JSON batch format, a JSON array of events.
+const BATCH_CONTENT_TYPE: &str = "application/cloudevents-batch+json";
+
+/// How long to wait to connect to an ingestion source.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Why events couldn't be read from an ingestion source.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("ingesting from {0:?} is not allowed by this server")]
+    HostNotAllowed(String),
+    #[error("source responded with status {0}")]
+    UnexpectedStatus(StatusCode),
+    #[error("event {index} of the source is more than {max_bytes} bytes")]
+    EventTooLarge { index: usize, max_bytes: usize },
+    #[error("event {index} of the source is not JSON: {message}")]
+    InvalidJson { index: usize, message: String },
+}
+
+/// Fails with `HostNotAllowed` unless `url` is HTTP or HTTPS, and its host, or its host and
+/// port, is in `allowed_hosts`.
+pub fn check_url(url: &Url, allowed_hosts: &[String]) -> Result<(), Error> {
+    let host = url.host_str().unwrap_or_default();
+    let host_and_port = url.port_or_known_default().map(|port| format!("{}:{}", host, port));
+
+    let allowed = matches!(url.scheme(), "http" | "https")
+        && allowed_hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(host) || Some(allowed) == host_and_port.as_ref());
+
+    if !allowed {
+        return Err(Error::HostNotAllowed(url.to_string()));
+    }
+
+    Ok(())
+}
+
+/// Events fetched from a URL, in NDJSON or the CloudEvents batch format. NDJSON is read a line
+/// at a time as it arrives, so only a batch has to fit in memory at once.
+pub struct EventSource {
+    response: reqwest::Response,
+    /// Largest single event, or whole batch-format body, read in bytes.
+    max_bytes: usize,
+    /// Bytes received that don't yet make up a whole line.
+    buffer: Vec<u8>,
+    /// Events parsed but not yet returned, when reading the batch format.
+    pending: Vec<Value>,
+    is_batch: bool,
+    index: usize,
+    finished: bool,
+}
+
+impl EventSource {
+    /// Requests `url`, which must pass `check_url`, sending `authorization` as the
+    /// `Authorization` header if given. Redirects aren't followed, since they could lead
+    /// anywhere. Reading fails once `timeout` has passed since the request was sent, so a
+    /// source that stops sending can't hold the stream's ingestion open forever.
+    pub async fn open(url: &Url, allowed_hosts: &[String], authorization: Option<&str>, max_bytes: usize, timeout: Duration) -> Result<Self> {
+        check_url(url, allowed_hosts)?;
+
+        let client = reqwest::Client::builder()
+            .redirect(redirect::Policy::none())
+            .connect_timeout(CONNECT_TIMEOUT.min(timeout))
+            .timeout(timeout)
+            .build()
+            .context("Failed to build HTTP client for ingestion")?;
+
+        let mut request = client.get(url.clone());
+        if let Some(authorization) = authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+
+        let response = request.send().await
+            .with_context(|| format!("Failed to request events from {}", url))?;
+
+        if !response.status().is_success() {
+            return Err(Error::UnexpectedStatus(response.status()).into());
+        }
+
+        let is_batch = response.headers().get(header::CONTENT_TYPE)
+            .is_some_and(|content_type| content_type.as_bytes().starts_with(BATCH_CONTENT_TYPE.as_bytes()));
+
+        Ok(Self {
+            response,
+            max_bytes,
+            buffer: Vec::new(),
+            pending: Vec::new(),
+            is_batch,
+            index: 0,
+            finished: false,
+        })
+    }
+
+    /// Reads up to `limit` more events, or none once the source is exhausted.
+    pub async fn next_batch(&mut self, limit: usize) -> Result<Vec<Value>> {
+        if self.is_batch {
+            if !self.finished {
+                self.read_batch_body().await?;
+            }
+
+            let end = limit.min(self.pending.len());
+            return Ok(self.pending.drain(..end).collect());
+        }
+
+        let mut events = Vec::new();
+
+        while events.len() < limit {
+            let Some(line) = self.next_line().await? else {
+                break;
+            };
+
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+
+            let event = serde_json::from_slice(&line)
+                .map_err(|err| Error::InvalidJson { index: self.index, message: err.to_string() })?;
+
+            events.push(event);
+            self.index += 1;
+        }
+
+        Ok(events)
+    }
+
+    async fn read_batch_body(&mut self) -> Result<()> {
+        while let Some(chunk) = self.response.chunk().await.context("Failed to read events from source")? {
+            self.buffer.extend_from_slice(&chunk);
+
+            if self.buffer.len() > self.max_bytes {
+                return Err(Error::EventTooLarge { index: 0, max_bytes: self.max_bytes }.into());
+            }
+        }
+
+        self.pending = serde_json::from_slice(&mem::take(&mut self.buffer))
+            .map_err(|err| Error::InvalidJson { index: 0, message: err.to_string() })?;
+        self.finished = true;
+
+        Ok(())
+    }
+
+    /// Reads the next line, without its newline, or `None` at the end of the body.
+    async fn next_line(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(newline) = self.buffer.iter().position(|byte| *byte == b'\n') {
+                let rest = self.buffer.split_off(newline + 1);
+                let mut line = mem::replace(&mut self.buffer, rest);
+                line.pop();
+                return Ok(Some(line));
+            }
+
+            if self.buffer.len() > self.max_bytes {
+                return Err(Error::EventTooLarge { index: self.index, max_bytes: self.max_bytes }.into());
+            }
+
+            if self.finished {
+                return Ok((!self.buffer.is_empty()).then(|| mem::take(&mut self.buffer)));
+            }
+
+            match self.response.chunk().await.context("Failed to read events from source")? {
+                Some(chunk) => self.buffer.extend_from_slice(&chunk),
+                None => self.finished = true,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_allowed_hosts_pass() {
+        let allowed_hosts = vec!["events.example.com".to_string(), "127.0.0.1:8081".to_string()];
+
+        assert!(check_url(&"https://events.example.com/export.ndjson".parse().unwrap(), &allowed_hosts).is_ok());
+        assert!(check_url(&"http://127.0.0.1:8081/events".parse().unwrap(), &allowed_hosts).is_ok());
+
+        assert!(check_url(&"http://127.0.0.1:8082/events".parse().unwrap(), &allowed_hosts).is_err());
+        assert!(check_url(&"http://169.254.169.254/latest/meta-data".parse().unwrap(), &allowed_hosts).is_err());
+        assert!(check_url(&"file:///etc/passwd".parse().unwrap(), &allowed_hosts).is_err());
+        assert!(check_url(&"https://events.example.com.evil.test/".parse().unwrap(), &allowed_hosts).is_err());
+    }
+}
@@ -1,9 +1,16 @@
 use shadow_rs::shadow;
 
 pub mod api;
+pub mod config;
 pub mod db;
+pub mod enrichment;
+pub mod ingest;
+pub mod lru;
+pub mod metrics;
 pub mod server;
 pub mod openid;
+pub mod rate_limit;
+pub mod validation;
 
 shadow!(build);
 
@@ -0,0 +1,77 @@
+use std::{collections::{BTreeMap, HashMap}, hash::Hash};
+
+/// The order keys were last used in, so the least recently used can be evicted first.
+#[derive(Debug)]
+pub struct LruOrder<K> {
+    next_use: u64,
+    /// When each key was last used.
+    uses: HashMap<K, u64>,
+    /// Each key, by when it was last used.
+    keys: BTreeMap<u64, K>,
+}
+
+impl<K: Clone + Eq + Hash> LruOrder<K> {
+    pub fn new() -> Self {
+        Self { next_use: 0, uses: HashMap::new(), keys: BTreeMap::new() }
+    }
+
+    /// Marks `key` as the most recently used, adding it if it isn't tracked yet.
+    pub fn touch(&mut self, key: K) {
+        if let Some(last_use) = self.uses.insert(key.clone(), self.next_use) {
+            self.keys.remove(&last_use);
+        }
+
+        self.keys.insert(self.next_use, key);
+        self.next_use += 1;
+    }
+
+    /// Stops tracking `key`.
+    pub fn remove(&mut self, key: &K) {
+        if let Some(last_use) = self.uses.remove(key) {
+            self.keys.remove(&last_use);
+        }
+    }
+
+    /// Stops tracking the least recently used key, and returns it.
+    pub fn pop_oldest(&mut self) -> Option<K> {
+        let (_, key) = self.keys.pop_first()?;
+        self.uses.remove(&key);
+
+        Some(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.uses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.uses.is_empty()
+    }
+}
+
+impl<K: Clone + Eq + Hash> Default for LruOrder<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_popped_least_recently_used_first() {
+        let mut order = LruOrder::new();
+        order.touch("a");
+        order.touch("b");
+        order.touch("c");
+        order.touch("a");
+        order.remove(&"c");
+
+        assert_eq!(order.len(), 2);
+        assert_eq!(order.pop_oldest(), Some("b"));
+        assert_eq!(order.pop_oldest(), Some("a"));
+        assert_eq!(order.pop_oldest(), None);
+        assert!(order.is_empty());
+    }
+}
@@ -1,10 +1,13 @@
 use anyhow::Context;
-use axum::{response::Response, http::{header, StatusCode}, extract::Request, middleware::{Next, self}};
-use hematite::api;
-use tracing::info;
+use axum::{http::StatusCode, middleware};
+use hematite::{api, config::Config, server::AppState};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::{prelude::*, filter::EnvFilter, fmt, Registry};
 use url::Url;
-use std::{env, fs, path::PathBuf};
+use std::{env, fs, path::PathBuf, sync::Arc, time::Duration};
+
+const CACHE_METRICS_LOG_INTERVAL: Duration = Duration::from_secs(60);
 
 
 #[tokio::main]
@@ -20,36 +23,134 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let streams_dir = PathBuf::from(streams_dir);
     fs::create_dir_all(&streams_dir).expect("Could not create stream database directory.");
 
-    let oidc_url: Url =
-        env::var("HEMATITE_OIDC_URL")
-        .with_context(|| "Env var HEMATITE_OIDC_URL is missing.")?
-        .parse()
-        .with_context(|| "Failed to parse HEMATITE_OIDC_URL as a URL")?;
+    let config = Config::from_env()?;
+
+    // Introspection checks tokens without any provider's discovery document or keys.
+    let oidc_urls: Vec<Url> = match env::var("HEMATITE_OIDC_URL") {
+        Ok(oidc_urls) => oidc_urls
+            .split(',')
+            .map(|oidc_url| oidc_url.trim().parse())
+            .collect::<Result<_, _>>()
+            .with_context(|| "Failed to parse HEMATITE_OIDC_URL as a comma-separated list of URLs")?,
+        Err(_) if config.token_introspection.is_some() => vec![],
+        Err(err) => Err(err).with_context(|| "Env var HEMATITE_OIDC_URL is missing.")?,
+    };
 
     info!("Starting Hematite DB version: {}", hematite::build::VERSION);
     info!("Stream database directory: {}", streams_dir.display());
 
-    let app = api::stream_routes(streams_dir, oidc_url).await?
-        .layer(middleware::from_fn(apply_secure_headers))
+    let csp = Arc::new(config.content_security_policy.clone());
+    let request_id_format = config.request_id_format;
+
+    let state = Arc::new(AppState::new(streams_dir, config).await?);
+    tokio::spawn(reload_config_on_hangup(state.clone()));
+    tokio::spawn(compact_on_schedule(state.clone()));
+    tokio::spawn(log_cache_metrics(state.clone()));
+
+    let app = api::stream_routes(state.clone(), oidc_urls).await?
+        .layer(middleware::from_fn_with_state(csp, api::apply_secure_headers))
+        .layer(middleware::from_fn_with_state(request_id_format, api::assign_request_id))
         .fallback(fallback);
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await?;
 
-    axum::serve(listener, app).await?;
+    // Subscriptions never end on their own, so they're ended before waiting for connections
+    // to close.
+    let shutdown = {
+        let state = state.clone();
+        async move {
+            shutdown_signal().await;
+            state.shut_down();
+        }
+    };
+
+    // Connections still open once the grace period is up are closed along with the server.
+    let grace_period_over = {
+        let grace_period = state.config().shutdown_grace_period;
+        let shutting_down = state.shutting_down();
+        async move {
+            shutting_down.await;
+            tokio::time::sleep(grace_period).await;
+        }
+    };
+
+    tokio::select! {
+        served = axum::serve(listener, app).with_graceful_shutdown(shutdown) => served?,
+        () = grace_period_over => warn!("Closing connections still open after the shutdown grace period"),
+    }
+
+    info!("Stopping streams...");
+    let stopped = state.stop_streams().await;
+    info!("Stopped {} streams", stopped);
+
+    Ok(())
+}
+
+/// Resolves once the process receives `SIGINT` or `SIGTERM`, so in-flight requests can finish
+/// before the streams are stopped.
+async fn shutdown_signal() {
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            },
+            Err(err) => {
+                error!("Failed to listen for SIGTERM, only stopping on SIGINT: {:?}", err);
+                std::future::pending::<()>().await;
+            },
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT, shutting down"),
+        _ = terminate => info!("Received SIGTERM, shutting down"),
+    }
+}
+
+/// Re-reads the config each time the process receives `SIGHUP`, keeping the current config
+/// if the new one is invalid.
+async fn reload_config_on_hangup(state: Arc<AppState>) -> anyhow::Result<()> {
+    let mut hangups = signal(SignalKind::hangup())?;
+
+    while hangups.recv().await.is_some() {
+        match Config::from_env() {
+            Ok(config) => {
+                state.reload_config(config);
+                info!("Reloaded configuration");
+            },
+            Err(err) => error!("Failed to reload configuration, keeping the current one: {:?}", err),
+        }
+    }
 
     Ok(())
 }
 
-async fn apply_secure_headers(request: Request, next: Next) -> Response {
-    let mut response = next.run(request).await;
+/// Checks for compacted streams that are due for compaction every `compaction_interval`.
+async fn compact_on_schedule(state: Arc<AppState>) {
+    loop {
+        tokio::time::sleep(state.config().compaction_interval).await;
+        state.compact_streams().await;
+    }
+}
+
+/// Logs each cache's hits and misses since the last log, at debug level.
+async fn log_cache_metrics(state: Arc<AppState>) {
+    let mut last = state.cache_metrics.counts();
+
+    loop {
+        tokio::time::sleep(CACHE_METRICS_LOG_INTERVAL).await;
 
-    let headers = response.headers_mut();
-    headers.insert(header::X_CONTENT_TYPE_OPTIONS, "nosniff".parse().unwrap());
-    headers.insert(header::X_FRAME_OPTIONS, "DENY".parse().unwrap());
-    headers.insert(header::X_XSS_PROTECTION, "1; mode=block".parse().unwrap());
-    headers.insert(header::CONTENT_SECURITY_POLICY, "frame-ancestors 'none'".parse().unwrap());
+        let counts = state.cache_metrics.counts();
+        for (cache, now, before) in [
+            ("stream_stats", counts.stream_stats, last.stream_stats),
+            ("oidc_discovery", counts.oidc_discovery, last.oidc_discovery),
+            ("jwks", counts.jwks, last.jwks),
+        ] {
+            debug!("cache={} hits={} misses={} msg=\"Cache usage\"", cache, now.hits - before.hits, now.misses - before.misses);
+        }
 
-    return response;
+        last = counts;
+    }
 }
 
 async fn fallback() -> StatusCode {
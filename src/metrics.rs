@@ -0,0 +1,63 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Serialize;
+
+/// Hits and misses of one cache, for judging whether it's sized and expired well.
+#[derive(Debug, Default)]
+pub struct CacheCounter {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// A `CacheCounter`'s counts at one point in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct CacheCounts {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheCounter {
+    pub fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn counts(&self) -> CacheCounts {
+        CacheCounts {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Counters for every cache the server keeps.
+#[derive(Debug, Default)]
+pub struct CacheMetrics {
+    /// Streams' revision, mtime, and size, served to `consistency=cached` reads.
+    pub stream_stats: CacheCounter,
+    /// OpenID providers' discovery documents.
+    pub oidc_discovery: CacheCounter,
+    /// OpenID providers' signing keys.
+    pub jwks: CacheCounter,
+}
+
+/// `CacheMetrics` at one point in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct CacheMetricsCounts {
+    pub stream_stats: CacheCounts,
+    pub oidc_discovery: CacheCounts,
+    pub jwks: CacheCounts,
+}
+
+impl CacheMetrics {
+    pub fn counts(&self) -> CacheMetricsCounts {
+        CacheMetricsCounts {
+            stream_stats: self.stream_stats.counts(),
+            oidc_discovery: self.oidc_discovery.counts(),
+            jwks: self.jwks.counts(),
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+
+use crate::server::UserId;
+
+/// How fast each user may post events: `burst` requests at once, refilled at `per_second`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WriteRateLimit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+/// A token bucket per user. Buckets are created full on a user's first request.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: WriteRateLimit,
+    buckets: DashMap<UserId, Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limit: WriteRateLimit) -> Self {
+        Self { limit, buckets: DashMap::new() }
+    }
+
+    /// Takes a token from the user's bucket, or returns how long until one is available.
+    pub fn try_acquire(&self, user_id: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let burst = f64::from(self.limit.burst);
+
+        let mut bucket = self.buckets.entry(user_id.to_string())
+            .or_insert_with(|| Bucket { tokens: burst, refilled_at: now });
+
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.limit.per_second).min(burst);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.limit.per_second))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_user_has_their_own_bucket() {
+        let limiter = RateLimiter::new(WriteRateLimit { per_second: 0.5, burst: 1 });
+
+        assert!(limiter.try_acquire("alice").is_ok());
+        let retry_after = limiter.try_acquire("alice").unwrap_err();
+        assert!(retry_after > Duration::from_secs(1) && retry_after <= Duration::from_secs(2));
+
+        assert!(limiter.try_acquire("bob").is_ok());
+    }
+}
@@ -1,19 +1,36 @@
 use std::{
+    collections::BTreeMap,
     fs,
-    path::PathBuf,
+    ops::{Range, RangeInclusive},
+    path::{Path, PathBuf},
     str,
-    sync::Arc, fmt,
+    sync::{Arc, PoisonError, RwLock}, fmt,
+    time::{Duration, SystemTime},
 };
-use anyhow::{Context, Result};
+use anyhow::{ensure, Context, Result};
 use cloudevents::Event;
-use dashmap::DashMap;
+use dashmap::{mapref::entry::Entry, DashMap};
+use futures::{future, stream, StreamExt};
 use data_encoding::BASE32_NOPAD;
-use tokio::sync::Mutex;
-use tracing::{debug, error, info};
-use serde::Serialize;
-use crate::db::{
-    Database,
-    ExpectedRevision,
+use tokio::sync::{watch, Mutex, OwnedMutexGuard, Semaphore};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+use serde::{Deserialize, Serialize};
+use crate::{
+    config::Config,
+    lru::LruOrder,
+    metrics::CacheMetrics,
+    db::{
+        self,
+        Activity,
+        Database,
+        ExpectedRevision,
+        IndexInfo,
+        RunState,
+        StreamMetadata,
+        TimeRange,
+        DEFAULT_MIN_DIRTY_RATIO,
+    },
 };
 
 
@@ -23,6 +40,26 @@ pub enum Error {
     StreamNotFound,
 }
 
+#[derive(thiserror::Error, Debug)]
+#[error("another client holds the write lease on this stream")]
+pub struct LeaseHeld;
+
+#[derive(thiserror::Error, Debug)]
+#[error("a stream with this ID already exists")]
+pub struct StreamExists;
+
+/// The user has used up one of their quotas.
+#[derive(thiserror::Error, Debug)]
+pub enum QuotaExceeded {
+    /// They already have as many streams as `max_streams_per_user` allows, so another can't be
+    /// created. Their existing streams can still be written to.
+    #[error("user has reached the limit of {max_streams} streams")]
+    Streams { max_streams: usize },
+    /// Their streams take up so many bytes that an append would put them over `max_bytes_per_user`.
+    #[error("user has reached the limit of {max_bytes} bytes")]
+    Bytes { max_bytes: u64 },
+}
+
 pub type UserId = String;
 pub type StreamId = String;
 pub type UserStreamId = (String, String);
@@ -31,6 +68,30 @@ fn user_stream_id(user_id: &UserId, stream_id: &StreamId) -> UserStreamId {
     (user_id.to_owned(), stream_id.to_owned())
 }
 
+fn decode_stream_id(encoded_stream_id: &str) -> Result<StreamId> {
+    let stream_id_bytes = BASE32_NOPAD
+        .decode(encoded_stream_id.as_bytes())
+        .with_context(|| format!("Expected file in stream dir to have a Base32 no-pad encoded filename, but it was {}", encoded_stream_id))?;
+    let stream_id = str::from_utf8(stream_id_bytes.as_slice())
+        .context("Failed to convert stream file name into into string stream ID")?
+        .to_string();
+
+    Ok(stream_id)
+}
+
+/// An advisory lease giving one client the sole right to append to a stream until it expires.
+#[derive(Clone, Debug)]
+pub struct Lease {
+    pub token: String,
+    pub expires_at: SystemTime,
+}
+
+impl Lease {
+    fn is_held_at(&self, now: SystemTime) -> bool {
+        self.expires_at > now
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct User {
     pub id: UserId,
@@ -41,8 +102,76 @@ pub struct Stream {
     #[serde(skip)]
     pub id: StreamId,
     pub revision: u64,
+    pub count: u64,
     pub last_modified: u64,
+    /// Unix timestamp at which the stream was created, or `None` if it was created before
+    /// creation times were recorded.
+    pub created_at: Option<u64>,
     pub usage: u64,
+    #[serde(flatten)]
+    pub metadata: StreamMetadata,
+}
+
+/// Bytes added to a user's usage by `AppState::reserve_bytes` ahead of a write, and taken off
+/// again when dropped.
+struct BytesReservation<'a> {
+    user_usage: &'a DashMap<UserId, u64>,
+    user_id: UserId,
+    bytes: u64,
+}
+
+impl Drop for BytesReservation<'_> {
+    fn drop(&mut self) {
+        if self.bytes == 0 {
+            return;
+        }
+
+        if let Some(mut usage) = self.user_usage.get_mut(&self.user_id) {
+            *usage = usage.saturating_sub(self.bytes);
+        }
+    }
+}
+
+/// A page of a stream's events, read by `AppState::get_event_many`.
+#[derive(Debug)]
+pub struct EventPage {
+    /// Each event on the page, with its rownum.
+    pub events: Vec<(u64, Event)>,
+    /// Rownum the previous page starts at, if this isn't the first.
+    pub prev: Option<u64>,
+    /// Rownum the next page starts at, if this isn't the last.
+    pub next: Option<u64>,
+    pub revision: u64,
+    /// Number of events in the whole stream.
+    pub count: u64,
+}
+
+/// Events read by `AppState::get_events_after`.
+#[derive(Debug)]
+pub struct EventPoll {
+    pub events: Vec<Event>,
+    /// Revision to poll from for the events after these.
+    pub next_revision: u64,
+    pub head_revision: u64,
+}
+
+/// Which of a stream's events `AppState::get_event_many` lists.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EventFilter<'a> {
+    /// Only list events with this `type` attribute.
+    pub event_type: Option<&'a str>,
+    pub time_range: TimeRange,
+}
+
+/// How fresh the values reported for a stream must be.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Consistency {
+    /// Read revision, mtime, and size from disk on every request.
+    #[default]
+    Strong,
+    /// Serve values cached in memory, which may briefly lag behind the filesystem.
+    Cached,
 }
 
 #[derive(Serialize)]
@@ -60,6 +189,22 @@ type StreamMap = DashMap<UserStreamId, Arc<Mutex<Database>>>;
 pub struct AppState {
     pub streams_path: PathBuf,
     pub streams: StreamMap,
+    pub leases: DashMap<UserStreamId, Lease>,
+    config: RwLock<Arc<Config>>,
+    /// Permits for background jobs, so they never take more than `max_background_jobs` at once.
+    background_jobs: Semaphore,
+    pub cache_metrics: Arc<CacheMetrics>,
+    /// Streams whose databases are open, for closing the least recently used when there are
+    /// more than `max_open_streams`. Only tracked when there's a limit.
+    open_streams: std::sync::Mutex<LruOrder<UserStreamId>>,
+    /// Bytes each user's streams take up on disk, as last counted by `user_usage` and kept up
+    /// to date by appends since.
+    user_usage: DashMap<UserId, u64>,
+    /// Set once the server starts shutting down, ending every subscription.
+    shutting_down: watch::Sender<bool>,
+    /// Held while a stream is created for a user, so that checking `max_streams_per_user` and
+    /// adding the stream to `streams` can't interleave with another creation.
+    stream_creation: DashMap<UserId, Arc<Mutex<()>>>,
 }
 
 impl fmt::Debug for AppState {
@@ -71,47 +216,58 @@ impl fmt::Debug for AppState {
 
 impl AppState {
     #[tracing::instrument]
-    pub async fn new(streams_path: PathBuf) -> Result<Self> {
+    pub async fn new(streams_path: PathBuf, config: Config) -> Result<Self> {
         let state = AppState {
             streams_path,
             streams: DashMap::new(),
+            leases: DashMap::new(),
+            background_jobs: Semaphore::new(config.max_background_jobs),
+            config: RwLock::new(Arc::new(config)),
+            cache_metrics: Arc::new(CacheMetrics::default()),
+            open_streams: std::sync::Mutex::new(LruOrder::new()),
+            user_usage: DashMap::new(),
+            shutting_down: watch::Sender::new(false),
+            stream_creation: DashMap::new(),
         };
 
         info!("Initializing streams...");
 
-        for user_dir_result in state
+        for user_dir in state
             .streams_path
             .read_dir()
             .with_context(|| format!("Couldn't read stream directory at {:?}", state.streams_path))?
+            .flatten()
         {
-            if let Ok(user_dir) = user_dir_result {
-                let user_path = user_dir.path();
-                let user_id: UserId = user_path.file_stem().unwrap().to_str().unwrap().to_string();
+            let user_path = user_dir.path();
 
-                if user_id == "lost+found" {
+            let Some(user_id) = state.stream_entry_name(&user_path) else {
+                continue;
+            };
+            let user_id: UserId = user_id.to_string();
+
+            for db_dir in user_dir
+                .path()
+                .read_dir()
+                .with_context(|| format!("Couldn't read user directory at {:?}", user_dir))?
+                .flatten()
+            {
+                let db_dir_path = db_dir.path();
+
+                let Some(encoded_stream_id) = state.stream_entry_name(&db_dir_path) else {
                     continue;
-                }
+                };
 
-                for db_dir_result in user_dir
-                    .path()
-                    .read_dir()
-                    .with_context(|| format!("Couldn't read user directory at {:?}", user_dir))?
-                {
-                    if let Ok(db_dir) = db_dir_result {
-                        let db_dir_path = db_dir.path();
-                        let encoded_stream_id = db_dir_path.file_name().unwrap().to_str().unwrap();
-                        let stream_id_bytes = BASE32_NOPAD
-                            .decode(encoded_stream_id.as_bytes())
-                            .with_context(|| format!("Expected file in stream dir to have a Base32 no-pad encoded filename, but it was {}", encoded_stream_id))?;
-                        let stream_id: StreamId = str::from_utf8(stream_id_bytes.as_slice())
-                            .with_context(|| format!("Failed to convert stream file name into into string stream ID"))?
-                            .to_string();
-
-                        let user_stream_id = user_stream_id(&user_id, &stream_id);
-
-                        state.initialize_database(&user_stream_id)?;
+                let stream_id = match decode_stream_id(encoded_stream_id) {
+                    Ok(stream_id) => stream_id,
+                    Err(err) => {
+                        warn!("path={:?} msg=\"Skipping entry in user directory that isn't a stream\" error={:#}", db_dir_path, err);
+                        continue;
                     }
-                }
+                };
+
+                let user_stream_id = user_stream_id(&user_id, &stream_id);
+
+                state.initialize_database(&user_stream_id).await?;
             }
         }
 
@@ -120,144 +276,1030 @@ impl AppState {
         Ok(state)
     }
 
+    /// The current configuration. Settings read from it may change after `reload_config`.
+    pub fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// Swaps in the hot-reloadable settings from `config`, leaving the others as they were at startup.
+    #[tracing::instrument]
+    pub fn reload_config(&self, config: Config) {
+        let mut current = self.config.write().unwrap_or_else(PoisonError::into_inner);
+        *current = Arc::new(current.with_reloadable(config));
+    }
+
+    /// The name of a user or stream directory found while scanning the streams directory,
+    /// or `None` if the entry should be skipped.
+    fn stream_entry_name<'a>(&self, path: &'a Path) -> Option<&'a str> {
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            warn!("path={:?} msg=\"Skipping entry in streams directory with a non-UTF-8 name\"", path);
+            return None;
+        };
+
+        if name.starts_with('.') || self.config().ignored_stream_entries.iter().any(|ignored| ignored == name) {
+            debug!("path={:?} msg=\"Skipping ignored entry in streams directory\"", path);
+            return None;
+        }
+
+        if !path.is_dir() {
+            warn!("path={:?} msg=\"Skipping file in streams directory that isn't a directory\"", path);
+            return None;
+        }
+
+        Some(name)
+    }
+
+    /// Compacts every open stream marked `compacted` that has gone `compaction_idle` without
+    /// writes and has at least its `min_dirty_ratio` of superseded events, and returns how many
+    /// events were removed. Each stream takes a background job permit while it's checked.
+    #[tracing::instrument]
+    pub async fn compact_streams(&self) -> u64 {
+        let streams: Vec<(UserStreamId, Arc<Mutex<Database>>)> = self.streams.iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+
+        let compactions = streams.into_iter().map(|(stream_id, db)| async move {
+            let Ok(_permit) = self.background_jobs.acquire().await else {
+                return 0;
+            };
+
+            // Closed streams are left as they are rather than reloaded just to check them.
+            let mut db = db.lock().await;
+            if db.run_state() == RunState::Stopped {
+                return 0;
+            }
+
+            match self.compact_if_due(&mut db).await {
+                Ok(Some((removed, reclaimed_bytes))) => {
+                    info!("user_id={} stream_id={} removed_events={} reclaimed_bytes={} msg=\"Compacted stream\"", stream_id.0, stream_id.1, removed, reclaimed_bytes);
+                    self.user_usage.remove(&stream_id.0);
+                    removed
+                },
+                Ok(None) => 0,
+                Err(err) => {
+                    error!("user_id={} stream_id={} Failed to compact stream: {:?}", stream_id.0, stream_id.1, err);
+                    0
+                },
+            }
+        });
+
+        future::join_all(compactions).await.into_iter().sum()
+    }
+
+    /// Ends every subscription, including those started from now on, so that SSE responses
+    /// and long polls finish and the server can stop serving.
+    pub fn shut_down(&self) {
+        self.shutting_down.send_replace(true);
+    }
+
+    /// Whether `shut_down` has been called.
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutting_down.borrow()
+    }
+
+    /// Resolves once `shut_down` has been called.
+    pub fn shutting_down(&self) -> impl std::future::Future<Output = ()> + use<> {
+        let mut shutting_down = self.shutting_down.subscribe();
+
+        async move {
+            // Only fails if the state was dropped, which is as good as shutting down.
+            let _ = shutting_down.wait_for(|shutting_down| *shutting_down).await;
+        }
+    }
+
+    /// Syncs every open stream to disk and stops it, for shutting down, and returns how many were
+    /// stopped. Each waits for any write in progress on it to finish first. A stream that fails
+    /// to stop is logged and skipped.
+    #[tracing::instrument]
+    pub async fn stop_streams(&self) -> u64 {
+        let streams: Vec<(UserStreamId, Arc<Mutex<Database>>)> = self.streams.iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+
+        let mut stopped = 0;
+
+        for (stream_id, db) in streams {
+            match db.lock().await.stop().await {
+                Ok(true) => stopped += 1,
+                Ok(false) => {},
+                Err(err) => error!("user_id={} stream_id={} Failed to stop stream: {:?}", stream_id.0, stream_id.1, err),
+            }
+        }
+
+        stopped
+    }
+
+    /// Compacts `db` if its policy says it's due, returning how many events and bytes it removed.
+    async fn compact_if_due(&self, db: &mut Database) -> Result<Option<(u64, u64)>> {
+        if !db.metadata().compacted {
+            return Ok(None);
+        }
+
+        let stats = db.stats().await?;
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |since_epoch| since_epoch.as_secs());
+        if now.saturating_sub(stats.last_modified) < self.config().compaction_idle.as_secs() {
+            return Ok(None);
+        }
+
+        let min_dirty_ratio = db.metadata().min_dirty_ratio.unwrap_or(DEFAULT_MIN_DIRTY_RATIO);
+        if db.dirty_ratio().await? < min_dirty_ratio {
+            return Ok(None);
+        }
+
+        let removed = db.compact_by_subject().await?;
+        let usage = db.stats().await?.usage;
+
+        Ok(Some((removed, stats.usage.saturating_sub(usage))))
+    }
+
     #[tracing::instrument]
     pub fn check_health(&self) -> ApiHealth {
         ApiHealth { status: HealthStatus::Pass }
     }
 
-    fn initialize_database(&self, stream_id: &UserStreamId) -> Result<bool> {
-        let init_db = !self.streams.contains_key(stream_id);
+    /// Directory holding a stream's files, named with the Base32 encoding of its ID inside
+    /// its user's directory.
+    fn stream_path(&self, stream_id: &UserStreamId) -> PathBuf {
+        let stream_file_name: String = BASE32_NOPAD.encode(stream_id.1.as_bytes());
 
-        if init_db {
-            debug!("user_id={} stream_id={} msg=\"Initializing stream\"", stream_id.0, stream_id.1);
+        self.streams_path
+            .join(&stream_id.0)
+            .join(stream_file_name)
+    }
 
-            let user_dir_path =
-                self.streams_path
-                .join(stream_id.0.to_string());
+    /// A database at `path` with the configured storage settings, not yet started.
+    fn new_database(&self, path: &Path) -> Database {
+        let config = self.config();
 
-            fs::create_dir_all(&user_dir_path)
-                .with_context(|| format!("Could not create user directory at {:?}", user_dir_path))?;
+        let mut db = Database::new(path);
+        db.set_segment_bytes(config.segment_bytes);
+        db.set_compression_block_events(config.compression_block_events);
+        db.set_max_event_bytes(config.max_event_bytes);
+        db.set_storage_format(config.storage_format);
+        db
+    }
 
-            let stream_file_name: String = BASE32_NOPAD.encode(stream_id.1.as_bytes());
-            let db_path =
-                user_dir_path
-                .join(stream_file_name);
+    /// Creates an empty stream with the given settings, failing with `StreamExists` if the
+    /// stream already exists. Requests for the stream wait until its settings are in place.
+    #[tracing::instrument]
+    pub async fn create_stream(&self, user_id: &UserId, stream_id: &StreamId, metadata: StreamMetadata) -> Result<()> {
+        let stream_id = user_stream_id(user_id, stream_id);
+        let db_path = self.stream_path(&stream_id);
+
+        let creating = self.lock_stream_creation(user_id).await;
+        if !self.streams.contains_key(&stream_id) {
+            self.check_stream_quota(user_id)?;
+        }
 
-            let db = Database::new(&db_path);
+        let db_mutex = Arc::new(Mutex::new(self.new_database(&db_path)));
+        let mut db = db_mutex.lock().await;
 
-            self.streams.insert(stream_id.clone(), Arc::new(Mutex::new(db)));
+        match self.streams.entry(stream_id.clone()) {
+            Entry::Occupied(_) => return Err(StreamExists.into()),
+            Entry::Vacant(entry) => {
+                entry.insert(db_mutex.clone());
+            },
         }
+        drop(creating);
+
+        let result = async {
+            fs::create_dir_all(&db_path)
+                .with_context(|| format!("Could not create stream directory at {:?}", db_path))?;
+
+            db.start().await?;
 
-        Ok(init_db)
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |since_epoch| since_epoch.as_secs());
+            db.set_created_at(now).await?;
+            db.set_metadata(metadata).await
+        }.await;
+
+        if let Err(err) = result {
+            self.streams.remove(&stream_id);
+            if let Err(stop_err) = db.stop().await {
+                error!("user_id={} stream_id={} Failed to stop stream after failing to create it: {:?}", stream_id.0, stream_id.1, stop_err);
+            }
+
+            return Err(err.context(format!("user_id={} stream_id={} Failed to create stream", stream_id.0, stream_id.1)));
+        }
+
+        drop(db);
+        self.mark_used(&stream_id).await;
+
+        Ok(())
+    }
+
+    /// Serializes creating streams for `user_id` while the guard is held, so the stream count
+    /// `check_stream_quota` sees can't change before the new stream is added to `streams`.
+    /// Nothing is locked when there's no limit to check.
+    async fn lock_stream_creation(&self, user_id: &UserId) -> Option<OwnedMutexGuard<()>> {
+        self.config().max_streams_per_user?;
+
+        let lock = self.stream_creation.entry(user_id.clone()).or_default().clone();
+        Some(lock.lock_owned().await)
+    }
+
+    /// Fails with `QuotaExceeded` if `user_id` already has `max_streams_per_user` streams.
+    /// Callers hold `lock_stream_creation` until they've added the stream they're creating.
+    fn check_stream_quota(&self, user_id: &UserId) -> Result<(), QuotaExceeded> {
+        let Some(max_streams) = self.config().max_streams_per_user else {
+            return Ok(());
+        };
+
+        let stream_count = self.streams.iter()
+            .filter(|entry| &entry.key().0 == user_id)
+            .count();
+
+        if stream_count >= max_streams {
+            return Err(QuotaExceeded::Streams { max_streams });
+        }
+
+        Ok(())
+    }
+
+    /// Counts the bytes `user_id`'s streams and their snapshots take up on disk. The count is
+    /// cached and kept up to date by writes, so streams are only measured again after one is
+    /// deleted, moved, or compacted.
+    pub async fn user_usage(&self, user_id: &UserId) -> Result<u64> {
+        if let Some(usage) = self.user_usage.get(user_id) {
+            return Ok(*usage);
+        }
+
+        let streams: Vec<(UserStreamId, Arc<Mutex<Database>>)> = self.streams.iter()
+            .filter(|entry| &entry.key().0 == user_id)
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+
+        let mut usage = 0;
+        for (stream_id, db) in streams {
+            let db = db.lock().await;
+            usage += db.disk_usage().await
+                .with_context(|| format!("user_id={} stream_id={} Failed to measure stream", stream_id.0, stream_id.1))?;
+        }
+
+        // Another request may have measured and started reserving in the meantime, and its
+        // count is at least as current as this one.
+        let usage = *self.user_usage.entry(user_id.clone()).or_insert(usage);
+
+        Ok(usage)
+    }
+
+    /// Adds the `bytes` a write will take to `user_id`'s usage ahead of writing them, or fails
+    /// with `QuotaExceeded` if that would put them over `max_bytes_per_user`. Without a quota,
+    /// `bytes` isn't called at all. The check and the addition happen
+    /// together under the lock on the user's usage entry, so concurrent writes can't all fit
+    /// in the same room. The reservation is given back when it's dropped, by which time
+    /// `record_usage` has counted what the write actually took.
+    ///
+    /// Must be called before taking the lock on any of the user's streams.
+    async fn reserve_bytes(&self, user_id: &UserId, bytes: impl FnOnce() -> u64) -> Result<BytesReservation<'_>> {
+        let Some(max_bytes) = self.config().max_bytes_per_user else {
+            return Ok(BytesReservation { user_usage: &self.user_usage, user_id: user_id.clone(), bytes: 0 });
+        };
+        let bytes = bytes();
+
+        // The count can be dropped between measuring and reserving, by a compaction say, in
+        // which case it's measured again.
+        loop {
+            self.user_usage(user_id).await?;
+
+            if let Some(mut usage) = self.user_usage.get_mut(user_id) {
+                if *usage + bytes > max_bytes {
+                    return Err(QuotaExceeded::Bytes { max_bytes }.into());
+                }
+
+                *usage += bytes;
+                return Ok(BytesReservation { user_usage: &self.user_usage, user_id: user_id.clone(), bytes });
+            }
+        }
+    }
+
+    /// Measures a stream before writing to it, if its user's usage is cached and will need
+    /// updating by `record_usage` afterward.
+    async fn usage_before_write(&self, stream_id: &UserStreamId, db: &Database) -> Option<u64> {
+        if !self.user_usage.contains_key(&stream_id.0) {
+            return None;
+        }
+
+        match db.disk_usage().await {
+            Ok(len) => Some(len),
+            Err(_) => {
+                self.user_usage.remove(&stream_id.0);
+                None
+            },
+        }
+    }
+
+    /// Adds what a write grew a stream by to its user's cached usage.
+    async fn record_usage(&self, stream_id: &UserStreamId, db: &Database, before: Option<u64>) {
+        let Some(before) = before else {
+            return;
+        };
+
+        match db.disk_usage().await {
+            Ok(after) => {
+                if let Some(mut usage) = self.user_usage.get_mut(&stream_id.0) {
+                    *usage = (*usage + after).saturating_sub(before);
+                }
+            },
+            Err(_) => {
+                self.user_usage.remove(&stream_id.0);
+            },
+        }
+    }
+
+    async fn initialize_database(&self, stream_id: &UserStreamId) -> Result<bool> {
+        if self.streams.contains_key(stream_id) {
+            return Ok(false);
+        }
+
+        debug!("user_id={} stream_id={} msg=\"Initializing stream\"", stream_id.0, stream_id.1);
+
+        let db_path = self.stream_path(stream_id);
+
+        // Streams already on disk are loaded whatever the quota, so only new ones count against
+        // it. The creation lock is held until the new stream is in `streams`.
+        let _creating = if !db_path.exists() {
+            let creating = self.lock_stream_creation(&stream_id.0).await;
+
+            // Another request may have created the same stream while this one waited.
+            if self.streams.contains_key(stream_id) {
+                return Ok(false);
+            }
+
+            self.check_stream_quota(&stream_id.0)?;
+            creating
+        } else {
+            None
+        };
+
+        fs::create_dir_all(&db_path)
+            .with_context(|| format!("Could not create stream directory at {:?}", db_path))?;
+
+        let mut db = self.new_database(&db_path);
+        db.start().await
+            .with_context(|| format!("user_id={} stream_id={} Failed to start stream", stream_id.0, stream_id.1))?;
+
+        // A new stream, or one that was deleted and is being written to again, has no
+        // creation time yet.
+        if db.created_at().is_none() && db.count() == 0 {
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |since_epoch| since_epoch.as_secs());
+            db.set_created_at(now).await
+                .with_context(|| format!("user_id={} stream_id={} Failed to record stream creation time", stream_id.0, stream_id.1))?;
+        }
+
+        // Another request may have initialized the same stream while this one was loading.
+        match self.streams.entry(stream_id.clone()) {
+            Entry::Occupied(_) => return Ok(false),
+            Entry::Vacant(entry) => {
+                entry.insert(Arc::new(Mutex::new(db)));
+            }
+        }
+
+        self.mark_used(stream_id).await;
+
+        Ok(true)
+    }
+
+    /// Locks a stream's database, reopening it first if it was closed to keep within
+    /// `max_open_streams`. Fails with `StreamNotFound` if the stream was deleted or moved
+    /// while this waited for the lock, as it would have if the request came in afterward.
+    async fn open_stream(&self, stream_id: &UserStreamId) -> Result<OwnedMutexGuard<Database>> {
+        let db_mutex = self.streams.get(stream_id).ok_or(Error::StreamNotFound)?.clone();
+        let mut db = db_mutex.clone().lock_owned().await;
+
+        if !self.streams.get(stream_id).is_some_and(|current| Arc::ptr_eq(&current, &db_mutex)) {
+            return Err(Error::StreamNotFound.into());
+        }
+
+        if db.run_state() == RunState::Stopped {
+            debug!("user_id={} stream_id={} msg=\"Reopening closed stream\"", stream_id.0, stream_id.1);
+            db.start().await
+                .with_context(|| format!("user_id={} stream_id={} Failed to reopen stream", stream_id.0, stream_id.1))?;
+        }
+
+        self.mark_used(stream_id).await;
+
+        Ok(db)
+    }
+
+    /// Records that a stream was just used, then closes the least recently used open streams
+    /// until no more than `max_open_streams` are open. Streams in use by another request are
+    /// skipped, and kept open until they're next closed.
+    async fn mark_used(&self, stream_id: &UserStreamId) {
+        let Some(max_open_streams) = self.config().max_open_streams else {
+            return;
+        };
+
+        let mut closing = vec![];
+        {
+            let mut open_streams = self.open_streams.lock().unwrap_or_else(PoisonError::into_inner);
+            open_streams.touch(stream_id.clone());
+
+            let mut in_use = vec![];
+            while open_streams.len() > max_open_streams {
+                let Some(oldest) = open_streams.pop_oldest() else {
+                    break;
+                };
+                let Some(db_mutex) = self.streams.get(&oldest).map(|db| db.clone()) else {
+                    continue;
+                };
+
+                match db_mutex.try_lock_owned() {
+                    Ok(db) => closing.push((oldest, db)),
+                    Err(_) => in_use.push(oldest),
+                }
+            }
+
+            for stream_id in in_use {
+                open_streams.touch(stream_id);
+            }
+        }
+
+        for (stream_id, mut db) in closing {
+            debug!("user_id={} stream_id={} msg=\"Closing least recently used stream\"", stream_id.0, stream_id.1);
+            if let Err(err) = db.close().await {
+                error!("user_id={} stream_id={} Failed to close stream: {:?}", stream_id.0, stream_id.1, err);
+            }
+        }
+    }
+
+    /// Stops counting a deleted or moved stream as open.
+    fn forget_open_stream(&self, stream_id: &UserStreamId) {
+        self.open_streams.lock().unwrap_or_else(PoisonError::into_inner).remove(stream_id);
     }
 
     #[tracing::instrument]
-    pub async fn get_event(&self, user_id: &UserId, stream_id: &StreamId, rownum: u64) -> Result<Option<Event>> {
+    pub async fn get_event(&self, user_id: &UserId, stream_id: &StreamId, rownum: u64, apply_corrections: bool) -> Result<Option<Event>> {
         let stream_id = user_stream_id(user_id, stream_id);
-        let db = self.streams.get(&stream_id).ok_or(Error::StreamNotFound)?;
-
-        let result = db.lock().await.query(rownum, 1).await;
+        let db = self.open_stream(&stream_id).await?;
 
-        if let Ok(mut events) = result {
-            Ok(events.pop())
+        // Compaction and truncation leave gaps in the rownums, so look the row up exactly
+        // rather than reading from it onward.
+        if apply_corrections {
+            db.get_corrected(rownum).await
         } else {
-            Err(result.unwrap_err())
+            db.get(rownum).await
         }
     }
 
     #[tracing::instrument]
-    pub async fn get_event_many(&self, user_id: &UserId, stream_id: &StreamId, start: u64, limit: usize) -> Result<Vec<Event>> {
+    pub async fn get_event_by_source_id(&self, user_id: &UserId, stream_id: &StreamId, source: &str, id: &str) -> Result<Option<(u64, Event)>> {
         let stream_id = user_stream_id(user_id, stream_id);
-        let db = self.streams.get(&stream_id).ok_or(Error::StreamNotFound)?;
+        let db = self.open_stream(&stream_id).await?;
 
-        let result = db.lock().await.query(start, limit).await;
+        let result = db.get_by_source_id(source, id).await;
+        result
+    }
+
+    /// Reads a page of events, along with where it sits in the stream and the stream's revision
+    /// and count, all under the same lock.
+    #[tracing::instrument]
+    pub async fn get_event_many(&self, user_id: &UserId, stream_id: &StreamId, start: u64, limit: usize, filter: &EventFilter<'_>, apply_corrections: bool) -> Result<EventPage> {
+        let stream_id = user_stream_id(user_id, stream_id);
+        let db = self.open_stream(&stream_id).await?;
+        let event_type = filter.event_type;
+
+        // Time-filtered pages are found by scanning forward, so they only link onward.
+        if !filter.time_range.is_unbounded() {
+            let mut events =
+                if apply_corrections {
+                    db.query_by_time_corrected(&filter.time_range, event_type, start, limit.saturating_add(1)).await?
+                } else {
+                    db.query_by_time(&filter.time_range, event_type, start, limit.saturating_add(1)).await?
+                };
+
+            let next = events.get(limit).map(|(rownum, _)| *rownum);
+            events.truncate(limit);
+
+            return Ok(EventPage {
+                events,
+                prev: None,
+                next,
+                revision: db.revision(),
+                count: db.count(),
+            });
+        }
+
+        let events = match (event_type, apply_corrections) {
+            (Some(event_type), true) => db.query_by_type_corrected(event_type, start, limit).await?,
+            (Some(event_type), false) => db.query_by_type(event_type, start, limit).await?,
+            (None, true) => db.query_corrected(start, limit).await?,
+            (None, false) => db.query(start, limit).await?,
+        };
+
+        let page = db.page(event_type, start, limit).await?;
+        ensure!(events.len() == page.rownums.len(), "Read {} events but expected {}", events.len(), page.rownums.len());
+
+        Ok(EventPage {
+            events: page.rownums.into_iter().zip(events).collect(),
+            prev: page.prev,
+            next: page.next,
+            revision: db.revision(),
+            count: db.count(),
+        })
+    }
+
+    /// Reads a stream's last `limit` events, oldest first, or all of them if there are fewer.
+    #[tracing::instrument]
+    pub async fn get_event_tail(&self, user_id: &UserId, stream_id: &StreamId, limit: usize) -> Result<EventPage> {
+        let stream_id = user_stream_id(user_id, stream_id);
+        let db = self.open_stream(&stream_id).await?;
+        let start = db.tail_start(limit);
+        let events = db.query(start, limit).await?;
+
+        let page = db.page(None, start, limit).await?;
+        ensure!(events.len() == page.rownums.len(), "Read {} events but expected {}", events.len(), page.rownums.len());
+
+        Ok(EventPage {
+            events: page.rownums.into_iter().zip(events).collect(),
+            prev: page.prev,
+            next: page.next,
+            revision: db.revision(),
+            count: db.count(),
+        })
+    }
+
+    #[tracing::instrument]
+    pub async fn get_events_by_subject(&self, user_id: &UserId, stream_id: &StreamId, subject: &str, start: u64, limit: usize) -> Result<Vec<Event>> {
+        let stream_id = user_stream_id(user_id, stream_id);
+        let db = self.open_stream(&stream_id).await?;
+
+        let result = db.query_by_subject(subject, start, limit).await;
+        result
+    }
+
+    /// Reads up to `limit` events appended after the stream reached `revision`, along with the
+    /// revision to poll from next and the current head revision, under a single lock so they
+    /// agree.
+    #[tracing::instrument]
+    pub async fn get_events_after(&self, user_id: &UserId, stream_id: &StreamId, revision: u64, limit: usize) -> Result<EventPoll> {
+        let stream_id = user_stream_id(user_id, stream_id);
+        let db = self.open_stream(&stream_id).await?;
+        let items = db.query_with_meta(revision, limit).await?;
+        let head_revision = db.revision();
+
+        // A page cut short by `limit` ends before the head, so the next poll picks up after
+        // the last event on it rather than skipping to the head.
+        let next_revision = match items.last() {
+            Some(item) => item.rownum + 1,
+            None => revision.max(head_revision),
+        };
+
+        Ok(EventPoll {
+            events: items.into_iter().map(|item| item.event).collect(),
+            next_revision,
+            head_revision,
+        })
+    }
+
+    /// Streams the events with rownums in `rows` instead of reading them all up front. The
+    /// stream stops at the returned head revision, even if more events are appended while
+    /// it's read, and the rownums it spans are returned alongside it.
+    #[tracing::instrument]
+    pub async fn export_events(&self, user_id: &UserId, stream_id: &StreamId, rows: Range<u64>) -> Result<(impl stream::Stream<Item = Result<Event>> + use<>, Option<RangeInclusive<u64>>, u64)> {
+        let stream_id = user_stream_id(user_id, stream_id);
+        let db = self.open_stream(&stream_id).await?;
+
+        let result = db.query_snapshot(rows).await;
+        result
+    }
+
+    /// Streams events from rownum `from` onward, then each event as it's appended. Only new
+    /// events are streamed when `from` is `None`.
+    #[tracing::instrument]
+    pub async fn subscribe(&self, user_id: &UserId, stream_id: &StreamId, from: Option<u64>) -> Result<impl stream::Stream<Item = Result<(u64, Event)>> + use<>> {
+        let stream_id = user_stream_id(user_id, stream_id);
+        let db = self.open_stream(&stream_id).await?;
+        let from = match from {
+            Some(from) => from,
+            None => db.revision(),
+        };
+
+        Ok(Database::subscribe(&db, from).take_until(self.shutting_down()))
+    }
+
+    #[tracing::instrument]
+    pub async fn event_types(&self, user_id: &UserId, stream_id: &StreamId) -> Result<BTreeMap<String, u64>> {
+        let stream_id = user_stream_id(user_id, stream_id);
+        let db = self.open_stream(&stream_id).await?;
+
+        let result = db.event_types().await;
+        result
+    }
+
+    #[tracing::instrument]
+    pub async fn activity(&self, user_id: &UserId, stream_id: &StreamId, bucket: Duration, since: Option<i64>) -> Result<Activity> {
+        let stream_id = user_stream_id(user_id, stream_id);
+        let db = self.open_stream(&stream_id).await?;
+
+        let result = db.activity(bucket, since).await;
+        result
+    }
+
+    pub async fn put_snapshot(&self, user_id: &UserId, stream_id: &StreamId, revision: u64, bytes: Vec<u8>) -> Result<()> {
+        let stream_id = user_stream_id(user_id, stream_id);
+        // Reserved as if the old snapshot stays, since its size isn't known until the stream
+        // is locked. `record_usage` counts the difference once it's replaced.
+        let _reservation = self.reserve_bytes(user_id, || 8 + bytes.len() as u64).await?;
+        let mut db = self.open_stream(&stream_id).await?;
+
+        let usage_before = self.usage_before_write(&stream_id, &db).await;
+        let result = db.put_snapshot(revision, bytes).await;
+        self.record_usage(&stream_id, &db, usage_before).await;
+        result
+    }
+
+    #[tracing::instrument]
+    pub async fn get_latest_snapshot(&self, user_id: &UserId, stream_id: &StreamId) -> Result<Option<(u64, Vec<u8>)>> {
+        let stream_id = user_stream_id(user_id, stream_id);
+        let db = self.open_stream(&stream_id).await?;
+
+        let result = db.get_latest_snapshot().await;
         result
     }
 
     #[tracing::instrument]
     pub async fn insert_event(&self, user_id: &UserId, stream_id: &StreamId, event: Event, revision: ExpectedRevision) -> Result<u64> {
         let stream_id = user_stream_id(user_id, stream_id);
-        self.initialize_database(&stream_id)?;
+        let events = db::encode_events(vec![event], self.config().max_event_bytes)?;
+        let _reservation = self.reserve_bytes(user_id, || events.stored_len()).await?;
+        self.initialize_database(&stream_id).await?;
 
-        let db = self.streams.get(&stream_id).ok_or(Error::StreamNotFound)?;
+        let mut db = self.open_stream(&stream_id).await?;
 
-        let result = db.lock().await.append(vec![event], revision).await;
+        let usage_before = self.usage_before_write(&stream_id, &db).await;
+        let result = db.append_encoded(events, revision).await;
+        self.record_usage(&stream_id, &db, usage_before).await;
         result
     }
 
     #[tracing::instrument]
     pub async fn insert_event_many(&self, user_id: &UserId, stream_id: &StreamId, events: Vec<Event>, revision: ExpectedRevision) -> Result<u64> {
         let stream_id = user_stream_id(user_id, stream_id);
-        self.initialize_database(&stream_id)?;
+        let events = db::encode_events(events, self.config().max_event_bytes)?;
+        let _reservation = self.reserve_bytes(user_id, || events.stored_len()).await?;
+        self.initialize_database(&stream_id).await?;
+
+        let mut db = self.open_stream(&stream_id).await?;
+
+        let usage_before = self.usage_before_write(&stream_id, &db).await;
+        let result = db.append_encoded(events, revision).await;
+        self.record_usage(&stream_id, &db, usage_before).await;
+        result
+    }
+
+    /// Reserves the next `count` rownums of a stream for `insert_reserved`, creating the stream
+    /// if it doesn't exist. Returns the first reserved rownum.
+    #[tracing::instrument]
+    pub async fn reserve(&self, user_id: &UserId, stream_id: &StreamId, count: usize, ttl: Duration) -> Result<u64> {
+        let stream_id = user_stream_id(user_id, stream_id);
+        self.initialize_database(&stream_id).await?;
 
-        let db = self.streams.get(&stream_id).ok_or(Error::StreamNotFound)?;
+        let mut db = self.open_stream(&stream_id).await?;
 
-        let result = db.lock().await.append(events, revision).await;
+        let result = db.reserve(count, ttl).await;
         result
     }
 
+    /// Fills reserved rownums of a stream from `start` on, as in `Database::append_reserved`.
+    #[tracing::instrument]
+    pub async fn insert_reserved(&self, user_id: &UserId, stream_id: &StreamId, start: u64, events: Vec<Event>) -> Result<u64> {
+        let stream_id = user_stream_id(user_id, stream_id);
+
+        // Only a loaded stream can have reservations.
+        if !self.streams.contains_key(&stream_id) {
+            return Err(db::Error::NotReserved { start, end: start.saturating_add(events.len() as u64) }.into());
+        }
+
+        let events = db::encode_events(events, self.config().max_event_bytes)?;
+        let _reservation = self.reserve_bytes(user_id, || events.stored_len()).await?;
+
+        let mut db = self.open_stream(&stream_id).await?;
+        let usage_before = self.usage_before_write(&stream_id, &db).await;
+        let result = db.append_reserved_encoded(start, events).await;
+        self.record_usage(&stream_id, &db, usage_before).await;
+        result
+    }
+
+    #[tracing::instrument]
+    pub async fn correct_event(&self, user_id: &UserId, stream_id: &StreamId, rownum: u64, correction: Event) -> Result<u64> {
+        let stream_id = user_stream_id(user_id, stream_id);
+        let correction = db::encode_correction(rownum, correction, self.config().max_event_bytes)?;
+        let _reservation = self.reserve_bytes(user_id, || correction.stored_len()).await?;
+        let mut db = self.open_stream(&stream_id).await?;
+
+        let usage_before = self.usage_before_write(&stream_id, &db).await;
+        let result = db.correct_encoded(rownum, correction).await;
+        self.record_usage(&stream_id, &db, usage_before).await;
+        result
+    }
+
+    /// Lists `user_id`'s streams. Their stats come from each database's cache, so listing only
+    /// reads from the filesystem for streams that haven't been looked at since they were loaded,
+    /// and doesn't reopen streams that were closed to stay under `max_open_streams`.
     pub async fn streams(&self, user_id: &UserId) -> Result<Vec<Stream>> {
         let mut stream_ids = vec![];
 
-        for stream_file_result in self
-            .streams_path
-            .join(user_id)
-            .read_dir()
-            .with_context(|| format!("Couldn't read user directory at {:?}", self.streams_path.join(user_id)))?
-        {
-            if let Ok(stream_file) = stream_file_result {
-                let stream_path = stream_file.path();
-                let stream_name = stream_path.file_stem().unwrap().to_str().expect("Expected stream filename to be valid unicode");
-                let stream_id_bytes = BASE32_NOPAD
-                        .decode(stream_name.as_bytes())
-                        .with_context(|| format!("Expected file in stream dir to have a Base32 no-pad encoded filename, but it was {}", stream_name))?;
-                let stream_id = str::from_utf8(stream_id_bytes.as_slice())?.to_string();
+        // A user who has no streams, or whose streams were all deleted, has no directory.
+        let user_dir = match self.streams_path.join(user_id).read_dir() {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            user_dir => user_dir.with_context(|| format!("Couldn't read user directory at {:?}", self.streams_path.join(user_id)))?,
+        };
 
+        for stream_file in user_dir.flatten() {
+            let stream_path = stream_file.path();
+
+            let Some(stream_name) = self.stream_entry_name(&stream_path) else {
+                continue;
+            };
+
+            if let Ok(stream_id) = decode_stream_id(stream_name) {
                 stream_ids.push(stream_id)
             }
-
         }
 
         let mut streams = vec![];
 
         for stream_id in stream_ids {
-            if let Ok(stream) = self.get_stream(user_id, &stream_id).await {
+            if let Ok(stream) = self.get_stream(user_id, &stream_id, Consistency::Cached).await {
                 streams.push(stream);
             }
         }
 
-        return Ok(streams);
+        Ok(streams)
     }
 
     #[tracing::instrument]
-    pub async fn get_stream(&self, user_id: &UserId, stream_id: &StreamId) -> Result<Stream> {
+    pub async fn get_stream(&self, user_id: &UserId, stream_id: &StreamId, consistency: Consistency) -> Result<Stream> {
         let user_stream_id = user_stream_id(user_id, stream_id);
-        let db_lock = self.streams.get(&user_stream_id).ok_or(Error::StreamNotFound)?;
 
-        let db = db_lock.lock().await;
-        let revision = db.revision().await?;
-        let last_modified = db.last_modified().await?;
-        let usage = db.file_len().await?;
+        // A closed stream kept the stats it had when it was closed, and can't have changed
+        // since, so it isn't reopened just to report them.
+        let db_mutex = self.streams.get(&user_stream_id).ok_or(Error::StreamNotFound)?.clone();
+        let closed = db_mutex.lock_owned().await;
+        let mut db = if consistency == Consistency::Cached && closed.run_state() == RunState::Stopped && closed.has_cached_stats() {
+            closed
+        } else {
+            drop(closed);
+            self.open_stream(&user_stream_id).await?
+        };
+        let stats = match consistency {
+            Consistency::Strong => db.stats().await?,
+            Consistency::Cached => {
+                if db.has_cached_stats() {
+                    self.cache_metrics.stream_stats.hit();
+                } else {
+                    self.cache_metrics.stream_stats.miss();
+                }
+
+                db.cached_stats().await?
+            },
+        };
 
         Ok(Stream {
             id: stream_id.to_string(),
-            usage,
-            revision,
-            last_modified,
+            usage: stats.usage,
+            revision: stats.revision,
+            count: stats.count,
+            last_modified: stats.last_modified,
+            created_at: db.created_at(),
+            metadata: db.metadata().clone(),
         })
     }
 
+    /// Reports the size of a stream's in-memory index.
+    #[tracing::instrument]
+    pub async fn index_info(&self, user_id: &UserId, stream_id: &StreamId) -> Result<IndexInfo> {
+        let user_stream_id = user_stream_id(user_id, stream_id);
+        let db = self.open_stream(&user_stream_id).await?;
+
+        let info = db.index_info();
+        Ok(info)
+    }
+
+    /// Replaces a stream's settings. A sealed stream stays sealed, since only `unseal_stream`
+    /// can unseal it.
+    #[tracing::instrument]
+    pub async fn set_stream_metadata(&self, user_id: &UserId, stream_id: &StreamId, mut metadata: StreamMetadata) -> Result<()> {
+        let user_stream_id = user_stream_id(user_id, stream_id);
+        let mut db = self.open_stream(&user_stream_id).await?;
+        metadata.sealed |= db.metadata().sealed;
+        db.set_metadata(metadata).await
+    }
+
+    /// Lets events be appended to a sealed stream again.
+    #[tracing::instrument]
+    pub async fn unseal_stream(&self, user_id: &UserId, stream_id: &StreamId) -> Result<()> {
+        let user_stream_id = user_stream_id(user_id, stream_id);
+        let mut db = self.open_stream(&user_stream_id).await?;
+        let metadata = StreamMetadata { sealed: false, ..db.metadata().clone() };
+        db.set_metadata(metadata).await
+    }
+
+    /// Grants a write lease on a stream for `ttl`, or renews the current lease if `token` is its token.
+    /// Returns the lease and whether it was newly granted.
+    #[tracing::instrument]
+    pub fn acquire_lease(&self, user_id: &UserId, stream_id: &StreamId, token: Option<&str>, ttl: Duration) -> Result<(Lease, bool), LeaseHeld> {
+        let now = SystemTime::now();
+        let expires_at = now + ttl;
+
+        match self.leases.entry(user_stream_id(user_id, stream_id)) {
+            Entry::Occupied(mut entry) if entry.get().is_held_at(now) => {
+                if token != Some(entry.get().token.as_str()) {
+                    return Err(LeaseHeld);
+                }
+
+                entry.get_mut().expires_at = expires_at;
+                Ok((entry.get().clone(), false))
+            },
+            entry => {
+                let lease = Lease { token: Uuid::now_v7().to_string(), expires_at };
+                entry.insert(lease.clone());
+                Ok((lease, true))
+            },
+        }
+    }
+
+    /// Gives up the write lease on a stream. Returns `false` if no lease was held.
+    #[tracing::instrument]
+    pub fn release_lease(&self, user_id: &UserId, stream_id: &StreamId, token: Option<&str>) -> Result<bool, LeaseHeld> {
+        self.check_lease(user_id, stream_id, token)?;
+
+        let released = self.leases.remove(&user_stream_id(user_id, stream_id))
+            .is_some_and(|(_, lease)| lease.is_held_at(SystemTime::now()));
+
+        Ok(released)
+    }
+
+    /// Fails with `LeaseHeld` if the stream is leased and `token` isn't the lease's token.
+    #[tracing::instrument]
+    pub fn check_lease(&self, user_id: &UserId, stream_id: &StreamId, token: Option<&str>) -> Result<(), LeaseHeld> {
+        let Some(lease) = self.leases.get(&user_stream_id(user_id, stream_id)) else {
+            return Ok(());
+        };
+
+        if lease.is_held_at(SystemTime::now()) && token != Some(lease.token.as_str()) {
+            return Err(LeaseHeld);
+        }
+
+        Ok(())
+    }
+
+    /// Moves a stream, with its events, metadata, and lease, to another user or ID. Fails with
+    /// `StreamNotFound` if there is no stream at `source`, or `StreamExists` if there already
+    /// is one at `target`.
+    #[tracing::instrument]
+    pub async fn move_stream(&self, source: &UserStreamId, target: &UserStreamId) -> Result<()> {
+        let db_mutex = self.streams.get(source).ok_or(Error::StreamNotFound)?.clone();
+        let creating = if source.0 != target.0 {
+            let creating = self.lock_stream_creation(&target.0).await;
+            self.check_stream_quota(&target.0)?;
+            creating
+        } else {
+            None
+        };
+        let mut db = db_mutex.lock().await;
+
+        // The stream may have been deleted or moved while we waited for the lock.
+        if !self.streams.get(source).is_some_and(|current| Arc::ptr_eq(&current, &db_mutex)) {
+            return Err(Error::StreamNotFound.into());
+        }
+
+        // Claim the target first, so requests for it wait on the lock until the move is done.
+        match self.streams.entry(target.clone()) {
+            Entry::Occupied(_) => return Err(StreamExists.into()),
+            Entry::Vacant(entry) => {
+                entry.insert(db_mutex.clone());
+            },
+        }
+        drop(creating);
+
+        let target_path = self.stream_path(target);
+        let relocated = match target_path.parent() {
+            Some(user_dir_path) => fs::create_dir_all(user_dir_path)
+                .with_context(|| format!("Could not create user directory at {:?}", user_dir_path)),
+            None => Ok(()),
+        };
+        let relocated = match relocated {
+            Ok(()) => db.relocate(&target_path).await,
+            Err(err) => Err(err),
+        };
+
+        if let Err(err) = relocated {
+            self.streams.remove(target);
+
+            // A failed move can leave the stream stopped, so try to bring it back where it was.
+            if let Err(restart_err) = db.start().await {
+                error!("user_id={} stream_id={} Failed to restart stream after a failed move: {:?}", source.0, source.1, restart_err);
+            }
+
+            return Err(err.context(format!("user_id={} stream_id={} Failed to move stream", source.0, source.1)));
+        }
+
+        self.streams.remove(source);
+        if let Some((_, lease)) = self.leases.remove(source) {
+            self.leases.insert(target.clone(), lease);
+        }
+        self.forget_open_stream(source);
+        drop(db);
+        self.user_usage.remove(&source.0);
+        self.user_usage.remove(&target.0);
+        self.mark_used(target).await;
+
+        Ok(())
+    }
+
+    /// Renames one of `user_id`'s streams from `from` to `to`, keeping its events and metadata,
+    /// as `move_stream` does. Returns `false` if there's no stream `from` to rename, or fails with
+    /// `StreamExists` if there's already a stream `to`.
+    #[tracing::instrument]
+    pub async fn rename_stream(&self, user_id: &UserId, from: &StreamId, to: &StreamId) -> Result<bool> {
+        let source = user_stream_id(user_id, from);
+        let target = user_stream_id(user_id, to);
+
+        match self.move_stream(&source, &target).await {
+            Ok(()) => Ok(true),
+            Err(err) if matches!(err.downcast_ref::<Error>(), Some(Error::StreamNotFound)) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Deletes every stream belonging to `user_id`, along with the user's directory if that
+    /// leaves it empty, and returns how many streams were deleted. Each stream's lock is taken
+    /// before it's deleted, and it's stopped afterward, so writes already waiting on it fail
+    /// instead of landing in a deleted stream.
+    #[tracing::instrument]
+    pub async fn delete_all_streams(&self, user_id: &UserId) -> Result<u64> {
+        let stream_ids: Vec<UserStreamId> = self.streams.iter()
+            .filter(|entry| &entry.key().0 == user_id)
+            .map(|entry| entry.key().clone())
+            .collect();
+
+        let mut deleted = 0;
+
+        for stream_id in stream_ids {
+            let Some(db_mutex) = self.streams.get(&stream_id).map(|db| db.clone()) else {
+                continue;
+            };
+            let mut db = db_mutex.lock().await;
+
+            // Another request may have deleted or moved the stream while we waited for the lock.
+            if self.streams.remove_if(&stream_id, |_, current| Arc::ptr_eq(current, &db_mutex)).is_none() {
+                continue;
+            }
+
+            self.delete_removed_stream(&stream_id, &mut db).await?;
+            deleted += 1;
+        }
+
+        self.user_usage.remove(user_id);
+
+        let user_dir_path = self.streams_path.join(user_id);
+        if let Err(err) = fs::remove_dir(&user_dir_path) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                warn!("user_id={} path={:?} Left user directory in place after deleting its streams: {}", user_id, user_dir_path, err);
+            }
+        }
+
+        Ok(deleted)
+    }
+
     #[tracing::instrument]
     pub async fn delete_stream(&self, user_id: &UserId, stream_id: &StreamId) -> Result<bool> {
         let stream_id = user_stream_id(user_id, stream_id);
 
-        if let Some((_, db_mutex)) = self.streams.remove(&stream_id) {
-            let mut db = db_mutex.lock().await;
-            db.delete().await.with_context(|| format!("user_id={} stream_id={} Failed to delete stream", stream_id.0, stream_id.1))?;
-            Ok(true)
-        } else {
-            Ok(false)
+        let Some(db_mutex) = self.streams.get(&stream_id).map(|db| db.clone()) else {
+            return Ok(false);
+        };
+        let mut db = db_mutex.lock().await;
+
+        // Another request may have deleted or moved the stream while we waited for the lock.
+        if self.streams.remove_if(&stream_id, |_, current| Arc::ptr_eq(current, &db_mutex)).is_none() {
+            return Ok(false);
         }
+
+        self.delete_removed_stream(&stream_id, &mut db).await?;
+        self.user_usage.remove(user_id);
+
+        Ok(true)
+    }
+
+    /// Deletes the files, directory and lease of a stream that's locked and was just taken out
+    /// of `streams`. The database is stopped too, so requests that were already waiting for it
+    /// fail rather than writing the stream back to disk.
+    async fn delete_removed_stream(&self, stream_id: &UserStreamId, db: &mut Database) -> Result<()> {
+        db.delete().await.with_context(|| format!("user_id={} stream_id={} Failed to delete stream", stream_id.0, stream_id.1))?;
+        db.stop().await.with_context(|| format!("user_id={} stream_id={} Failed to stop deleted stream", stream_id.0, stream_id.1))?;
+
+        let db_path = self.stream_path(stream_id);
+        if let Err(err) = fs::remove_dir_all(&db_path) {
+            warn!("user_id={} stream_id={} path={:?} Failed to remove deleted stream's directory: {}", stream_id.0, stream_id.1, db_path, err);
+        }
+
+        self.leases.remove(stream_id);
+        self.forget_open_stream(stream_id);
+
+        Ok(())
     }
 }
 
@@ -0,0 +1,270 @@
+use std::{fmt, io, str::FromStr, time::{SystemTime, UNIX_EPOCH}};
+
+use anyhow::{anyhow, Result};
+use cloudevents::{event::SpecVersion, AttributesReader, Data, Event};
+use regex::Regex;
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::db::{CORRECTED_BY_EXTENSION, CORRECTS_EXTENSION};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("event id {id:?} is not a valid {format}")]
+    InvalidId { id: String, format: EventIdFormat },
+    #[error("specversion {version} is not accepted by this server")]
+    UnsupportedSpecVersion { version: SpecVersion },
+    #[error("event time {time} is more than {max_seconds} seconds in the future")]
+    TimeInFuture { time: String, max_seconds: u64 },
+    #[error("event time {time} is more than {max_seconds} seconds in the past")]
+    TimeInPast { time: String, max_seconds: u64 },
+    #[error("event data is nested more than {max_depth} levels deep")]
+    DataTooDeep { max_depth: usize },
+    #[error("event data is {bytes} bytes of JSON, more than the maximum of {max_bytes} bytes accepted by this server")]
+    DataTooLarge { bytes: usize, max_bytes: usize },
+    #[error("extension {name} is set by the server and can't be posted")]
+    ReservedExtension { name: &'static str },
+}
+
+impl Error {
+    /// The CloudEvents attribute that failed validation.
+    pub fn attribute(&self) -> &'static str {
+        match self {
+            Error::InvalidId { .. } => "id",
+            Error::UnsupportedSpecVersion { .. } => "specversion",
+            Error::TimeInFuture { .. } | Error::TimeInPast { .. } => "time",
+            Error::DataTooDeep { .. } | Error::DataTooLarge { .. } => "data",
+            Error::ReservedExtension { name } => name,
+        }
+    }
+}
+
+/// A format that event `id` attributes can be required to follow.
+#[derive(Clone, Debug)]
+pub enum EventIdFormat {
+    Uuid,
+    Ulid,
+    Regex(Regex),
+}
+
+impl EventIdFormat {
+    pub fn matches(&self, id: &str) -> bool {
+        match self {
+            EventIdFormat::Uuid => Uuid::parse_str(id).is_ok(),
+            EventIdFormat::Ulid => is_ulid(id),
+            EventIdFormat::Regex(regex) => regex.is_match(id),
+        }
+    }
+}
+
+impl FromStr for EventIdFormat {
+    type Err = anyhow::Error;
+
+    /// Parses `uuid`, `ulid`, or `regex:<pattern>`.
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "uuid" => Ok(EventIdFormat::Uuid),
+            "ulid" => Ok(EventIdFormat::Ulid),
+            _ => {
+                let pattern = format.strip_prefix("regex:")
+                    .ok_or_else(|| anyhow!("Expected uuid, ulid, or regex:<pattern> but got {:?}", format))?;
+
+                // Anchor the pattern so it has to match the whole id.
+                let regex = Regex::new(&format!("^(?:{})$", pattern))?;
+
+                Ok(EventIdFormat::Regex(regex))
+            }
+        }
+    }
+}
+
+impl fmt::Display for EventIdFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EventIdFormat::Uuid => write!(f, "UUID"),
+            EventIdFormat::Ulid => write!(f, "ULID"),
+            EventIdFormat::Regex(regex) => write!(f, "match for {}", regex),
+        }
+    }
+}
+
+fn is_ulid(id: &str) -> bool {
+    const CROCKFORD_BASE32: &str = "0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+    // The first character only carries three bits, so anything above 7 overflows 128 bits.
+    id.len() == 26
+        && id.starts_with(|c: char| ('0'..='7').contains(&c))
+        && id.chars().all(|c| CROCKFORD_BASE32.contains(c.to_ascii_uppercase()))
+}
+
+/// Checks an event against the policies enabled in `config`, including the limits on its
+/// data, for events that weren't checked with `validate_data` as they were posted.
+pub fn validate_event(config: &Config, event: &Event) -> Result<(), Error> {
+    // Only the server links corrections to the events they correct.
+    for name in [CORRECTS_EXTENSION, CORRECTED_BY_EXTENSION] {
+        if event.extension(name).is_some() {
+            return Err(Error::ReservedExtension { name });
+        }
+    }
+
+    if !config.spec_versions.contains(&event.specversion()) {
+        return Err(Error::UnsupportedSpecVersion { version: event.specversion() });
+    }
+
+    if let Some(format) = &config.event_id_format {
+        if !format.matches(event.id()) {
+            return Err(Error::InvalidId { id: event.id().to_string(), format: format.clone() });
+        }
+    }
+
+    if let Some(time) = event.time() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since_epoch| since_epoch.as_secs() as i64);
+        let offset = time.timestamp() - now;
+
+        if let Some(max_skew) = config.max_clock_skew {
+            if offset > max_skew.as_secs() as i64 {
+                return Err(Error::TimeInFuture { time: time.to_rfc3339(), max_seconds: max_skew.as_secs() });
+            }
+        }
+
+        if let Some(max_age) = config.max_event_age {
+            if -offset > max_age.as_secs() as i64 {
+                return Err(Error::TimeInPast { time: time.to_rfc3339(), max_seconds: max_age.as_secs() });
+            }
+        }
+    }
+
+    match event.data() {
+        Some(Data::Json(data)) => validate_data(config, data),
+        Some(Data::String(data)) => validate_data(config, &Value::String(data.clone())),
+        Some(Data::Binary(data)) => {
+            // Binary data is written as a base64 string, which never needs escaping.
+            let bytes = data.len().div_ceil(3) * 4 + 2;
+
+            match config.max_data_bytes {
+                Some(max_bytes) if bytes > max_bytes => Err(Error::DataTooLarge { bytes, max_bytes }),
+                _ => Ok(()),
+            }
+        },
+        None => Ok(()),
+    }
+}
+
+/// Checks an event's `data`, as posted, against the depth and size limits in `config`.
+pub fn validate_data(config: &Config, data: &Value) -> Result<(), Error> {
+    if let Some(max_depth) = config.max_data_depth {
+        if exceeds_depth(data, max_depth) {
+            return Err(Error::DataTooDeep { max_depth });
+        }
+    }
+
+    if let Some(max_bytes) = config.max_data_bytes {
+        let mut counter = ByteCounter(0);
+        // Writing to a counter can't fail.
+        serde_json::to_writer(&mut counter, data).expect("Failed to measure event data");
+
+        if counter.0 > max_bytes {
+            return Err(Error::DataTooLarge { bytes: counter.0, max_bytes });
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether arrays and objects in `value` are nested more than `max_depth` levels deep, where
+/// a scalar is zero levels and `[]` is one.
+fn exceeds_depth(value: &Value, max_depth: usize) -> bool {
+    let children: Box<dyn Iterator<Item = &Value>> = match value {
+        Value::Array(values) => Box::new(values.iter()),
+        Value::Object(members) => Box::new(members.values()),
+        _ => return false,
+    };
+
+    max_depth == 0 || children.into_iter().any(|child| exceeds_depth(child, max_depth - 1))
+}
+
+struct ByteCounter(usize);
+
+impl io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cloudevents::{EventBuilder, EventBuilderV10};
+
+    use super::*;
+
+    #[test]
+    fn uuid_format() {
+        let format: EventIdFormat = "uuid".parse().unwrap();
+
+        assert!(format.matches("0191d1b4-7f4c-7c3a-9a8e-2f6f4b1c2d3e"));
+        assert!(!format.matches("A234-1234-1234"));
+    }
+
+    #[test]
+    fn ulid_format() {
+        let format: EventIdFormat = "ulid".parse().unwrap();
+
+        assert!(format.matches("01ARZ3NDEKTSV4RRFFQ69G5FAV"));
+        assert!(!format.matches("81ARZ3NDEKTSV4RRFFQ69G5FAV"));
+        assert!(!format.matches("01ARZ3NDEKTSV4RRFFQ69G5FAU!"));
+    }
+
+    #[test]
+    fn regex_format_matches_whole_id() {
+        let format: EventIdFormat = "regex:evt-[0-9]+".parse().unwrap();
+
+        assert!(format.matches("evt-42"));
+        assert!(!format.matches("xevt-42"));
+    }
+
+    #[test]
+    fn data_depth_counts_arrays_and_objects() {
+        let config = Config { max_data_depth: Some(2), ..Default::default() };
+
+        assert!(validate_data(&config, &serde_json::json!("scalar")).is_ok());
+        assert!(validate_data(&config, &serde_json::json!({"a": [1, 2]})).is_ok());
+        assert!(matches!(
+            validate_data(&config, &serde_json::json!({"a": [{}]})),
+            Err(Error::DataTooDeep { max_depth: 2 }),
+        ));
+    }
+
+    #[test]
+    fn decoded_events_are_held_to_the_data_limits() {
+        let config = Config { max_data_depth: Some(1), max_data_bytes: Some(16), ..Default::default() };
+        let event = |data: Data| EventBuilderV10::new()
+            .id("1").source("test").ty("test")
+            .data("application/json", data)
+            .build().unwrap();
+
+        assert!(validate_event(&config, &event(Data::Json(serde_json::json!({"a": 1})))).is_ok());
+        assert!(matches!(
+            validate_event(&config, &event(Data::Json(serde_json::json!({"a": [1]})))),
+            Err(Error::DataTooDeep { max_depth: 1 }),
+        ));
+        assert!(matches!(
+            validate_event(&config, &event(Data::String("x".repeat(15)))),
+            Err(Error::DataTooLarge { bytes: 17, max_bytes: 16 }),
+        ));
+        assert!(matches!(
+            validate_event(&config, &event(Data::Binary(vec![0; 12]))),
+            Err(Error::DataTooLarge { bytes: 18, max_bytes: 16 }),
+        ));
+    }
+
+    #[test]
+    fn unknown_format_is_rejected() {
+        assert!("snowflake".parse::<EventIdFormat>().is_err());
+    }
+}